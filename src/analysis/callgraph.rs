@@ -0,0 +1,198 @@
+//! The call graph of a program
+//!
+//! There is a node for each function defined in the program, with an edge to each function it
+//! calls directly. Taking the address of a function with `@name` is recorded separately, since
+//! the function may then be called from anywhere through a function pointer. Recursion is found
+//! by splitting the graph into strongly connected components with Tarjan's algorithm
+
+use std::{collections::HashMap, rc::Rc};
+
+use crate::ir::{Callee, Instruction, TopLevel};
+
+/// The functions defined in a program and how they refer to each other
+#[derive(Debug, Clone)]
+pub struct CallGraph {
+    /// Index into the program of the function of each node, in the order of the program
+    pub functions: Vec<usize>,
+    node_of: HashMap<Rc<String>, usize>,
+    /// Nodes each node calls directly, sorted
+    pub callees: Vec<Vec<usize>>,
+    /// Nodes that call each node directly, sorted
+    pub callers: Vec<Vec<usize>>,
+    /// Nodes whose addresses each node takes, sorted
+    pub address_refs: Vec<Vec<usize>>,
+    /// Whether a function pointer to each node is taken anywhere
+    pub address_taken: Vec<bool>,
+    /// Strongly connected components, every component comes after the ones it calls
+    pub sccs: Vec<Vec<usize>>,
+    scc_of: Vec<usize>,
+}
+impl CallGraph {
+    pub fn new(program: &[TopLevel]) -> Self {
+        let functions: Vec<usize> = program
+            .iter()
+            .enumerate()
+            .filter(|(_, top_level)| matches!(top_level, TopLevel::Fn { .. }))
+            .map(|(i, _)| i)
+            .collect();
+        let node_of: HashMap<Rc<String>, usize> = functions
+            .iter()
+            .enumerate()
+            .map(|(node, &i)| match &program[i] {
+                TopLevel::Fn { name, .. } => (name.clone(), node),
+                _ => unreachable!(),
+            })
+            .collect();
+        let mut callees = vec![Vec::new(); functions.len()];
+        let mut address_refs = vec![Vec::new(); functions.len()];
+        for (node, &i) in functions.iter().enumerate() {
+            let TopLevel::Fn { body, .. } = &program[i] else {
+                unreachable!()
+            };
+            for instruction in body {
+                collect_refs(
+                    instruction,
+                    &node_of,
+                    &mut callees[node],
+                    &mut address_refs[node],
+                );
+            }
+            callees[node].sort_unstable();
+            callees[node].dedup();
+            address_refs[node].sort_unstable();
+            address_refs[node].dedup();
+        }
+        let mut callers = vec![Vec::new(); functions.len()];
+        let mut address_taken = vec![false; functions.len()];
+        for node in 0..functions.len() {
+            for &callee in &callees[node] {
+                callers[callee].push(node);
+            }
+            for &referred in &address_refs[node] {
+                address_taken[referred] = true;
+            }
+        }
+        let mut graph = Self {
+            functions,
+            node_of,
+            callees,
+            callers,
+            address_refs,
+            address_taken,
+            sccs: Vec::new(),
+            scc_of: Vec::new(),
+        };
+        graph.find_sccs();
+        graph
+    }
+    fn find_sccs(&mut self) {
+        struct Tarjan<'a> {
+            callees: &'a [Vec<usize>],
+            index: Vec<Option<usize>>,
+            low_link: Vec<usize>,
+            on_stack: Vec<bool>,
+            stack: Vec<usize>,
+            next_index: usize,
+            sccs: Vec<Vec<usize>>,
+        }
+        impl Tarjan<'_> {
+            fn visit(&mut self, node: usize) {
+                self.index[node] = Some(self.next_index);
+                self.low_link[node] = self.next_index;
+                self.next_index += 1;
+                self.stack.push(node);
+                self.on_stack[node] = true;
+                for &callee in &self.callees[node] {
+                    match self.index[callee] {
+                        None => {
+                            self.visit(callee);
+                            self.low_link[node] = self.low_link[node].min(self.low_link[callee]);
+                        }
+                        Some(index) if self.on_stack[callee] => {
+                            self.low_link[node] = self.low_link[node].min(index);
+                        }
+                        Some(_) => (),
+                    }
+                }
+                if Some(self.low_link[node]) == self.index[node] {
+                    let mut scc = Vec::new();
+                    loop {
+                        let member = self.stack.pop().unwrap();
+                        self.on_stack[member] = false;
+                        scc.push(member);
+                        if member == node {
+                            break;
+                        }
+                    }
+                    scc.sort_unstable();
+                    self.sccs.push(scc);
+                }
+            }
+        }
+        let len = self.functions.len();
+        let mut tarjan = Tarjan {
+            callees: &self.callees,
+            index: vec![None; len],
+            low_link: vec![0; len],
+            on_stack: vec![false; len],
+            stack: Vec::new(),
+            next_index: 0,
+            sccs: Vec::new(),
+        };
+        for node in 0..len {
+            if tarjan.index[node].is_none() {
+                tarjan.visit(node);
+            }
+        }
+        self.scc_of = vec![0; len];
+        for (i, scc) in tarjan.sccs.iter().enumerate() {
+            for &node in scc {
+                self.scc_of[node] = i;
+            }
+        }
+        self.sccs = tarjan.sccs;
+    }
+    pub fn len(&self) -> usize {
+        self.functions.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.functions.is_empty()
+    }
+    /// Node of the function named `name`, `None` if it's not defined in the program
+    pub fn node_of(&self, name: &Rc<String>) -> Option<usize> {
+        self.node_of.get(name).copied()
+    }
+    /// Index of the strongly connected component of the node in `sccs`
+    pub fn scc_of(&self, node: usize) -> usize {
+        self.scc_of[node]
+    }
+    /// Whether the function may call itself, directly or through other functions
+    pub fn is_recursive(&self, node: usize) -> bool {
+        self.sccs[self.scc_of[node]].len() > 1 || self.callees[node].contains(&node)
+    }
+    /// Nodes in an order where every function comes after the ones it calls, unless they call
+    /// each other
+    pub fn bottom_up(&self) -> impl Iterator<Item = usize> + '_ {
+        self.sccs.iter().flatten().copied()
+    }
+}
+
+/// Add the functions called directly and the functions whose addresses are taken in the operand
+fn collect_refs(
+    operand: &Instruction,
+    node_of: &HashMap<Rc<String>, usize>,
+    callees: &mut Vec<usize>,
+    address_refs: &mut Vec<usize>,
+) {
+    match operand {
+        Instruction::Call {
+            callee: Callee::Direct(name),
+            ..
+        } => callees.extend(node_of.get(name)),
+        Instruction::GlobalPtr(name) => address_refs.extend(node_of.get(name)),
+        _ => (),
+    }
+    for operand in operand.operands() {
+        collect_refs(operand, node_of, callees, address_refs);
+    }
+}
@@ -0,0 +1,154 @@
+//! Control flow graph of a function body
+
+use std::{collections::HashMap, ops::Range};
+
+use crate::ir::Instruction;
+
+/// A straight line of instructions that is only entered at the top and left at the bottom
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock {
+    /// The label at the start of the block
+    /// `None` for an entry block that doesn't start with a label, or instructions after a
+    /// terminator that can never be reached
+    pub label: Option<String>,
+    /// Indices of the instructions in the body, not including the label
+    pub range: Range<usize>,
+    pub preds: Vec<usize>,
+    pub succs: Vec<usize>,
+}
+
+/// The blocks of a function body in order, the first one is the entry
+/// A block that doesn't end with a terminator falls through into the next block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cfg {
+    pub blocks: Vec<BasicBlock>,
+    labels: HashMap<String, usize>,
+}
+impl Cfg {
+    pub fn new(body: &[Instruction]) -> Self {
+        let mut blocks = Vec::<BasicBlock>::new();
+        let mut label = None::<String>;
+        let mut start = 0;
+        for (i, instruction) in body.iter().enumerate() {
+            match instruction {
+                Instruction::Label(name) => {
+                    // The only block that can be empty without a label is the entry, which then
+                    // becomes this labelled block
+                    if label.is_some() || start != i {
+                        blocks.push(BasicBlock::new(label.take(), start..i));
+                    }
+                    label = Some(name.clone());
+                    start = i + 1;
+                }
+                _ if instruction.is_terminator() => {
+                    blocks.push(BasicBlock::new(label.take(), start..i + 1));
+                    start = i + 1;
+                }
+                _ => (),
+            }
+        }
+        if label.is_some() || start != body.len() || blocks.is_empty() {
+            blocks.push(BasicBlock::new(label, start..body.len()));
+        }
+        let labels: HashMap<String, usize> = blocks
+            .iter()
+            .enumerate()
+            .filter_map(|(i, block)| Some((block.label.clone()?, i)))
+            .collect();
+        for i in 0..blocks.len() {
+            let range = blocks[i].range.clone();
+            let mut succs: Vec<usize> = match range.clone().last().map(|last| &body[last]) {
+                Some(last) if last.is_terminator() => last
+                    .jump_targets()
+                    .into_iter()
+                    .filter_map(|label| labels.get(label).copied())
+                    .collect(),
+                _ if i + 1 < blocks.len() => vec![i + 1],
+                _ => Vec::new(),
+            };
+            succs.dedup();
+            for &succ in &succs {
+                blocks[succ].preds.push(i);
+            }
+            blocks[i].succs = succs;
+        }
+        Self { blocks, labels }
+    }
+    pub fn entry(&self) -> usize {
+        0
+    }
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+    /// The block that starts with `:label`
+    pub fn block_of_label(&self, label: &str) -> Option<usize> {
+        self.labels.get(label).copied()
+    }
+    /// Index of the block that every instruction is in, labels count as part of the block they
+    /// start
+    pub fn block_of_instructions(&self) -> Vec<usize> {
+        let mut block_of = Vec::new();
+        for (i, block) in self.blocks.iter().enumerate() {
+            block_of.resize(block.range.end, i);
+        }
+        block_of
+    }
+    /// Reachable blocks in reverse post order, every block comes before its successors except
+    /// along back edges
+    pub fn reverse_post_order(&self) -> Vec<usize> {
+        reverse_post_order(self.len(), self.entry(), |block| &self.blocks[block].succs)
+    }
+    /// Whether each block can be reached from the entry
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.len()];
+        for block in self.reverse_post_order() {
+            reachable[block] = true;
+        }
+        reachable
+    }
+}
+
+impl BasicBlock {
+    fn new(label: Option<String>, range: Range<usize>) -> Self {
+        Self {
+            label,
+            range,
+            preds: Vec::new(),
+            succs: Vec::new(),
+        }
+    }
+}
+
+/// Nodes of a graph reachable from `root` in reverse post order, the graph is given as the
+/// successors of each of its `len` nodes
+pub(crate) fn reverse_post_order<'a>(
+    len: usize,
+    root: usize,
+    succs: impl Fn(usize) -> &'a [usize],
+) -> Vec<usize> {
+    let mut visited = vec![false; len];
+    let mut post_order = Vec::with_capacity(len);
+    // Iterative DFS, each entry is a node and the index of the next successor to visit
+    let mut stack = vec![(root, 0usize)];
+    visited[root] = true;
+    while let Some((node, next)) = stack.last_mut() {
+        match succs(*node).get(*next) {
+            Some(&succ) => {
+                *next += 1;
+                if !visited[succ] {
+                    visited[succ] = true;
+                    stack.push((succ, 0));
+                }
+            }
+            None => {
+                post_order.push(*node);
+                stack.pop();
+            }
+        }
+    }
+    post_order.reverse();
+    post_order
+}
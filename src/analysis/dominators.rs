@@ -0,0 +1,204 @@
+//! Dominator trees and dominance frontiers, computed with the algorithm from Cooper, Harvey and
+//! Kennedy's "A Simple, Fast Dominance Algorithm"
+
+use super::cfg::{self, Cfg};
+
+/// Block `a` dominates block `b` if every path from the entry to `b` goes through `a`
+/// Unreachable blocks are not in the tree
+/// The same structure also holds post-dominator trees, see `DomTree::post_dominators`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DomTree {
+    root: usize,
+    /// Immediate dominator of each block, `None` for the root and unreachable blocks
+    idoms: Vec<Option<usize>>,
+    children: Vec<Vec<usize>>,
+    /// Predecessors of each block in the graph that the tree is built from
+    preds: Vec<Vec<usize>>,
+    /// Reachable blocks in reverse post order
+    reverse_post_order: Vec<usize>,
+    /// Pre-order and post-order numbers of the blocks in the tree, for constant time dominance
+    /// checks
+    pre_order: Vec<usize>,
+    post_order: Vec<usize>,
+}
+impl DomTree {
+    pub fn new(cfg: &Cfg) -> Self {
+        let preds = cfg.blocks.iter().map(|block| block.preds.clone()).collect();
+        Self::from_graph(cfg.entry(), preds, cfg.reverse_post_order())
+    }
+    /// The post-dominator tree, where `a` post-dominates `b` if every path from `b` to the exit
+    /// goes through `a`
+    /// The root is a virtual exit numbered `cfg.len()`, which every block that returns or falls
+    /// off the end flows into
+    /// Blocks that can never reach the exit, such as infinite loops, are not in the tree
+    /// Frontiers of this tree are the reverse dominance frontiers, the blocks a block is control
+    /// dependent on
+    pub fn post_dominators(cfg: &Cfg) -> Self {
+        let exit = cfg.len();
+        // The graph with every edge reversed, so the predecessors are the successors in the CFG
+        let mut preds: Vec<Vec<usize>> =
+            cfg.blocks.iter().map(|block| block.succs.clone()).collect();
+        let mut succs: Vec<Vec<usize>> =
+            cfg.blocks.iter().map(|block| block.preds.clone()).collect();
+        let mut exit_succs = Vec::new();
+        for (i, block) in cfg.blocks.iter().enumerate() {
+            if block.succs.is_empty() {
+                preds[i].push(exit);
+                exit_succs.push(i);
+            }
+        }
+        preds.push(Vec::new());
+        succs.push(exit_succs);
+        let reverse_post_order = cfg::reverse_post_order(exit + 1, exit, |block| &succs[block]);
+        Self::from_graph(exit, preds, reverse_post_order)
+    }
+    /// Build the tree of any graph given as the predecessors of each node, and the nodes
+    /// reachable from `root` in reverse post order
+    pub(crate) fn from_graph(
+        root: usize,
+        preds: Vec<Vec<usize>>,
+        reverse_post_order: Vec<usize>,
+    ) -> Self {
+        let len = preds.len();
+        let mut rpo_index = vec![usize::MAX; len];
+        for (i, &block) in reverse_post_order.iter().enumerate() {
+            rpo_index[block] = i;
+        }
+        let mut idoms: Vec<Option<usize>> = vec![None; len];
+        idoms[root] = Some(root);
+        let intersect = |idoms: &[Option<usize>], mut a: usize, mut b: usize| {
+            while a != b {
+                while rpo_index[a] > rpo_index[b] {
+                    a = idoms[a].unwrap();
+                }
+                while rpo_index[b] > rpo_index[a] {
+                    b = idoms[b].unwrap();
+                }
+            }
+            a
+        };
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &block in &reverse_post_order[1..] {
+                let new_idom = preds[block]
+                    .iter()
+                    .copied()
+                    .filter(|&pred| idoms[pred].is_some())
+                    .reduce(|a, b| intersect(&idoms, a, b));
+                if new_idom.is_some() && idoms[block] != new_idom {
+                    idoms[block] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+        idoms[root] = None;
+
+        let mut children = vec![Vec::<usize>::new(); len];
+        for &block in &reverse_post_order {
+            if let Some(idom) = idoms[block] {
+                children[idom].push(block);
+            }
+        }
+        let mut tree = Self {
+            root,
+            idoms,
+            children,
+            preds,
+            reverse_post_order,
+            pre_order: vec![usize::MAX; len],
+            post_order: vec![usize::MAX; len],
+        };
+        tree.number();
+        tree
+    }
+    /// Give every block in the tree its pre-order and post-order number
+    fn number(&mut self) {
+        let mut pre = 0;
+        let mut post = 0;
+        let mut stack = vec![(self.root, 0usize)];
+        self.pre_order[self.root] = pre;
+        pre += 1;
+        while let Some((block, next)) = stack.last_mut() {
+            match self.children[*block].get(*next) {
+                Some(&child) => {
+                    *next += 1;
+                    self.pre_order[child] = pre;
+                    pre += 1;
+                    stack.push((child, 0));
+                }
+                None => {
+                    self.post_order[*block] = post;
+                    post += 1;
+                    stack.pop();
+                }
+            }
+        }
+    }
+    pub fn root(&self) -> usize {
+        self.root
+    }
+    /// The closest block that strictly dominates `block`, `None` for the root and unreachable
+    /// blocks
+    pub fn idom(&self, block: usize) -> Option<usize> {
+        self.idoms[block]
+    }
+    /// Blocks immediately dominated by `block`
+    pub fn children(&self, block: usize) -> &[usize] {
+        &self.children[block]
+    }
+    pub fn is_reachable(&self, block: usize) -> bool {
+        self.pre_order[block] != usize::MAX
+    }
+    /// Reachable blocks in reverse post order of the graph
+    pub fn reverse_post_order(&self) -> &[usize] {
+        &self.reverse_post_order
+    }
+    /// Whether `a` dominates `b`, every block dominates itself
+    pub fn dominates(&self, a: usize, b: usize) -> bool {
+        self.is_reachable(a)
+            && self.is_reachable(b)
+            && self.pre_order[a] <= self.pre_order[b]
+            && self.post_order[a] >= self.post_order[b]
+    }
+    /// Whether `a` dominates `b` and is not `b`
+    pub fn strictly_dominates(&self, a: usize, b: usize) -> bool {
+        a != b && self.dominates(a, b)
+    }
+    /// Reachable blocks in pre-order of the tree, every block comes after its dominators
+    pub fn pre_order(&self) -> Vec<usize> {
+        let mut blocks = self.reverse_post_order.clone();
+        blocks.sort_by_key(|&block| self.pre_order[block]);
+        blocks
+    }
+    /// The dominance frontier of every block: the blocks where its dominance ends, which are
+    /// the blocks that it doesn't strictly dominate but dominates a predecessor of
+    pub fn frontiers(&self) -> Vec<Vec<usize>> {
+        let mut frontiers = vec![Vec::<usize>::new(); self.idoms.len()];
+        for &block in &self.reverse_post_order {
+            let preds = &self.preds[block];
+            // The root is also entered from outside the graph, so a single edge into it already
+            // makes it a join point
+            if preds.is_empty() || (preds.len() < 2 && block != self.root) {
+                continue;
+            }
+            for &pred in preds {
+                if !self.is_reachable(pred) {
+                    continue;
+                }
+                let mut runner = pred;
+                while Some(runner) != self.idoms[block] {
+                    if !frontiers[runner].contains(&block) {
+                        frontiers[runner].push(block);
+                    }
+                    match self.idoms[runner] {
+                        Some(idom) => runner = idom,
+                        // Reached the root, only happens when `block` is the root itself
+                        None => break,
+                    }
+                }
+            }
+        }
+        frontiers
+    }
+}
@@ -0,0 +1,147 @@
+//! Escape analysis of `alloc` slots
+//!
+//! A pointer is derived from a slot if it's the slot itself, a copy of a derived pointer, or a
+//! field or element pointer into one. The slot escapes if a pointer derived from it is used in
+//! any way other than being loaded from, stored into, or having another pointer derived from it,
+//! such as being passed to a call, stored into memory, returned or merged by a `phi`. The memory
+//! of a slot that doesn't escape can only be accessed through the loads and stores in the
+//! function itself
+
+use std::collections::{HashMap, HashSet};
+
+use crate::ir::{DataType, Instruction, TypeDefs};
+
+/// Where a pointer derived from a slot points to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlotPtr {
+    pub slot: u64,
+    /// Byte offset into the slot, `None` if it's not known at compile time
+    pub offset: Option<u64>,
+}
+
+/// The `alloc` slots of a function and the pointers derived from them
+#[derive(Debug, Clone, Default)]
+pub struct EscapeInfo {
+    /// Slots in the order they're defined in
+    slots: Vec<u64>,
+    pointers: HashMap<u64, SlotPtr>,
+    escaping: HashSet<u64>,
+}
+impl EscapeInfo {
+    pub fn new(body: &[Instruction], type_defs: &TypeDefs) -> Self {
+        let mut info = Self::default();
+        for instruction in body {
+            if let Instruction::DefReg { id, rhs } = instruction {
+                if let Instruction::Alloc(_) = rhs.as_ref() {
+                    info.slots.push(*id);
+                    info.pointers.insert(
+                        *id,
+                        SlotPtr {
+                            slot: *id,
+                            offset: Some(0),
+                        },
+                    );
+                }
+            }
+        }
+        // A pointer may be used in the body before it's defined, if the blocks aren't in the
+        // order of the dominator tree
+        loop {
+            let len = info.pointers.len();
+            for instruction in body {
+                if let Instruction::DefReg { id, rhs } = instruction {
+                    if let Some(ptr) = info.derive(rhs, type_defs) {
+                        info.pointers.insert(*id, ptr);
+                    }
+                }
+            }
+            if info.pointers.len() == len {
+                break;
+            }
+        }
+        for instruction in body {
+            match instruction {
+                Instruction::DefReg { rhs, .. } if info.derive(rhs, type_defs).is_some() => {
+                    if let Instruction::ElemPtr { index, .. } = rhs.as_ref() {
+                        info.find_escaping(index);
+                    }
+                }
+                Instruction::Store { rhs, .. } => info.find_escaping(rhs),
+                instruction => info.find_escaping(instruction),
+            }
+        }
+        info
+    }
+    /// Where the pointer defined as `rhs` points to, if it's derived from a slot
+    fn derive(&self, rhs: &Instruction, type_defs: &TypeDefs) -> Option<SlotPtr> {
+        // TODO: dynamic word size
+        let (id, offset) = match rhs {
+            Instruction::Reg(DataType::Ptr, id) => (id, Some(0)),
+            Instruction::FieldPtr { ty, id, index } => {
+                (id, Some(type_defs.offset_of(ty, *index, 8)))
+            }
+            Instruction::ElemPtr { ty, id, index } => {
+                let index = match **index {
+                    Instruction::UInt(_, u) => Some(u),
+                    Instruction::Int(_, i) => u64::try_from(i).ok(),
+                    _ => None,
+                };
+                let size = type_defs.layout(ty, 8).size;
+                (id, index.and_then(|index| index.checked_mul(size)))
+            }
+            _ => return None,
+        };
+        let base = self.pointers.get(id)?;
+        Some(SlotPtr {
+            slot: base.slot,
+            offset: base
+                .offset
+                .zip(offset)
+                .and_then(|(base, offset)| base.checked_add(offset)),
+        })
+    }
+    fn find_escaping(&mut self, operand: &Instruction) {
+        match operand {
+            Instruction::Reg(_, id)
+            | Instruction::FieldPtr { id, .. }
+            | Instruction::ElemPtr { id, .. }
+            | Instruction::VaArg { id, .. }
+            | Instruction::VaStart(id)
+            | Instruction::VaEnd(id) => {
+                if let Some(ptr) = self.pointers.get(id) {
+                    self.escaping.insert(ptr.slot);
+                }
+            }
+            _ => (),
+        }
+        for operand in operand.operands() {
+            self.find_escaping(operand);
+        }
+    }
+    /// Where the pointer in the vreg `id` points to, `None` if it's not derived from a slot
+    pub fn pointer(&self, id: u64) -> Option<SlotPtr> {
+        self.pointers.get(&id).copied()
+    }
+    /// Whether the address of the slot `slot` may leave the function
+    pub fn escapes(&self, slot: u64) -> bool {
+        self.escaping.contains(&slot)
+    }
+    /// The slot that the pointer in the vreg `id` points into, if that slot doesn't escape
+    /// The memory of such a slot can only be changed through pointers derived from it
+    pub fn non_escaping_slot(&self, id: u64) -> Option<u64> {
+        self.pointer(id)
+            .map(|ptr| ptr.slot)
+            .filter(|slot| !self.escapes(*slot))
+    }
+    /// Whether the address of any slot may leave the function
+    pub fn any_escapes(&self) -> bool {
+        !self.escaping.is_empty()
+    }
+    /// The slots that don't escape, in the order they're defined in
+    pub fn non_escaping_slots(&self) -> impl Iterator<Item = u64> + '_ {
+        self.slots
+            .iter()
+            .copied()
+            .filter(|slot| !self.escapes(*slot))
+    }
+}
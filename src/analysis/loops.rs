@@ -0,0 +1,93 @@
+//! Natural loops and how deeply they are nested
+
+use std::{cmp::Reverse, collections::BTreeMap};
+
+use super::{cfg::Cfg, dominators::DomTree};
+
+/// A natural loop, made of a header and the blocks that can reach a back edge into the header
+/// without going through the header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Loop {
+    pub header: usize,
+    /// Blocks with a back edge into the header
+    pub latches: Vec<usize>,
+    /// All the blocks in the loop including the header, sorted
+    pub blocks: Vec<usize>,
+    /// Index of the innermost loop that contains this one
+    pub parent: Option<usize>,
+    /// 1 for loops that are not inside any other loop
+    pub depth: usize,
+}
+impl Loop {
+    pub fn contains(&self, block: usize) -> bool {
+        self.blocks.binary_search(&block).is_ok()
+    }
+}
+
+/// The natural loops of a function, loops sharing a header are merged into one
+/// Irreducible loops, which are entered at more than one block, have no header that dominates
+/// the rest of the loop and are not detected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoopInfo {
+    /// Outer loops come before the loops nested inside them
+    pub loops: Vec<Loop>,
+    /// Innermost loop of each block
+    innermost: Vec<Option<usize>>,
+}
+impl LoopInfo {
+    pub fn new(cfg: &Cfg, dom_tree: &DomTree) -> Self {
+        // An edge is a back edge if its target dominates its source
+        let mut latches = BTreeMap::<usize, Vec<usize>>::new();
+        for &block in dom_tree.reverse_post_order() {
+            for &succ in &cfg.blocks[block].succs {
+                if dom_tree.dominates(succ, block) {
+                    latches.entry(succ).or_default().push(block);
+                }
+            }
+        }
+        let mut loops: Vec<Loop> = latches
+            .into_iter()
+            .map(|(header, latches)| {
+                let mut in_loop = vec![false; cfg.len()];
+                in_loop[header] = true;
+                let mut stack = latches.clone();
+                while let Some(block) = stack.pop() {
+                    if in_loop[block] {
+                        continue;
+                    }
+                    in_loop[block] = true;
+                    let preds = cfg.blocks[block].preds.iter().copied();
+                    stack.extend(preds.filter(|&pred| dom_tree.is_reachable(pred)));
+                }
+                Loop {
+                    header,
+                    latches,
+                    blocks: (0..cfg.len()).filter(|&block| in_loop[block]).collect(),
+                    parent: None,
+                    depth: 1,
+                }
+            })
+            .collect();
+        // A loop is bigger than every loop nested inside it, so after the sorting the innermost
+        // loop seen so far around a header is the parent
+        loops.sort_by_key(|l| (Reverse(l.blocks.len()), l.header));
+        let mut innermost = vec![None; cfg.len()];
+        for i in 0..loops.len() {
+            let parent = innermost[loops[i].header];
+            loops[i].parent = parent;
+            loops[i].depth = parent.map_or(1, |parent: usize| loops[parent].depth + 1);
+            for &block in &loops[i].blocks {
+                innermost[block] = Some(i);
+            }
+        }
+        Self { loops, innermost }
+    }
+    /// Index of the innermost loop that `block` is in
+    pub fn innermost_loop(&self, block: usize) -> Option<usize> {
+        self.innermost[block]
+    }
+    /// How many loops `block` is in, 0 if it's not in any loop
+    pub fn depth(&self, block: usize) -> usize {
+        self.innermost[block].map_or(0, |l| self.loops[l].depth)
+    }
+}
@@ -0,0 +1,8 @@
+//! Analyses over function bodies and the whole program, which have to be recomputed after the
+//! IR changes
+
+pub mod callgraph;
+pub mod cfg;
+pub mod dominators;
+pub mod escape;
+pub mod loops;
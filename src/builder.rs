@@ -0,0 +1,526 @@
+use std::{collections::HashMap, rc::Rc};
+
+use crate::ir::{Callee, Constant, DataType, Instruction, SymbolAttrs, TopLevel, Type};
+
+/// A virtual register produced by a `FunctionBuilder`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VReg {
+    pub id: u64,
+    pub dtype: DataType,
+}
+impl From<VReg> for Instruction {
+    fn from(vreg: VReg) -> Self {
+        Instruction::Reg(vreg.dtype, vreg.id)
+    }
+}
+
+/// A block inside a function, identified by the label at its start
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    label: String,
+}
+impl Block {
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Argument types of a function
+struct Signature {
+    args: Vec<DataType>,
+    is_variadic: bool,
+}
+
+/// Builds a program in the IR without going through the text format
+/// ```
+/// use mir::{
+///     builder::ModuleBuilder,
+///     ir::{DataType, Instruction, SymbolAttrs},
+///     printer::print_program,
+///     verifier::verify,
+/// };
+///
+/// let mut module = ModuleBuilder::new();
+/// module.extern_fn("puts", vec![DataType::Ptr], false);
+/// let mut main = module.function("main", SymbolAttrs::default(), Vec::new(), false);
+/// let s = main.string(b"hello\0".to_vec());
+/// main.call("puts", None, vec![s.into()]);
+/// main.ret(Some(Instruction::Int(DataType::I32, 0)));
+/// main.finish();
+/// let program = module.finish();
+/// assert_eq!(verify(&program), Ok(()));
+/// print!("{}", print_program(&program));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ModuleBuilder {
+    items: Vec<TopLevel>,
+    signatures: HashMap<Rc<String>, Signature>,
+}
+impl ModuleBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// `type %name = { fields }`
+    pub fn type_def(&mut self, name: &str, fields: Vec<Type>) {
+        self.items.push(TopLevel::TypeDef {
+            name: Rc::new(name.to_string()),
+            fields,
+        });
+    }
+    /// A global variable, zero-initialized if `init` is `None`
+    pub fn global(&mut self, name: &str, attrs: SymbolAttrs, ty: Type, init: Option<Constant>) {
+        self.items.push(TopLevel::Global {
+            name: Rc::new(name.to_string()),
+            attrs,
+            ty,
+            init,
+            is_const: false,
+        });
+    }
+    /// A global constant in read-only data
+    pub fn constant(&mut self, name: &str, attrs: SymbolAttrs, ty: Type, init: Constant) {
+        self.items.push(TopLevel::Global {
+            name: Rc::new(name.to_string()),
+            attrs,
+            ty,
+            init: Some(init),
+            is_const: true,
+        });
+    }
+    /// Declare a function that's defined somewhere else
+    pub fn extern_fn(&mut self, name: &str, args: Vec<DataType>, is_variadic: bool) {
+        let name = Rc::new(name.to_string());
+        self.declare(&name, &args, is_variadic);
+        self.items.push(TopLevel::Extern {
+            name,
+            args,
+            is_variadic,
+        });
+    }
+    /// Start building a function, which is added to the module by `FunctionBuilder::finish`
+    /// Calls to the function can be checked against its signature even before it's finished
+    pub fn function(
+        &mut self,
+        name: &str,
+        attrs: SymbolAttrs,
+        args: Vec<DataType>,
+        is_variadic: bool,
+    ) -> FunctionBuilder<'_> {
+        let name = Rc::new(name.to_string());
+        self.declare(&name, &args, is_variadic);
+        FunctionBuilder {
+            module: self,
+            name,
+            attrs,
+            args,
+            is_variadic,
+            body: Vec::new(),
+            next_vreg: 0,
+            next_block: 0,
+            block_start: 0,
+            terminated: false,
+        }
+    }
+    /// Finish building and returns the program
+    pub fn finish(self) -> Vec<TopLevel> {
+        self.items
+    }
+    fn declare(&mut self, name: &Rc<String>, args: &[DataType], is_variadic: bool) {
+        let signature = Signature {
+            args: args.to_vec(),
+            is_variadic,
+        };
+        if self.signatures.insert(Rc::clone(name), signature).is_some() {
+            panic!("Function `@{name}` is declared more than once");
+        }
+    }
+}
+
+/// Builds the body of one function, handing out fresh VRegs and block labels
+/// Every block has to end with `ret`, `jmp` or `br` before the next one starts
+#[derive(Debug)]
+pub struct FunctionBuilder<'a> {
+    module: &'a mut ModuleBuilder,
+    name: Rc<String>,
+    attrs: SymbolAttrs,
+    args: Vec<DataType>,
+    is_variadic: bool,
+    body: Vec<Instruction>,
+    next_vreg: u64,
+    next_block: u64,
+    /// Index of the first instruction of the current block after its label
+    block_start: usize,
+    /// Whether the current block already ends with a terminator
+    terminated: bool,
+}
+impl FunctionBuilder<'_> {
+    /// The `index`th argument of the function
+    pub fn arg(&self, index: u64) -> Instruction {
+        match self.args.get(index as usize) {
+            Some(&dtype) => Instruction::Arg(dtype, index),
+            None => panic!("`@{}` does not have an argument #{index}", self.name),
+        }
+    }
+    /// Create a new block with a unique label, `hint` is used as part of the label
+    /// The block is empty until `switch_to_block` is called with it
+    pub fn create_block(&mut self, hint: &str) -> Block {
+        let label = format!("{hint}.{}", self.next_block);
+        self.next_block += 1;
+        Block { label }
+    }
+    /// Start appending instructions into `block`
+    /// Will panic if the current block doesn't end with a terminator yet
+    pub fn switch_to_block(&mut self, block: &Block) {
+        if !self.body.is_empty() && !self.terminated {
+            panic!(
+                "Block before :{} in `@{}` does not end with a terminator",
+                block.label, self.name
+            );
+        }
+        self.body.push(Instruction::Label(block.label.clone()));
+        self.block_start = self.body.len();
+        self.terminated = false;
+    }
+    /// `%n = rhs`, for any operand that isn't covered by the other methods
+    pub fn def(&mut self, rhs: Instruction) -> VReg {
+        let dtype = rhs
+            .dtype()
+            .unwrap_or_else(|| panic!("{rhs:?} does not have a value"));
+        let id = self.fresh_vreg_id();
+        self.push(Instruction::DefReg {
+            id,
+            rhs: Box::new(rhs),
+        });
+        VReg { id, dtype }
+    }
+    pub fn add(&mut self, lhs: impl Into<Instruction>, rhs: impl Into<Instruction>) -> VReg {
+        let (dtype, lhs, rhs) = Self::check_binary("+", lhs.into(), rhs.into());
+        self.def(Instruction::Add(dtype, lhs, rhs))
+    }
+    pub fn sub(&mut self, lhs: impl Into<Instruction>, rhs: impl Into<Instruction>) -> VReg {
+        let (dtype, lhs, rhs) = Self::check_binary("-", lhs.into(), rhs.into());
+        self.def(Instruction::Sub(dtype, lhs, rhs))
+    }
+    pub fn mul(&mut self, lhs: impl Into<Instruction>, rhs: impl Into<Instruction>) -> VReg {
+        let (dtype, lhs, rhs) = Self::check_binary("*", lhs.into(), rhs.into());
+        self.def(Instruction::Mul(dtype, lhs, rhs))
+    }
+    pub fn div(&mut self, lhs: impl Into<Instruction>, rhs: impl Into<Instruction>) -> VReg {
+        let (dtype, lhs, rhs) = Self::check_binary("/", lhs.into(), rhs.into());
+        self.def(Instruction::Div(dtype, lhs, rhs))
+    }
+    pub fn and(&mut self, lhs: impl Into<Instruction>, rhs: impl Into<Instruction>) -> VReg {
+        let (dtype, lhs, rhs) = Self::check_binary("&", lhs.into(), rhs.into());
+        self.def(Instruction::And(dtype, lhs, rhs))
+    }
+    pub fn or(&mut self, lhs: impl Into<Instruction>, rhs: impl Into<Instruction>) -> VReg {
+        let (dtype, lhs, rhs) = Self::check_binary("|", lhs.into(), rhs.into());
+        self.def(Instruction::Or(dtype, lhs, rhs))
+    }
+    pub fn xor(&mut self, lhs: impl Into<Instruction>, rhs: impl Into<Instruction>) -> VReg {
+        let (dtype, lhs, rhs) = Self::check_binary("^", lhs.into(), rhs.into());
+        self.def(Instruction::Xor(dtype, lhs, rhs))
+    }
+    pub fn rem(&mut self, lhs: impl Into<Instruction>, rhs: impl Into<Instruction>) -> VReg {
+        let (dtype, lhs, rhs) = Self::check_binary("%", lhs.into(), rhs.into());
+        self.def(Instruction::Rem(dtype, lhs, rhs))
+    }
+    pub fn mul_hi(&mut self, lhs: impl Into<Instruction>, rhs: impl Into<Instruction>) -> VReg {
+        let (dtype, lhs, rhs) = Self::check_binary("mulhi", lhs.into(), rhs.into());
+        self.def(Instruction::MulHi(dtype, lhs, rhs))
+    }
+    pub fn shl(&mut self, lhs: impl Into<Instruction>, rhs: impl Into<Instruction>) -> VReg {
+        let (dtype, lhs, rhs) = Self::check_binary("<<", lhs.into(), rhs.into());
+        self.def(Instruction::Shl(dtype, lhs, rhs))
+    }
+    pub fn shr(&mut self, lhs: impl Into<Instruction>, rhs: impl Into<Instruction>) -> VReg {
+        let (dtype, lhs, rhs) = Self::check_binary(">>", lhs.into(), rhs.into());
+        self.def(Instruction::Shr(dtype, lhs, rhs))
+    }
+    /// Compare two operands of the same type, the result is a `u8` of 1 or 0
+    pub fn cmp(
+        &mut self,
+        comparison: Comparison,
+        lhs: impl Into<Instruction>,
+        rhs: impl Into<Instruction>,
+    ) -> VReg {
+        let (_, lhs, rhs) = Self::check_binary("comparison", lhs.into(), rhs.into());
+        let dtype = DataType::U8;
+        self.def(match comparison {
+            Comparison::Eq => Instruction::Eq(dtype, lhs, rhs),
+            Comparison::Ne => Instruction::Ne(dtype, lhs, rhs),
+            Comparison::Lt => Instruction::Lt(dtype, lhs, rhs),
+            Comparison::Le => Instruction::Le(dtype, lhs, rhs),
+            Comparison::Gt => Instruction::Gt(dtype, lhs, rhs),
+            Comparison::Ge => Instruction::Ge(dtype, lhs, rhs),
+        })
+    }
+    /// Allocate a stack space, returns the pointer to it
+    pub fn alloc(&mut self, ty: impl Into<Type>) -> VReg {
+        self.def(Instruction::Alloc(ty.into()))
+    }
+    pub fn load(&mut self, dtype: DataType, ptr: VReg) -> VReg {
+        Self::check_ptr(ptr);
+        self.def(Instruction::Load { id: ptr.id, dtype })
+    }
+    /// `dtype [ptr] = value`
+    pub fn store(&mut self, ptr: VReg, value: impl Into<Instruction>) {
+        Self::check_ptr(ptr);
+        let value = value.into();
+        let lhs_dtype = Self::check_operand(&value);
+        self.push(Instruction::Store {
+            lhs_dtype,
+            id: ptr.id,
+            rhs: Box::new(value),
+        });
+    }
+    /// Address of a global
+    pub fn global_ptr(&mut self, name: &str) -> VReg {
+        self.def(Instruction::GlobalPtr(Rc::new(name.to_string())))
+    }
+    /// Pointer to a string literal, add a 0 at the end of `bytes` for a NUL-terminated string
+    pub fn string(&mut self, bytes: Vec<u8>) -> VReg {
+        self.def(Instruction::String(bytes))
+    }
+    /// Address of the `index`th field of the struct, or element of the array, at `base`
+    pub fn field_ptr(&mut self, ty: Type, base: VReg, index: u64) -> VReg {
+        Self::check_ptr(base);
+        self.def(Instruction::FieldPtr {
+            ty,
+            id: base.id,
+            index,
+        })
+    }
+    /// Address of the `index`th element of type `ty` in the array at `base`
+    pub fn elem_ptr(&mut self, ty: Type, base: VReg, index: impl Into<Instruction>) -> VReg {
+        Self::check_ptr(base);
+        let index = index.into();
+        match Self::check_operand(&index) {
+            DataType::F64 | DataType::F32 => panic!("Array index {index:?} is not an integer"),
+            _ => (),
+        }
+        self.def(Instruction::ElemPtr {
+            ty,
+            id: base.id,
+            index: Box::new(index),
+        })
+    }
+    /// Call a function by name, returns the return value if `ret_type` is not `None`
+    /// Arguments are checked against the signature if the function is declared in the module
+    pub fn call(
+        &mut self,
+        name: &str,
+        ret_type: Option<DataType>,
+        args: Vec<Instruction>,
+    ) -> Option<VReg> {
+        let name = Rc::new(name.to_string());
+        if let Some(signature) = self.module.signatures.get(&name) {
+            let arg_types: Vec<DataType> = args.iter().map(Self::check_operand).collect();
+            let arity_matches = if signature.is_variadic {
+                arg_types.len() >= signature.args.len()
+            } else {
+                arg_types.len() == signature.args.len()
+            };
+            if !arity_matches || arg_types[..signature.args.len()] != signature.args[..] {
+                panic!(
+                    "Arguments {arg_types:?} do not match the signature of `@{name}`: {:?}",
+                    signature.args
+                );
+            }
+        }
+        self.push_call(ret_type, Callee::Direct(name), args)
+    }
+    /// Call a function through a function pointer
+    pub fn call_indirect(
+        &mut self,
+        fn_ptr: VReg,
+        ret_type: Option<DataType>,
+        args: Vec<Instruction>,
+    ) -> Option<VReg> {
+        Self::check_ptr(fn_ptr);
+        args.iter().for_each(|arg| {
+            Self::check_operand(arg);
+        });
+        self.push_call(ret_type, Callee::Indirect(Box::new(fn_ptr.into())), args)
+    }
+    pub fn ret(&mut self, value: Option<Instruction>) {
+        if let Some(value) = &value {
+            Self::check_operand(value);
+        }
+        self.push(Instruction::Ret(value.map(Box::new)));
+        self.terminated = true;
+    }
+    pub fn jmp(&mut self, block: &Block) {
+        self.push(Instruction::Jmp(block.label.clone()));
+        self.terminated = true;
+    }
+    /// Jump to `if_true` if `cond` is not zero, otherwise to `if_false`
+    pub fn br(&mut self, cond: impl Into<Instruction>, if_true: &Block, if_false: &Block) {
+        let cond = cond.into();
+        match Self::check_operand(&cond) {
+            DataType::F64 | DataType::F32 => panic!("Branch condition {cond:?} is not an integer"),
+            _ => (),
+        }
+        self.push(Instruction::Br {
+            cond: Box::new(cond),
+            if_true: if_true.label.clone(),
+            if_false: if_false.label.clone(),
+        });
+        self.terminated = true;
+    }
+    /// `%n = dtype phi`, with the incoming values added later by `add_incoming`, since they are
+    /// often not built yet, such as the values from the end of a loop
+    /// Has to be at the start of a block, before any instruction other than phis
+    pub fn phi(&mut self, dtype: DataType) -> VReg {
+        if !self.body[self.block_start..]
+            .iter()
+            .all(Instruction::is_phi_def)
+        {
+            panic!(
+                "Phi in `@{}` must be at the start of a block, before other instructions",
+                self.name
+            );
+        }
+        self.def(Instruction::Phi {
+            dtype,
+            incoming: Vec::new(),
+        })
+    }
+    /// Add the value that `phi` takes when control comes from `block`
+    pub fn add_incoming(&mut self, phi: VReg, block: &Block, value: impl Into<Instruction>) {
+        let value = value.into();
+        let value_dtype = Self::check_operand(&value);
+        if value_dtype != phi.dtype {
+            panic!(
+                "Incoming value {value:?} of `%{}` is {value_dtype:?}, expects {:?}",
+                phi.id, phi.dtype
+            );
+        }
+        let incoming = self
+            .body
+            .iter_mut()
+            .rev()
+            .find_map(|instruction| match instruction {
+                Instruction::DefReg { id, rhs } if *id == phi.id => match rhs.as_mut() {
+                    Instruction::Phi { incoming, .. } => Some(incoming),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .unwrap_or_else(|| panic!("`%{}` is not a phi", phi.id));
+        incoming.push((block.label.clone(), value));
+    }
+    /// `list` has to point to 24 bytes aligned to 8, such as an `alloc [3 x u64]`
+    pub fn va_start(&mut self, list: VReg) {
+        self.check_variadic();
+        Self::check_ptr(list);
+        self.push(Instruction::VaStart(list.id));
+    }
+    pub fn va_arg(&mut self, dtype: DataType, list: VReg) -> VReg {
+        self.check_variadic();
+        Self::check_ptr(list);
+        self.def(Instruction::VaArg { dtype, id: list.id })
+    }
+    pub fn va_end(&mut self, list: VReg) {
+        self.check_variadic();
+        Self::check_ptr(list);
+        self.push(Instruction::VaEnd(list.id));
+    }
+    /// Add the function to the module
+    /// Will panic if the last block doesn't end with a terminator
+    pub fn finish(self) {
+        if !self.terminated {
+            panic!(
+                "The last block of `@{}` does not end with a terminator",
+                self.name
+            );
+        }
+        self.module.items.push(TopLevel::Fn {
+            name: self.name,
+            attrs: self.attrs,
+            args: self.args,
+            is_variadic: self.is_variadic,
+            body: self.body,
+        });
+    }
+
+    fn fresh_vreg_id(&mut self) -> u64 {
+        self.next_vreg += 1;
+        self.next_vreg - 1
+    }
+    fn push(&mut self, instruction: Instruction) {
+        if self.terminated {
+            panic!(
+                "{instruction:?} is after the terminator of a block in `@{}`, start a new block first",
+                self.name
+            );
+        }
+        self.body.push(instruction);
+    }
+    fn push_call(
+        &mut self,
+        ret_type: Option<DataType>,
+        callee: Callee,
+        args: Vec<Instruction>,
+    ) -> Option<VReg> {
+        let call = Instruction::Call {
+            ret_type,
+            callee,
+            args,
+            is_tail: false,
+        };
+        match ret_type {
+            Some(_) => Some(self.def(call)),
+            None => {
+                self.push(call);
+                None
+            }
+        }
+    }
+    fn check_variadic(&self) {
+        if !self.is_variadic {
+            panic!("`@{}` is not variadic", self.name);
+        }
+    }
+    /// Returns the type of the operand, will panic if it's not an operand
+    fn check_operand(operand: &Instruction) -> DataType {
+        match operand {
+            Instruction::Arg(..)
+            | Instruction::Reg(..)
+            | Instruction::UInt(..)
+            | Instruction::Int(..)
+            | Instruction::Float(..)
+            | Instruction::String(_)
+            | Instruction::GlobalPtr(_) => operand.dtype().unwrap(),
+            _ => panic!("{operand:?} cannot be used as an operand, assign it to a VReg first"),
+        }
+    }
+    fn check_ptr(vreg: VReg) {
+        if vreg.dtype != DataType::Ptr {
+            panic!("%{} is {:?}, expects ptr", vreg.id, vreg.dtype);
+        }
+    }
+    fn check_binary(
+        op: &str,
+        lhs: Instruction,
+        rhs: Instruction,
+    ) -> (DataType, Box<Instruction>, Box<Instruction>) {
+        let lhs_dtype = Self::check_operand(&lhs);
+        let rhs_dtype = Self::check_operand(&rhs);
+        if lhs_dtype != rhs_dtype {
+            panic!("Operands of {op} have different types: {lhs_dtype:?} and {rhs_dtype:?}");
+        }
+        (lhs_dtype, Box::new(lhs), Box::new(rhs))
+    }
+}
@@ -0,0 +1,146 @@
+//! Compiling a whole program, and the optimization levels
+//!
+//! Each level is a pipeline of passes and a choice of register allocator. `-O0` runs no passes
+//! and keeps every vreg in its own stack slot, `-O1` runs the cheap scalar cleanups, `-O2` adds
+//! inlining and the more expensive passes, and `-Os` is `-O2` without the passes that trade size
+//! for speed. Every level but `-O0` turns the calls in tail position into jumps last
+
+use std::str::FromStr;
+
+use crate::{
+    fileformat::FileFormat,
+    generation::{platform::x86_64, RegAllocStrategy},
+    ir::TopLevel,
+    pass::{Pipeline, PipelineBuilder},
+    transform::{dce, gvn, inline, ipo, licm, mem2reg, sccp, strength, tailcall, unroll},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OptLevel {
+    #[default]
+    O0,
+    O1,
+    O2,
+    Os,
+}
+impl OptLevel {
+    /// The passes run at this level, more can be added to the end of it
+    pub fn pipeline(self) -> PipelineBuilder {
+        let pipeline = Pipeline::builder();
+        match self {
+            Self::O0 => pipeline,
+            Self::O1 => pipeline
+                .pass(ipo::GlobalDce)
+                .pass(mem2reg::Mem2Reg)
+                .pass(sccp::Sccp)
+                .pass(dce::Dce)
+                .pass(tailcall::TailCall),
+            Self::O2 => pipeline
+                .pass(ipo::ConstArgs)
+                .pass(inline::Inline::default())
+                .pass(ipo::GlobalDce)
+                .pass(mem2reg::Mem2Reg)
+                .pass(sccp::Sccp)
+                .pass(licm::Licm)
+                .pass(unroll::Unroll)
+                .pass(sccp::Sccp)
+                .pass(strength::StrengthReduce)
+                .pass(gvn::Gvn)
+                .pass(sccp::Sccp)
+                .pass(dce::Dse)
+                .pass(dce::Dce)
+                .pass(tailcall::TailCall),
+            // Division by a constant is a single `div`, which is smaller than the sequence that
+            // strength reduction turns it into, and unrolling only makes loops bigger
+            Self::Os => pipeline
+                .pass(ipo::ConstArgs)
+                .pass(inline::Inline {
+                    threshold: inline::SIZE_THRESHOLD,
+                })
+                .pass(ipo::GlobalDce)
+                .pass(mem2reg::Mem2Reg)
+                .pass(sccp::Sccp)
+                .pass(licm::Licm)
+                .pass(gvn::Gvn)
+                .pass(dce::Dse)
+                .pass(dce::Dce)
+                .pass(tailcall::TailCall),
+        }
+    }
+    pub fn reg_alloc(self) -> RegAllocStrategy {
+        match self {
+            Self::O0 => RegAllocStrategy::SpillAll,
+            Self::O1 | Self::O2 | Self::Os => RegAllocStrategy::LinearScan,
+        }
+    }
+}
+impl FromStr for OptLevel {
+    type Err = ();
+    /// The part after `-O`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(Self::O0),
+            "1" => Ok(Self::O1),
+            "2" => Ok(Self::O2),
+            "s" => Ok(Self::Os),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Options for turning a program into assembly
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompileOptions {
+    pub opt_level: OptLevel,
+    pub file_format: FileFormat,
+}
+impl Default for CompileOptions {
+    fn default() -> Self {
+        Self {
+            opt_level: OptLevel::default(),
+            file_format: FileFormat::Macho64,
+        }
+    }
+}
+impl CompileOptions {
+    /// Generate the assembly for a program that has already been through the pipeline
+    pub fn gen_asm(&self, program: Vec<TopLevel>) -> String {
+        let code = self.gen_model(program);
+        let mut asm = String::new();
+        x86_64::gen_asm_from_model(self.file_format, code, &mut asm).unwrap();
+        asm
+    }
+    /// Generate a relocatable object file for a program that has already been through the
+    /// pipeline, without going through an assembler
+    /// Will panic if the file format is not `Elf64`, the only one that can be written so far
+    pub fn gen_object(&self, program: Vec<TopLevel>) -> Vec<u8> {
+        if self.file_format != FileFormat::Elf64 {
+            panic!(
+                "Object files can only be written in elf64, not {:?}",
+                self.file_format
+            );
+        }
+        let code = self.gen_model(program);
+        x86_64::elf::write_elf64(&x86_64::encode::encode(&code))
+    }
+    fn gen_model(&self, program: Vec<TopLevel>) -> Vec<x86_64::Instruction> {
+        let mut code = x86_64::gen_code(program, self.opt_level.reg_alloc());
+        // Every instruction is kept as generated at `-O0`, so it's easier to follow in a debugger
+        if self.opt_level != OptLevel::O0 {
+            x86_64::peephole::peephole(&mut code);
+        }
+        code
+    }
+}
+
+/// Run the passes of the optimization level on the program and generate its assembly
+pub fn compile(mut program: Vec<TopLevel>, options: &CompileOptions) -> String {
+    options.opt_level.pipeline().build().run(&mut program);
+    options.gen_asm(program)
+}
+
+/// Run the passes of the optimization level on the program and generate an object file for it
+pub fn compile_to_object(mut program: Vec<TopLevel>, options: &CompileOptions) -> Vec<u8> {
+    options.opt_level.pipeline().build().run(&mut program);
+    options.gen_object(program)
+}
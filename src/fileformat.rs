@@ -1,3 +1,5 @@
+use std::str::FromStr;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FileFormat {
     Elf64,
@@ -11,12 +13,14 @@ impl FileFormat {
             Self::Macho64 => format!("_{}", original),
         }
     }
-    pub fn from_str(s: String) -> Option<Self> {
+}
+impl FromStr for FileFormat {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s.to_lowercase().as_str() {
-            "macho64" => Some(Self::Macho64),
-            "elf64" => Some(Self::Elf64),
-            _ => None,
+            "macho64" => Ok(Self::Macho64),
+            "elf64" => Ok(Self::Elf64),
+            _ => Err(()),
         }
     }
 }
-
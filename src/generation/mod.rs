@@ -1,5 +1,6 @@
-mod str_fmt;
 pub mod platform;
+pub mod stack_alloc;
+mod str_fmt;
 pub(crate) mod vreg_alloc;
-pub(crate) mod stack_alloc;
 
+pub use vreg_alloc::RegAllocStrategy;
@@ -1,2 +1 @@
 pub mod x86_64;
-
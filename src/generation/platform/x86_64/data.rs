@@ -0,0 +1,221 @@
+use std::{collections::HashMap, rc::Rc};
+
+use crate::ir::{
+    Constant, DataType, Instruction as IRInstruction, Linkage, SymbolAttrs, Type, TypeDefs,
+};
+
+use super::Instruction;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    Text,
+    Data,
+    Rodata,
+    Bss,
+}
+impl Section {
+    /// Every section, in the order they're emitted in
+    pub const ALL: [Section; 4] = [Section::Text, Section::Data, Section::Rodata, Section::Bss];
+    /// Position of the section in `ALL`
+    pub fn index(self) -> usize {
+        match self {
+            Section::Text => 0,
+            Section::Data => 1,
+            Section::Rodata => 2,
+            Section::Bss => 3,
+        }
+    }
+    pub fn name(self) -> &'static str {
+        match self {
+            Section::Text => ".text",
+            Section::Data => ".data",
+            Section::Rodata => ".rodata",
+            Section::Bss => ".bss",
+        }
+    }
+}
+
+/// A global variable or constant that's waiting to be put into a data section
+#[derive(Debug, Clone)]
+pub struct DataItem {
+    pub name: Rc<String>,
+    pub attrs: SymbolAttrs,
+    pub align: usize,
+    /// `None` if it's zero-initialized, in which case `size` bytes are reserved in `.bss`
+    pub bytes: Option<Vec<u8>>,
+    pub size: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+/// Globals and constants of a program, sorted into the sections they belong to
+pub struct DataSections {
+    data: Vec<DataItem>,
+    rodata: Vec<DataItem>,
+    bss: Vec<DataItem>,
+    /// Labels of the string literals that are already in `.rodata`
+    strings: HashMap<Vec<u8>, Rc<String>>,
+}
+impl DataSections {
+    /// Lay out a global and put it into `.data`, `.rodata` or `.bss`
+    /// Globals whose initial values are all zeros are put into `.bss` as well
+    pub fn add_global(
+        &mut self,
+        name: Rc<String>,
+        attrs: SymbolAttrs,
+        ty: &Type,
+        init: Option<&Constant>,
+        is_const: bool,
+        type_defs: &TypeDefs,
+    ) {
+        // TODO: dynamic word size
+        let layout = type_defs.layout(ty, 8);
+        let bytes = init.map(|init| {
+            let mut bytes = Vec::with_capacity(layout.size as usize);
+            gen_constant_bytes(ty, init, type_defs, &mut bytes);
+            bytes
+        });
+        let item = DataItem {
+            name,
+            attrs,
+            align: layout.align as usize,
+            size: layout.size as usize,
+            bytes,
+        };
+        if is_const {
+            self.rodata.push(item);
+        } else if item.bytes.iter().flatten().all(|&b| b == 0) {
+            self.bss.push(DataItem {
+                bytes: None,
+                ..item
+            });
+        } else {
+            self.data.push(item);
+        }
+    }
+    /// Put a string literal into `.rodata` if there isn't an identical one already, returns the
+    /// label of the string
+    pub fn add_string(&mut self, bytes: &[u8]) -> Rc<String> {
+        if let Some(name) = self.strings.get(bytes) {
+            return Rc::clone(name);
+        }
+        let name = Rc::new(format!("__str.{}", self.strings.len()));
+        self.strings.insert(bytes.to_vec(), Rc::clone(&name));
+        self.rodata.push(DataItem {
+            name: Rc::clone(&name),
+            attrs: SymbolAttrs {
+                linkage: Linkage::Private,
+                ..Default::default()
+            },
+            align: 1,
+            bytes: Some(bytes.to_vec()),
+            size: bytes.len(),
+        });
+        name
+    }
+    /// Replace all the string literals in a function body with pointers to their copies in
+    /// `.rodata`
+    pub fn lower_string_literals(&mut self, body: &mut [IRInstruction]) {
+        fn lower(data_sections: &mut DataSections, instruction: &mut IRInstruction) {
+            if let IRInstruction::String(bytes) = instruction {
+                *instruction = IRInstruction::GlobalPtr(data_sections.add_string(bytes));
+                return;
+            }
+            for operand in instruction.operands_mut() {
+                lower(data_sections, operand);
+            }
+        }
+        for instruction in body {
+            lower(self, instruction);
+        }
+    }
+    /// Generate the data sections after the code
+    pub fn gen_code(self, target: &mut Vec<Instruction>) {
+        for (section, items) in [
+            (Section::Data, self.data),
+            (Section::Rodata, self.rodata),
+            (Section::Bss, self.bss),
+        ] {
+            if items.is_empty() {
+                continue;
+            }
+            target.push(Instruction::Section(section));
+            for item in items {
+                target.push(Instruction::Align(item.align));
+                target.push(Instruction::GlobalLabel(item.name, item.attrs));
+                match item.bytes {
+                    Some(bytes) => target.push(Instruction::Bytes(bytes)),
+                    None => target.push(Instruction::Reserve(item.size)),
+                }
+            }
+        }
+    }
+}
+
+/// Generate the little-endian bytes of a constant of type `ty`, including all the paddings
+/// Will panic if the shape of the constant does not match the type
+pub fn gen_constant_bytes(
+    ty: &Type,
+    constant: &Constant,
+    type_defs: &TypeDefs,
+    bytes: &mut Vec<u8>,
+) {
+    // TODO: dynamic word size
+    let start = bytes.len();
+    match (ty, constant) {
+        (Type::Scalar(dtype), Constant::Int(i)) => {
+            let size = dtype.size(8) as usize;
+            match dtype {
+                DataType::F64 => bytes.extend_from_slice(&(*i as i64 as f64).to_le_bytes()),
+                DataType::F32 => bytes.extend_from_slice(&(*i as i64 as f32).to_le_bytes()),
+                _ => bytes.extend_from_slice(&i.to_le_bytes()[..size]),
+            }
+        }
+        (Type::Scalar(DataType::F64), Constant::Float(f)) => {
+            bytes.extend_from_slice(&f.to_le_bytes())
+        }
+        (Type::Scalar(DataType::F32), Constant::Float(f)) => {
+            bytes.extend_from_slice(&(*f as f32).to_le_bytes())
+        }
+        (Type::Struct(name), Constant::Aggregate(fields)) => {
+            let field_types = type_defs.fields(name).unwrap();
+            if field_types.len() != fields.len() {
+                panic!(
+                    "Struct %{name} has {} fields, but {} are given",
+                    field_types.len(),
+                    fields.len()
+                );
+            }
+            let layout = type_defs.layout(ty, 8);
+            for ((field_type, field), offset) in field_types
+                .iter()
+                .zip(fields)
+                .zip(layout.field_offsets.iter())
+            {
+                bytes.resize(start + *offset as usize, 0);
+                gen_constant_bytes(field_type, field, type_defs, bytes);
+            }
+        }
+        (Type::Array(elem_type, count), Constant::Aggregate(elems)) => {
+            if elems.len() as u64 > *count {
+                panic!("Too many elements for an array of {count}");
+            }
+            for elem in elems {
+                gen_constant_bytes(elem_type, elem, type_defs, bytes);
+            }
+        }
+        (Type::Array(elem_type, count), Constant::Bytes(str_bytes))
+            if matches!(elem_type.as_scalar(), Some(DataType::U8 | DataType::I8)) =>
+        {
+            if str_bytes.len() as u64 > *count {
+                panic!(
+                    "String of {} bytes is too long for an array of {count}",
+                    str_bytes.len()
+                );
+            }
+            bytes.extend_from_slice(str_bytes);
+        }
+        (ty, constant) => panic!("{constant:?} is not a valid constant of type {ty:?}"),
+    }
+    // Paddings at the end of structs, and the uninitialized rest of arrays
+    bytes.resize(start + type_defs.layout(ty, 8).size as usize, 0);
+}
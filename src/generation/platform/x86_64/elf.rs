@@ -0,0 +1,341 @@
+//! Writing an encoded program as an ELF64 relocatable object file
+//!
+//! The object has `.text`, `.data`, `.rodata` and `.bss`, in that order, followed by the
+//! relocation sections, the symbol table and the string tables. Exported symbols are global,
+//! weak ones are weak, and internal ones are local. Private symbols and the labels generated by
+//! the compiler are left out of the symbol table, and relocations against them go through the
+//! symbol of their section instead
+
+use std::{collections::HashMap, rc::Rc};
+
+use crate::ir::{Linkage, Visibility};
+
+use super::{
+    encode::{Object, RelocationKind},
+    Section,
+};
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+
+const SHF_WRITE: u64 = 0x1;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STB_WEAK: u8 = 2;
+
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+
+const R_X86_64_PC32: u32 = 2;
+const R_X86_64_PLT32: u32 = 4;
+
+/// Index of the first section in `Section::ALL` order, after the null section
+const FIRST_SECTION: usize = 1;
+
+/// An entry of the symbol table
+struct ElfSymbol {
+    name: u32,
+    info: u8,
+    other: u8,
+    section: u16,
+    value: u64,
+}
+
+/// A section other than the null section
+struct ElfSection {
+    name: u32,
+    kind: u32,
+    flags: u64,
+    contents: Vec<u8>,
+    /// Differs from the size of `contents` for `.bss`
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entry_size: u64,
+}
+
+/// Names of sections or symbols, each ending with a null byte
+struct StringTable {
+    bytes: Vec<u8>,
+}
+impl StringTable {
+    fn new() -> Self {
+        Self { bytes: vec![0] }
+    }
+    fn add(&mut self, name: &str) -> u32 {
+        let index = self.bytes.len() as u32;
+        self.bytes.extend_from_slice(name.as_bytes());
+        self.bytes.push(0);
+        index
+    }
+}
+
+/// Lay out the object as an ELF64 relocatable file for x86_64
+pub fn write_elf64(object: &Object) -> Vec<u8> {
+    let mut section_names = StringTable::new();
+    let mut symbol_names = StringTable::new();
+
+    // Local symbols have to come before the others
+    let mut symbols = vec![ElfSymbol {
+        name: 0,
+        info: 0,
+        other: 0,
+        section: 0,
+        value: 0,
+    }];
+    for section in Section::ALL {
+        symbols.push(ElfSymbol {
+            name: 0,
+            info: STT_SECTION,
+            other: 0,
+            section: (FIRST_SECTION + section.index()) as u16,
+            value: 0,
+        });
+    }
+    // The section symbols come right after the null symbol
+    let section_symbol = |section: Section| (1 + section.index()) as u32;
+    // Index of each symbol that's in the symbol table
+    let mut indices = HashMap::<Rc<String>, u32>::new();
+    for is_local in [true, false] {
+        for symbol in &object.symbols {
+            let Some(attrs) = symbol.attrs else {
+                continue;
+            };
+            let binding = match attrs.linkage {
+                Linkage::Private => continue,
+                Linkage::Internal => STB_LOCAL,
+                Linkage::Export => STB_GLOBAL,
+                Linkage::Weak => STB_WEAK,
+            };
+            if (binding == STB_LOCAL) != is_local {
+                continue;
+            }
+            let kind = if symbol.section == Section::Text {
+                STT_FUNC
+            } else {
+                STT_OBJECT
+            };
+            let visibility = match attrs.visibility {
+                Visibility::Default => 0,
+                Visibility::Hidden => 2,
+                Visibility::Protected => 3,
+            };
+            indices.insert(Rc::clone(&symbol.name), symbols.len() as u32);
+            symbols.push(ElfSymbol {
+                name: symbol_names.add(&symbol.name),
+                info: binding << 4 | kind,
+                other: visibility,
+                section: (FIRST_SECTION + symbol.section.index()) as u16,
+                value: symbol.offset as u64,
+            });
+        }
+    }
+    let first_global = symbols
+        .iter()
+        .position(|symbol| symbol.info >> 4 != STB_LOCAL)
+        .unwrap_or(symbols.len());
+    // Symbols that are referred to but not defined anywhere are external as well
+    let undefined = object.externs.iter().chain(
+        object
+            .relocations
+            .iter()
+            .map(|relocation| &relocation.symbol),
+    );
+    for name in undefined {
+        if indices.contains_key(name) || object.symbols.iter().any(|s| &s.name == name) {
+            continue;
+        }
+        indices.insert(Rc::clone(name), symbols.len() as u32);
+        symbols.push(ElfSymbol {
+            name: symbol_names.add(name),
+            info: STB_GLOBAL << 4 | STT_NOTYPE,
+            other: 0,
+            section: 0,
+            value: 0,
+        });
+    }
+
+    let mut sections = Vec::<ElfSection>::new();
+    for section in Section::ALL {
+        let data = object.section(section);
+        let (kind, flags) = match section {
+            Section::Text => (SHT_PROGBITS, SHF_ALLOC | SHF_EXECINSTR),
+            Section::Data => (SHT_PROGBITS, SHF_ALLOC | SHF_WRITE),
+            Section::Rodata => (SHT_PROGBITS, SHF_ALLOC),
+            Section::Bss => (SHT_NOBITS, SHF_ALLOC | SHF_WRITE),
+        };
+        sections.push(ElfSection {
+            name: section_names.add(section.name()),
+            kind,
+            flags,
+            contents: data.bytes.clone(),
+            size: data.size as u64,
+            link: 0,
+            info: 0,
+            align: data.align.max(1) as u64,
+            entry_size: 0,
+        });
+    }
+    let symtab_index = (FIRST_SECTION + Section::ALL.len()) as u32;
+    let symtab_index = symtab_index
+        + Section::ALL
+            .iter()
+            .filter(|&&section| {
+                object
+                    .relocations
+                    .iter()
+                    .any(|relocation| relocation.section == section)
+            })
+            .count() as u32;
+    for section in Section::ALL {
+        let mut contents = Vec::new();
+        for relocation in &object.relocations {
+            if relocation.section != section {
+                continue;
+            }
+            let (symbol, addend) = match indices.get(&relocation.symbol) {
+                Some(&index) => (index, relocation.addend),
+                // Local labels are relative to their sections
+                None => {
+                    let symbol = object
+                        .symbols
+                        .iter()
+                        .find(|symbol| symbol.name == relocation.symbol)
+                        .unwrap();
+                    (
+                        section_symbol(symbol.section),
+                        relocation.addend + symbol.offset as i64,
+                    )
+                }
+            };
+            let kind = match relocation.kind {
+                RelocationKind::Pc32 => R_X86_64_PC32,
+                RelocationKind::Plt32 => R_X86_64_PLT32,
+            };
+            contents.extend_from_slice(&(relocation.offset as u64).to_le_bytes());
+            contents.extend_from_slice(&((symbol as u64) << 32 | kind as u64).to_le_bytes());
+            contents.extend_from_slice(&addend.to_le_bytes());
+        }
+        if contents.is_empty() {
+            continue;
+        }
+        sections.push(ElfSection {
+            name: section_names.add(&format!(".rela{}", section.name())),
+            kind: SHT_RELA,
+            flags: SHF_INFO_LINK,
+            size: contents.len() as u64,
+            contents,
+            link: symtab_index,
+            info: (FIRST_SECTION + section.index()) as u32,
+            align: 8,
+            entry_size: 24,
+        });
+    }
+    let mut symtab = Vec::with_capacity(symbols.len() * 24);
+    for symbol in &symbols {
+        symtab.extend_from_slice(&symbol.name.to_le_bytes());
+        symtab.push(symbol.info);
+        symtab.push(symbol.other);
+        symtab.extend_from_slice(&symbol.section.to_le_bytes());
+        symtab.extend_from_slice(&symbol.value.to_le_bytes());
+        // Sizes are unknown, like NASM leaves them
+        symtab.extend_from_slice(&0u64.to_le_bytes());
+    }
+    sections.push(ElfSection {
+        name: section_names.add(".symtab"),
+        kind: SHT_SYMTAB,
+        flags: 0,
+        size: symtab.len() as u64,
+        contents: symtab,
+        link: symtab_index + 1,
+        info: first_global as u32,
+        align: 8,
+        entry_size: 24,
+    });
+    sections.push(ElfSection {
+        name: section_names.add(".strtab"),
+        kind: SHT_STRTAB,
+        flags: 0,
+        size: symbol_names.bytes.len() as u64,
+        contents: symbol_names.bytes,
+        link: 0,
+        info: 0,
+        align: 1,
+        entry_size: 0,
+    });
+    // The stack doesn't need to be executable
+    sections.push(ElfSection {
+        name: section_names.add(".note.GNU-stack"),
+        kind: SHT_PROGBITS,
+        flags: 0,
+        contents: Vec::new(),
+        size: 0,
+        link: 0,
+        info: 0,
+        align: 1,
+        entry_size: 0,
+    });
+    let shstrtab_name = section_names.add(".shstrtab");
+    sections.push(ElfSection {
+        name: shstrtab_name,
+        kind: SHT_STRTAB,
+        flags: 0,
+        size: section_names.bytes.len() as u64,
+        contents: section_names.bytes,
+        link: 0,
+        info: 0,
+        align: 1,
+        entry_size: 0,
+    });
+
+    // Header, then the contents of each section, then the section headers
+    let mut file = vec![0; 64];
+    let mut offsets = Vec::with_capacity(sections.len());
+    for section in &sections {
+        file.resize(file.len().next_multiple_of(section.align as usize), 0);
+        offsets.push(file.len() as u64);
+        file.extend_from_slice(&section.contents);
+    }
+    file.resize(file.len().next_multiple_of(8), 0);
+    let section_headers = file.len() as u64;
+    // The null section
+    file.extend_from_slice(&[0; 64]);
+    for (section, offset) in sections.iter().zip(offsets) {
+        file.extend_from_slice(&section.name.to_le_bytes());
+        file.extend_from_slice(&section.kind.to_le_bytes());
+        file.extend_from_slice(&section.flags.to_le_bytes());
+        // Address, which is only known after linking
+        file.extend_from_slice(&0u64.to_le_bytes());
+        file.extend_from_slice(&offset.to_le_bytes());
+        file.extend_from_slice(&section.size.to_le_bytes());
+        file.extend_from_slice(&section.link.to_le_bytes());
+        file.extend_from_slice(&section.info.to_le_bytes());
+        file.extend_from_slice(&section.align.to_le_bytes());
+        file.extend_from_slice(&section.entry_size.to_le_bytes());
+    }
+
+    let header = &mut file[..64];
+    // Magic, 64-bit, little endian, version 1, System V ABI
+    header[..8].copy_from_slice(&[0x7F, b'E', b'L', b'F', 2, 1, 1, 0]);
+    // Relocatable file for x86_64, version 1
+    header[16..18].copy_from_slice(&1u16.to_le_bytes());
+    header[18..20].copy_from_slice(&62u16.to_le_bytes());
+    header[20..24].copy_from_slice(&1u32.to_le_bytes());
+    header[40..48].copy_from_slice(&section_headers.to_le_bytes());
+    // Size of this header and of each section header
+    header[52..54].copy_from_slice(&64u16.to_le_bytes());
+    header[58..60].copy_from_slice(&64u16.to_le_bytes());
+    header[60..62].copy_from_slice(&(sections.len() as u16 + 1).to_le_bytes());
+    header[62..64].copy_from_slice(&(sections.len() as u16).to_le_bytes());
+    file
+}
@@ -0,0 +1,878 @@
+//! Encoding of the instruction model into machine code
+//!
+//! Each instruction is encoded as it's written out by `gen_asm_from_model`, so the bytes do the
+//! same as the assembly would after going through NASM. Memory operands are encoded with the
+//! ModRM byte, a SIB byte if they have an index register or are based on `rsp` or `r12`, and the
+//! shortest displacement that fits. Globals are always addressed relative to `rip`, like under
+//! `default rel`
+//!
+//! References to labels are left as fixups until everything is encoded. The ones to labels in
+//! the same section are patched in place, and the rest become relocations for the linker, such as
+//! references from `.text` into `.rodata` and to symbols that are defined in another object file.
+//! Calls and tail jumps to global symbols are always relocations, since the linker may bind them
+//! to a definition in a shared library. Jumps to labels that are already defined use the short
+//! encoding if they're close enough, the other jumps use 32-bit displacements
+
+use std::{collections::HashMap, rc::Rc};
+
+use crate::ir::SymbolAttrs;
+
+use super::{
+    Condition, EvalTreeNode, FloatOp, Instruction, Operand, Section, X64Register, X86WordSize,
+};
+
+/// Contents of a section after encoding
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SectionData {
+    /// Empty for `.bss`, which has no contents
+    pub bytes: Vec<u8>,
+    /// Size in bytes, including `.bss`
+    pub size: usize,
+    /// Largest alignment asked for by an `align` in the section
+    pub align: usize,
+}
+
+/// A label defined in the code or data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: Rc<String>,
+    pub section: Section,
+    /// Byte offset into the section
+    pub offset: usize,
+    /// `None` for labels generated by the compiler, which are local to the object file
+    pub attrs: Option<SymbolAttrs>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocationKind {
+    /// 32-bit offset from the field to the symbol, as used by `rip` relative addresses
+    Pc32,
+    /// 32-bit offset of a call or jump to a function, which may go through the PLT
+    Plt32,
+}
+
+/// A 32-bit field that's left to the linker to fill in, with the address of `symbol` plus
+/// `addend` minus the address of the field itself
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    pub section: Section,
+    /// Byte offset of the field into the section
+    pub offset: usize,
+    pub symbol: Rc<String>,
+    pub kind: RelocationKind,
+    pub addend: i64,
+}
+
+/// Machine code and data of a program, with what's left for the linker
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Object {
+    /// `.text`, `.data`, `.rodata` and `.bss`, in the order of `Section::ALL`
+    pub sections: [SectionData; 4],
+    /// Labels in the order they're defined
+    pub symbols: Vec<Symbol>,
+    /// Symbols defined in other object files
+    pub externs: Vec<Rc<String>>,
+    pub relocations: Vec<Relocation>,
+}
+impl Object {
+    pub fn section(&self, section: Section) -> &SectionData {
+        &self.sections[section.index()]
+    }
+}
+
+/// Encode a whole program, starting in `.text`
+pub fn encode(instructions: &[Instruction]) -> Object {
+    let mut encoder = Encoder {
+        object: Object::default(),
+        section: Section::Text,
+        defined: HashMap::new(),
+        fixups: Vec::new(),
+    };
+    for instruction in instructions {
+        encoder.encode(instruction);
+    }
+    encoder.finish()
+}
+
+/// A 32-bit field that refers to a label that may not be defined yet
+#[derive(Debug, Clone)]
+struct Fixup {
+    section: Section,
+    offset: usize,
+    symbol: Rc<String>,
+    addend: i64,
+    /// Whether it's a call or tail jump, which goes through a relocation for global symbols
+    is_call: bool,
+}
+
+/// Memory operand, split into its parts
+#[derive(Debug, Clone, Default)]
+struct Address {
+    base: Option<X64Register>,
+    /// Index register and its scale
+    index: Option<(X64Register, u8)>,
+    disp: i64,
+    /// Global the address is relative to, in which case it's relative to `rip`
+    label: Option<Rc<String>>,
+}
+impl Address {
+    fn from_tree(tree: &EvalTreeNode) -> Self {
+        let mut address = Self::default();
+        address.add(tree, 1);
+        address
+    }
+    fn add(&mut self, tree: &EvalTreeNode, sign: i64) {
+        match tree {
+            EvalTreeNode::Add(lhs, rhs) => {
+                self.add(lhs, sign);
+                self.add(rhs, sign);
+            }
+            EvalTreeNode::Sub(lhs, rhs) => {
+                self.add(lhs, sign);
+                self.add(rhs, -sign);
+            }
+            EvalTreeNode::Mul(lhs, rhs) => match (lhs.as_ref(), rhs.as_ref()) {
+                (EvalTreeNode::Reg(reg), EvalTreeNode::Num(scale))
+                | (EvalTreeNode::Num(scale), EvalTreeNode::Reg(reg))
+                    if sign == 1 =>
+                {
+                    self.add_index(*reg, *scale as u8)
+                }
+                (EvalTreeNode::Num(lhs), EvalTreeNode::Num(rhs)) => {
+                    self.disp += sign * (*lhs as i64) * (*rhs as i64)
+                }
+                _ => panic!("`{tree}` cannot be encoded as an address"),
+            },
+            EvalTreeNode::Num(num) => self.disp += sign * *num as i64,
+            EvalTreeNode::Reg(reg) if sign == 1 => match self.base {
+                None => self.base = Some(*reg),
+                Some(_) => self.add_index(*reg, 1),
+            },
+            EvalTreeNode::Label(name) if sign == 1 && self.label.is_none() => {
+                self.label = Some(Rc::clone(name))
+            }
+            _ => panic!("`{tree}` cannot be encoded as an address"),
+        }
+    }
+    fn add_index(&mut self, reg: X64Register, scale: u8) {
+        if self.index.is_some() || ![1, 2, 4, 8].contains(&scale) {
+            panic!("Address has more than one index register or a scale of {scale}");
+        }
+        self.index = Some((reg, scale));
+    }
+}
+
+/// Register or memory operand of the ModRM byte
+#[derive(Debug, Clone)]
+enum Rm {
+    Reg(u8),
+    Mem(Address),
+}
+
+/// The parts of an instruction that go around its ModRM byte
+#[derive(Debug, Clone, Copy, Default)]
+struct Encoding<'a> {
+    /// Operand size and mandatory prefixes, which go before the REX prefix
+    prefixes: &'a [u8],
+    rex_w: bool,
+    /// A REX prefix is needed to address `spl`, `bpl`, `sil` and `dil`
+    force_rex: bool,
+    opcode: &'a [u8],
+}
+
+struct Encoder {
+    object: Object,
+    section: Section,
+    /// Section and offset of every label defined so far
+    defined: HashMap<Rc<String>, (Section, usize)>,
+    fixups: Vec<Fixup>,
+}
+impl Encoder {
+    fn bytes(&mut self) -> &mut Vec<u8> {
+        &mut self.object.sections[self.section.index()].bytes
+    }
+    fn offset(&self) -> usize {
+        self.object.sections[self.section.index()].size
+    }
+    fn emit(&mut self, bytes: &[u8]) {
+        if self.section == Section::Bss {
+            panic!("Cannot put code or data into `.bss`");
+        }
+        self.bytes().extend_from_slice(bytes);
+        self.object.sections[self.section.index()].size += bytes.len();
+    }
+    fn define(&mut self, name: &Rc<String>, attrs: Option<SymbolAttrs>) {
+        let offset = self.offset();
+        if self
+            .defined
+            .insert(Rc::clone(name), (self.section, offset))
+            .is_some()
+        {
+            panic!("Label `{name}` is defined more than once");
+        }
+        self.object.symbols.push(Symbol {
+            name: Rc::clone(name),
+            section: self.section,
+            offset,
+            attrs,
+        });
+    }
+    /// Emit a 32-bit field to be filled in with the offset to the label
+    fn emit_fixup(&mut self, symbol: &Rc<String>, addend: i64, is_call: bool) {
+        self.fixups.push(Fixup {
+            section: self.section,
+            offset: self.offset(),
+            symbol: Rc::clone(symbol),
+            addend,
+            is_call,
+        });
+        self.emit(&[0; 4]);
+    }
+
+    fn finish(mut self) -> Object {
+        for fixup in std::mem::take(&mut self.fixups) {
+            let is_global = |name: &Rc<String>| {
+                self.object.symbols.iter().any(|symbol| {
+                    &symbol.name == name && symbol.attrs.is_some_and(|attrs| attrs.is_global())
+                })
+            };
+            match self.defined.get(&fixup.symbol) {
+                Some(&(section, target))
+                    if section == fixup.section && !(fixup.is_call && is_global(&fixup.symbol)) =>
+                {
+                    let value = target as i64 + fixup.addend - fixup.offset as i64;
+                    let value = i32::try_from(value).expect("Jump is too far");
+                    self.object.sections[section.index()].bytes[fixup.offset..fixup.offset + 4]
+                        .copy_from_slice(&value.to_le_bytes());
+                }
+                _ => self.object.relocations.push(Relocation {
+                    section: fixup.section,
+                    offset: fixup.offset,
+                    symbol: fixup.symbol,
+                    kind: if fixup.is_call {
+                        RelocationKind::Plt32
+                    } else {
+                        RelocationKind::Pc32
+                    },
+                    addend: fixup.addend,
+                }),
+            }
+        }
+        self.object
+    }
+
+    fn encode(&mut self, instruction: &Instruction) {
+        match instruction {
+            Instruction::GlobalLabel(name, attrs) => self.define(name, Some(*attrs)),
+            Instruction::Label(name) => self.define(&Rc::new(name.clone()), None),
+            Instruction::Extern(name) => self.object.externs.push(Rc::clone(name)),
+            Instruction::Section(section) => self.section = *section,
+            Instruction::Align(align) => {
+                let section = &mut self.object.sections[self.section.index()];
+                section.align = section.align.max(*align);
+                let padding = section.size.next_multiple_of(*align) - section.size;
+                match self.section {
+                    Section::Bss => section.size += padding,
+                    // `nop`s, which may be run through
+                    Section::Text => self.emit(&vec![0x90; padding]),
+                    _ => self.emit(&vec![0; padding]),
+                }
+            }
+            Instruction::Bytes(bytes) => self.emit(bytes),
+            Instruction::Reserve(size) => match self.section {
+                Section::Bss => self.object.sections[Section::Bss.index()].size += size,
+                _ => self.emit(&vec![0; *size]),
+            },
+
+            // push rbp; mov rbp, rsp
+            Instruction::FnProlog => self.emit(&[0x55, 0x48, 0x89, 0xE5]),
+            Instruction::Ret => self.emit(&[0xC3]),
+            Instruction::Cqo => self.emit(&[0x48, 0x99]),
+            Instruction::AllocStack(depth) => self.encode_alu(
+                5,
+                &X64Register::Rsp.into(),
+                &Operand::Im((*depth as u64).to_be_bytes()),
+            ),
+            Instruction::DeallocStack(depth) => self.encode_alu(
+                0,
+                &X64Register::Rsp.into(),
+                &Operand::Im((*depth as u64).to_be_bytes()),
+            ),
+
+            Instruction::Mov(Operand::Reg(reg), Operand::Im([0, 0, 0, 0, 0, 0, 0, 0])) => {
+                self.encode_alu(6, &Operand::Reg(*reg), &Operand::Reg(*reg))
+            }
+            Instruction::Mov(lhs, rhs) => self.encode_mov(lhs, rhs),
+            Instruction::Movzx(lhs, rhs) => {
+                let (reg, size) = reg_operand(lhs);
+                let opcode: &[u8] = match rhs.word_size() {
+                    Some(X86WordSize::Byte) => &[0x0F, 0xB6],
+                    Some(X86WordSize::Word) => &[0x0F, 0xB7],
+                    _ => panic!("`movzx` from {rhs:?}"),
+                };
+                self.encode_modrm(Encoding::sized(size, opcode), reg, &rm_operand(rhs), &[]);
+            }
+            Instruction::Movsx(lhs, rhs) => {
+                let (reg, size) = reg_operand(lhs);
+                let opcode: &[u8] = match rhs.word_size() {
+                    Some(X86WordSize::Byte) => &[0x0F, 0xBE],
+                    Some(X86WordSize::Word) => &[0x0F, 0xBF],
+                    // `movsxd`
+                    Some(X86WordSize::Dword) => &[0x63],
+                    _ => panic!("`movsx` from {rhs:?}"),
+                };
+                self.encode_modrm(Encoding::sized(size, opcode), reg, &rm_operand(rhs), &[]);
+            }
+            Instruction::Lea(lhs, rhs) => {
+                let (reg, size) = reg_operand(lhs);
+                let Rm::Mem(address) = rm_operand(rhs) else {
+                    panic!("`lea` from {rhs:?}, which is not an address");
+                };
+                self.encode_modrm(Encoding::sized(size, &[0x8D]), reg, &Rm::Mem(address), &[]);
+            }
+
+            Instruction::Add(lhs, rhs) => self.encode_alu(0, lhs, rhs),
+            Instruction::Or(lhs, rhs) => self.encode_alu(1, lhs, rhs),
+            Instruction::And(lhs, rhs) => self.encode_alu(4, lhs, rhs),
+            Instruction::Sub(lhs, rhs) => self.encode_alu(5, lhs, rhs),
+            Instruction::Xor(lhs, rhs) => self.encode_alu(6, lhs, rhs),
+            Instruction::Cmp(lhs, rhs) => self.encode_alu(7, lhs, rhs),
+            Instruction::Test(lhs, rhs) => self.encode_test(lhs, rhs),
+            Instruction::Imul(lhs, rhs) => {
+                let (reg, size) = reg_operand(lhs);
+                match rhs {
+                    Operand::Im(bytes) => {
+                        let imm = i64::from_be_bytes(*bytes);
+                        if let Ok(imm) = i8::try_from(imm) {
+                            let encoding = Encoding::sized(size, &[0x6B]);
+                            self.encode_modrm(encoding, reg, &Rm::Reg(reg), &[imm as u8]);
+                        } else {
+                            let encoding = Encoding::sized(size, &[0x69]);
+                            let imm = operand_imm(imm, size);
+                            self.encode_modrm(encoding, reg, &Rm::Reg(reg), &imm);
+                        }
+                    }
+                    rhs => {
+                        let encoding = Encoding::sized(size, &[0x0F, 0xAF]);
+                        self.encode_modrm(encoding, reg, &rm_operand(rhs), &[]);
+                    }
+                }
+            }
+            Instruction::Mul(operand) => self.encode_unary(&[0xF6, 0xF7], 4, operand),
+            Instruction::ImulWide(operand) => self.encode_unary(&[0xF6, 0xF7], 5, operand),
+            Instruction::Div(operand) => self.encode_unary(&[0xF6, 0xF7], 6, operand),
+            Instruction::Idiv(operand) => self.encode_unary(&[0xF6, 0xF7], 7, operand),
+            Instruction::Shl(lhs, rhs) => self.encode_shift(4, lhs, rhs),
+            Instruction::Shr(lhs, rhs) => self.encode_shift(5, lhs, rhs),
+            Instruction::Sar(lhs, rhs) => self.encode_shift(7, lhs, rhs),
+            Instruction::Setcc(condition, operand) => {
+                if operand.word_size() != Some(X86WordSize::Byte) {
+                    panic!("`set{}` into {operand:?}", condition.suffix());
+                }
+                let encoding = Encoding {
+                    force_rex: needs_rex(operand),
+                    opcode: &[0x0F, 0x90 | condition.code()],
+                    ..Default::default()
+                };
+                self.encode_modrm(encoding, 0, &rm_operand(operand), &[]);
+            }
+
+            Instruction::Jmp(label) => self.encode_jump(&[0xEB], &[0xE9], label),
+            Instruction::Jcc(condition, label) => self.encode_jump(
+                &[0x70 | condition.code()],
+                &[0x0F, 0x80 | condition.code()],
+                label,
+            ),
+            Instruction::Call(Operand::Label(name)) => {
+                self.emit(&[0xE8]);
+                self.emit_fixup(&Rc::new(name.clone()), -4, true);
+            }
+            Instruction::Call(operand) => self.encode_unary(&[0xFF, 0xFF], 2, &qword(operand)),
+            Instruction::TailJmp(Operand::Label(name)) => {
+                self.emit(&[0xE9]);
+                self.emit_fixup(&Rc::new(name.clone()), -4, true);
+            }
+            Instruction::TailJmp(operand) => self.encode_unary(&[0xFF, 0xFF], 4, &qword(operand)),
+
+            Instruction::FloatArith(op, size, lhs, rhs) => {
+                let opcode = match op {
+                    FloatOp::Add => 0x58,
+                    FloatOp::Mul => 0x59,
+                    FloatOp::Sub => 0x5C,
+                    FloatOp::Div => 0x5E,
+                };
+                let encoding = Encoding {
+                    prefixes: if *size == X86WordSize::Qword {
+                        &[0xF2]
+                    } else {
+                        &[0xF3]
+                    },
+                    opcode: &[0x0F, opcode],
+                    ..Default::default()
+                };
+                self.encode_modrm(encoding, xmm_operand(lhs), &xmm_rm_operand(rhs), &[]);
+            }
+            Instruction::Ucomis(size, lhs, rhs) => {
+                let encoding = Encoding {
+                    prefixes: if *size == X86WordSize::Qword {
+                        &[0x66]
+                    } else {
+                        &[]
+                    },
+                    opcode: &[0x0F, 0x2E],
+                    ..Default::default()
+                };
+                self.encode_modrm(encoding, xmm_operand(lhs), &xmm_rm_operand(rhs), &[]);
+            }
+            Instruction::Movd(lhs, rhs) => self.encode_movd(false, lhs, rhs),
+            Instruction::Movq(Operand::Xmm(lhs), Operand::Xmm(rhs)) => {
+                let encoding = Encoding {
+                    prefixes: &[0xF3],
+                    opcode: &[0x0F, 0x7E],
+                    ..Default::default()
+                };
+                self.encode_modrm(encoding, *lhs, &Rm::Reg(*rhs), &[]);
+            }
+            Instruction::Movq(lhs, rhs) => self.encode_movd(true, lhs, rhs),
+            Instruction::Movaps(lhs, rhs) => {
+                let (opcode, reg, rm) = match (lhs, rhs) {
+                    (Operand::Xmm(reg), rm) => (0x28, *reg, rm),
+                    (rm, Operand::Xmm(reg)) => (0x29, *reg, rm),
+                    _ => panic!("`movaps` between {lhs:?} and {rhs:?}"),
+                };
+                let encoding = Encoding {
+                    opcode: &[0x0F, opcode],
+                    ..Default::default()
+                };
+                self.encode_modrm(encoding, reg, &xmm_rm_operand(rm), &[]);
+            }
+
+            Instruction::Push(Operand::Reg(reg)) => self.encode_push_pop(0x50, *reg),
+            Instruction::Push(Operand::Im(bytes)) => {
+                let imm = i64::from_be_bytes(*bytes);
+                match i8::try_from(imm) {
+                    Ok(imm) => self.emit(&[0x6A, imm as u8]),
+                    Err(_) => {
+                        self.emit(&[0x68]);
+                        self.emit(&imm_bytes(imm, X86WordSize::Dword));
+                    }
+                }
+            }
+            Instruction::Push(operand) => self.encode_unary(&[0xFF, 0xFF], 6, &qword(operand)),
+            Instruction::Pop(Operand::Reg(reg)) => self.encode_push_pop(0x58, *reg),
+            Instruction::Pop(operand) => self.encode_unary(&[0x8F, 0x8F], 0, &qword(operand)),
+        }
+    }
+
+    /// Emit the prefixes, opcode, ModRM byte and everything after it
+    /// `reg` goes into the reg field, which is the register operand or an opcode extension
+    fn encode_modrm(&mut self, encoding: Encoding, reg: u8, rm: &Rm, imm: &[u8]) {
+        self.emit(encoding.prefixes);
+        let (index, base) = match rm {
+            Rm::Reg(rm) => (0, *rm),
+            Rm::Mem(address) => (
+                address.index.map_or(0, |(index, _)| reg_index(index)),
+                address.base.map_or(0, reg_index),
+            ),
+        };
+        let rex = 0x40
+            | (encoding.rex_w as u8) << 3
+            | (reg >> 3 & 1) << 2
+            | (index >> 3 & 1) << 1
+            | (base >> 3 & 1);
+        if rex != 0x40 || encoding.force_rex {
+            self.emit(&[rex]);
+        }
+        self.emit(encoding.opcode);
+        let reg = (reg & 7) << 3;
+        let address = match rm {
+            Rm::Reg(rm) => {
+                self.emit(&[0xC0 | reg | rm & 7]);
+                self.emit(imm);
+                return;
+            }
+            Rm::Mem(address) => address,
+        };
+        if let Some(label) = &address.label {
+            if address.base.is_some() || address.index.is_some() {
+                panic!("`rip` relative address of `{label}` cannot have registers");
+            }
+            // The offset is from the end of the instruction, which is after the immediate
+            self.emit(&[reg | 0b101]);
+            self.emit_fixup(label, address.disp - 4 - imm.len() as i64, false);
+            self.emit(imm);
+            return;
+        }
+        let disp = i32::try_from(address.disp).expect("Displacement does not fit in 32 bits");
+        let sib = address.index.map(|(index, scale)| {
+            if index == X64Register::Rsp {
+                panic!("`rsp` cannot be an index register");
+            }
+            (scale.trailing_zeros() as u8) << 6 | (reg_index(index) & 7) << 3
+        });
+        match address.base {
+            None => {
+                // Only a displacement, or an index register without a base
+                let sib = sib.unwrap_or(0b100 << 3);
+                self.emit(&[reg | 0b100, sib | 0b101]);
+                self.emit(&disp.to_le_bytes());
+            }
+            Some(base) => {
+                let base = reg_index(base) & 7;
+                // `rbp` and `r13` can't be addressed without a displacement, that encoding means
+                // no base or `rip` relative instead
+                let (mode, disp): (u8, &[u8]) = match i8::try_from(disp) {
+                    Ok(0) if base != 0b101 => (0b00, &[]),
+                    Ok(disp) => (0b01, &[disp as u8]),
+                    Err(_) => (0b10, &disp.to_le_bytes()),
+                };
+                // `rsp` and `r12` always need a SIB byte
+                match sib {
+                    Some(sib) => self.emit(&[mode << 6 | reg | 0b100, sib | base]),
+                    None if base == 0b100 => {
+                        self.emit(&[mode << 6 | reg | 0b100, 0b100 << 3 | base])
+                    }
+                    None => self.emit(&[mode << 6 | reg | base]),
+                }
+                self.emit(disp);
+            }
+        }
+        self.emit(imm);
+    }
+
+    fn encode_mov(&mut self, lhs: &Operand, rhs: &Operand) {
+        match (lhs, rhs) {
+            (Operand::Reg(reg), Operand::Im(bytes)) => {
+                let imm = i64::from_be_bytes(*bytes);
+                let size = reg.word_size();
+                let index = reg_index(*reg);
+                let rex_b = index >> 3;
+                match size {
+                    // A 32-bit move clears the upper half, which is shorter if the value fits
+                    X86WordSize::Qword if u32::try_from(imm).is_ok() => {
+                        self.encode_mov(&reg.of_size(X86WordSize::Dword).into(), rhs)
+                    }
+                    X86WordSize::Qword if i32::try_from(imm).is_ok() => {
+                        let encoding = Encoding::sized(size, &[0xC7]);
+                        let imm = imm_bytes(imm, X86WordSize::Dword);
+                        self.encode_modrm(encoding, 0, &Rm::Reg(index), &imm);
+                    }
+                    X86WordSize::Qword => {
+                        self.emit(&[0x48 | rex_b, 0xB8 | index & 7]);
+                        self.emit(&imm.to_le_bytes());
+                    }
+                    _ => {
+                        if size == X86WordSize::Word {
+                            self.emit(&[0x66]);
+                        }
+                        if rex_b != 0 || needs_rex(lhs) {
+                            self.emit(&[0x40 | rex_b]);
+                        }
+                        let opcode = if size == X86WordSize::Byte {
+                            0xB0
+                        } else {
+                            0xB8
+                        };
+                        self.emit(&[opcode | index & 7]);
+                        self.emit(&imm_bytes(imm, size));
+                    }
+                }
+            }
+            (lhs, Operand::Im(bytes)) => {
+                let size = lhs
+                    .word_size()
+                    .unwrap_or_else(|| panic!("`mov` into {lhs:?}"));
+                let opcode = if size == X86WordSize::Byte {
+                    0xC6
+                } else {
+                    0xC7
+                };
+                let imm = operand_imm(i64::from_be_bytes(*bytes), size);
+                self.encode_modrm(Encoding::sized(size, &[opcode]), 0, &rm_operand(lhs), &imm);
+            }
+            (lhs, Operand::Reg(reg)) => self.encode_reg_rm(0x88, *reg, lhs),
+            (Operand::Reg(reg), rhs) => self.encode_reg_rm(0x8A, *reg, rhs),
+            _ => panic!("`mov` from {rhs:?} into {lhs:?}"),
+        }
+    }
+
+    /// Encode an instruction of the `add` family, `ext` is both the opcode extension of the
+    /// immediate forms and the row of the other opcodes
+    fn encode_alu(&mut self, ext: u8, lhs: &Operand, rhs: &Operand) {
+        match (lhs, rhs) {
+            (lhs, Operand::Im(bytes)) => {
+                let size = lhs
+                    .word_size()
+                    .unwrap_or_else(|| panic!("Immediate into {lhs:?}"));
+                let imm = i64::from_be_bytes(*bytes);
+                let (opcode, imm) = match i8::try_from(imm) {
+                    _ if size == X86WordSize::Byte => (0x80, imm_bytes(imm, size)),
+                    Ok(imm) => (0x83, vec![imm as u8]),
+                    Err(_) => (0x81, operand_imm(imm, size)),
+                };
+                let opcode = [opcode];
+                let encoding = Encoding {
+                    force_rex: needs_rex(lhs),
+                    ..Encoding::sized(size, &opcode)
+                };
+                self.encode_modrm(encoding, ext, &rm_operand(lhs), &imm);
+            }
+            (lhs, Operand::Reg(reg)) => self.encode_reg_rm(ext << 3, *reg, lhs),
+            (Operand::Reg(reg), rhs) => self.encode_reg_rm(ext << 3 | 2, *reg, rhs),
+            _ => panic!("Arithmetic between {lhs:?} and {rhs:?}"),
+        }
+    }
+
+    fn encode_test(&mut self, lhs: &Operand, rhs: &Operand) {
+        match (lhs, rhs) {
+            (lhs, Operand::Im(bytes)) => {
+                let size = lhs
+                    .word_size()
+                    .unwrap_or_else(|| panic!("`test` of {lhs:?}"));
+                let opcode = if size == X86WordSize::Byte {
+                    0xF6
+                } else {
+                    0xF7
+                };
+                let imm = operand_imm(i64::from_be_bytes(*bytes), size);
+                let opcode = [opcode];
+                let encoding = Encoding {
+                    force_rex: needs_rex(lhs),
+                    ..Encoding::sized(size, &opcode)
+                };
+                self.encode_modrm(encoding, 0, &rm_operand(lhs), &imm);
+            }
+            (rm, Operand::Reg(reg)) | (Operand::Reg(reg), rm) => self.encode_reg_rm(0x84, *reg, rm),
+            _ => panic!("`test` between {lhs:?} and {rhs:?}"),
+        }
+    }
+
+    /// Encode an instruction between a register and a register or memory, `opcode` is the byte
+    /// form, the other sizes are the opcode after it
+    fn encode_reg_rm(&mut self, opcode: u8, reg: X64Register, rm: &Operand) {
+        let size = reg.word_size();
+        let opcode = if size == X86WordSize::Byte {
+            opcode
+        } else {
+            opcode + 1
+        };
+        let opcode = [opcode];
+        let encoding = Encoding {
+            force_rex: needs_rex(&reg.into()) || needs_rex(rm),
+            ..Encoding::sized(size, &opcode)
+        };
+        self.encode_modrm(encoding, reg_index(reg), &rm_operand(rm), &[]);
+    }
+
+    /// Encode an instruction with one register or memory operand, and an opcode extension
+    fn encode_unary(&mut self, opcodes: &[u8; 2], ext: u8, operand: &Operand) {
+        let size = operand
+            .word_size()
+            .unwrap_or_else(|| panic!("Unsized operand {operand:?}"));
+        let opcode = if size == X86WordSize::Byte {
+            opcodes[0]
+        } else {
+            opcodes[1]
+        };
+        // Calls, jumps, pushes and pops are always 64-bit
+        let is_default_qword = opcode == 0xFF || opcode == 0x8F;
+        let opcode = [opcode];
+        let encoding = Encoding {
+            force_rex: needs_rex(operand),
+            rex_w: size == X86WordSize::Qword && !is_default_qword,
+            ..Encoding::sized(size, &opcode)
+        };
+        self.encode_modrm(encoding, ext, &rm_operand(operand), &[]);
+    }
+
+    fn encode_shift(&mut self, ext: u8, lhs: &Operand, rhs: &Operand) {
+        let size = lhs
+            .word_size()
+            .unwrap_or_else(|| panic!("Shifting {lhs:?}"));
+        let byte = size == X86WordSize::Byte;
+        let (opcode, imm) = match rhs {
+            Operand::Im(bytes) => match i64::from_be_bytes(*bytes) {
+                1 => (if byte { 0xD0 } else { 0xD1 }, None),
+                count => (if byte { 0xC0 } else { 0xC1 }, Some(count as u8)),
+            },
+            Operand::Reg(X64Register::Cl) => (if byte { 0xD2 } else { 0xD3 }, None),
+            _ => panic!("Shifting by {rhs:?}, which is not an immediate or `cl`"),
+        };
+        let opcode = [opcode];
+        let encoding = Encoding {
+            force_rex: needs_rex(lhs),
+            ..Encoding::sized(size, &opcode)
+        };
+        let imm: &[u8] = match &imm {
+            Some(imm) => std::slice::from_ref(imm),
+            None => &[],
+        };
+        self.encode_modrm(encoding, ext, &rm_operand(lhs), imm);
+    }
+
+    /// Encode a `movd`, or a `movq` between a vector register and a general purpose register or
+    /// memory
+    fn encode_movd(&mut self, is_qword: bool, lhs: &Operand, rhs: &Operand) {
+        let (opcode, xmm, rm) = match (lhs, rhs) {
+            (Operand::Xmm(xmm), rm) => (0x6E, *xmm, rm),
+            (rm, Operand::Xmm(xmm)) => (0x7E, *xmm, rm),
+            _ => panic!("Move between {lhs:?} and {rhs:?} without a vector register"),
+        };
+        let encoding = Encoding {
+            prefixes: &[0x66],
+            rex_w: is_qword,
+            opcode: &[0x0F, opcode],
+            ..Default::default()
+        };
+        self.encode_modrm(encoding, xmm, &rm_operand(rm), &[]);
+    }
+
+    fn encode_push_pop(&mut self, opcode: u8, reg: X64Register) {
+        if reg.word_size() != X86WordSize::Qword {
+            panic!("Only 64-bit registers can be pushed or popped, found `{reg}`");
+        }
+        let index = reg_index(reg);
+        if index >= 8 {
+            self.emit(&[0x41]);
+        }
+        self.emit(&[opcode | index & 7]);
+    }
+
+    /// Encode a jump to a label, short if it's already defined close enough before here
+    fn encode_jump(&mut self, short: &[u8], near: &[u8], label: &str) {
+        let label = Rc::new(label.to_string());
+        if let Some(&(section, target)) = self.defined.get(&label) {
+            let end = self.offset() + short.len() + 1;
+            if let Ok(rel) = i8::try_from(target as i64 - end as i64) {
+                if section == self.section {
+                    self.emit(short);
+                    self.emit(&[rel as u8]);
+                    return;
+                }
+            }
+        }
+        self.emit(near);
+        self.emit_fixup(&label, -4, false);
+    }
+}
+
+impl Encoding<'_> {
+    /// Encoding with the operand size prefix or REX.W for the size
+    fn sized(size: X86WordSize, opcode: &[u8]) -> Encoding<'_> {
+        Encoding {
+            prefixes: if size == X86WordSize::Word {
+                &[0x66]
+            } else {
+                &[]
+            },
+            rex_w: size == X86WordSize::Qword,
+            force_rex: false,
+            opcode,
+        }
+    }
+}
+
+impl Condition {
+    /// The condition code in the low 4 bits of `jcc` and `setcc`
+    fn code(self) -> u8 {
+        match self {
+            Self::B => 0x2,
+            Self::Ae => 0x3,
+            Self::E => 0x4,
+            Self::Ne => 0x5,
+            Self::Be => 0x6,
+            Self::A => 0x7,
+            Self::P => 0xA,
+            Self::Np => 0xB,
+            Self::L => 0xC,
+            Self::Ge => 0xD,
+            Self::Le => 0xE,
+            Self::G => 0xF,
+        }
+    }
+}
+
+/// Number of the register in the encoding, the 4th bit goes into the REX prefix
+/// `X64Register` has `rbx` before `rcx` and `rdx`, unlike the encoding
+fn reg_index(reg: X64Register) -> u8 {
+    match reg as usize & 0x0F {
+        1 => 3,
+        2 => 1,
+        3 => 2,
+        index => index as u8,
+    }
+}
+
+/// Whether the operand is `spl`, `bpl`, `sil` or `dil`, which need a REX prefix to not be `ah`,
+/// `ch`, `dh` or `bh` instead
+fn needs_rex(operand: &Operand) -> bool {
+    matches!(
+        operand,
+        Operand::Reg(X64Register::Spl | X64Register::Bpl | X64Register::Sil | X64Register::Dil)
+    )
+}
+
+fn reg_operand(operand: &Operand) -> (u8, X86WordSize) {
+    match operand {
+        Operand::Reg(reg) => (reg_index(*reg), reg.word_size()),
+        _ => panic!("{operand:?} is not a register"),
+    }
+}
+
+fn rm_operand(operand: &Operand) -> Rm {
+    match operand {
+        Operand::Reg(reg) => Rm::Reg(reg_index(*reg)),
+        Operand::Load(address) | Operand::WordPtr(_, address) => {
+            Rm::Mem(Address::from_tree(address))
+        }
+        _ => panic!("{operand:?} is not a register or memory"),
+    }
+}
+
+fn xmm_operand(operand: &Operand) -> u8 {
+    match operand {
+        Operand::Xmm(xmm) => *xmm,
+        _ => panic!("{operand:?} is not a vector register"),
+    }
+}
+
+fn xmm_rm_operand(operand: &Operand) -> Rm {
+    match operand {
+        Operand::Xmm(xmm) => Rm::Reg(*xmm),
+        operand => rm_operand(operand),
+    }
+}
+
+/// A memory operand as a qword access, for instructions that don't need its size written out
+fn qword(operand: &Operand) -> Operand {
+    match operand {
+        Operand::Load(address) => Operand::WordPtr(X86WordSize::Qword, address.clone()),
+        operand => operand.clone(),
+    }
+}
+
+/// Immediate of an instruction on an operand of the size, 64-bit operands take 32-bit immediates
+/// that are sign-extended
+fn operand_imm(imm: i64, size: X86WordSize) -> Vec<u8> {
+    match size {
+        X86WordSize::Qword => match i32::try_from(imm) {
+            Ok(imm) => imm.to_le_bytes().to_vec(),
+            Err(_) => panic!("Immediate {imm} does not fit in a sign-extended dword"),
+        },
+        size => imm_bytes(imm, size),
+    }
+}
+
+/// Little endian bytes of an immediate of the size, which has to fit
+fn imm_bytes(imm: i64, size: X86WordSize) -> Vec<u8> {
+    let fits = match size {
+        X86WordSize::Byte => i8::try_from(imm).is_ok() || u8::try_from(imm).is_ok(),
+        X86WordSize::Word => i16::try_from(imm).is_ok() || u16::try_from(imm).is_ok(),
+        X86WordSize::Dword => i32::try_from(imm).is_ok() || u32::try_from(imm).is_ok(),
+        X86WordSize::Qword => true,
+    };
+    if !fits {
+        panic!("Immediate {imm} does not fit in a {}", size.fmt_into_asm());
+    }
+    imm.to_le_bytes()[..size as usize].to_vec()
+}
@@ -1,19 +1,31 @@
+mod data;
+pub mod elf;
+pub mod encode;
+pub mod peephole;
 mod reg;
 
 use std::{
+    collections::HashMap,
     fmt::{Display, Write},
     rc::Rc,
 };
 
-use reg::X64Register;
+use data::DataSections;
+pub use data::Section;
+pub use reg::X64Register;
 
 use crate::{
     fileformat::FileFormat,
     generation::{
         stack_alloc::{StackAllocation, StackAllocator},
-        vreg_alloc::{Register, VRegAllocation},
+        str_fmt::asm_str_from,
+        vreg_alloc::{RegAllocStrategy, Register, VRegAllocation},
     },
-    ir::{DataType, Instruction as IRInstruction, TopLevel as IRTopLevel},
+    ir::{
+        Callee, DataType, Instruction as IRInstruction, Linkage, SymbolAttrs,
+        TopLevel as IRTopLevel, TypeDefs, Visibility,
+    },
+    transform::ssa::destruct_ssa,
 };
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -41,8 +53,9 @@ impl From<DataType> for X86WordSize {
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Instruction {
-    GlobalLabel(Rc<String>), // GlobalLabel are usually for functions, which are usually wrapped
-    // in Rc because of their rapid occurance
+    GlobalLabel(Rc<String>, SymbolAttrs), // GlobalLabel are usually for functions, which are
+    // usually wrapped in Rc because of their rapid occurance
+    // Internal and private ones are emitted as plain labels
     Label(String), // Labels aren't wrapped in Rc because they're mostly generated by the compiler
     FnProlog,
     Ret,
@@ -51,12 +64,66 @@ pub enum Instruction {
 
     Mov(Operand, Operand),
     Movzx(Operand, Operand),
+    Movsx(Operand, Operand),
     Lea(Operand, Operand),
 
+    Add(Operand, Operand),
+    Sub(Operand, Operand),
+    Imul(Operand, Operand),
+    /// Unsigned multiplication of `rax`, into `rdx:rax`
+    Mul(Operand),
+    /// Signed multiplication of `rax`, into `rdx:rax`
+    ImulWide(Operand),
+    /// Unsigned division of `rdx:rax`
+    Div(Operand),
+    /// Signed division of `rdx:rax`
+    Idiv(Operand),
+    /// Sign-extend `rax` into `rdx:rax`
+    Cqo,
+    And(Operand, Operand),
+    Or(Operand, Operand),
+    Xor(Operand, Operand),
+    /// Shifts by an immediate or by `cl`
+    Shl(Operand, Operand),
+    Shr(Operand, Operand),
+    Sar(Operand, Operand),
+    Cmp(Operand, Operand),
+    Test(Operand, Operand),
+    Setcc(Condition, Operand),
+
+    /// Scalar float arithmetic on the lower lanes of vector registers, `addsd` and so on for a
+    /// qword and `addss` and so on for a dword
+    FloatArith(FloatOp, X86WordSize, Operand, Operand),
+    /// Compare scalar floats, setting the flags like an unsigned `cmp`, and the parity flag if
+    /// either is NaN
+    Ucomis(X86WordSize, Operand, Operand),
+
+    Jmp(String),
+    Jcc(Condition, String),
+
+    /// Move between a general purpose register or memory and the lower half of a vector register
+    Movd(Operand, Operand),
+    Movq(Operand, Operand),
+    Movaps(Operand, Operand),
+
     Push(Operand),
     Pop(Operand),
 
-    Call(Rc<String>),
+    /// Call a function directly by its label, or indirectly through a register or memory
+    Call(Operand),
+    /// Jump to a function in place of calling it, once the caller's frame has been torn down
+    TailJmp(Operand),
+    /// Declare a symbol defined in another object file
+    Extern(Rc<String>),
+
+    /// Switch to another section
+    Section(Section),
+    /// Align the next item in a data section
+    Align(usize),
+    /// Raw bytes in a data section
+    Bytes(Vec<u8>),
+    /// Uninitialized bytes in `.bss`
+    Reserve(usize),
 }
 impl Instruction {
     /// Shorthand for `pop rbp`
@@ -65,9 +132,80 @@ impl Instruction {
     }
 }
 
+/// Condition code of a conditional jump
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    E,
+    Ne,
+    /// Less, signed
+    L,
+    Le,
+    /// Greater, signed
+    G,
+    Ge,
+    /// Below, unsigned
+    B,
+    Be,
+    /// Above, unsigned
+    A,
+    Ae,
+    /// Parity, set by `ucomisd` if the comparison is unordered
+    P,
+    Np,
+}
+impl Condition {
+    pub fn suffix(self) -> &'static str {
+        match self {
+            Self::E => "e",
+            Self::Ne => "ne",
+            Self::L => "l",
+            Self::Le => "le",
+            Self::G => "g",
+            Self::Ge => "ge",
+            Self::B => "b",
+            Self::Be => "be",
+            Self::A => "a",
+            Self::Ae => "ae",
+            Self::P => "p",
+            Self::Np => "np",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FloatOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+impl FloatOp {
+    /// Mnemonic of the operation on doubles if `size` is a qword, and on singles if it's a dword
+    pub fn mnemonic(self, size: X86WordSize) -> String {
+        let op = match self {
+            Self::Add => "add",
+            Self::Sub => "sub",
+            Self::Mul => "mul",
+            Self::Div => "div",
+        };
+        format!("{op}{}", float_suffix(size))
+    }
+}
+
+/// `sd` for a scalar double and `ss` for a scalar single
+fn float_suffix(size: X86WordSize) -> &'static str {
+    match size {
+        X86WordSize::Qword => "sd",
+        X86WordSize::Dword => "ss",
+        size => panic!("There are no {size:?} floats"),
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Operand {
     Reg(X64Register),
+    /// `xmm0` to `xmm15`
+    Xmm(u8),
     Im([u8; 8]),
     Label(String),
     Load(EvalTreeNode),                 // [ ... ]
@@ -78,15 +216,30 @@ impl Operand {
         let mut code = String::new();
         match self {
             Self::Reg(reg) => write!(code, "{}", reg)?,
-            Self::Im(bytes) => write!(code, "{}", u64::from_be_bytes(bytes.clone()))?,
+            Self::Xmm(i) => write!(code, "xmm{}", i)?,
+            Self::Im(bytes) => write!(code, "{}", u64::from_be_bytes(*bytes))?,
             Self::Label(name) => write!(code, "{}", file_format.mangle(name))?,
-            Self::Load(eval_tree) => write!(code, "[{}]", eval_tree)?,
+            Self::Load(eval_tree) => {
+                write!(code, "[")?;
+                eval_tree.write_asm(file_format, &mut code)?;
+                write!(code, "]")?;
+            }
             Self::WordPtr(size, eval_tree) => {
-                write!(code, "{} [{}]", size.fmt_into_asm(), eval_tree)?
+                write!(code, "{} [", size.fmt_into_asm())?;
+                eval_tree.write_asm(file_format, &mut code)?;
+                write!(code, "]")?;
             }
         }
         Ok(code)
     }
+    /// Size of the operand if it's a register or a sized memory access
+    pub fn word_size(&self) -> Option<X86WordSize> {
+        match self {
+            Self::Reg(reg) => Some(reg.word_size()),
+            Self::WordPtr(size, _) => Some(*size),
+            _ => None,
+        }
+    }
     /// Shorthand for making a `word [rbp - {x}]` operand
     pub fn rbp_sub(word_size: X86WordSize, loc: usize) -> Self {
         Operand::WordPtr(
@@ -113,6 +266,8 @@ pub enum EvalTreeNode {
 
     Num(u64),
     Reg(X64Register),
+    /// Address of a global, relative to `rip` because of `default rel`
+    Label(Rc<String>),
 }
 impl EvalTreeNode {
     pub fn priority(&self) -> usize {
@@ -122,6 +277,7 @@ impl EvalTreeNode {
             Self::Mul(_, _) => 1,
             Self::Num(_) => 2,
             Self::Reg(_) => 2,
+            Self::Label(_) => 2,
         }
     }
     pub fn op_char(&self) -> char {
@@ -131,35 +287,53 @@ impl EvalTreeNode {
             Self::Mul(_, _) => '*',
             Self::Num(_) => '\0',
             Self::Reg(_) => '\0',
+            Self::Label(_) => '\0',
         }
     }
 }
-impl Display for EvalTreeNode {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl EvalTreeNode {
+    /// Whether the expression depends on any registers other than `rbp`
+    pub fn uses_regs_other_than_rbp(&self) -> bool {
+        match self {
+            Self::Add(lhs, rhs) | Self::Sub(lhs, rhs) | Self::Mul(lhs, rhs) => {
+                lhs.uses_regs_other_than_rbp() || rhs.uses_regs_other_than_rbp()
+            }
+            Self::Reg(reg) => *reg != X64Register::Rbp,
+            Self::Num(_) | Self::Label(_) => false,
+        }
+    }
+    /// Write the expression in NASM syntax, with the labels mangled according to `file_format`
+    pub fn write_asm(&self, file_format: FileFormat, f: &mut dyn Write) -> std::fmt::Result {
         match self {
             Self::Add(lhs, rhs) | Self::Sub(lhs, rhs) | Self::Mul(lhs, rhs) => {
                 if lhs.priority() < self.priority() {
-                    write!(f, "({})", lhs)?;
+                    write!(f, "(")?;
+                    lhs.write_asm(file_format, f)?;
+                    write!(f, ")")?;
                 } else {
-                    lhs.fmt(f)?;
+                    lhs.write_asm(file_format, f)?;
                 }
-                self.op_char().fmt(f)?;
+                write!(f, "{}", self.op_char())?;
                 if rhs.priority() < self.priority() {
-                    write!(f, "({})", rhs)?;
+                    write!(f, "(")?;
+                    rhs.write_asm(file_format, f)?;
+                    write!(f, ")")?;
                 } else {
-                    rhs.fmt(f)?;
+                    rhs.write_asm(file_format, f)?;
                 }
             }
-            Self::Num(num) => {
-                num.fmt(f)?;
-            }
-            Self::Reg(reg) => {
-                reg.fmt(f)?;
-            }
+            Self::Num(num) => write!(f, "{}", num)?,
+            Self::Reg(reg) => write!(f, "{}", reg)?,
+            Self::Label(name) => write!(f, "{}", file_format.mangle(name))?,
         }
         Ok(())
     }
 }
+impl Display for EvalTreeNode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.write_asm(FileFormat::Elf64, f)
+    }
+}
 impl From<X64Register> for EvalTreeNode {
     fn from(reg: X64Register) -> Self {
         Self::Reg(reg)
@@ -169,7 +343,7 @@ impl X64Register {
     pub fn from_raw(raw: usize) -> Self {
         unsafe {
             let ptr = &raw as *const usize;
-            return *(ptr as *const Self);
+            *(ptr as *const Self)
         }
     }
 }
@@ -185,7 +359,6 @@ impl X64Register {
         }
         Self::from_raw(raw)
     }
-    #[allow(dead_code)]
     fn word_size(self) -> X86WordSize {
         match (self as usize) & 0xF0 {
             0x00 => X86WordSize::Qword,
@@ -240,14 +413,17 @@ pub fn gen_asm_from_model(
     instructions: Vec<Instruction>,
     target: &mut dyn Write,
 ) -> Result<(), std::fmt::Error> {
+    // So that globals are addressed relative to `rip`
+    writeln!(target, "\tdefault\trel")?;
+    let mut current_section = Section::Text;
     for instruction in instructions {
         match instruction {
-            Instruction::GlobalLabel(name) => writeln!(
-                target,
-                "\tglobal\t{}\n{}:",
-                file_format.mangle(&name),
-                file_format.mangle(&name)
-            )?,
+            Instruction::GlobalLabel(name, attrs) => {
+                if attrs.is_global() {
+                    write_global_directive(file_format, &name, attrs, current_section, target)?;
+                }
+                writeln!(target, "{}:", file_format.mangle(&name))?
+            }
             Instruction::Label(name) => writeln!(target, "{}:", file_format.mangle(&name))?,
             Instruction::FnProlog => writeln!(target, "\tpush\trbp\n\tmov\trbp, rsp")?,
             Instruction::Ret => writeln!(target, "\tret")?,
@@ -270,102 +446,511 @@ pub fn gen_asm_from_model(
                 oper0.gen_code(file_format)?,
                 oper1.gen_code(file_format)?
             )?,
+            Instruction::Movsx(oper0, oper1) => writeln!(
+                target,
+                "\t{}\t{}, {}",
+                // `movsx` doesn't take 32-bit sources, it's called `movsxd` instead
+                if oper1.word_size() == Some(X86WordSize::Dword) {
+                    "movsxd"
+                } else {
+                    "movsx"
+                },
+                oper0.gen_code(file_format)?,
+                oper1.gen_code(file_format)?
+            )?,
             Instruction::Lea(oper0, oper1) => writeln!(
                 target,
                 "\tlea\t{}, {}",
                 oper0.gen_code(file_format)?,
                 oper1.gen_code(file_format)?
             )?,
+            Instruction::Add(oper0, oper1) => writeln!(
+                target,
+                "\tadd\t{}, {}",
+                oper0.gen_code(file_format)?,
+                oper1.gen_code(file_format)?
+            )?,
+            Instruction::Sub(oper0, oper1) => writeln!(
+                target,
+                "\tsub\t{}, {}",
+                oper0.gen_code(file_format)?,
+                oper1.gen_code(file_format)?
+            )?,
+            Instruction::Div(oper0) => writeln!(target, "\tdiv\t{}", oper0.gen_code(file_format)?)?,
+            Instruction::Idiv(oper0) => {
+                writeln!(target, "\tidiv\t{}", oper0.gen_code(file_format)?)?
+            }
+            Instruction::Cqo => writeln!(target, "\tcqo")?,
+            Instruction::Mul(oper0) => writeln!(target, "\tmul\t{}", oper0.gen_code(file_format)?)?,
+            Instruction::ImulWide(oper0) => {
+                writeln!(target, "\timul\t{}", oper0.gen_code(file_format)?)?
+            }
+            Instruction::And(oper0, oper1) => writeln!(
+                target,
+                "\tand\t{}, {}",
+                oper0.gen_code(file_format)?,
+                oper1.gen_code(file_format)?
+            )?,
+            Instruction::Or(oper0, oper1) => writeln!(
+                target,
+                "\tor\t{}, {}",
+                oper0.gen_code(file_format)?,
+                oper1.gen_code(file_format)?
+            )?,
+            Instruction::Xor(oper0, oper1) => writeln!(
+                target,
+                "\txor\t{}, {}",
+                oper0.gen_code(file_format)?,
+                oper1.gen_code(file_format)?
+            )?,
+            Instruction::Shl(oper0, oper1) => writeln!(
+                target,
+                "\tshl\t{}, {}",
+                oper0.gen_code(file_format)?,
+                oper1.gen_code(file_format)?
+            )?,
+            Instruction::Shr(oper0, oper1) => writeln!(
+                target,
+                "\tshr\t{}, {}",
+                oper0.gen_code(file_format)?,
+                oper1.gen_code(file_format)?
+            )?,
+            Instruction::Sar(oper0, oper1) => writeln!(
+                target,
+                "\tsar\t{}, {}",
+                oper0.gen_code(file_format)?,
+                oper1.gen_code(file_format)?
+            )?,
+            Instruction::Test(oper0, oper1) => writeln!(
+                target,
+                "\ttest\t{}, {}",
+                oper0.gen_code(file_format)?,
+                oper1.gen_code(file_format)?
+            )?,
+            Instruction::Setcc(condition, oper0) => writeln!(
+                target,
+                "\tset{}\t{}",
+                condition.suffix(),
+                oper0.gen_code(file_format)?
+            )?,
+            Instruction::Cmp(oper0, oper1) => writeln!(
+                target,
+                "\tcmp\t{}, {}",
+                oper0.gen_code(file_format)?,
+                oper1.gen_code(file_format)?
+            )?,
+            Instruction::FloatArith(op, size, oper0, oper1) => writeln!(
+                target,
+                "\t{}\t{}, {}",
+                op.mnemonic(size),
+                oper0.gen_code(file_format)?,
+                oper1.gen_code(file_format)?
+            )?,
+            Instruction::Ucomis(size, oper0, oper1) => writeln!(
+                target,
+                "\tucomi{}\t{}, {}",
+                float_suffix(size),
+                oper0.gen_code(file_format)?,
+                oper1.gen_code(file_format)?
+            )?,
+            Instruction::Jmp(label) => writeln!(target, "\tjmp\t{}", file_format.mangle(&label))?,
+            Instruction::Jcc(condition, label) => writeln!(
+                target,
+                "\tj{}\t{}",
+                condition.suffix(),
+                file_format.mangle(&label)
+            )?,
+            Instruction::Movd(oper0, oper1) => writeln!(
+                target,
+                "\tmovd\t{}, {}",
+                oper0.gen_code(file_format)?,
+                oper1.gen_code(file_format)?
+            )?,
+            Instruction::Movq(oper0, oper1) => writeln!(
+                target,
+                "\tmovq\t{}, {}",
+                oper0.gen_code(file_format)?,
+                oper1.gen_code(file_format)?
+            )?,
+            Instruction::Movaps(oper0, oper1) => writeln!(
+                target,
+                "\tmovaps\t{}, {}",
+                oper0.gen_code(file_format)?,
+                oper1.gen_code(file_format)?
+            )?,
+            Instruction::Imul(oper0, oper1) => writeln!(
+                target,
+                "\timul\t{}, {}",
+                oper0.gen_code(file_format)?,
+                oper1.gen_code(file_format)?
+            )?,
             Instruction::Push(oper0) => {
                 writeln!(target, "\tpush\t{}", oper0.gen_code(file_format)?)?
             }
             Instruction::Pop(oper0) => writeln!(target, "\tpop\t{}", oper0.gen_code(file_format)?)?,
-            Instruction::Call(name) => writeln!(target, "\tcall\t{}", file_format.mangle(&name))?,
+            // Direct calls go through the PLT on ELF, in case the function is in a shared library
+            Instruction::Call(oper0 @ Operand::Label(_)) if file_format == FileFormat::Elf64 => {
+                writeln!(target, "\tcall\t{} wrt ..plt", oper0.gen_code(file_format)?)?
+            }
+            Instruction::Call(oper0) => {
+                writeln!(target, "\tcall\t{}", oper0.gen_code(file_format)?)?
+            }
+            Instruction::TailJmp(oper0 @ Operand::Label(_)) if file_format == FileFormat::Elf64 => {
+                writeln!(target, "\tjmp\t{} wrt ..plt", oper0.gen_code(file_format)?)?
+            }
+            Instruction::TailJmp(oper0) => {
+                writeln!(target, "\tjmp\t{}", oper0.gen_code(file_format)?)?
+            }
+            Instruction::Extern(name) => {
+                writeln!(target, "\textern\t{}", file_format.mangle(&name))?
+            }
+            Instruction::Section(section) => {
+                current_section = section;
+                writeln!(target, "\tsection\t{}", section.name())?
+            }
+            // `align` pads with `nop`s, which can't be put into `.bss`
+            Instruction::Align(align) if current_section == Section::Bss => {
+                writeln!(target, "\talignb\t{}", align)?
+            }
+            Instruction::Align(align) => writeln!(target, "\talign\t{}", align)?,
+            Instruction::Bytes(bytes) => {
+                for line in bytes.chunks(16) {
+                    writeln!(target, "\tdb\t{}", asm_str_from(line))?;
+                }
+            }
+            Instruction::Reserve(size) => writeln!(target, "\tresb\t{}", size)?,
         }
     }
     Ok(())
 }
 
-pub fn gen_code(ir: Vec<IRTopLevel>) -> Vec<Instruction> {
-    let mut generated = Vec::<Instruction>::new();
+/// Write the `global` directive of a symbol
+/// NASM keeps internal and private symbols in the symbol table as local symbols either way, so
+/// they don't need any directives
+fn write_global_directive(
+    file_format: FileFormat,
+    name: &String,
+    attrs: SymbolAttrs,
+    section: Section,
+    target: &mut dyn Write,
+) -> std::fmt::Result {
+    write!(target, "\tglobal\t{}", file_format.mangle(name))?;
+    let is_weak = attrs.linkage == Linkage::Weak;
+    match file_format {
+        FileFormat::Elf64 => {
+            let kind = if section == Section::Text {
+                "function"
+            } else {
+                "data"
+            };
+            write!(target, ":{kind}")?;
+            if is_weak {
+                write!(target, " weak")?;
+            }
+            match attrs.visibility {
+                Visibility::Default => (),
+                Visibility::Hidden => write!(target, " hidden")?,
+                Visibility::Protected => write!(target, " protected")?,
+            }
+        }
+        FileFormat::Macho64 => {
+            // Mach-O has no protected visibility, symbols are already bound within their own
+            // image by the two-level namespace
+            let mut specials = Vec::new();
+            if is_weak {
+                specials.push("weak");
+            }
+            if attrs.visibility == Visibility::Hidden {
+                specials.push("private_extern");
+            }
+            if !specials.is_empty() {
+                write!(target, ":{}", specials.join(" "))?;
+            }
+        }
+    }
+    writeln!(target)
+}
+
+pub fn gen_code(ir: Vec<IRTopLevel>, reg_alloc: RegAllocStrategy) -> Vec<Instruction> {
+    let type_defs = TypeDefs::from_ir(&ir);
+    let mut data_sections = DataSections::default();
+    let mut generated = vec![Instruction::Section(Section::Text)];
+    // Whether each of the known functions is variadic, calls to unknown functions are treated as
+    // variadic
+    let variadic_fns: HashMap<Rc<String>, bool> = ir
+        .iter()
+        .filter_map(|top_level| match top_level {
+            IRTopLevel::Extern {
+                name, is_variadic, ..
+            }
+            | IRTopLevel::Fn {
+                name, is_variadic, ..
+            } => Some((Rc::clone(name), *is_variadic)),
+            _ => None,
+        })
+        .collect();
     for ir_top_level in ir {
         match ir_top_level {
-            IRTopLevel::Extern(_) => todo!(),
-            IRTopLevel::Fn { name, args, body } => gen_inside_fn(name, args, body, &mut generated),
+            IRTopLevel::Extern { name, .. } => generated.push(Instruction::Extern(name)),
+            IRTopLevel::TypeDef { .. } => (),
+            IRTopLevel::Global {
+                name,
+                attrs,
+                ty,
+                init,
+                is_const,
+            } => data_sections.add_global(name, attrs, &ty, init.as_ref(), is_const, &type_defs),
+            IRTopLevel::Fn {
+                name,
+                attrs,
+                args,
+                is_variadic,
+                mut body,
+            } => {
+                data_sections.lower_string_literals(&mut body);
+                gen_inside_fn(
+                    name,
+                    attrs,
+                    args,
+                    is_variadic,
+                    body,
+                    &type_defs,
+                    &variadic_fns,
+                    reg_alloc,
+                    &mut generated,
+                )
+            }
         }
     }
+    data_sections.gen_code(&mut generated);
     generated
 }
 
+/// Size of the SysV register save area, 6 general purpose registers and 8 vector registers
+const REG_SAVE_AREA_SIZE: usize = 6 * 8 + 8 * 16;
+
+#[allow(clippy::too_many_arguments)]
 fn gen_inside_fn(
     name: Rc<String>,
+    attrs: SymbolAttrs,
     args: Vec<DataType>,
+    is_variadic: bool,
     body: Vec<IRInstruction>,
+    type_defs: &TypeDefs,
+    variadic_fns: &HashMap<Rc<String>, bool>,
+    reg_alloc: RegAllocStrategy,
     target: &mut Vec<Instruction>,
 ) {
-    let _ = &args; // Silence the warning
-    let mut stack_allocator = StackAllocator::new(16, 8);
-    let vreg_allocations =
-        VRegAllocation::<X64Register>::generate_from(&body, &mut stack_allocator);
-
-    vreg_allocations.print_reg_lifetime_map();
-    vreg_allocations.print_reg_infos();
+    let mut body = body;
+    destruct_ssa(&mut body);
+    let arg_slots = lower_args(&args, &mut body);
+    let mut stack_allocator = StackAllocator::new(16, 0);
+    let vreg_allocations = VRegAllocation::<X64Register>::generate_from(
+        &body,
+        type_defs,
+        reg_alloc,
+        &mut stack_allocator,
+    );
+    let uses_va_start = body
+        .iter()
+        .any(|instruction| matches!(instruction, IRInstruction::VaStart(_)));
+    if uses_va_start && !is_variadic {
+        panic!("`va_start` used in non-variadic function `@{name}`");
+    }
+    let reg_save_area =
+        uses_va_start.then(|| stack_allocator.add_aggregate(REG_SAVE_AREA_SIZE, 16));
+    let mut va_arg_count = 0usize;
 
     let stack_alloc = stack_allocator.allocate();
-    println!("Stack allocation:");
-    stack_alloc.locations.iter().enumerate().for_each(|(i, l)| {
-        println!("{i}:\t{l}");
-    });
-    println!("Stack depth: {}", stack_alloc.stack_depth);
 
-    target.push(Instruction::GlobalLabel(name));
+    target.push(Instruction::GlobalLabel(Rc::clone(&name), attrs));
     target.push(Instruction::FnProlog);
     if !stack_alloc.locations.is_empty() {
         target.push(Instruction::AllocStack(stack_alloc.stack_depth));
     }
+    if let Some(reg_save_area) = reg_save_area {
+        gen_save_arg_regs(stack_alloc.var_location(reg_save_area), target);
+    }
+    gen_spill_args(&args, &arg_slots, &stack_alloc, &vreg_allocations, target);
+    // Labels in the IR are local to the function
+    let local_label = |label: &String| format!("{name}.{label}");
+    // The `ret` after a tail call is never reached, the callee returns in its place
+    let mut after_tail_call = false;
     for (step, instruction) in body.into_iter().enumerate() {
+        if std::mem::take(&mut after_tail_call) && matches!(instruction, IRInstruction::Ret(_)) {
+            continue;
+        }
         match instruction {
             IRInstruction::DefReg { id, rhs } => {
                 match *rhs {
+                    IRInstruction::Call {
+                        callee,
+                        args,
+                        is_tail: true,
+                        ..
+                    } => {
+                        gen_tail_call(
+                            callee,
+                            args,
+                            variadic_fns,
+                            &stack_alloc,
+                            &vreg_allocations,
+                            target,
+                        );
+                        after_tail_call = true;
+                        continue;
+                    }
+                    IRInstruction::Call {
+                        ret_type,
+                        callee,
+                        args,
+                        is_tail: false,
+                    } => {
+                        gen_call(
+                            step,
+                            callee,
+                            args,
+                            variadic_fns,
+                            &stack_alloc,
+                            &vreg_allocations,
+                            target,
+                        );
+                        let ret_type = ret_type.unwrap_or_else(|| {
+                            panic!("Call without a return type is assigned to %{id}")
+                        });
+                        let size: X86WordSize = ret_type.into();
+                        if let Some(dest) = vreg_location(id, size, &stack_alloc, &vreg_allocations)
+                        {
+                            if ret_type.is_float() {
+                                gen_move_from_xmm(ret_type, 0, target);
+                            }
+                            let rax = X64Register::Rax.of_size(size);
+                            gen_move_instruction(size, dest, size, rax.into(), target);
+                        }
+                        continue;
+                    }
+                    ref compound if is_compound(compound) => {
+                        let size: X86WordSize = compound.dtype().unwrap().into();
+                        // Culled because the result is never used
+                        let Some(dest) = vreg_location(id, size, &stack_alloc, &vreg_allocations)
+                        else {
+                            continue;
+                        };
+                        gen_eval(compound.clone(), &stack_alloc, &vreg_allocations, target);
+                        let rax = X64Register::Rax.of_size(size);
+                        gen_move_instruction(size, dest, size, rax.into(), target);
+                        continue;
+                    }
+                    IRInstruction::VaArg { dtype, id: list } => {
+                        let labels = (
+                            format!("{name}.va_arg{va_arg_count}.overflow"),
+                            format!("{name}.va_arg{va_arg_count}.done"),
+                        );
+                        va_arg_count += 1;
+                        gen_va_arg(dtype, list, labels, &stack_alloc, &vreg_allocations, target);
+                        let size: X86WordSize = dtype.into();
+                        let rhs_oper = Operand::WordPtr(size, X64Register::Rax.into());
+                        if let Some(dest) = vreg_location(id, size, &stack_alloc, &vreg_allocations)
+                        {
+                            gen_move_instruction(size, dest, size, rhs_oper, target);
+                        }
+                        continue;
+                    }
                     IRInstruction::Alloc(_) => continue,
                     IRInstruction::Reg(_, _) => continue,
+                    IRInstruction::GlobalPtr(_) => continue,
+                    IRInstruction::FieldPtr { .. } | IRInstruction::ElemPtr { .. } => {
+                        gen_field_ptr(id, *rhs, &stack_alloc, &vreg_allocations, type_defs, target);
+                        continue;
+                    }
                     _ => (),
                 }
-                let (rhs_dtype, rhs_oper) = gen_operand(*rhs, &stack_alloc, &vreg_allocations);
+                let (rhs_dtype, rhs_oper) =
+                    gen_operand(*rhs, &stack_alloc, &vreg_allocations, target);
                 let size: X86WordSize = rhs_dtype.into();
-                if let Some(real_reg) = vreg_allocations.get_alloced_reg(id) {
-                    let lhs_oper = real_reg.of_size(size).into();
+                if let Some(dest) = vreg_location(id, size, &stack_alloc, &vreg_allocations) {
+                    gen_move_instruction(size, dest, size, rhs_oper, target);
+                } else if let Some(stack_ptr) = vreg_allocations.get_alloced_stackptr(id) {
+                    let lhs_oper = Operand::rbp_sub(
+                        rhs_dtype.into(),
+                        stack_ptr_location(&stack_alloc, stack_ptr),
+                    );
                     gen_move_instruction(size, lhs_oper, size, rhs_oper, target);
-                } else if let Some(stackspace_id) = vreg_allocations.get_alloced_stackptr(id) {
-                    let lhs_oper =
-                        Operand::rbp_sub(rhs_dtype.into(), stack_alloc.var_location(stackspace_id));
-                    gen_move_instruction(size, lhs_oper, size, rhs_oper, target);
-                } else if let Some(_) = vreg_allocations.get_alloced_const(id) {
+                } else if vreg_allocations.get_alloced_const(id).is_some() {
                     // No need to generate anything here since for every occurance of this register
                     // we can just replace it with the const value
                 }
-                // TODO: Allocate VReg on stack
                 // Sometimes a VReg doesn't not have any allocation, it's because VReg allocator
                 // decides to cull it
             }
-            IRInstruction::Store { lhs_dtype, id: vreg_id, rhs } => {
-                let stackspace_id = vreg_allocations
-                    .get_alloced_stackptr(vreg_id)
-                    .expect("Storing into a register who is not a stack pointer");
-                let stack_location = stack_alloc.var_location(stackspace_id);
-                let (rhs_dtype, rhs_oper) = gen_operand(*rhs, &stack_alloc, &vreg_allocations);
+            IRInstruction::Store {
+                lhs_dtype,
+                id: vreg_id,
+                rhs,
+            } => {
+                // A spilled pointer is reloaded into `r11`, which the value can't be using then
+                let spilled_pointer = vreg_allocations.get_alloced_spill(vreg_id).is_some();
+                let (rhs_dtype, rhs_oper) = if is_compound(&rhs) || spilled_pointer {
+                    let dtype = gen_eval(*rhs, &stack_alloc, &vreg_allocations, target);
+                    (dtype, X64Register::Rax.of_size(dtype.into()).into())
+                } else {
+                    gen_operand(*rhs, &stack_alloc, &vreg_allocations, target)
+                };
+                let lhs_oper =
+                    gen_deref(vreg_id, lhs_dtype, &stack_alloc, &vreg_allocations, target);
                 let rhs_size: X86WordSize = rhs_dtype.into();
-                let lhs_oper = Operand::rbp_sub(rhs_dtype.into(), stack_location);
                 gen_move_instruction(lhs_dtype.into(), lhs_oper, rhs_size, rhs_oper, target);
             }
             IRInstruction::Ret(ret_val) => {
-                if let Some(ret_val) = ret_val {
-                    // Has return value
-                    let (oper_dtype, operand) =
-                        gen_operand(*ret_val, &stack_alloc, &vreg_allocations);
-                    let size: X86WordSize = oper_dtype.into();
-                    let rax_sized = X64Register::Rax.of_size(size);
-                    gen_move_instruction(size, rax_sized.into(), size, operand, target);
+                match ret_val.map(|ret_val| *ret_val) {
+                    Some(IRInstruction::Call {
+                        callee,
+                        args,
+                        is_tail: true,
+                        ..
+                    }) => {
+                        gen_tail_call(
+                            callee,
+                            args,
+                            variadic_fns,
+                            &stack_alloc,
+                            &vreg_allocations,
+                            target,
+                        );
+                        continue;
+                    }
+                    Some(IRInstruction::Call {
+                        ret_type: _,
+                        callee,
+                        args,
+                        is_tail: false,
+                    }) => gen_call(
+                        step,
+                        callee,
+                        args,
+                        variadic_fns,
+                        &stack_alloc,
+                        &vreg_allocations,
+                        target,
+                    ),
+                    Some(ret_val) if is_compound(&ret_val) => {
+                        let dtype = gen_eval(ret_val, &stack_alloc, &vreg_allocations, target);
+                        if dtype.is_float() {
+                            gen_move_to_xmm(dtype, 0, target);
+                        }
+                    }
+                    Some(ret_val) => {
+                        let (oper_dtype, operand) =
+                            gen_operand(ret_val, &stack_alloc, &vreg_allocations, target);
+                        let size: X86WordSize = oper_dtype.into();
+                        let rax_sized = X64Register::Rax.of_size(size);
+                        gen_move_instruction(size, rax_sized.into(), size, operand, target);
+                        // Floats are returned in `xmm0`
+                        if oper_dtype.is_float() {
+                            gen_move_to_xmm(oper_dtype, 0, target);
+                        }
+                    }
+                    None => (),
                 }
                 if !stack_alloc.locations.is_empty() {
                     target.push(Instruction::DeallocStack(stack_alloc.stack_depth));
@@ -373,50 +958,735 @@ fn gen_inside_fn(
                 target.push(Instruction::pop_rop());
                 target.push(Instruction::Ret);
             }
+            IRInstruction::Call {
+                callee,
+                args,
+                is_tail: true,
+                ..
+            } => {
+                gen_tail_call(
+                    callee,
+                    args,
+                    variadic_fns,
+                    &stack_alloc,
+                    &vreg_allocations,
+                    target,
+                );
+                after_tail_call = true;
+            }
             IRInstruction::Call {
                 ret_type: _,
-                fn_name,
+                callee,
                 args,
+                is_tail: false,
+            } => gen_call(
+                step,
+                callee,
+                args,
+                variadic_fns,
+                &stack_alloc,
+                &vreg_allocations,
+                target,
+            ),
+            IRInstruction::Label(label) => target.push(Instruction::Label(local_label(&label))),
+            IRInstruction::Jmp(label) => target.push(Instruction::Jmp(local_label(&label))),
+            IRInstruction::Br {
+                cond,
+                if_true,
+                if_false,
             } => {
-                let arg_regs = X64Register::caller_saved();
-                // TODO: push currently occupied registers
-                // Load arguments in reverse order because for some reason gcc and clang do that
-                vreg_allocations
-                    .for_each_living_reg(step, |r| target.push(Instruction::Push(r.into())));
-                for (i, arg_instruction) in args.into_iter().rev().enumerate() {
-                    let (arg_dtype, arg_oper) =
-                        gen_operand(arg_instruction, &stack_alloc, &vreg_allocations);
-                    let size: X86WordSize = arg_dtype.into();
-                    let arg_reg = arg_regs[i].of_size(size);
-                    gen_move_instruction(size, arg_reg.into(), size, arg_oper, target);
-                }
-                target.push(Instruction::Call(fn_name));
-                vreg_allocations
-                    .for_each_living_reg_rev(step, |r| target.push(Instruction::Pop(r.into())));
+                let dtype = gen_eval(*cond, &stack_alloc, &vreg_allocations, target);
+                let rax = X64Register::Rax.of_size(dtype.into());
+                target.push(Instruction::Test(rax.into(), rax.into()));
+                target.push(Instruction::Jcc(Condition::Ne, local_label(&if_true)));
+                target.push(Instruction::Jmp(local_label(&if_false)));
+            }
+            IRInstruction::VaStart(list) => {
+                let reg_save_area = stack_alloc.var_location(reg_save_area.unwrap());
+                gen_va_start(
+                    &args,
+                    list,
+                    reg_save_area,
+                    &stack_alloc,
+                    &vreg_allocations,
+                    target,
+                );
             }
-            IRInstruction::Label(name) => target.push(Instruction::Label(name)),
+            IRInstruction::VaEnd(_) => (),
             illegal => panic!("{:?} is illegal as root node", illegal),
         }
     }
 }
 
+/// Whether a call with arguments of these types passes all of them in registers
+/// Up to 6 integer or pointer arguments and 8 float arguments fit, the rest would go on the stack
+pub fn args_fit_in_regs(args: impl IntoIterator<Item = DataType>) -> bool {
+    let (floats, ints): (Vec<DataType>, Vec<DataType>) =
+        args.into_iter().partition(|dtype| dtype.is_float());
+    ints.len() <= X64Register::caller_saved().len() && floats.len() <= 8
+}
+
+/// Generate a call, the return value is left in `rax`, or in `xmm0` if it's a float
+fn gen_call(
+    step: usize,
+    callee: Callee,
+    args: Vec<IRInstruction>,
+    variadic_fns: &HashMap<Rc<String>, bool>,
+    stack_alloc: &StackAllocation,
+    vreg_alloc: &VRegAllocation<X64Register>,
+    target: &mut Vec<Instruction>,
+) {
+    let (args, stack_args) = split_stack_args(args);
+    let mut pushed_count = 0usize;
+    vreg_alloc.for_each_living_reg(step, |r| {
+        pushed_count += 1;
+        target.push(Instruction::Push(r.into()))
+    });
+    // Keep `rsp` aligned to 16 bytes at the call, the padding goes above the arguments on the
+    // stack
+    let padding = (pushed_count + stack_args.len()) % 2 * 8;
+    if padding != 0 {
+        target.push(Instruction::AllocStack(padding));
+    }
+    // The function pointer has to be evaluated before the argument registers are
+    // overwritten
+    let sets_al = sets_al(&callee, variadic_fns);
+    let callee_oper = gen_callee(callee, stack_alloc, vreg_alloc, target);
+    let stack_args_size = stack_args.len() * 8;
+    gen_stack_args(stack_args, stack_alloc, vreg_alloc, target);
+    let float_count = gen_args(args, stack_alloc, vreg_alloc, target);
+    if sets_al {
+        target.push(Instruction::Mov(
+            X64Register::Eax.into(),
+            Operand::Im((float_count as u64).to_be_bytes()),
+        ));
+    }
+    target.push(Instruction::Call(callee_oper));
+    if padding + stack_args_size != 0 {
+        target.push(Instruction::DeallocStack(padding + stack_args_size));
+    }
+    vreg_alloc.for_each_living_reg_rev(step, |r| target.push(Instruction::Pop(r.into())));
+}
+
+/// Generate a call in tail position as a jump, after tearing down the frame of the caller
+/// The callee returns straight to the caller's caller, with its return value in `rax` or `xmm0`
+fn gen_tail_call(
+    callee: Callee,
+    args: Vec<IRInstruction>,
+    variadic_fns: &HashMap<Rc<String>, bool>,
+    stack_alloc: &StackAllocation,
+    vreg_alloc: &VRegAllocation<X64Register>,
+    target: &mut Vec<Instruction>,
+) {
+    let arg_dtypes = args.iter().filter_map(IRInstruction::dtype);
+    if !args_fit_in_regs(arg_dtypes) {
+        panic!("`tail call` passes arguments on the stack, which would be in the caller's frame");
+    }
+    let sets_al = sets_al(&callee, variadic_fns);
+    let callee_oper = match gen_callee(callee, stack_alloc, vreg_alloc, target) {
+        oper @ (Operand::Label(_) | Operand::Reg(_)) => oper,
+        // Stack slots are gone after the frame is torn down
+        oper => {
+            let r10 = X64Register::R10;
+            gen_move_instruction(
+                X86WordSize::Qword,
+                r10.into(),
+                X86WordSize::Qword,
+                oper,
+                target,
+            );
+            r10.into()
+        }
+    };
+    let float_count = gen_args(args, stack_alloc, vreg_alloc, target);
+    if sets_al {
+        target.push(Instruction::Mov(
+            X64Register::Eax.into(),
+            Operand::Im((float_count as u64).to_be_bytes()),
+        ));
+    }
+    if !stack_alloc.locations.is_empty() {
+        target.push(Instruction::DeallocStack(stack_alloc.stack_depth));
+    }
+    target.push(Instruction::pop_rop());
+    target.push(Instruction::TailJmp(callee_oper));
+}
+
+/// Whether `al` has to hold the number of vector registers used by the arguments, which is
+/// needed when calling a variadic function
+/// Function pointers may point to variadic functions as well
+fn sets_al(callee: &Callee, variadic_fns: &HashMap<Rc<String>, bool>) -> bool {
+    match callee {
+        Callee::Direct(name) => variadic_fns.get(name).copied().unwrap_or(true),
+        Callee::Indirect(_) => true,
+    }
+}
+
+/// Split the arguments of a call into the ones passed in registers and the ones passed on the
+/// stack, each in their original order
+/// Integer and pointer arguments after the 6th and float arguments after the 8th go on the stack
+fn split_stack_args(args: Vec<IRInstruction>) -> (Vec<IRInstruction>, Vec<IRInstruction>) {
+    let (mut int_count, mut float_count) = (0usize, 0usize);
+    args.into_iter().partition(|arg| {
+        let dtype = arg
+            .dtype()
+            .unwrap_or_else(|| panic!("{arg:?} cannot be an argument"));
+        if dtype.is_float() {
+            float_count += 1;
+            float_count <= 8
+        } else {
+            int_count += 1;
+            int_count <= X64Register::caller_saved().len()
+        }
+    })
+}
+
+/// Push the arguments passed on the stack, the first one ends up at the lowest address
+/// Each takes 8 bytes, the upper bytes of the narrower ones are left undefined
+fn gen_stack_args(
+    args: Vec<IRInstruction>,
+    stack_alloc: &StackAllocation,
+    vreg_alloc: &VRegAllocation<X64Register>,
+    target: &mut Vec<Instruction>,
+) {
+    for arg_instruction in args.into_iter().rev() {
+        let (arg_dtype, arg_oper) = gen_operand(arg_instruction, stack_alloc, vreg_alloc, target);
+        match arg_oper {
+            Operand::Reg(reg) => {
+                target.push(Instruction::Push(reg.of_size(X86WordSize::Qword).into()))
+            }
+            Operand::Im(bytes) if fits_in_imm32(bytes) => {
+                target.push(Instruction::Push(Operand::Im(bytes)))
+            }
+            arg_oper => {
+                let size: X86WordSize = arg_dtype.into();
+                let rax = X64Register::Rax.of_size(size);
+                gen_move_instruction(size, rax.into(), size, arg_oper, target);
+                target.push(Instruction::Push(X64Register::Rax.into()));
+            }
+        }
+    }
+}
+
+/// Move the arguments of a call into the argument registers, returns the number of vector
+/// registers used
+/// The moves happen all at once, as if in parallel, so an argument that's read from a register
+/// another argument goes into is saved on the stack before that register is overwritten
+fn gen_args(
+    args: Vec<IRInstruction>,
+    stack_alloc: &StackAllocation,
+    vreg_alloc: &VRegAllocation<X64Register>,
+    target: &mut Vec<Instruction>,
+) -> u8 {
+    let arg_regs = X64Register::caller_saved();
+    // Integer and pointer arguments go into general purpose registers, floats go into
+    // vector registers, each counted separately
+    let (mut int_count, mut float_count) = (0usize, 0u8);
+    let args: Vec<(IRInstruction, usize)> = args
+        .into_iter()
+        .map(|arg_instruction| {
+            let arg_dtype = arg_instruction
+                .dtype()
+                .unwrap_or_else(|| panic!("{arg_instruction:?} cannot be an argument"));
+            let index = if arg_dtype.is_float() {
+                float_count += 1;
+                float_count as usize - 1
+            } else {
+                int_count += 1;
+                int_count - 1
+            };
+            (arg_instruction, index)
+        })
+        .collect();
+    if int_count > arg_regs.len() || float_count > 8 {
+        panic!("Arguments that don't fit in registers have to be pushed by `gen_stack_args`");
+    }
+    let written = &arg_regs[..int_count];
+    let (saved, direct): (Vec<_>, Vec<_>) = args.into_iter().partition(|(arg_instruction, i)| {
+        let own = (!arg_instruction.dtype().unwrap().is_float()).then(|| arg_regs[*i]);
+        regs_read(arg_instruction, vreg_alloc)
+            .into_iter()
+            .any(|reg| written.contains(&reg) && Some(reg) != own)
+    });
+    for (arg_instruction, _) in &saved {
+        let (arg_dtype, arg_oper) =
+            gen_operand(arg_instruction.clone(), stack_alloc, vreg_alloc, target);
+        let size: X86WordSize = arg_dtype.into();
+        let rax = X64Register::Rax.of_size(size);
+        gen_move_instruction(size, rax.into(), size, arg_oper, target);
+        target.push(Instruction::Push(X64Register::Rax.into()));
+    }
+    // Load arguments in reverse order because for some reason gcc and clang do that
+    // Each operand is generated right before it's moved, since loads through spilled pointers
+    // share `r11`
+    for (arg_instruction, i) in direct.into_iter().rev() {
+        let (arg_dtype, arg_oper) = gen_operand(arg_instruction, stack_alloc, vreg_alloc, target);
+        let size: X86WordSize = arg_dtype.into();
+        match arg_dtype {
+            DataType::F64 | DataType::F32 => {
+                let rax = X64Register::Rax.of_size(size);
+                gen_move_instruction(size, rax.into(), size, arg_oper, target);
+                gen_move_to_xmm(arg_dtype, i as u8, target);
+            }
+            _ => {
+                let arg_reg = arg_regs[i].of_size(size);
+                gen_move_instruction(size, arg_reg.into(), size, arg_oper, target);
+            }
+        }
+    }
+    for (arg_instruction, i) in saved.into_iter().rev() {
+        match arg_instruction.dtype().unwrap() {
+            arg_dtype @ (DataType::F64 | DataType::F32) => {
+                target.push(Instruction::Pop(X64Register::Rax.into()));
+                gen_move_to_xmm(arg_dtype, i as u8, target);
+            }
+            _ => target.push(Instruction::Pop(arg_regs[i].into())),
+        }
+    }
+    float_count
+}
+
+/// Move a float argument from `rax` into `xmm{i}`
+fn gen_move_to_xmm(dtype: DataType, i: u8, target: &mut Vec<Instruction>) {
+    if dtype == DataType::F64 {
+        target.push(Instruction::Movq(Operand::Xmm(i), X64Register::Rax.into()));
+    } else {
+        target.push(Instruction::Movd(Operand::Xmm(i), X64Register::Eax.into()));
+    }
+}
+
+/// Move a float from `xmm{i}` into `rax`, such as the return value of a call
+fn gen_move_from_xmm(dtype: DataType, i: u8, target: &mut Vec<Instruction>) {
+    if dtype == DataType::F64 {
+        target.push(Instruction::Movq(X64Register::Rax.into(), Operand::Xmm(i)));
+    } else {
+        target.push(Instruction::Movd(X64Register::Eax.into(), Operand::Xmm(i)));
+    }
+}
+
+/// The registers an operand reads, through the vregs it uses
+fn regs_read(
+    operand: &IRInstruction,
+    vreg_alloc: &VRegAllocation<X64Register>,
+) -> Vec<X64Register> {
+    let mut regs = Vec::new();
+    if let IRInstruction::Reg(_, id) | IRInstruction::Load { id, .. } = operand {
+        regs.extend(vreg_alloc.get_alloced_reg(*id));
+    }
+    for operand in operand.operands() {
+        regs.extend(regs_read(operand, vreg_alloc));
+    }
+    regs
+}
+
+/// Whether the instruction is an arithmetic or comparison that has to be evaluated by `gen_eval`
+fn is_compound(instruction: &IRInstruction) -> bool {
+    matches!(
+        instruction,
+        IRInstruction::Add(..)
+            | IRInstruction::Sub(..)
+            | IRInstruction::Mul(..)
+            | IRInstruction::Div(..)
+            | IRInstruction::Not(..)
+            | IRInstruction::And(..)
+            | IRInstruction::Or(..)
+            | IRInstruction::Xor(..)
+            | IRInstruction::Rem(..)
+            | IRInstruction::MulHi(..)
+            | IRInstruction::Shl(..)
+            | IRInstruction::Shr(..)
+            | IRInstruction::Eq(..)
+            | IRInstruction::Ne(..)
+            | IRInstruction::Lt(..)
+            | IRInstruction::Le(..)
+            | IRInstruction::Gt(..)
+            | IRInstruction::Ge(..)
+    )
+}
+
+fn is_signed(dtype: DataType) -> bool {
+    matches!(
+        dtype,
+        DataType::I8 | DataType::I16 | DataType::I32 | DataType::I64 | DataType::ISize
+    )
+}
+
+/// Evaluate an operand into `rax`, operands narrower than 64 bits are sign-extended or
+/// zero-extended according to their types
+/// Uses `r11` as scratch, and the stack for nested expressions
+fn gen_eval(
+    instruction: IRInstruction,
+    stack_alloc: &StackAllocation,
+    vreg_alloc: &VRegAllocation<X64Register>,
+    target: &mut Vec<Instruction>,
+) -> DataType {
+    let rax = X64Register::Rax;
+    let r11 = X64Register::R11;
+    if !is_compound(&instruction) {
+        let (dtype, oper) = gen_operand(instruction, stack_alloc, vreg_alloc, target);
+        gen_extend_to_qword(rax, dtype, oper, target);
+        return dtype;
+    }
+    if let IRInstruction::Not(_, _, _) = instruction {
+        panic!("`~` cannot be lowered yet, the verifier rejects it");
+    }
+    let dtype = instruction.dtype().unwrap();
+    let (lhs, rhs) = match instruction.operands()[..] {
+        [lhs, rhs] => (lhs.clone(), rhs.clone()),
+        _ => unreachable!(),
+    };
+    // Shifts by constants don't need to go through `cl`
+    let constant_amount = match rhs {
+        IRInstruction::UInt(_, amount) => Some(amount),
+        IRInstruction::Int(_, amount) if amount >= 0 => Some(amount as u64),
+        _ => None,
+    };
+    if let (IRInstruction::Shl(..) | IRInstruction::Shr(..), Some(amount)) =
+        (&instruction, constant_amount)
+    {
+        gen_eval(lhs, stack_alloc, vreg_alloc, target);
+        let amount = Operand::Im(amount.to_be_bytes());
+        let is_left = matches!(instruction, IRInstruction::Shl(..));
+        target.push(gen_shift(is_left, dtype, rax.into(), amount));
+        return dtype;
+    }
+    // Signedness of comparisons comes from the operands, not the result
+    let signed_operands = lhs.dtype().is_some_and(is_signed);
+    let float_operands = lhs.dtype().filter(|dtype| dtype.is_float());
+    if is_compound(&rhs) {
+        gen_eval(rhs, stack_alloc, vreg_alloc, target);
+        target.push(Instruction::Push(rax.into()));
+        gen_eval(lhs, stack_alloc, vreg_alloc, target);
+        target.push(Instruction::Pop(r11.into()));
+    } else {
+        gen_eval(lhs, stack_alloc, vreg_alloc, target);
+        let (rhs_dtype, rhs_oper) = gen_operand(rhs, stack_alloc, vreg_alloc, target);
+        gen_extend_to_qword(r11, rhs_dtype, rhs_oper, target);
+    }
+    if let Some(float_dtype) = float_operands {
+        gen_float_op(&instruction, float_dtype, target);
+        return dtype;
+    }
+    let condition = |signed, unsigned| {
+        if signed_operands {
+            signed
+        } else {
+            unsigned
+        }
+    };
+    let condition = match instruction {
+        IRInstruction::Add(..) => {
+            target.push(Instruction::Add(rax.into(), r11.into()));
+            return dtype;
+        }
+        IRInstruction::Sub(..) => {
+            target.push(Instruction::Sub(rax.into(), r11.into()));
+            return dtype;
+        }
+        IRInstruction::Mul(..) => {
+            target.push(Instruction::Imul(rax.into(), r11.into()));
+            return dtype;
+        }
+        IRInstruction::Div(..) => {
+            // `rdx` may be holding a VReg
+            let rdx = X64Register::Rdx;
+            target.push(Instruction::Push(rdx.into()));
+            if is_signed(dtype) {
+                target.push(Instruction::Cqo);
+                target.push(Instruction::Idiv(r11.into()));
+            } else {
+                target.push(Instruction::Mov(
+                    X64Register::Edx.into(),
+                    Operand::Im([0; 8]),
+                ));
+                target.push(Instruction::Div(r11.into()));
+            }
+            target.push(Instruction::Pop(rdx.into()));
+            return dtype;
+        }
+        IRInstruction::Rem(..) => {
+            // `rdx` may be holding a VReg
+            let rdx = X64Register::Rdx;
+            target.push(Instruction::Push(rdx.into()));
+            if is_signed(dtype) {
+                target.push(Instruction::Cqo);
+                target.push(Instruction::Idiv(r11.into()));
+            } else {
+                target.push(Instruction::Mov(
+                    X64Register::Edx.into(),
+                    Operand::Im([0; 8]),
+                ));
+                target.push(Instruction::Div(r11.into()));
+            }
+            target.push(Instruction::Mov(rax.into(), rdx.into()));
+            target.push(Instruction::Pop(rdx.into()));
+            return dtype;
+        }
+        IRInstruction::MulHi(..) if X86WordSize::from(dtype) == X86WordSize::Qword => {
+            let rdx = X64Register::Rdx;
+            target.push(Instruction::Push(rdx.into()));
+            if is_signed(dtype) {
+                target.push(Instruction::ImulWide(r11.into()));
+            } else {
+                target.push(Instruction::Mul(r11.into()));
+            }
+            target.push(Instruction::Mov(rax.into(), rdx.into()));
+            target.push(Instruction::Pop(rdx.into()));
+            return dtype;
+        }
+        IRInstruction::MulHi(..) => {
+            // The operands are extended to 64 bits, so the full product fits in `rax`
+            let width = X86WordSize::from(dtype) as u64 * 8;
+            target.push(Instruction::Imul(rax.into(), r11.into()));
+            target.push(gen_shift(
+                false,
+                dtype,
+                rax.into(),
+                Operand::Im(width.to_be_bytes()),
+            ));
+            return dtype;
+        }
+        IRInstruction::Shl(..) | IRInstruction::Shr(..) => {
+            // `rcx` may be holding a VReg
+            let rcx = X64Register::Rcx;
+            target.push(Instruction::Push(rcx.into()));
+            target.push(Instruction::Mov(rcx.into(), r11.into()));
+            let is_left = matches!(instruction, IRInstruction::Shl(..));
+            target.push(gen_shift(
+                is_left,
+                dtype,
+                rax.into(),
+                X64Register::Cl.into(),
+            ));
+            target.push(Instruction::Pop(rcx.into()));
+            return dtype;
+        }
+        IRInstruction::And(..) => {
+            target.push(Instruction::And(rax.into(), r11.into()));
+            return dtype;
+        }
+        IRInstruction::Or(..) => {
+            target.push(Instruction::Or(rax.into(), r11.into()));
+            return dtype;
+        }
+        IRInstruction::Xor(..) => {
+            target.push(Instruction::Xor(rax.into(), r11.into()));
+            return dtype;
+        }
+        IRInstruction::Eq(..) => Condition::E,
+        IRInstruction::Ne(..) => Condition::Ne,
+        IRInstruction::Lt(..) => condition(Condition::L, Condition::B),
+        IRInstruction::Le(..) => condition(Condition::Le, Condition::Be),
+        IRInstruction::Gt(..) => condition(Condition::G, Condition::A),
+        IRInstruction::Ge(..) => condition(Condition::Ge, Condition::Ae),
+        _ => unreachable!(),
+    };
+    target.push(Instruction::Cmp(rax.into(), r11.into()));
+    target.push(Instruction::Setcc(condition, X64Register::Al.into()));
+    target.push(Instruction::Movzx(
+        X64Register::Eax.into(),
+        X64Register::Al.into(),
+    ));
+    dtype
+}
+
+/// Generate a float arithmetic or comparison on the bits of the operands in `rax` and `r11`,
+/// leaving the result in `rax`
+/// Uses `xmm0` and `xmm1` as scratch, which only ever hold values between instructions
+fn gen_float_op(instruction: &IRInstruction, dtype: DataType, target: &mut Vec<Instruction>) {
+    let size: X86WordSize = dtype.into();
+    let (lhs, rhs) = (Operand::Xmm(0), Operand::Xmm(1));
+    for (xmm, reg) in [(0, X64Register::Rax), (1, X64Register::R11)] {
+        target.push(if size == X86WordSize::Qword {
+            Instruction::Movq(Operand::Xmm(xmm), reg.into())
+        } else {
+            Instruction::Movd(Operand::Xmm(xmm), reg.of_size(size).into())
+        });
+    }
+    let op = match instruction {
+        IRInstruction::Add(..) => Some(FloatOp::Add),
+        IRInstruction::Sub(..) => Some(FloatOp::Sub),
+        IRInstruction::Mul(..) => Some(FloatOp::Mul),
+        IRInstruction::Div(..) => Some(FloatOp::Div),
+        _ => None,
+    };
+    if let Some(op) = op {
+        target.push(Instruction::FloatArith(op, size, lhs, rhs));
+        gen_move_from_xmm(dtype, 0, target);
+        return;
+    }
+    // Comparisons with NaN are unordered, which sets the zero, carry and parity flags. Only `!=`
+    // is true then, so `<` and `<=` are turned around to look at the carry flag being clear
+    let al = X64Register::Al;
+    let r11b = X64Register::R11b;
+    let (condition, lhs, rhs) = match instruction {
+        IRInstruction::Eq(..) | IRInstruction::Ne(..) => (None, lhs, rhs),
+        IRInstruction::Gt(..) => (Some(Condition::A), lhs, rhs),
+        IRInstruction::Ge(..) => (Some(Condition::Ae), lhs, rhs),
+        IRInstruction::Lt(..) => (Some(Condition::A), rhs, lhs),
+        IRInstruction::Le(..) => (Some(Condition::Ae), rhs, lhs),
+        illegal => panic!("{illegal:?} cannot be done on floats"),
+    };
+    target.push(Instruction::Ucomis(size, lhs, rhs));
+    match (condition, instruction) {
+        (Some(condition), _) => target.push(Instruction::Setcc(condition, al.into())),
+        (None, IRInstruction::Eq(..)) => target.extend([
+            Instruction::Setcc(Condition::E, al.into()),
+            Instruction::Setcc(Condition::Np, r11b.into()),
+            Instruction::And(al.into(), r11b.into()),
+        ]),
+        (None, _) => target.extend([
+            Instruction::Setcc(Condition::Ne, al.into()),
+            Instruction::Setcc(Condition::P, r11b.into()),
+            Instruction::Or(al.into(), r11b.into()),
+        ]),
+    }
+    target.push(Instruction::Movzx(X64Register::Eax.into(), al.into()));
+}
+
+/// Shift `oper` by `amount`, operands are extended to 64 bits so right shifts of signed types
+/// have to be arithmetic
+fn gen_shift(is_left: bool, dtype: DataType, oper: Operand, amount: Operand) -> Instruction {
+    if is_left {
+        Instruction::Shl(oper, amount)
+    } else if is_signed(dtype) {
+        Instruction::Sar(oper, amount)
+    } else {
+        Instruction::Shr(oper, amount)
+    }
+}
+
+/// Replace the arguments with loads from stack slots, which are filled in by `gen_spill_args` in
+/// the prolog, returns the IDs of the VRegs pointing to the slots
+fn lower_args(args: &[DataType], body: &mut Vec<IRInstruction>) -> Vec<u64> {
+    fn max_vreg_id(instruction: &IRInstruction) -> u64 {
+        let own = match instruction {
+            IRInstruction::Reg(_, id)
+            | IRInstruction::Load { id, .. }
+            | IRInstruction::DefReg { id, .. }
+            | IRInstruction::Store { id, .. }
+            | IRInstruction::FieldPtr { id, .. }
+            | IRInstruction::ElemPtr { id, .. }
+            | IRInstruction::VaArg { id, .. }
+            | IRInstruction::VaStart(id)
+            | IRInstruction::VaEnd(id) => *id,
+            _ => 0,
+        };
+        instruction
+            .operands()
+            .into_iter()
+            .map(max_vreg_id)
+            .fold(own, u64::max)
+    }
+    fn replace_args(instruction: &mut IRInstruction, slots: &[u64]) {
+        if let IRInstruction::Arg(dtype, i) = *instruction {
+            let id = *slots
+                .get(i as usize)
+                .unwrap_or_else(|| panic!("Argument #{i} does not exist"));
+            *instruction = IRInstruction::Load { id, dtype };
+            return;
+        }
+        for operand in instruction.operands_mut() {
+            replace_args(operand, slots);
+        }
+    }
+    let first_id = body.iter().map(max_vreg_id).max().unwrap_or(0) + 1;
+    let slots: Vec<u64> = (0..args.len() as u64).map(|i| first_id + i).collect();
+    for instruction in body.iter_mut() {
+        replace_args(instruction, &slots);
+    }
+    body.splice(
+        0..0,
+        args.iter()
+            .zip(&slots)
+            .map(|(&dtype, &id)| IRInstruction::DefReg {
+                id,
+                rhs: Box::new(IRInstruction::Alloc(dtype.into())),
+            }),
+    );
+    slots
+}
+
+/// Copy the arguments from where the caller put them into their stack slots
+fn gen_spill_args(
+    args: &[DataType],
+    slots: &[u64],
+    stack_alloc: &StackAllocation,
+    vreg_alloc: &VRegAllocation<X64Register>,
+    target: &mut Vec<Instruction>,
+) {
+    let arg_regs = X64Register::caller_saved();
+    let (mut int_count, mut float_count, mut stack_count) = (0usize, 0u8, 0u64);
+    for (&dtype, &slot) in args.iter().zip(slots) {
+        let size: X86WordSize = dtype.into();
+        let source: Operand = match dtype {
+            DataType::F64 | DataType::F32 if float_count < 8 => {
+                float_count += 1;
+                Operand::Xmm(float_count - 1)
+            }
+            DataType::F64 | DataType::F32 => {
+                stack_count += 1;
+                Operand::WordPtr(
+                    size,
+                    EvalTreeNode::Add(
+                        Box::new(X64Register::Rbp.into()),
+                        Box::new(EvalTreeNode::Num(8 + 8 * stack_count)),
+                    ),
+                )
+            }
+            _ if int_count < arg_regs.len() => {
+                int_count += 1;
+                arg_regs[int_count - 1].of_size(size).into()
+            }
+            _ => {
+                stack_count += 1;
+                Operand::WordPtr(
+                    size,
+                    EvalTreeNode::Add(
+                        Box::new(X64Register::Rbp.into()),
+                        Box::new(EvalTreeNode::Num(8 + 8 * stack_count)),
+                    ),
+                )
+            }
+        };
+        // Unused arguments don't get a slot
+        if vreg_alloc.get_alloced_stackptr(slot).is_none() {
+            continue;
+        }
+        let dest = gen_deref(slot, dtype, stack_alloc, vreg_alloc, target);
+        match source {
+            Operand::Xmm(_) if size == X86WordSize::Qword => {
+                target.push(Instruction::Movq(dest, source))
+            }
+            Operand::Xmm(_) => target.push(Instruction::Movd(dest, source)),
+            source => gen_move_instruction(size, dest, size, source, target),
+        }
+    }
+}
+
 /// Generate an operand
+/// Loads through spilled pointers first reload the pointer into `r11`
 /// Will panic if the instruction is not an operand (including calls)
 fn gen_operand(
     instruction: IRInstruction,
     stack_alloc: &StackAllocation,
     vreg_alloc: &VRegAllocation<X64Register>,
+    target: &mut Vec<Instruction>,
 ) -> (DataType, Operand) {
     match instruction {
-        IRInstruction::Arg(_, _) => todo!(),
+        IRInstruction::Arg(_, _) => unreachable!("Arg is lowered before codegen"),
         IRInstruction::Reg(dtype, reg_id) => (
             dtype,
             if let Some(reg) = vreg_alloc.get_alloced_reg(reg_id) {
                 reg.of_size(dtype.into()).into()
+            } else if let Some(slot) = vreg_alloc.get_alloced_spill(reg_id) {
+                Operand::rbp_sub(dtype.into(), stack_alloc.var_location(slot))
             } else if let Some(val) = vreg_alloc.get_alloced_const(reg_id) {
                 Operand::Im(val)
-            } else if let Some(stackspace_id) = vreg_alloc.get_alloced_stackptr(reg_id) {
-                let stack_loc = stack_alloc.var_location(stackspace_id);
+            } else if let Some(global) = vreg_alloc.get_alloced_global(reg_id) {
+                Operand::Load(global_address(global))
+            } else if let Some(stack_ptr) = vreg_alloc.get_alloced_stackptr(reg_id) {
+                let stack_loc = stack_ptr_location(stack_alloc, stack_ptr);
                 Operand::Load(EvalTreeNode::Sub(
                     Box::new(X64Register::Rbp.into()),
                     Box::new(EvalTreeNode::Num(stack_loc as u64)),
@@ -430,22 +1700,367 @@ fn gen_operand(
         ),
         IRInstruction::UInt(dtype, val) => (dtype, Operand::Im(val.to_be_bytes())),
         IRInstruction::Int(dtype, val) => (dtype, Operand::Im(val.to_be_bytes())),
-        IRInstruction::Float(dtype, val) => (dtype, Operand::Im(val.to_be_bytes())),
-        IRInstruction::Load { id, dtype } => (
-            dtype,
-            Operand::rbp_sub(
-                dtype.into(),
-                stack_alloc.var_location(
-                    vreg_alloc
-                        .get_alloced_stackptr(id)
-                        .expect("Loading from a regsiter that is not a stack ptr"),
-                ),
-            ),
+        IRInstruction::Float(DataType::F32, val) => (
+            DataType::F32,
+            Operand::Im(((val as f32).to_bits() as u64).to_be_bytes()),
         ),
+        IRInstruction::Float(dtype, val) => (dtype, Operand::Im(val.to_be_bytes())),
+        IRInstruction::GlobalPtr(name) => (DataType::Ptr, Operand::Load(EvalTreeNode::Label(name))),
+        IRInstruction::Load { id, dtype } => {
+            (dtype, gen_deref(id, dtype, stack_alloc, vreg_alloc, target))
+        }
         illegal => panic!("{:?} cannot be an operand", illegal),
     }
 }
 
+/// Generate the operand of a `call` instruction
+/// Function pointers that may live in argument registers are first moved into `r10`
+fn gen_callee(
+    callee: Callee,
+    stack_alloc: &StackAllocation,
+    vreg_alloc: &VRegAllocation<X64Register>,
+    target: &mut Vec<Instruction>,
+) -> Operand {
+    let fn_ptr = match callee {
+        Callee::Direct(name) => return Operand::Label(name.to_string()),
+        Callee::Indirect(fn_ptr) => fn_ptr,
+    };
+    let (dtype, oper) = gen_operand(*fn_ptr, stack_alloc, vreg_alloc, target);
+    if dtype != DataType::Ptr {
+        panic!("Calling a function pointer of type {dtype:?}, expects ptr");
+    }
+    match oper {
+        // `ptr @f`, or a VReg holding the address of `@f`
+        Operand::Load(EvalTreeNode::Label(name)) => Operand::Label(name.to_string()),
+        // Function pointers in memory that isn't addressed by any argument registers, such as
+        // stack slots and globals, can be called directly without loading them first
+        Operand::WordPtr(size, address) if !address.uses_regs_other_than_rbp() => {
+            Operand::WordPtr(size, address)
+        }
+        // `r11` may still be needed to load the arguments
+        oper => {
+            let r10 = X64Register::R10;
+            gen_move_instruction(
+                X86WordSize::Qword,
+                r10.into(),
+                X86WordSize::Qword,
+                oper,
+                target,
+            );
+            r10.into()
+        }
+    }
+}
+
+/// Spill the argument registers into the register save area at `rbp - location` in the prolog of
+/// a variadic function
+fn gen_save_arg_regs(location: usize, target: &mut Vec<Instruction>) {
+    for (i, reg) in X64Register::caller_saved().into_iter().enumerate() {
+        let oper = Operand::rbp_sub(X86WordSize::Qword, location - i * 8);
+        target.push(Instruction::Mov(oper, reg.into()));
+    }
+    // TODO: skip saving the vector registers if `al` is zero
+    for i in 0..8 {
+        let address = EvalTreeNode::Sub(
+            Box::new(X64Register::Rbp.into()),
+            Box::new(EvalTreeNode::Num((location - 48 - i * 16) as u64)),
+        );
+        target.push(Instruction::Movaps(
+            Operand::Load(address),
+            Operand::Xmm(i as u8),
+        ));
+    }
+}
+
+/// Initialize a `va_list`:
+/// ```txt
+/// u32 gp_offset           ; offset of the next integer argument in the register save area
+/// u32 fp_offset           ; offset of the next float argument in the register save area
+/// ptr overflow_arg_area   ; next argument passed on stack
+/// ptr reg_save_area
+/// ```
+fn gen_va_start(
+    named_args: &[DataType],
+    list: u64,
+    reg_save_area: usize,
+    stack_alloc: &StackAllocation,
+    vreg_alloc: &VRegAllocation<X64Register>,
+    target: &mut Vec<Instruction>,
+) {
+    let float_count = named_args
+        .iter()
+        .filter(|&&dtype| matches!(dtype, DataType::F64 | DataType::F32))
+        .count();
+    let int_count = named_args.len() - float_count;
+    let gp_offset = (int_count * 8) as u64;
+    let fp_offset = (48 + float_count * 16) as u64;
+    gen_reload_pointer(list, stack_alloc, vreg_alloc, target);
+    let field = |size, offset| gen_deref_at(list, size, offset, stack_alloc, vreg_alloc);
+    let rbp = || Box::new(X64Register::Rbp.into());
+    let dword = X86WordSize::Dword;
+    let qword = X86WordSize::Qword;
+    let fields = [
+        (dword, 0, Operand::Im(gp_offset.to_be_bytes())),
+        (dword, 4, Operand::Im(fp_offset.to_be_bytes())),
+        // Arguments passed on stack start right above the return address
+        (
+            qword,
+            8,
+            Operand::Load(EvalTreeNode::Add(rbp(), Box::new(EvalTreeNode::Num(16)))),
+        ),
+        (
+            qword,
+            16,
+            Operand::Load(EvalTreeNode::Sub(
+                rbp(),
+                Box::new(EvalTreeNode::Num(reg_save_area as u64)),
+            )),
+        ),
+    ];
+    for (size, offset, value) in fields {
+        gen_move_instruction(size, field(size, offset), size, value, target);
+    }
+}
+
+/// Calculate the address of the next variadic argument into `rax` and advance the `va_list`
+fn gen_va_arg(
+    dtype: DataType,
+    list: u64,
+    (overflow_label, done_label): (String, String),
+    stack_alloc: &StackAllocation,
+    vreg_alloc: &VRegAllocation<X64Register>,
+    target: &mut Vec<Instruction>,
+) {
+    gen_reload_pointer(list, stack_alloc, vreg_alloc, target);
+    let field = |size, offset| gen_deref_at(list, size, offset, stack_alloc, vreg_alloc);
+    // Offset of the field in `va_list`, end of the registers in the register save area, and size
+    // of each of the registers
+    let (offset_field, limit, step) = match dtype {
+        DataType::F64 | DataType::F32 => (4, 48 + 8 * 16, 16),
+        _ => (0, 48, 8),
+    };
+    let dword = X86WordSize::Dword;
+    let qword = X86WordSize::Qword;
+    let rax = X64Register::Rax;
+    let im = |x: u64| Operand::Im(x.to_be_bytes());
+    target.extend([
+        Instruction::Mov(X64Register::Eax.into(), field(dword, offset_field)),
+        Instruction::Cmp(X64Register::Eax.into(), im(limit)),
+        Instruction::Jcc(Condition::Ae, overflow_label.clone()),
+        // Writing to `eax` cleared the upper half of `rax`
+        Instruction::Add(rax.into(), field(qword, 16)),
+        Instruction::Add(field(dword, offset_field), im(step)),
+        Instruction::Jmp(done_label.clone()),
+        Instruction::Label(overflow_label),
+        Instruction::Mov(rax.into(), field(qword, 8)),
+        Instruction::Add(field(qword, 8), im(8)),
+        Instruction::Label(done_label),
+    ]);
+}
+
+/// Location of a stack pointer VReg relative to `rbp`
+fn stack_ptr_location(stack_alloc: &StackAllocation, stack_ptr: (usize, usize)) -> usize {
+    let (stackspace_id, offset) = stack_ptr;
+    stack_alloc.var_location(stackspace_id) - offset
+}
+
+/// Generate an operand for the memory that the VReg `id` points to
+fn gen_deref(
+    id: u64,
+    dtype: DataType,
+    stack_alloc: &StackAllocation,
+    vreg_alloc: &VRegAllocation<X64Register>,
+    target: &mut Vec<Instruction>,
+) -> Operand {
+    gen_reload_pointer(id, stack_alloc, vreg_alloc, target);
+    gen_deref_at(id, dtype.into(), 0, stack_alloc, vreg_alloc)
+}
+
+/// Load the VReg `id` into `r11` if it's spilled, for `gen_deref_at` to dereference it
+fn gen_reload_pointer(
+    id: u64,
+    stack_alloc: &StackAllocation,
+    vreg_alloc: &VRegAllocation<X64Register>,
+    target: &mut Vec<Instruction>,
+) {
+    if let Some(slot) = vreg_alloc.get_alloced_spill(id) {
+        target.push(Instruction::Mov(
+            X64Register::R11.into(),
+            Operand::rbp_sub(X86WordSize::Qword, stack_alloc.var_location(slot)),
+        ));
+    }
+}
+
+/// Where the value of the VReg `id` is kept, `None` if it's not in a real register or spilled
+fn vreg_location(
+    id: u64,
+    size: X86WordSize,
+    stack_alloc: &StackAllocation,
+    vreg_alloc: &VRegAllocation<X64Register>,
+) -> Option<Operand> {
+    if let Some(reg) = vreg_alloc.get_alloced_reg(id) {
+        Some(reg.of_size(size).into())
+    } else {
+        let slot = vreg_alloc.get_alloced_spill(id)?;
+        Some(Operand::rbp_sub(size, stack_alloc.var_location(slot)))
+    }
+}
+
+/// Generate an operand for the memory `offset` bytes after where the VReg `id` points to
+/// A spilled VReg has to be reloaded with `gen_reload_pointer` first
+fn gen_deref_at(
+    id: u64,
+    size: X86WordSize,
+    offset: usize,
+    stack_alloc: &StackAllocation,
+    vreg_alloc: &VRegAllocation<X64Register>,
+) -> Operand {
+    if let Some(stack_ptr) = vreg_alloc.get_alloced_stackptr(id) {
+        Operand::rbp_sub(size, stack_ptr_location(stack_alloc, stack_ptr) - offset)
+    } else if let Some(reg) = vreg_alloc
+        .get_alloced_reg(id)
+        .or_else(|| vreg_alloc.get_alloced_spill(id).map(|_| X64Register::R11))
+    {
+        let address = if offset == 0 {
+            reg.into()
+        } else {
+            EvalTreeNode::Add(
+                Box::new(reg.into()),
+                Box::new(EvalTreeNode::Num(offset as u64)),
+            )
+        };
+        Operand::WordPtr(size, address)
+    } else if let Some((name, global_offset)) = vreg_alloc.get_alloced_global(id) {
+        Operand::WordPtr(size, global_address((name, global_offset + offset)))
+    } else {
+        panic!(
+            "Dereferencing a register that is not a pointer (vreg: {})",
+            id
+        )
+    }
+}
+
+/// Address of a global plus an offset
+fn global_address((name, offset): (Rc<String>, usize)) -> EvalTreeNode {
+    if offset == 0 {
+        EvalTreeNode::Label(name)
+    } else {
+        EvalTreeNode::Add(
+            Box::new(EvalTreeNode::Label(name)),
+            Box::new(EvalTreeNode::Num(offset as u64)),
+        )
+    }
+}
+
+/// Generate the address calculation of a `FieldPtr` or `ElemPtr` into the VReg `id`
+fn gen_field_ptr(
+    id: u64,
+    instruction: IRInstruction,
+    stack_alloc: &StackAllocation,
+    vreg_alloc: &VRegAllocation<X64Register>,
+    type_defs: &TypeDefs,
+    target: &mut Vec<Instruction>,
+) {
+    if vreg_alloc.get_alloced_stackptr(id).is_some() || vreg_alloc.get_alloced_global(id).is_some()
+    {
+        // Pointers into stack spaces or globals with constant offsets are resolved at compile time
+        return;
+    }
+    let Some(dest) = vreg_location(id, X86WordSize::Qword, stack_alloc, vreg_alloc) else {
+        return; // Culled by the VReg allocator
+    };
+    // TODO: dynamic word size
+    let (base_id, offset) = match instruction {
+        IRInstruction::FieldPtr { ty, id, index } => {
+            (id, EvalTreeNode::Num(type_defs.offset_of(&ty, index, 8)))
+        }
+        IRInstruction::ElemPtr { ty, id, index } => {
+            let elem_size = type_defs.layout(&ty, 8).size;
+            let (index_dtype, index_oper) = gen_operand(*index, stack_alloc, vreg_alloc, target);
+            gen_extend_to_qword(X64Register::Rax, index_dtype, index_oper, target);
+            match elem_size {
+                1 | 2 | 4 | 8 => (
+                    id,
+                    EvalTreeNode::Mul(
+                        Box::new(X64Register::Rax.into()),
+                        Box::new(EvalTreeNode::Num(elem_size)),
+                    ),
+                ),
+                _ => {
+                    target.push(Instruction::Imul(
+                        X64Register::Rax.into(),
+                        Operand::Im(elem_size.to_be_bytes()),
+                    ));
+                    (id, X64Register::Rax.into())
+                }
+            }
+        }
+        illegal => panic!("{:?} is not a field or element pointer", illegal),
+    };
+    let base = if let Some(stack_ptr) = vreg_alloc.get_alloced_stackptr(base_id) {
+        EvalTreeNode::Sub(
+            Box::new(X64Register::Rbp.into()),
+            Box::new(EvalTreeNode::Num(
+                stack_ptr_location(stack_alloc, stack_ptr) as u64,
+            )),
+        )
+    } else if let Some(reg) = vreg_alloc.get_alloced_reg(base_id) {
+        reg.into()
+    } else if vreg_alloc.get_alloced_spill(base_id).is_some() {
+        gen_reload_pointer(base_id, stack_alloc, vreg_alloc, target);
+        X64Register::R11.into()
+    } else if let Some(global) = vreg_alloc.get_alloced_global(base_id) {
+        // `rip` relative addresses can't have index registers
+        target.push(Instruction::Lea(
+            X64Register::R11.into(),
+            Operand::Load(global_address(global)),
+        ));
+        X64Register::R11.into()
+    } else {
+        panic!(
+            "Indexing into a register that is not a pointer (vreg: {})",
+            base_id
+        )
+    };
+    let address = Operand::Load(EvalTreeNode::Add(Box::new(base), Box::new(offset)));
+    match dest {
+        Operand::Reg(_) => target.push(Instruction::Lea(dest, address)),
+        // `lea` can't write into memory
+        _ => {
+            let rax = X64Register::Rax;
+            target.push(Instruction::Lea(rax.into(), address));
+            target.push(Instruction::Mov(dest, rax.into()));
+        }
+    }
+}
+
+/// Generate a move of an integer operand into a 64-bit register, sign-extending or zero-extending
+/// according to its data type
+fn gen_extend_to_qword(
+    reg: X64Register,
+    dtype: DataType,
+    operand: Operand,
+    target: &mut Vec<Instruction>,
+) {
+    let size: X86WordSize = dtype.into();
+    let signed = is_signed(dtype);
+    match (&operand, size) {
+        (Operand::Load(_), _) => target.push(Instruction::Lea(reg.into(), operand)),
+        (Operand::Im(_), _) | (_, X86WordSize::Qword) => {
+            target.push(Instruction::Mov(reg.into(), operand))
+        }
+        _ if signed => target.push(Instruction::Movsx(reg.into(), operand)),
+        // Writing to a 32-bit register clears the upper half
+        (_, X86WordSize::Dword) => target.push(Instruction::Mov(reg.of_size(size).into(), operand)),
+        _ => target.push(Instruction::Movzx(reg.into(), operand)),
+    }
+}
+
+/// Whether an immediate can be encoded as a sign-extended 32-bit immediate
+fn fits_in_imm32(bytes: [u8; 8]) -> bool {
+    i32::try_from(i64::from_be_bytes(bytes)).is_ok()
+}
+
 /// Generate a `mov` instruction
 fn gen_move_instruction(
     lhs_size: X86WordSize,
@@ -457,14 +2072,34 @@ fn gen_move_instruction(
     match (&lhs_oper, &rhs_oper) {
         (Operand::WordPtr(_, _), Operand::Load(_)) => {
             let rax = X64Register::Rax.of_size(lhs_size);
-            target.push(Instruction::Lea(rax.into(), lhs_oper));
-            target.push(Instruction::Mov(rhs_oper, rax.into()));
+            target.push(Instruction::Lea(rax.into(), rhs_oper));
+            target.push(Instruction::Mov(lhs_oper, rax.into()));
         }
         (_, Operand::Load(_)) => target.push(Instruction::Lea(lhs_oper, rhs_oper)),
         (Operand::WordPtr(_, _), Operand::WordPtr(_, _)) => {
             let rax = X64Register::Rax.of_size(lhs_size);
-            target.push(Instruction::Mov(rax.into(), lhs_oper));
-            target.push(Instruction::Mov(rhs_oper, rax.into()));
+            gen_move_instruction(lhs_size, rax.into(), rhs_size, rhs_oper, target);
+            target.push(Instruction::Mov(lhs_oper, rax.into()));
+        }
+        (Operand::WordPtr(_, _), Operand::Im(bytes))
+            if lhs_size == X86WordSize::Qword && !fits_in_imm32(*bytes) =>
+        {
+            // There's no `mov` from a 64-bit immediate into memory
+            target.push(Instruction::Mov(X64Register::Rax.into(), rhs_oper));
+            target.push(Instruction::Mov(lhs_oper, X64Register::Rax.into()));
+        }
+        (Operand::WordPtr(_, _), Operand::Reg(_)) if rhs_size < lhs_size => {
+            let rax = X64Register::Rax.of_size(lhs_size);
+            gen_move_instruction(lhs_size, rax.into(), rhs_size, rhs_oper, target);
+            target.push(Instruction::Mov(lhs_oper, rax.into()));
+        }
+        (_, Operand::Reg(reg)) if rhs_size > lhs_size => {
+            target.push(Instruction::Mov(lhs_oper, reg.of_size(lhs_size).into()))
+        }
+        (_, Operand::Im(_)) => target.push(Instruction::Mov(lhs_oper, rhs_oper)),
+        (Operand::Reg(reg), _) if rhs_size == X86WordSize::Dword && lhs_size > rhs_size => {
+            // Writing to a 32-bit register clears the upper half, and there's no 32-bit `movzx`
+            target.push(Instruction::Mov(reg.of_size(rhs_size).into(), rhs_oper))
         }
         _ => {
             if rhs_size < lhs_size {
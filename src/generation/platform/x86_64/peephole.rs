@@ -0,0 +1,144 @@
+//! Peephole optimizations on the generated instructions
+//!
+//! Looks at one or two adjacent instructions at a time and replaces them with cheaper ones,
+//! repeating until nothing changes. Labels are never removed or moved, so no rewrite can cross
+//! a jump target
+
+use super::{EvalTreeNode, Instruction, Operand, X64Register, X86WordSize};
+
+/// Optimize the instructions in place
+pub fn peephole(code: &mut Vec<Instruction>) {
+    while remove_unreachable(code) | simplify(code) {}
+}
+
+/// Remove the instructions after a `ret` or a jump that no label leads to
+fn remove_unreachable(code: &mut Vec<Instruction>) -> bool {
+    let len = code.len();
+    let mut reachable = true;
+    code.retain(|instruction| {
+        if !is_code(instruction) || matches!(instruction, Instruction::Label(_)) {
+            reachable = true;
+            return true;
+        }
+        let keep = reachable;
+        if matches!(
+            instruction,
+            Instruction::Ret | Instruction::Jmp(_) | Instruction::TailJmp(_)
+        ) {
+            reachable = false;
+        }
+        keep
+    });
+    code.len() != len
+}
+
+/// Whether the instruction is executed, rather than a directive or a function entry
+fn is_code(instruction: &Instruction) -> bool {
+    !matches!(
+        instruction,
+        Instruction::GlobalLabel(..)
+            | Instruction::Extern(_)
+            | Instruction::Section(_)
+            | Instruction::Align(_)
+            | Instruction::Bytes(_)
+            | Instruction::Reserve(_)
+    )
+}
+
+fn simplify(code: &mut Vec<Instruction>) -> bool {
+    let mut changed = false;
+    let mut simplified = Vec::<Instruction>::with_capacity(code.len());
+    for instruction in code.drain(..) {
+        let instruction = match instruction {
+            // `test` is shorter and sets the flags the same way
+            Instruction::Cmp(Operand::Reg(reg), Operand::Im(bytes)) if bytes == [0; 8] => {
+                changed = true;
+                Instruction::Test(reg.into(), reg.into())
+            }
+            Instruction::Mov(Operand::Reg(lhs), Operand::Reg(rhs))
+                if lhs == rhs && lhs.word_size() != X86WordSize::Dword =>
+            {
+                changed = true;
+                continue;
+            }
+            instruction => instruction,
+        };
+        let combined = simplified
+            .last()
+            .and_then(|last| combine(last, &instruction));
+        match combined {
+            Some(combined) => {
+                simplified.pop();
+                simplified.extend(combined);
+                changed = true;
+            }
+            None => simplified.push(instruction),
+        }
+    }
+    *code = simplified;
+    changed
+}
+
+/// What two adjacent instructions can be replaced with
+fn combine(first: &Instruction, second: &Instruction) -> Option<Vec<Instruction>> {
+    use Instruction::*;
+    Some(match (first, second) {
+        // `mov a, b` then `mov b, a`, the second one moves the same value back
+        // Moving into a 32-bit register also clears the upper half, so it's not a no-op
+        (Mov(a, b), Mov(c, d))
+            if a == d && b == c && !reads(b, a) && c.word_size() != Some(X86WordSize::Dword) =>
+        {
+            vec![first.clone()]
+        }
+        // The first `mov` is overwritten before it's read
+        (Mov(a, _), Mov(c, d)) if a == c && !reads(d, a) => vec![second.clone()],
+        // `lea r, [...]` then `mov r, [r]`, as long as the `mov` overwrites all of `r`
+        (
+            Lea(Operand::Reg(a), Operand::Load(address)),
+            Mov(Operand::Reg(c), Operand::WordPtr(size, EvalTreeNode::Reg(b))),
+        ) if a == b && overlaps(*a, *c) && c.word_size() >= X86WordSize::Dword => {
+            vec![Mov(
+                Operand::Reg(*c),
+                Operand::WordPtr(*size, address.clone()),
+            )]
+        }
+        (AllocStack(a), AllocStack(b)) => vec![AllocStack(a + b)],
+        (DeallocStack(a), DeallocStack(b)) => vec![DeallocStack(a + b)],
+        (AllocStack(a), DeallocStack(b)) | (DeallocStack(b), AllocStack(a)) => match a.cmp(b) {
+            std::cmp::Ordering::Less => vec![DeallocStack(b - a)],
+            std::cmp::Ordering::Equal => Vec::new(),
+            std::cmp::Ordering::Greater => vec![AllocStack(a - b)],
+        },
+        (Push(a), Pop(b)) if a == b => Vec::new(),
+        // Jumping to the next instruction
+        (Jmp(target), Label(label)) if target == label => vec![second.clone()],
+        _ => return None,
+    })
+}
+
+/// Whether the two registers are parts of the same register
+fn overlaps(a: X64Register, b: X64Register) -> bool {
+    a.of_size(X86WordSize::Qword) == b.of_size(X86WordSize::Qword)
+}
+
+/// Whether evaluating the operand reads any part of `written`
+fn reads(operand: &Operand, written: &Operand) -> bool {
+    let Operand::Reg(written) = written else {
+        return false;
+    };
+    match operand {
+        Operand::Reg(reg) => overlaps(*reg, *written),
+        Operand::Load(address) | Operand::WordPtr(_, address) => uses_reg(address, *written),
+        Operand::Xmm(_) | Operand::Im(_) | Operand::Label(_) => false,
+    }
+}
+
+fn uses_reg(address: &EvalTreeNode, reg: X64Register) -> bool {
+    match address {
+        EvalTreeNode::Add(lhs, rhs) | EvalTreeNode::Sub(lhs, rhs) | EvalTreeNode::Mul(lhs, rhs) => {
+            uses_reg(lhs, reg) || uses_reg(rhs, reg)
+        }
+        EvalTreeNode::Reg(other) => overlaps(*other, reg),
+        EvalTreeNode::Num(_) | EvalTreeNode::Label(_) => false,
+    }
+}
@@ -1,8 +1,11 @@
-#[allow(dead_code)]
-pub fn asm_str_from(bytes: &Vec<u8>) -> String {
+/// Format bytes into a comma separated list for `db`
+pub fn asm_str_from(bytes: &[u8]) -> String {
     let mut result = String::new();
-    for b in bytes {
-        result.push_str(format!("{b:#02X}, ").as_str());
+    for (i, b) in bytes.iter().enumerate() {
+        if i != 0 {
+            result.push_str(", ");
+        }
+        result.push_str(format!("{b:#02X}").as_str());
     }
     result
 }
@@ -1,20 +1,30 @@
-use std::{collections::HashMap, ops::Range};
+use std::{collections::HashMap, ops::Range, rc::Rc};
 
-use crate::{generation::stack_alloc::StackAllocator, ir::Instruction};
+use crate::{
+    analysis::{cfg::Cfg, dominators::DomTree, loops::LoopInfo},
+    generation::stack_alloc::StackAllocator,
+    ir::{Instruction, TypeDefs},
+};
 
 pub trait Register
 where
     Self: Sized + Copy + Eq + std::fmt::Debug,
 {
     fn caller_saved() -> Vec<Self>;
+    #[allow(dead_code)]
     fn callee_saved() -> Vec<Self>;
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 /// The type of content inside the VReg, could be either a pointer to a stack space, or a value
 enum VRegContentKind {
-    /// `u8` is the dtype size
-    StackPtr(u8),
+    /// Pointer to a new stack space, `usize`s are the size and alignment of the space
+    StackPtr(usize, usize),
+    /// Pointer into the stack space of a previous `StackPtr` register
+    /// First `usize` is internal id of the `StackPtr` register, second `usize` is the byte offset
+    StackOffset(usize, usize),
+    /// Pointer into a global, `usize`s are the index into the symbol table and the byte offset
+    Global(usize, usize),
     Normal,
     Const([u8; 8]),
     /// same content as a previously occured register, `usize` is internal id
@@ -31,6 +41,9 @@ pub struct VRegInfo {
     /// Where the real location of the register is, can be either inside a real register in the CPU
     /// or on the stack
     pub allocation: Option<VRegAlloc>,
+    /// How costly it is to keep the VReg on the stack instead of in a real register
+    /// Every definition and use adds to it, weighted by how deeply nested in loops it is
+    pub spill_weight: u64,
 }
 impl Default for VRegInfo {
     fn default() -> Self {
@@ -39,6 +52,7 @@ impl Default for VRegInfo {
             content_kind: VRegContentKind::Normal,
             lifetime: Default::default(),
             allocation: None,
+            spill_weight: 0,
         }
     }
 }
@@ -48,9 +62,15 @@ pub enum VRegAlloc {
     /// Use a real register in place for the virtual register
     RealReg(usize),
     /// VReg is a point to a stack space
-    /// `usize` is the Stackspace ID
-    StackPtr(usize),
+    /// First `usize` is the Stackspace ID, second `usize` is the byte offset into the Stackspace
+    StackPtr(usize, usize),
+    /// VReg is a pointer into a global
+    /// First `usize` is the index into the symbol table, second `usize` is the byte offset
+    Global(usize, usize),
     Const([u8; 8]),
+    /// The value of the VReg is kept on the stack because there aren't enough real registers
+    /// `usize` is the Stackspace ID of the slot
+    Spilled(usize),
 }
 
 impl VRegAlloc {
@@ -61,10 +81,18 @@ impl VRegAlloc {
             None
         }
     }
-    /// Returns the Stackspace ID of the register
-    pub fn as_stack_ptr(&self) -> Option<usize> {
-        if let Self::StackPtr(v) = self {
-            Some(*v)
+    /// Returns the Stackspace ID of the register and the offset into the Stackspace
+    pub fn as_stack_ptr(&self) -> Option<(usize, usize)> {
+        if let Self::StackPtr(id, offset) = self {
+            Some((*id, *offset))
+        } else {
+            None
+        }
+    }
+    /// Returns the index into the symbol table and the offset into the global
+    pub fn as_global(&self) -> Option<(usize, usize)> {
+        if let Self::Global(symbol, offset) = self {
+            Some((*symbol, *offset))
         } else {
             None
         }
@@ -76,21 +104,36 @@ impl VRegAlloc {
             None
         }
     }
+    /// Returns the Stackspace ID of the slot that the VReg is spilled into
+    pub fn as_spilled(&self) -> Option<usize> {
+        if let Self::Spilled(v) = self {
+            Some(*v)
+        } else {
+            None
+        }
+    }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How the VRegs holding values are assigned to real registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RegAllocStrategy {
+    /// Every VReg gets its own stack slot, so all of them can be inspected in a debugger
+    SpillAll,
+    /// Real registers are handed out in order of the lifetimes, spilling the VRegs with the lowest
+    /// spill weights when they run out
+    #[default]
+    LinearScan,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 /// State of a virtual register's life at one since step
 enum VRegLifeStage {
     Born = 0x10,
     Live,
     Dying,
+    #[default]
     Dead = 000,
 }
-impl Default for VRegLifeStage {
-    fn default() -> Self {
-        Self::Dead
-    }
-}
 impl VRegLifeStage {
     #[allow(dead_code)]
     fn is_living(self) -> bool {
@@ -133,9 +176,13 @@ where
     /// Information of all of the virtual registers used
     /// Ordered by internal ID's
     vreg_infos: Vec<VRegInfo>,
+    /// Names of the globals that are pointed to by the virtual registers
+    symbols: Vec<Rc<String>>,
     /// A big map of the status of the status of every VReg and real registers in every step of one
     /// block
     step_map: Vec<RegStatus>,
+    /// How much a definition or use in each step adds to the spill weight of a VReg
+    step_weights: Vec<u64>,
 }
 impl<R> VRegAllocation<R>
 where
@@ -148,9 +195,11 @@ where
             reg_ids: regs,
             vreg_ids: HashMap::with_capacity(vreg_count),
             vreg_infos: Vec::with_capacity(vreg_count),
+            symbols: Vec::new(),
             step_map: (0..step_count)
                 .map(|_| RegStatus::empty(vreg_count))
                 .collect(),
+            step_weights: vec![1; step_count],
         }
     }
     /// Add a new virtual register
@@ -162,6 +211,7 @@ where
             content_kind: kind,
             lifetime: 0..0,
             allocation: None,
+            spill_weight: 0,
         })
     }
     /// Mark a virtual register alive at `step` as `SingleDay`
@@ -171,27 +221,44 @@ where
         let internal_id = *self.vreg_ids.get(&id).unwrap();
         let vreg_info = self.vreg_infos.get_mut(internal_id).unwrap();
         vreg_info.lifetime = step..step;
+        vreg_info.spill_weight += self.step_weights[step];
         self.step_map[step].life_stages[internal_id] = VRegLifeStage::Born;
     }
+    /// Extend the lifetime of a virtual register to a step that uses it, and add the use to the
+    /// spill weight of the VReg that holds the allocation
+    fn mark_used(&mut self, id: u64, step: usize) {
+        self.mark_alive_until(id, step);
+        let internal_id = self.alias_root(self.vreg_ids[&id]);
+        self.vreg_infos[internal_id].spill_weight += self.step_weights[step];
+    }
     /// Extend the lifetime of a virtual register to `step`
     /// Start of the lifetime does not change
     /// If `step` < `start` the function would mark the register alive to the end of the body
     fn mark_alive_until(&mut self, id: u64, end: usize) {
         let internal_id = *self.vreg_ids.get(&id).unwrap();
         let vreg_info = self.vreg_infos.get_mut(internal_id).unwrap();
+        if end < vreg_info.lifetime.end {
+            return;
+        }
         let start_index = if vreg_info.lifetime.start == vreg_info.lifetime.end {
             vreg_info.lifetime.end + 1
         } else {
             vreg_info.lifetime.end
         };
-        self.step_map[start_index..end]
+        self.step_map[start_index.min(end)..end]
             .iter_mut()
             .for_each(|status| status.life_stages[internal_id] = VRegLifeStage::Live);
         self.step_map[end].life_stages[internal_id] = VRegLifeStage::Dying;
         vreg_info.lifetime.end = end;
+        // An aliased VReg shares the allocation of the original one, so the original one has to
+        // live at least as long
+        if let VRegContentKind::Aliased(aliased_id) = vreg_info.content_kind {
+            let aliased_external_id = self.vreg_infos[aliased_id].external_id;
+            self.mark_alive_until(aliased_external_id, end);
+        }
     }
     /// Try to allocate a real register for the VReg, returns the internal ID for the register
-    fn try_alloc_real_reg(reg_occupations: &mut Vec<bool>) -> Option<usize> {
+    fn try_alloc_real_reg(reg_occupations: &mut [bool]) -> Option<usize> {
         reg_occupations
             .iter_mut()
             .enumerate()
@@ -204,102 +271,249 @@ where
                 }
             })
     }
+    /// Extend the lifetimes of all the virtual registers used by an operand to `step`
+    fn mark_uses(&mut self, instr: &Instruction, step: usize) {
+        match instr {
+            Instruction::Reg(_, id)
+            | Instruction::Load { id, dtype: _ }
+            | Instruction::FieldPtr { id, .. }
+            | Instruction::VaArg { id, .. } => {
+                self.mark_used(*id, step);
+            }
+            Instruction::ElemPtr { ty: _, id, index } => {
+                self.mark_used(*id, step);
+                self.mark_uses(index, step);
+            }
+            Instruction::Add(_, lhs, rhs)
+            | Instruction::Sub(_, lhs, rhs)
+            | Instruction::Mul(_, lhs, rhs)
+            | Instruction::Div(_, lhs, rhs)
+            | Instruction::Not(_, lhs, rhs)
+            | Instruction::And(_, lhs, rhs)
+            | Instruction::Or(_, lhs, rhs)
+            | Instruction::Xor(_, lhs, rhs)
+            | Instruction::Rem(_, lhs, rhs)
+            | Instruction::MulHi(_, lhs, rhs)
+            | Instruction::Shl(_, lhs, rhs)
+            | Instruction::Shr(_, lhs, rhs)
+            | Instruction::Eq(_, lhs, rhs)
+            | Instruction::Ne(_, lhs, rhs)
+            | Instruction::Lt(_, lhs, rhs)
+            | Instruction::Le(_, lhs, rhs)
+            | Instruction::Gt(_, lhs, rhs)
+            | Instruction::Ge(_, lhs, rhs) => {
+                self.mark_uses(lhs, step);
+                self.mark_uses(rhs, step);
+            }
+            Instruction::Call { .. } => {
+                self.step_map[step].has_fn_call = true;
+                for operand in instr.operands() {
+                    self.mark_uses(operand, step);
+                }
+            }
+            _ => (),
+        }
+    }
+    /// If the VReg points to somewhere inside a stack space or a global, returns the content kind
+    /// of a pointer `offset` bytes after it
+    fn offset_pointer(&self, id: u64, offset: usize) -> Option<VRegContentKind> {
+        let internal_id = self.vreg_ids[&id];
+        match self.vreg_infos[internal_id].content_kind {
+            VRegContentKind::StackPtr(_, _) => {
+                Some(VRegContentKind::StackOffset(internal_id, offset))
+            }
+            VRegContentKind::StackOffset(root, root_offset) => {
+                Some(VRegContentKind::StackOffset(root, root_offset + offset))
+            }
+            VRegContentKind::Global(symbol, global_offset) => {
+                Some(VRegContentKind::Global(symbol, global_offset + offset))
+            }
+            VRegContentKind::Aliased(aliased_id) => {
+                self.offset_pointer(self.vreg_infos[aliased_id].external_id, offset)
+            }
+            _ => None,
+        }
+    }
+    /// Index of a global in the symbol table, adding it if it's not there yet
+    fn symbol_index(&mut self, name: &Rc<String>) -> usize {
+        match self.symbols.iter().position(|symbol| symbol == name) {
+            Some(i) => i,
+            None => {
+                self.symbols.push(Rc::clone(name));
+                self.symbols.len() - 1
+            }
+        }
+    }
+    fn content_kind_of(&mut self, rhs: &Instruction, type_defs: &TypeDefs) -> VRegContentKind {
+        // TODO: dynamic word size
+        match rhs {
+            Instruction::Alloc(ty) => {
+                let layout = type_defs.layout(ty, 8);
+                VRegContentKind::StackPtr(layout.size as usize, layout.align as usize)
+            }
+            Instruction::GlobalPtr(name) => VRegContentKind::Global(self.symbol_index(name), 0),
+            Instruction::FieldPtr { ty, id, index } => self
+                .offset_pointer(*id, type_defs.offset_of(ty, *index, 8) as usize)
+                .unwrap_or(VRegContentKind::Normal),
+            Instruction::ElemPtr { ty, id, index } => {
+                let const_index = match index.as_ref() {
+                    Instruction::UInt(_, u) => Some(*u),
+                    Instruction::Int(_, i) if *i >= 0 => Some(*i as u64),
+                    _ => None,
+                };
+                const_index
+                    .and_then(|i| {
+                        self.offset_pointer(*id, (i * type_defs.layout(ty, 8).size) as usize)
+                    })
+                    .unwrap_or(VRegContentKind::Normal)
+            }
+            Instruction::Reg(_, id) => {
+                let aliased_id = self.vreg_ids[id];
+                VRegContentKind::Aliased(aliased_id)
+            }
+            Instruction::UInt(_, u) => VRegContentKind::Const(u.to_be_bytes()),
+            Instruction::Int(_, i) => VRegContentKind::Const(i.to_be_bytes()),
+            Instruction::Float(_, f) => VRegContentKind::Const(f.to_be_bytes()),
+            _ => VRegContentKind::Normal,
+        }
+    }
     /// Generate a register allocator for a block
-    pub fn generate_from(body: &Vec<Instruction>, stack_allocator: &mut StackAllocator) -> Self {
+    pub fn generate_from(
+        body: &[Instruction],
+        type_defs: &TypeDefs,
+        strategy: RegAllocStrategy,
+        stack_allocator: &mut StackAllocator,
+    ) -> Self {
         let vreg_count = body.iter().filter(|&i| i.is_def_reg()).count();
         let step_count = body.len();
         let mut allocator = Self::empty(step_count, vreg_count);
-        macro_rules! update_reg_lifetime_if_needed {
-            ($i: expr, $step: expr) => {
-                match $i {
-                    Instruction::Reg(_, id) | Instruction::Load { id, dtype: _ } => {
-                        allocator.mark_alive_until(*id, $step);
-                    }
-                    _ => (),
-                }
-            };
-        }
+        allocator.weight_steps_by_loop_depth(body);
         body.iter()
             .enumerate()
             .for_each(|(step, instr)| match instr {
                 Instruction::DefReg { id, rhs } => {
-                    allocator.add_vreg(
-                        *id,
-                        match rhs.as_ref() {
-                            Instruction::Alloc(dtype) => {
-                                // TODO: dynamic word size
-                                VRegContentKind::StackPtr(dtype.size(8))
-                            }
-                            Instruction::Reg(_, id) => {
-                                let aliased_id = allocator.vreg_ids[id];
-                                VRegContentKind::Aliased(aliased_id)
-                            }
-                            Instruction::UInt(_, u) => VRegContentKind::Const(u.to_be_bytes()),
-                            Instruction::Int(_, i) => VRegContentKind::Const(i.to_be_bytes()),
-                            Instruction::Float(_, f) => VRegContentKind::Const(f.to_be_bytes()),
-                            _ => VRegContentKind::Normal,
-                        },
-                    );
+                    let kind = allocator.content_kind_of(rhs, type_defs);
+                    allocator.add_vreg(*id, kind);
                     allocator.mark_alive(*id, step);
-                    update_reg_lifetime_if_needed!(rhs.as_ref(), step);
+                    allocator.mark_uses(rhs, step);
                 }
                 Instruction::Store {
                     lhs_dtype: _,
                     id,
                     rhs,
                 } => {
-                    allocator.mark_alive_until(*id, step);
-                    update_reg_lifetime_if_needed!(rhs.as_ref(), step);
-                }
-                Instruction::Ret(Some(ret_val)) => {
-                    update_reg_lifetime_if_needed!(ret_val.as_ref(), step);
-                }
-                Instruction::Add(_, lhs, rhs)
-                | Instruction::Sub(_, lhs, rhs)
-                | Instruction::Mul(_, lhs, rhs)
-                | Instruction::Div(_, lhs, rhs) => {
-                    update_reg_lifetime_if_needed!(lhs.as_ref(), step);
-                    update_reg_lifetime_if_needed!(rhs.as_ref(), step);
+                    allocator.mark_used(*id, step);
+                    allocator.mark_uses(rhs, step);
                 }
+                Instruction::Ret(Some(ret_val)) => allocator.mark_uses(ret_val, step),
                 Instruction::Ret(None) => (),
-                Instruction::Call {
-                    ret_type: _,
-                    fn_name: _,
-                    args,
-                } => {
-                    allocator.step_map[step].has_fn_call = true;
-                    for arg in args {
-                        update_reg_lifetime_if_needed!(arg, step);
-                    }
-                }
-                Instruction::Label(_) => (),
+                Instruction::Call { .. } => allocator.mark_uses(instr, step),
+                Instruction::Label(_) | Instruction::Jmp(_) => (),
+                Instruction::Br { cond, .. } => allocator.mark_uses(cond, step),
+                Instruction::VaStart(id) | Instruction::VaEnd(id) => allocator.mark_used(*id, step),
                 instr => panic!("{:?} in root level is invalid", instr),
             });
-        allocator.alloc_regs(stack_allocator);
+        allocator.extend_across_back_edges(body);
+        allocator.alloc_regs(strategy, stack_allocator);
         allocator
     }
+    /// Steps inside loops are assumed to run 10 times for each time the loop is entered
+    fn weight_steps_by_loop_depth(&mut self, body: &[Instruction]) {
+        let cfg = Cfg::new(body);
+        let loops = LoopInfo::new(&cfg, &DomTree::new(&cfg));
+        for (step, block) in cfg.block_of_instructions().into_iter().enumerate() {
+            self.step_weights[step] = 10u64.saturating_pow(loops.depth(block) as u32);
+        }
+    }
+    /// A VReg that is alive when entering a loop has to stay alive until the jump back to the start
+    /// of the loop, since the loop body may run again after its last use
+    fn extend_across_back_edges(&mut self, body: &[Instruction]) {
+        let label_steps: HashMap<&String, usize> = body
+            .iter()
+            .enumerate()
+            .filter_map(|(step, instr)| match instr {
+                Instruction::Label(name) => Some((name, step)),
+                _ => None,
+            })
+            .collect();
+        let back_edges: Vec<(usize, usize)> = body
+            .iter()
+            .enumerate()
+            .flat_map(|(step, instr)| {
+                instr
+                    .jump_targets()
+                    .into_iter()
+                    .filter_map(|label| label_steps.get(label).copied())
+                    .filter(move |&target| target <= step)
+                    .map(move |target| (target, step))
+            })
+            .collect();
+        // Extending one VReg may make it cross another back edge, so repeat until nothing changes
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &(loop_start, loop_end) in &back_edges {
+                for internal_id in 0..self.vreg_infos.len() {
+                    let lifetime = self.vreg_infos[internal_id].lifetime.clone();
+                    if lifetime.is_empty() || lifetime.start >= loop_start {
+                        continue;
+                    }
+                    if lifetime.end >= loop_start && lifetime.end < loop_end {
+                        let external_id = self.vreg_infos[internal_id].external_id;
+                        self.mark_alive_until(external_id, loop_end);
+                        changed = true;
+                    }
+                }
+            }
+        }
+    }
     /// Allocate real registers or stack space for the all virtual registers
-    fn alloc_regs(&mut self, stack_allocator: &mut StackAllocator) {
+    fn alloc_regs(&mut self, strategy: RegAllocStrategy, stack_allocator: &mut StackAllocator) {
         let mut reg_occupation: Vec<bool> = self.reg_ids.iter().map(|_| false).collect();
-        for row in &mut self.step_map {
-            let life_stages = &row.life_stages;
-            for (internal_id, life_stage) in life_stages.iter().enumerate() {
-                match life_stage {
-                    VRegLifeStage::Born if self.vreg_infos[internal_id].lifetime.len() != 0 => {
+        for step in 0..self.step_map.len() {
+            for internal_id in 0..self.vreg_infos.len() {
+                match self.step_map[step].life_stages[internal_id] {
+                    VRegLifeStage::Born if !self.vreg_infos[internal_id].lifetime.is_empty() => {
                         match self.vreg_infos[internal_id].content_kind {
-                            VRegContentKind::StackPtr(dtype_size) => {
-                                let stackspace_id = stack_allocator.add_var(dtype_size);
+                            VRegContentKind::StackPtr(size, align) => {
+                                let stackspace_id = stack_allocator.add_aggregate(size, align);
                                 self.vreg_infos[internal_id].allocation =
-                                    Some(VRegAlloc::StackPtr(stackspace_id));
+                                    Some(VRegAlloc::StackPtr(stackspace_id, 0));
+                            }
+                            VRegContentKind::StackOffset(root, offset) => {
+                                self.vreg_infos[internal_id].allocation = match self.vreg_infos
+                                    [root]
+                                    .allocation
+                                {
+                                    Some(VRegAlloc::StackPtr(stackspace_id, root_offset)) => Some(
+                                        VRegAlloc::StackPtr(stackspace_id, root_offset + offset),
+                                    ),
+                                    _ => None,
+                                };
+                            }
+                            VRegContentKind::Normal | VRegContentKind::Const(_)
+                                if strategy == RegAllocStrategy::SpillAll =>
+                            {
+                                // TODO: dynamic word size
+                                self.vreg_infos[internal_id].allocation =
+                                    Some(VRegAlloc::Spilled(stack_allocator.add_var(8)));
                             }
                             VRegContentKind::Normal => {
-                                if let Some(reg_id) = Self::try_alloc_real_reg(&mut reg_occupation)
+                                let allocation = match Self::try_alloc_real_reg(&mut reg_occupation)
                                 {
-                                    self.vreg_infos[internal_id].allocation =
-                                        Some(VRegAlloc::RealReg(reg_id));
-                                } else {
-                                    todo!("Allocate stack space for virtual register");
-                                }
+                                    Some(reg_id) => VRegAlloc::RealReg(reg_id),
+                                    None => self.spill_cheapest(
+                                        internal_id,
+                                        step,
+                                        &mut reg_occupation,
+                                        stack_allocator,
+                                    ),
+                                };
+                                self.vreg_infos[internal_id].allocation = Some(allocation);
+                            }
+                            VRegContentKind::Global(symbol, offset) => {
+                                self.vreg_infos[internal_id].allocation =
+                                    Some(VRegAlloc::Global(symbol, offset));
                             }
                             VRegContentKind::Const(val) => {
                                 self.vreg_infos[internal_id].allocation =
@@ -312,7 +526,14 @@ where
                         }
                     }
                     VRegLifeStage::Live => (),
-                    VRegLifeStage::Dying => {
+                    // An aliased VReg doesn't own the real register, the original one may still be
+                    // alive
+                    VRegLifeStage::Dying
+                        if !matches!(
+                            self.vreg_infos[internal_id].content_kind,
+                            VRegContentKind::Aliased(_)
+                        ) =>
+                    {
                         if let Some(VRegAlloc::RealReg(reg)) =
                             self.vreg_infos[internal_id].allocation
                         {
@@ -323,8 +544,60 @@ where
                     _ => (),
                 }
             }
-            row.reg_occupation = reg_occupation.to_vec();
+            self.step_map[step].reg_occupation = reg_occupation.to_vec();
+        }
+    }
+    /// When a VReg is born while all the real registers are taken, either the new VReg or one
+    /// holding a real register has to live on the stack, whichever has the lowest spill weight
+    /// Returns the allocation of the new VReg
+    fn spill_cheapest(
+        &mut self,
+        internal_id: usize,
+        step: usize,
+        reg_occupation: &mut [bool],
+        stack_allocator: &mut StackAllocator,
+    ) -> VRegAlloc {
+        let cheapest = (0..self.vreg_infos.len())
+            .filter(|&id| {
+                let info = &self.vreg_infos[id];
+                matches!(info.content_kind, VRegContentKind::Normal)
+                    && matches!(info.allocation, Some(VRegAlloc::RealReg(_)))
+                    && info.lifetime.start <= step
+                    && step < info.lifetime.end
+            })
+            .min_by_key(|&id| self.vreg_infos[id].spill_weight)
+            .filter(|&id| {
+                self.vreg_infos[id].spill_weight < self.vreg_infos[internal_id].spill_weight
+            });
+        // TODO: dynamic word size
+        let slot = VRegAlloc::Spilled(stack_allocator.add_var(8));
+        let Some(evicted) = cheapest else {
+            return slot;
+        };
+        // The evicted VReg lives on the stack for its whole lifetime, so it stops occupying the
+        // register from the start
+        let reg = self.vreg_infos[evicted]
+            .allocation
+            .unwrap()
+            .as_real_reg()
+            .unwrap();
+        for row in &mut self.step_map[self.vreg_infos[evicted].lifetime.start..step] {
+            row.reg_occupation[reg] = false;
+        }
+        for id in 0..self.vreg_infos.len() {
+            if id == evicted || self.alias_root(id) == evicted {
+                self.vreg_infos[id].allocation = Some(slot);
+            }
         }
+        reg_occupation[reg] = true;
+        VRegAlloc::RealReg(reg)
+    }
+    /// Internal ID of the VReg that an aliased VReg eventually refers to
+    fn alias_root(&self, mut internal_id: usize) -> usize {
+        while let VRegContentKind::Aliased(aliased_id) = self.vreg_infos[internal_id].content_kind {
+            internal_id = aliased_id;
+        }
+        internal_id
     }
     #[allow(dead_code)]
     /// Return a register if the VReg is allocated onto a real register
@@ -335,18 +608,29 @@ where
             .as_real_reg()?;
         Some(self.reg_ids[internal_reg_id])
     }
-    /// Returns the Stackspace ID of the VReg
-    pub fn get_alloced_stackptr(&self, id: u64) -> Option<usize> {
+    /// Returns the Stackspace ID of the VReg and the byte offset into the Stackspace
+    pub fn get_alloced_stackptr(&self, id: u64) -> Option<(usize, usize)> {
         let internal_vreg_id = self.vreg_ids[&id];
         let stack_id = self.vreg_infos[internal_vreg_id]
             .allocation?
             .as_stack_ptr()?;
         Some(stack_id)
     }
+    /// Returns the name of the global that the VReg points into, and the byte offset into it
+    pub fn get_alloced_global(&self, id: u64) -> Option<(Rc<String>, usize)> {
+        let internal_vreg_id = self.vreg_ids[&id];
+        let (symbol, offset) = self.vreg_infos[internal_vreg_id].allocation?.as_global()?;
+        Some((Rc::clone(&self.symbols[symbol]), offset))
+    }
     pub fn get_alloced_const(&self, id: u64) -> Option<[u8; 8]> {
         let internal_vreg_id = self.vreg_ids[&id];
         self.vreg_infos[internal_vreg_id].allocation?.as_const()
     }
+    /// Returns the Stackspace ID of the slot if the VReg is spilled onto the stack
+    pub fn get_alloced_spill(&self, id: u64) -> Option<usize> {
+        let internal_vreg_id = self.vreg_ids[&id];
+        self.vreg_infos[internal_vreg_id].allocation?.as_spilled()
+    }
 
     pub fn for_each_living_reg<F>(&self, step: usize, mut f: F)
     where
@@ -419,7 +703,9 @@ where
                     "{}:\t{}\t{:?}",
                     info.external_id,
                     match info.content_kind {
-                        VRegContentKind::StackPtr(_) => "stack",
+                        VRegContentKind::StackPtr(_, _) => "stack",
+                        VRegContentKind::StackOffset(_, _) => "offset",
+                        VRegContentKind::Global(_, _) => "global",
                         VRegContentKind::Normal => "normal",
                         VRegContentKind::Const(_) => "const",
                         VRegContentKind::Aliased(_) => "aliased",
@@ -429,8 +715,15 @@ where
                 if let Some(reg_alloc) = info.allocation {
                     match reg_alloc {
                         VRegAlloc::RealReg(reg_id) => println!("\t{}", self.reg_ids[reg_id]),
-                        VRegAlloc::StackPtr(loc) => println!("\tstack {}", loc),
+                        VRegAlloc::StackPtr(loc, 0) => println!("\tstack {}", loc),
+                        VRegAlloc::StackPtr(loc, offset) => {
+                            println!("\tstack {} + {}", loc, offset)
+                        }
+                        VRegAlloc::Global(symbol, offset) => {
+                            println!("\t@{} + {}", self.symbols[symbol], offset)
+                        }
                         VRegAlloc::Const(val) => println!("\tconst {}", u64::from_be_bytes(val)),
+                        VRegAlloc::Spilled(loc) => println!("\tspilled {}", loc),
                     }
                 } else {
                     println!("\tNo alloc")
@@ -1,4 +1,4 @@
-use std::rc::Rc;
+use std::{collections::HashMap, rc::Rc};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DataType {
@@ -28,6 +28,151 @@ impl DataType {
             DataType::USize | DataType::ISize | DataType::Ptr => word_size,
         }
     }
+    /// Alignment of the data type in bytes, scalars are always aligned to their own size
+    pub fn align(self, word_size: u8) -> u8 {
+        self.size(word_size)
+    }
+    pub fn is_signed(self) -> bool {
+        matches!(
+            self,
+            DataType::I64 | DataType::I32 | DataType::I16 | DataType::I8 | DataType::ISize
+        )
+    }
+    pub fn is_float(self) -> bool {
+        matches!(self, DataType::F64 | DataType::F32)
+    }
+}
+
+/// A type that can be allocated, either a scalar or an aggregate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Type {
+    Scalar(DataType),
+    /// A named struct declared by `type %Name = { ... }`
+    Struct(Rc<String>),
+    /// `[count x element]`
+    Array(Box<Type>, u64),
+}
+impl From<DataType> for Type {
+    fn from(dtype: DataType) -> Self {
+        Self::Scalar(dtype)
+    }
+}
+impl Type {
+    pub fn as_scalar(&self) -> Option<DataType> {
+        if let Self::Scalar(v) = self {
+            Some(*v)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Memory layout of a type
+pub struct Layout {
+    pub size: u64,
+    pub align: u64,
+    /// Byte offsets of the fields if the type is a struct, empty otherwise
+    pub field_offsets: Vec<u64>,
+}
+
+#[derive(Debug, Clone, Default)]
+/// Definitions of all the named struct types in a program, needed for computing layouts
+pub struct TypeDefs {
+    structs: HashMap<Rc<String>, Vec<Type>>,
+}
+impl TypeDefs {
+    /// Collect all the `TopLevel::TypeDef`s in a program
+    pub fn from_ir(ir: &[TopLevel]) -> Self {
+        let mut type_defs = Self::default();
+        for top_level in ir {
+            if let TopLevel::TypeDef { name, fields } = top_level {
+                if type_defs
+                    .structs
+                    .insert(Rc::clone(name), fields.clone())
+                    .is_some()
+                {
+                    panic!("Struct type %{name} is defined more than once");
+                }
+            }
+        }
+        type_defs
+    }
+    /// Fields of a struct type, `None` if it's not defined
+    pub fn fields(&self, name: &Rc<String>) -> Option<&Vec<Type>> {
+        self.structs.get(name)
+    }
+    /// Size, alignment and field offsets of a type
+    /// Fields are laid out in order, each field aligned to its own alignment, and the size is
+    /// rounded up to a multiple of the alignment like C does
+    /// If it's Ptr, USize or ISize, the size is `word_size`
+    /// Will panic if a struct is not defined or contains itself
+    pub fn layout(&self, ty: &Type, word_size: u8) -> Layout {
+        self.layout_inner(ty, word_size, &mut Vec::new())
+    }
+    /// Byte offset of the `index`th field of a struct, or the `index`th element of an array
+    pub fn offset_of(&self, ty: &Type, index: u64, word_size: u8) -> u64 {
+        match ty {
+            Type::Scalar(dtype) => panic!("Cannot index into a scalar type {dtype:?}"),
+            Type::Struct(name) => *self
+                .layout(ty, word_size)
+                .field_offsets
+                .get(index as usize)
+                .unwrap_or_else(|| panic!("Struct %{name} does not have a field {index}")),
+            Type::Array(elem, count) => {
+                if index >= *count {
+                    panic!("Index {index} out of bounds for array of {count} elements");
+                }
+                index * self.layout(elem, word_size).size
+            }
+        }
+    }
+    fn layout_inner(&self, ty: &Type, word_size: u8, visiting: &mut Vec<Rc<String>>) -> Layout {
+        match ty {
+            Type::Scalar(dtype) => Layout {
+                size: dtype.size(word_size) as u64,
+                align: dtype.align(word_size) as u64,
+                field_offsets: Vec::new(),
+            },
+            Type::Array(elem, count) => {
+                let elem_layout = self.layout_inner(elem, word_size, visiting);
+                Layout {
+                    size: elem_layout.size * count,
+                    align: elem_layout.align,
+                    field_offsets: Vec::new(),
+                }
+            }
+            Type::Struct(name) => {
+                if visiting.contains(name) {
+                    panic!("Struct type %{name} contains itself");
+                }
+                let fields = self
+                    .fields(name)
+                    .unwrap_or_else(|| panic!("Struct type %{name} is not defined"));
+                visiting.push(Rc::clone(name));
+                let mut size = 0;
+                let mut align = 1;
+                let mut field_offsets = Vec::with_capacity(fields.len());
+                for field in fields {
+                    let field_layout = self.layout_inner(field, word_size, visiting);
+                    size = round_up(size, field_layout.align);
+                    field_offsets.push(size);
+                    size += field_layout.size;
+                    align = align.max(field_layout.align);
+                }
+                visiting.pop();
+                Layout {
+                    size: round_up(size, align),
+                    align,
+                    field_offsets,
+                }
+            }
+        }
+    }
+}
+
+fn round_up(x: u64, align: u64) -> u64 {
+    x.div_ceil(align) * align
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -37,6 +182,7 @@ pub enum Instruction {
     UInt(DataType, u64),
     Int(DataType, i64),
     Float(DataType, f64),
+    /// Pointer to a string literal in read-only data
     String(Vec<u8>),
 
     Add(DataType, Box<Self>, Box<Self>),
@@ -48,13 +194,43 @@ pub enum Instruction {
     And(DataType, Box<Self>, Box<Self>),
     Or(DataType, Box<Self>, Box<Self>),
     Xor(DataType, Box<Self>, Box<Self>),
+    /// Remainder of the division, which has the sign of the dividend if signed
+    Rem(DataType, Box<Self>, Box<Self>),
+    /// Upper half of the product in twice the width of the type, signedness follows the type
+    MulHi(DataType, Box<Self>, Box<Self>),
+    /// Shift left, the amount has to be less than the width of the type
+    Shl(DataType, Box<Self>, Box<Self>),
+    /// Shift right, arithmetic for signed types and logical for unsigned types
+    Shr(DataType, Box<Self>, Box<Self>),
+
+    /// Comparisons evaluate to 1 if true and 0 if false, signedness follows the operand types
+    Eq(DataType, Box<Self>, Box<Self>),
+    Ne(DataType, Box<Self>, Box<Self>),
+    Lt(DataType, Box<Self>, Box<Self>),
+    Le(DataType, Box<Self>, Box<Self>),
+    Gt(DataType, Box<Self>, Box<Self>),
+    Ge(DataType, Box<Self>, Box<Self>),
 
     Load {
         id: u64,
         dtype: DataType,
     },
 
-    Alloc(DataType),
+    Alloc(Type),
+    /// Address of a global variable or constant
+    GlobalPtr(Rc<String>),
+    /// Address of a field of the struct, or an element of the array, pointed to by `id`
+    FieldPtr {
+        ty: Type,
+        id: u64,
+        index: u64,
+    },
+    /// Address of the `index`th element of type `ty` in the array pointed to by `id`
+    ElemPtr {
+        ty: Type,
+        id: u64,
+        index: Box<Self>,
+    },
     DefReg {
         id: u64,
         rhs: Box<Self>,
@@ -69,11 +245,49 @@ pub enum Instruction {
 
     Call {
         ret_type: Option<DataType>,
-        fn_name: Rc<String>,
+        callee: Callee,
         args: Vec<Self>,
+        /// `tail call`, which has to be returned right away and is always lowered into a jump
+        is_tail: bool,
     },
 
     Label(String),
+    /// `jmp :label`
+    Jmp(String),
+    /// `br <cond> :if_true :if_false`, jumps to `if_true` if `cond` is not zero
+    Br {
+        cond: Box<Self>,
+        if_true: String,
+        if_false: String,
+    },
+
+    /// `dtype phi [:label value]...`, the value coming from whichever of the labelled blocks
+    /// control came from
+    /// Only valid as the right hand side of a `DefReg` at the start of a block, before any other
+    /// instructions, and needs one incoming value for each predecessor of the block
+    Phi {
+        dtype: DataType,
+        incoming: Vec<(String, Self)>,
+    },
+
+    /// Initialize the `va_list` pointed to by `id`, only valid in variadic functions
+    /// A `va_list` is 24 bytes and 8-byte aligned, like `[3 x u64]`
+    VaStart(u64),
+    /// Fetch the next variadic argument from the `va_list` pointed to by `id`
+    VaArg {
+        dtype: DataType,
+        id: u64,
+    },
+    VaEnd(u64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The function being called by a `Call`
+pub enum Callee {
+    /// `call @name(...)`
+    Direct(Rc<String>),
+    /// `call ptr %fp(...)`, calling through a function pointer
+    Indirect(Box<Instruction>),
 }
 
 impl Instruction {
@@ -84,6 +298,13 @@ impl Instruction {
     pub fn is_def_reg(&self) -> bool {
         matches!(self, Self::DefReg { .. })
     }
+    /// Returns `true` if the instruction is a `DefReg` of a [`Phi`].
+    ///
+    /// [`Phi`]: Instruction::Phi
+    #[must_use]
+    pub fn is_phi_def(&self) -> bool {
+        matches!(self, Self::DefReg { rhs, .. } if matches!(rhs.as_ref(), Self::Phi { .. }))
+    }
     #[must_use]
     pub fn as_def_reg_id(&self) -> Option<u64> {
         if let Self::DefReg { id, .. } = self {
@@ -92,14 +313,216 @@ impl Instruction {
             None
         }
     }
+    /// Type of the value if the instruction is an operand, `None` if it's not or if it's a call
+    /// without a return value
+    pub fn dtype(&self) -> Option<DataType> {
+        match self {
+            Self::Arg(dtype, _)
+            | Self::Reg(dtype, _)
+            | Self::UInt(dtype, _)
+            | Self::Int(dtype, _)
+            | Self::Float(dtype, _)
+            | Self::Add(dtype, _, _)
+            | Self::Sub(dtype, _, _)
+            | Self::Mul(dtype, _, _)
+            | Self::Div(dtype, _, _)
+            | Self::Not(dtype, _, _)
+            | Self::And(dtype, _, _)
+            | Self::Or(dtype, _, _)
+            | Self::Xor(dtype, _, _)
+            | Self::Rem(dtype, _, _)
+            | Self::MulHi(dtype, _, _)
+            | Self::Shl(dtype, _, _)
+            | Self::Shr(dtype, _, _)
+            | Self::Eq(dtype, _, _)
+            | Self::Ne(dtype, _, _)
+            | Self::Lt(dtype, _, _)
+            | Self::Le(dtype, _, _)
+            | Self::Gt(dtype, _, _)
+            | Self::Ge(dtype, _, _)
+            | Self::Load { dtype, .. }
+            | Self::Phi { dtype, .. }
+            | Self::VaArg { dtype, .. } => Some(*dtype),
+            Self::String(_)
+            | Self::Alloc(_)
+            | Self::GlobalPtr(_)
+            | Self::FieldPtr { .. }
+            | Self::ElemPtr { .. } => Some(DataType::Ptr),
+            Self::Call { ret_type, .. } => *ret_type,
+            _ => None,
+        }
+    }
+    /// Whether the instruction ends a block
+    pub fn is_terminator(&self) -> bool {
+        matches!(self, Self::Ret(_) | Self::Jmp(_) | Self::Br { .. })
+    }
+    /// Labels that the instruction may jump to
+    pub fn jump_targets(&self) -> Vec<&String> {
+        match self {
+            Self::Jmp(label) => vec![label],
+            Self::Br {
+                if_true, if_false, ..
+            } => vec![if_true, if_false],
+            _ => Vec::new(),
+        }
+    }
+    pub fn jump_targets_mut(&mut self) -> Vec<&mut String> {
+        match self {
+            Self::Jmp(label) => vec![label],
+            Self::Br {
+                if_true, if_false, ..
+            } => vec![if_true, if_false],
+            _ => Vec::new(),
+        }
+    }
+    /// The operands directly inside the instruction
+    pub fn operands(&self) -> Vec<&Self> {
+        match self {
+            Self::Add(_, lhs, rhs)
+            | Self::Sub(_, lhs, rhs)
+            | Self::Mul(_, lhs, rhs)
+            | Self::Div(_, lhs, rhs)
+            | Self::Not(_, lhs, rhs)
+            | Self::And(_, lhs, rhs)
+            | Self::Or(_, lhs, rhs)
+            | Self::Xor(_, lhs, rhs)
+            | Self::Rem(_, lhs, rhs)
+            | Self::MulHi(_, lhs, rhs)
+            | Self::Shl(_, lhs, rhs)
+            | Self::Shr(_, lhs, rhs)
+            | Self::Eq(_, lhs, rhs)
+            | Self::Ne(_, lhs, rhs)
+            | Self::Lt(_, lhs, rhs)
+            | Self::Le(_, lhs, rhs)
+            | Self::Gt(_, lhs, rhs)
+            | Self::Ge(_, lhs, rhs) => vec![lhs, rhs],
+            Self::ElemPtr { index, .. } => vec![index],
+            Self::DefReg { rhs, .. } | Self::Store { rhs, .. } => vec![rhs],
+            Self::Ret(Some(val)) => vec![val],
+            Self::Br { cond, .. } => vec![cond],
+            Self::Phi { incoming, .. } => incoming.iter().map(|(_, value)| value).collect(),
+            Self::Call { callee, args, .. } => match callee {
+                Callee::Direct(_) => args.iter().collect(),
+                Callee::Indirect(fn_ptr) => std::iter::once(fn_ptr.as_ref()).chain(args).collect(),
+            },
+            _ => Vec::new(),
+        }
+    }
+    /// The operands directly inside the instruction, mutably
+    pub fn operands_mut(&mut self) -> Vec<&mut Self> {
+        match self {
+            Self::Add(_, lhs, rhs)
+            | Self::Sub(_, lhs, rhs)
+            | Self::Mul(_, lhs, rhs)
+            | Self::Div(_, lhs, rhs)
+            | Self::Not(_, lhs, rhs)
+            | Self::And(_, lhs, rhs)
+            | Self::Or(_, lhs, rhs)
+            | Self::Xor(_, lhs, rhs)
+            | Self::Rem(_, lhs, rhs)
+            | Self::MulHi(_, lhs, rhs)
+            | Self::Shl(_, lhs, rhs)
+            | Self::Shr(_, lhs, rhs)
+            | Self::Eq(_, lhs, rhs)
+            | Self::Ne(_, lhs, rhs)
+            | Self::Lt(_, lhs, rhs)
+            | Self::Le(_, lhs, rhs)
+            | Self::Gt(_, lhs, rhs)
+            | Self::Ge(_, lhs, rhs) => vec![lhs, rhs],
+            Self::ElemPtr { index, .. } => vec![index],
+            Self::DefReg { rhs, .. } | Self::Store { rhs, .. } => vec![rhs],
+            Self::Ret(Some(val)) => vec![val],
+            Self::Br { cond, .. } => vec![cond],
+            Self::Phi { incoming, .. } => incoming.iter_mut().map(|(_, value)| value).collect(),
+            Self::Call { callee, args, .. } => match callee {
+                Callee::Direct(_) => args.iter_mut().collect(),
+                Callee::Indirect(fn_ptr) => std::iter::once(fn_ptr.as_mut())
+                    .chain(args.iter_mut())
+                    .collect(),
+            },
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Initial value of a global variable or constant
+pub enum Constant {
+    /// An integer, negative numbers are stored as two's complement
+    Int(u64),
+    Float(f64),
+    /// Raw bytes of a string literal, for initializing byte arrays
+    Bytes(Vec<u8>),
+    /// Fields of a struct or elements of an array, in order
+    Aggregate(Vec<Constant>),
+}
+
+/// How a symbol is seen by the linker
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Linkage {
+    /// Visible to other object files, the default
+    #[default]
+    Export,
+    /// Only visible inside the object file
+    Internal,
+    /// Like `Internal`, but also left out of the symbol table where the format allows it
+    Private,
+    /// Exported, but can be overridden by a non-weak definition in another object file
+    Weak,
+}
+
+/// ELF symbol visibility, only meaningful for `Export` and `Weak` symbols
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Visibility {
+    #[default]
+    Default,
+    /// Not visible outside of the shared object or executable it's linked into
+    Hidden,
+    /// Visible outside, but references from inside can't be preempted
+    Protected,
+}
+
+/// Linkage and visibility of a function or global, written before `fn`, `global` or `const`,
+/// e.g. `internal fn @helper()` or `weak hidden global @x: i32`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SymbolAttrs {
+    pub linkage: Linkage,
+    pub visibility: Visibility,
+}
+impl SymbolAttrs {
+    /// Whether the symbol is visible outside of the object file
+    pub fn is_global(self) -> bool {
+        matches!(self.linkage, Linkage::Export | Linkage::Weak)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum TopLevel {
-    Extern(Rc<String>),
+    /// `extern @name(args...)`, a function defined somewhere else
+    Extern {
+        name: Rc<String>,
+        args: Vec<DataType>,
+        is_variadic: bool,
+    },
+    TypeDef {
+        name: Rc<String>,
+        fields: Vec<Type>,
+    },
+    /// `global @name: type = init`, or `const @name: type = init` if `is_const`
+    /// Globals without an initial value are zero-initialized
+    Global {
+        name: Rc<String>,
+        attrs: SymbolAttrs,
+        ty: Type,
+        init: Option<Constant>,
+        is_const: bool,
+    },
+    /// `fn @name(args...) { body }`, variadic if `...` is at the end of the arguments
     Fn {
         name: Rc<String>,
+        attrs: SymbolAttrs,
         args: Vec<DataType>,
+        is_variadic: bool,
         body: Vec<Instruction>,
     },
 }
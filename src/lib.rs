@@ -1,3 +1,11 @@
+pub mod analysis;
+pub mod builder;
+pub mod compile;
 pub mod fileformat;
 pub mod generation;
 pub mod ir;
+pub mod parser;
+pub mod pass;
+pub mod printer;
+pub mod transform;
+pub mod verifier;
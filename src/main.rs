@@ -1,32 +1,91 @@
 use std::{env, fs::read_to_string};
 
-pub mod fileformat;
-pub mod generation;
-pub mod ir;
-mod parser;
-
-use generation::platform;
+use mir::{compile::CompileOptions, fileformat::FileFormat, parser, printer, transform, verifier};
 
 fn main() {
-    let mut args = env::args().skip(1);
-    let src_path = args
+    let mut paths = Vec::<String>::new();
+    let mut options = CompileOptions::default();
+    let mut extra_passes = Vec::new();
+    let mut print_after = Vec::<String>::new();
+    let mut print_before_all = false;
+    let mut emit_ir = false;
+    for arg in env::args().skip(1) {
+        if let Some(level) = arg.strip_prefix("-O") {
+            options.opt_level = level.parse().unwrap_or_else(|()| {
+                exit_with_error(&format!("Unknown optimization level `{arg}`"))
+            });
+        } else if let Some(names) = arg.strip_prefix("--passes=") {
+            for name in names.split(',').filter(|name| !name.is_empty()) {
+                let pass = transform::pass_by_name(name)
+                    .unwrap_or_else(|| exit_with_error(&format!("Unknown pass `{name}`")));
+                extra_passes.push(pass);
+            }
+        } else if let Some(name) = arg.strip_prefix("--print-after=") {
+            if !transform::PASS_NAMES.contains(&name) {
+                exit_with_error(&format!("Unknown pass `{name}`"));
+            }
+            print_after.push(name.to_string());
+        } else if let Some(format) = arg.strip_prefix("--format=") {
+            options.file_format = format
+                .parse()
+                .unwrap_or_else(|()| exit_with_error(&format!("Unknown file format `{format}`")));
+        } else if arg == "--print-before-all" {
+            print_before_all = true;
+        } else if arg == "--emit-ir" {
+            emit_ir = true;
+        } else if arg.starts_with("--") {
+            exit_with_error(&format!("Unknown option `{arg}`"));
+        } else {
+            paths.push(arg);
+        }
+    }
+    let mut paths = paths.into_iter();
+    let src_path = paths
         .next()
         .expect("Expect one argument for the source file path");
-    let out_path = args
+    let out_path = paths
         .next()
         .expect("Expect one argument for the source file path");
     let src_content = read_to_string(src_path).expect("Enable to read file into string");
     let tokens = parser::parse_string_into_tokens(src_content);
-    let ir_program = parser::parse_tokens_into_ir(tokens);
-    println!("{ir_program:#?}");
-    let code = platform::x86_64::gen_code(ir_program);
-    let mut generated_asm = String::new();
-    platform::x86_64::gen_asm_from_model(
-        fileformat::FileFormat::Macho64,
-        code,
-        &mut generated_asm,
-    )
-    .unwrap();
-    std::fs::write(out_path.clone(), generated_asm).expect("Unable to write to output path");
+    let mut ir_program = parser::parse_tokens_into_ir(tokens);
+    if let Err(errors) = verifier::verify(&ir_program) {
+        for error in &errors {
+            eprintln!("Error {error}");
+        }
+        eprintln!("{} errors found in the IR", errors.len());
+        std::process::exit(1);
+    }
+    // Passes from `--passes` run after the ones of the optimization level
+    let mut pipeline = options.opt_level.pipeline();
+    for pass in extra_passes {
+        pipeline = pipeline.boxed_pass(pass);
+    }
+    for name in &print_after {
+        pipeline = pipeline.print_after(name);
+    }
+    pipeline
+        .print_before_all(print_before_all)
+        .build()
+        .run(&mut ir_program);
+    // The IR that goes into codegen, after all the passes
+    if emit_ir {
+        print!("{}", printer::print_program(&ir_program));
+    }
+    // Object files are written directly, anything else gets the assembly
+    let output = if out_path.ends_with(".o") {
+        if options.file_format != FileFormat::Elf64 {
+            exit_with_error("Object files can only be written with `--format=elf64`");
+        }
+        options.gen_object(ir_program)
+    } else {
+        options.gen_asm(ir_program).into_bytes()
+    };
+    std::fs::write(out_path.clone(), output).expect("Unable to write to output path");
     println!("Output written to {:?}", out_path);
 }
+
+fn exit_with_error(message: &str) -> ! {
+    eprintln!("Error {message}");
+    std::process::exit(1);
+}
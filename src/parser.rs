@@ -1,14 +1,29 @@
 use std::{iter::Peekable, rc::Rc, str::Chars, vec::IntoIter};
 
-use crate::ir::{DataType, Instruction, TopLevel};
+use crate::ir::{
+    Callee, Constant, DataType, Instruction, Linkage, SymbolAttrs, TopLevel, Type, Visibility,
+};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     Fn,
     Extern,
     Call,
+    Tail,
+    Jmp,
+    Br,
     Alloc,
     Ret,
+    Type,
+    Global,
+    Const,
+    Field,
+    Elem,
+    Times,
+    VaStart,
+    VaArg,
+    VaEnd,
+    Phi,
 
     Add,
     Sub,
@@ -19,9 +34,22 @@ pub enum Token {
     And,
     Or,
     Xor,
+    Rem,
+    MulHi,
+    Shl,
+    Shr,
+
+    CmpEq,
+    CmpNe,
+    Lt,
+    Le,
+    Gt,
+    Ge,
 
     Equal,
     Comma,
+    Colon,
+    Ellipsis,
     ParenOpen,
     ParenClose,
     RectParenOpen,
@@ -37,9 +65,12 @@ pub enum Token {
 
     Label(String),
     FnName(Rc<String>),
+    StructName(Rc<String>),
     RegID(u64),
     ArgID(u64),
     TypeName(DataType),
+    Linkage(Linkage),
+    Visibility(Visibility),
 
     LineBreak,
 }
@@ -69,6 +100,7 @@ impl Token {
         matches!(self, Self::BraceClose)
     }
 
+    #[allow(dead_code)]
     pub fn as_type_name(&self) -> Option<DataType> {
         if let Self::TypeName(v) = self {
             Some(*v)
@@ -77,6 +109,22 @@ impl Token {
         }
     }
 
+    pub fn as_struct_name(&self) -> Option<&Rc<String>> {
+        if let Self::StructName(v) = self {
+            Some(v)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_num_u(&self) -> Option<u64> {
+        if let Self::NumU(v) = self {
+            Some(*v)
+        } else {
+            None
+        }
+    }
+
     pub fn as_reg_id(&self) -> Option<&u64> {
         if let Self::RegID(v) = self {
             Some(v)
@@ -89,11 +137,7 @@ impl Token {
 pub fn parse_string_into_tokens(source: String) -> Vec<Token> {
     let mut tokens = Vec::<Token>::new();
     let mut chars_iter = source.chars().peekable();
-    loop {
-        let first_ch = match chars_iter.next() {
-            Some(c) => c,
-            None => break,
-        };
+    while let Some(first_ch) = chars_iter.next() {
         if first_ch.is_whitespace() {
             if first_ch == '\n' {
                 if let Some(peek) = chars_iter.peek() {
@@ -108,15 +152,44 @@ pub fn parse_string_into_tokens(source: String) -> Vec<Token> {
         if first_ch.is_ascii_alphabetic() {
             let mut word = String::with_capacity(6);
             word.push(first_ch);
-            while let Some(ch) = chars_iter.next_if(|c| c.is_ascii_alphanumeric()) {
+            while let Some(ch) = chars_iter.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
                 word.push(ch);
             }
+            if word == "c" && chars_iter.next_if_eq(&'"').is_some() {
+                // `c"..."` is a NUL-terminated string
+                let mut string = parse_string(&mut chars_iter);
+                if let Token::String(bytes) = &mut string {
+                    bytes.push(0);
+                }
+                tokens.push(string);
+                continue;
+            }
             match word.as_str() {
                 "fn" => tokens.push(Token::Fn),
                 "extern" => tokens.push(Token::Extern),
                 "call" => tokens.push(Token::Call),
+                "tail" => tokens.push(Token::Tail),
+                "jmp" => tokens.push(Token::Jmp),
+                "br" => tokens.push(Token::Br),
                 "alloc" => tokens.push(Token::Alloc),
                 "ret" => tokens.push(Token::Ret),
+                "type" => tokens.push(Token::Type),
+                "global" => tokens.push(Token::Global),
+                "const" => tokens.push(Token::Const),
+                "field" => tokens.push(Token::Field),
+                "elem" => tokens.push(Token::Elem),
+                "x" => tokens.push(Token::Times),
+                "export" => tokens.push(Token::Linkage(Linkage::Export)),
+                "internal" => tokens.push(Token::Linkage(Linkage::Internal)),
+                "private" => tokens.push(Token::Linkage(Linkage::Private)),
+                "weak" => tokens.push(Token::Linkage(Linkage::Weak)),
+                "hidden" => tokens.push(Token::Visibility(Visibility::Hidden)),
+                "protected" => tokens.push(Token::Visibility(Visibility::Protected)),
+                "va_start" => tokens.push(Token::VaStart),
+                "va_arg" => tokens.push(Token::VaArg),
+                "va_end" => tokens.push(Token::VaEnd),
+                "phi" => tokens.push(Token::Phi),
+                "mulhi" => tokens.push(Token::MulHi),
                 "u64" => tokens.push(Token::TypeName(DataType::U64)),
                 "u32" => tokens.push(Token::TypeName(DataType::U32)),
                 "u16" => tokens.push(Token::TypeName(DataType::U16)),
@@ -144,7 +217,15 @@ pub fn parse_string_into_tokens(source: String) -> Vec<Token> {
             }};
         }
         match first_ch {
+            '=' if chars_iter.next_if_eq(&'=').is_some() => tokens.push(Token::CmpEq),
             '=' => tokens.push(Token::Equal),
+            '!' if chars_iter.next_if_eq(&'=').is_some() => tokens.push(Token::CmpNe),
+            '<' if chars_iter.next_if_eq(&'<').is_some() => tokens.push(Token::Shl),
+            '<' if chars_iter.next_if_eq(&'=').is_some() => tokens.push(Token::Le),
+            '<' => tokens.push(Token::Lt),
+            '>' if chars_iter.next_if_eq(&'>').is_some() => tokens.push(Token::Shr),
+            '>' if chars_iter.next_if_eq(&'=').is_some() => tokens.push(Token::Ge),
+            '>' => tokens.push(Token::Gt),
             ',' => tokens.push(Token::Comma),
             '(' => tokens.push(Token::ParenOpen),
             ')' => tokens.push(Token::ParenClose),
@@ -152,6 +233,11 @@ pub fn parse_string_into_tokens(source: String) -> Vec<Token> {
             ']' => tokens.push(Token::RectParenClose),
             '{' => tokens.push(Token::BraceOpen),
             '}' => tokens.push(Token::BraceClose),
+            '.' if chars_iter.next_if_eq(&'.').is_some()
+                && chars_iter.next_if_eq(&'.').is_some() =>
+            {
+                tokens.push(Token::Ellipsis)
+            }
 
             '+' => tokens.push(Token::Add),
             '-' => tokens.push(Token::Sub),
@@ -166,17 +252,36 @@ pub fn parse_string_into_tokens(source: String) -> Vec<Token> {
             '$' => {
                 tokens.push(parse_number(collect_ch!(|c| c.is_ascii_alphanumeric()
                     || *c == '-'
+                    || *c == '+'
                     || *c == '.')));
             }
+            // A `%` on its own is the remainder, not a vreg
+            '%' if chars_iter.peek().is_none_or(|c| c.is_whitespace()) => tokens.push(Token::Rem),
+            '%' if chars_iter.peek().is_some_and(|c| c.is_ascii_alphabetic()) => {
+                tokens.push(Token::StructName(Rc::new(collect_ch!(|c| c
+                    .is_ascii_alphanumeric()
+                    || *c == '_'
+                    || *c == '.'))))
+            }
             '%' => tokens.push(Token::RegID(
                 (collect_ch!(|c| c.is_numeric())).parse().unwrap(),
             )),
+            '0'..='9' => {
+                let mut str = String::from(first_ch);
+                str.push_str(&collect_ch!(|c| c.is_ascii_alphanumeric() || *c == '.'));
+                tokens.push(parse_number(str));
+            }
             '#' => tokens.push(Token::ArgID(
                 (collect_ch!(|c| c.is_numeric())).parse().unwrap(),
             )),
-            ':' => tokens.push(Token::Label(collect_ch!(|c| c.is_ascii_alphanumeric()
-                || *c == '_'
-                || *c == '.'))),
+            ':' => {
+                let label = collect_ch!(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '.');
+                if label.is_empty() {
+                    tokens.push(Token::Colon);
+                } else {
+                    tokens.push(Token::Label(label));
+                }
+            }
             '@' => tokens.push(Token::FnName(Rc::new(collect_ch!(|c| c
                 .is_ascii_alphanumeric()
                 || *c == '_'
@@ -285,10 +390,13 @@ fn parse_number(str: String) -> Token {
             return Token::NumU(0);
         }
     }
-    if first_ch == '-' {
-        Token::NumI(str.parse().expect("Invalid number format"))
-    } else if has_dot {
+    if has_dot {
         Token::NumF(str.parse().expect("Invalid number format"))
+    } else if first_ch == '-' {
+        Token::NumI(str.parse().expect("Invalid number format"))
+    } else if first_ch == '+' {
+        // `$+42` is a signed number that happens to be positive
+        Token::NumI(str[1..].parse().expect("Invalid number format"))
     } else {
         Token::NumU(str.parse().expect("Invalid number format"))
     }
@@ -372,7 +480,7 @@ fn parse_string(chars_iter: &mut Peekable<Chars>) -> Token {
             '"' => break,
             _ => String::from(ch)
                 .as_bytes()
-                .into_iter()
+                .iter()
                 .for_each(|b| bytes.push(*b)),
         }
     }
@@ -390,24 +498,22 @@ pub fn parse_tokens_into_ir(tokens: Vec<Token>) -> Vec<TopLevel> {
             break;
         }
     }
-    return ir;
+    ir
 }
 
 fn parse_top_level(token_stream: &mut Peekable<IntoIter<Token>>) -> Option<TopLevel> {
+    let attrs = parse_symbol_attrs(token_stream)?;
     let current = token_stream.next()?;
+    if attrs != SymbolAttrs::default()
+        && !matches!(current, Token::Fn | Token::Global | Token::Const)
+    {
+        panic!("Linkage and visibility can only be applied to functions and globals, found {current:?}");
+    }
     match current {
         Token::Fn => {
             let name = Rc::clone(token_stream.next()?.as_fn_name()?);
-            let mut args = Vec::<DataType>::new();
+            let (args, is_variadic) = parse_signature(token_stream, &name)?;
             let mut body = Vec::<Instruction>::new();
-            token_stream.next()?; // ParenOpen
-            loop {
-                match token_stream.next()? {
-                    Token::TypeName(t) => args.push(t),
-                    Token::ParenClose => break,
-                    _ => panic!("Expects `)` or type name after `@{name}`"),
-                }
-            }
             token_stream.next()?; // BraceOpen
             loop {
                 token_stream.next_if(|t| t.is_line_break());
@@ -417,44 +523,181 @@ fn parse_top_level(token_stream: &mut Peekable<IntoIter<Token>>) -> Option<TopLe
                 body.push(parse_fn_body(token_stream)?);
             }
             token_stream.next()?; // BraceClose
-            Some(TopLevel::Fn { name, args, body })
+            Some(TopLevel::Fn {
+                name,
+                attrs,
+                args,
+                is_variadic,
+                body,
+            })
+        }
+        Token::Extern => {
+            let name = Rc::clone(token_stream.next()?.as_fn_name()?);
+            let (args, is_variadic) = parse_signature(token_stream, &name)?;
+            Some(TopLevel::Extern {
+                name,
+                args,
+                is_variadic,
+            })
+        }
+        Token::Type => {
+            let name = Rc::clone(token_stream.next()?.as_struct_name()?);
+            token_stream.next()?; // Equal
+            token_stream.next()?; // BraceOpen
+            let mut fields = Vec::<Type>::new();
+            loop {
+                token_stream.next_if(|t| t.is_line_break());
+                match token_stream.peek()? {
+                    Token::BraceClose => break,
+                    Token::Comma => {
+                        token_stream.next()?;
+                    }
+                    _ => fields.push(parse_type(token_stream)?),
+                }
+            }
+            token_stream.next()?; // BraceClose
+            Some(TopLevel::TypeDef { name, fields })
+        }
+        Token::Global | Token::Const => {
+            let is_const = current == Token::Const;
+            let name = Rc::clone(token_stream.next()?.as_fn_name()?);
+            match token_stream.next()? {
+                Token::Colon => (),
+                t => panic!("Expects `:` after `@{name}`, found {t:?}"),
+            }
+            let ty = parse_type(token_stream)?;
+            let init = if token_stream.next_if_eq(&Token::Equal).is_some() {
+                Some(parse_constant(token_stream)?)
+            } else if is_const {
+                panic!("Constant `@{name}` must have an initial value");
+            } else {
+                None
+            };
+            Some(TopLevel::Global {
+                name,
+                attrs,
+                ty,
+                init,
+                is_const,
+            })
         }
-        Token::Extern => todo!(),
         t => panic!("Invalid token at top level: {t:?}"),
     }
 }
 
-fn parse_fn_body(token_stream: &mut Peekable<IntoIter<Token>>) -> Option<Instruction> {
-    let current = token_stream.next()?;
-    match current {
-        Token::Call => {
-            let fn_name = Rc::clone(token_stream.next()?.as_fn_name()?);
-            let mut args = Vec::<Instruction>::new();
-            token_stream.next()?; // ParenOpen
+/// Parse the linkage and visibility keywords before a function or global, in any order
+fn parse_symbol_attrs(token_stream: &mut Peekable<IntoIter<Token>>) -> Option<SymbolAttrs> {
+    let mut linkage = None;
+    let mut visibility = None;
+    loop {
+        match token_stream.peek()? {
+            Token::Linkage(l) => {
+                if linkage.replace(*l).is_some() {
+                    panic!("More than one linkage specified");
+                }
+            }
+            Token::Visibility(v) => {
+                if visibility.replace(*v).is_some() {
+                    panic!("More than one visibility specified");
+                }
+            }
+            _ => break,
+        }
+        token_stream.next()?;
+    }
+    let attrs = SymbolAttrs {
+        linkage: linkage.unwrap_or_default(),
+        visibility: visibility.unwrap_or_default(),
+    };
+    if !attrs.is_global() && attrs.visibility != Visibility::Default {
+        panic!(
+            "Visibility cannot be applied to {:?} symbols",
+            attrs.linkage
+        );
+    }
+    Some(attrs)
+}
+
+/// Parse the initial value of a global
+fn parse_constant(token_stream: &mut Peekable<IntoIter<Token>>) -> Option<Constant> {
+    match token_stream.next()? {
+        Token::NumU(u) => Some(Constant::Int(u)),
+        Token::NumI(i) => Some(Constant::Int(i as u64)),
+        Token::NumF(f) => Some(Constant::Float(f)),
+        Token::Sub => match token_stream.next()? {
+            Token::NumU(u) => Some(Constant::Int(u.wrapping_neg())),
+            Token::NumF(f) => Some(Constant::Float(-f)),
+            t => panic!("Expects a number after `-`, found {t:?}"),
+        },
+        Token::String(bytes) => Some(Constant::Bytes(bytes)),
+        Token::BraceOpen => {
+            let mut elements = Vec::<Constant>::new();
             loop {
-                match token_stream.next()? {
-                    Token::TypeName(t) => args.push(match token_stream.next()? {
-                        Token::RegID(id) => Instruction::Reg(t, id),
-                        Token::RectParenOpen => {
-                            let reg = *token_stream.next()?.as_reg_id()?;
-                            token_stream.next()?; // RectParenClose
-                            Instruction::Reg(t, reg)
-                        }
-                        Token::NumU(u) => Instruction::UInt(t, u),
-                        Token::NumI(i) => Instruction::Int(t, i),
-                        Token::NumF(f) => Instruction::Float(t, f),
-                        _ => panic!("Expects register or number as function argument"),
-                    }),
-                    Token::ParenClose => break,
-                    _ => panic!("Expects `)` or type name"),
+                token_stream.next_if(|t| t.is_line_break());
+                match token_stream.peek()? {
+                    Token::BraceClose => break,
+                    Token::Comma => {
+                        token_stream.next()?;
+                    }
+                    _ => elements.push(parse_constant(token_stream)?),
                 }
             }
-            Some(Instruction::Call {
-                ret_type: None,
-                fn_name,
-                args,
-            })
+            token_stream.next()?; // BraceClose
+            Some(Constant::Aggregate(elements))
+        }
+        t => panic!("Invalid token for constant: {t:?}"),
+    }
+}
+
+/// Parse a scalar type name, a struct name or an array type `[count x element]`
+fn parse_type(token_stream: &mut Peekable<IntoIter<Token>>) -> Option<Type> {
+    match token_stream.next()? {
+        Token::TypeName(dtype) => Some(Type::Scalar(dtype)),
+        Token::StructName(name) => Some(Type::Struct(name)),
+        Token::RectParenOpen => {
+            let count = token_stream
+                .next()?
+                .as_num_u()
+                .expect("Expects array length after `[`");
+            match token_stream.next()? {
+                Token::Times => (),
+                t => panic!("Expects `x` after array length, found {t:?}"),
+            }
+            let elem = parse_type(token_stream)?;
+            token_stream.next()?; // RectParenClose
+            Some(Type::Array(Box::new(elem), count))
         }
+        t => panic!("Expects a type, found {t:?}"),
+    }
+}
+
+/// Parse the argument types of a function, `(i32 ptr)` or `(ptr ...)` if it's variadic
+fn parse_signature(
+    token_stream: &mut Peekable<IntoIter<Token>>,
+    name: &str,
+) -> Option<(Vec<DataType>, bool)> {
+    let mut args = Vec::<DataType>::new();
+    let mut is_variadic = false;
+    token_stream.next()?; // ParenOpen
+    loop {
+        match token_stream.next()? {
+            Token::TypeName(t) if !is_variadic => args.push(t),
+            Token::Ellipsis if !is_variadic => is_variadic = true,
+            Token::ParenClose => break,
+            Token::Ellipsis | Token::TypeName(_) => {
+                panic!("`...` must be at the end of the arguments of `@{name}`")
+            }
+            _ => panic!("Expects `)` or type name after `@{name}`"),
+        }
+    }
+    Some((args, is_variadic))
+}
+
+fn parse_fn_body(token_stream: &mut Peekable<IntoIter<Token>>) -> Option<Instruction> {
+    let current = token_stream.next()?;
+    match current {
+        Token::Call => parse_call(token_stream, None, false),
+        Token::Tail => parse_tail_call(token_stream, None),
         Token::Ret => match token_stream.peek()? {
             Token::LineBreak => Some(Instruction::Ret(None)),
             _ => Some(Instruction::Ret(Some(Box::new(parse_operand(
@@ -470,6 +713,19 @@ fn parse_fn_body(token_stream: &mut Peekable<IntoIter<Token>>) -> Option<Instruc
             })
         }
         Token::Label(name) => Some(Instruction::Label(name)),
+        Token::Jmp => Some(Instruction::Jmp(parse_jump_target(token_stream)?)),
+        Token::Br => {
+            let cond = parse_operand(token_stream)?;
+            let if_true = parse_jump_target(token_stream)?;
+            let if_false = parse_jump_target(token_stream)?;
+            Some(Instruction::Br {
+                cond: Box::new(cond),
+                if_true,
+                if_false,
+            })
+        }
+        Token::VaStart => Some(Instruction::VaStart(*token_stream.next()?.as_reg_id()?)),
+        Token::VaEnd => Some(Instruction::VaEnd(*token_stream.next()?.as_reg_id()?)),
         Token::TypeName(dtype) => match token_stream.next()? {
             Token::RectParenOpen => {
                 let id = *token_stream.next()?.as_reg_id()?;
@@ -531,42 +787,179 @@ fn parse_operand(token_stream: &mut Peekable<IntoIter<Token>>) -> Option<Instruc
                 let rhs = parse_operand(token_stream)?;
                 Some(Instruction::Xor(dtype, Box::new(lhs), Box::new(rhs)))
             }
+            Token::Rem => {
+                let lhs = parse_operand(token_stream)?;
+                let rhs = parse_operand(token_stream)?;
+                Some(Instruction::Rem(dtype, Box::new(lhs), Box::new(rhs)))
+            }
+            Token::MulHi => {
+                let lhs = parse_operand(token_stream)?;
+                let rhs = parse_operand(token_stream)?;
+                Some(Instruction::MulHi(dtype, Box::new(lhs), Box::new(rhs)))
+            }
+            Token::Shl => {
+                let lhs = parse_operand(token_stream)?;
+                let rhs = parse_operand(token_stream)?;
+                Some(Instruction::Shl(dtype, Box::new(lhs), Box::new(rhs)))
+            }
+            Token::Shr => {
+                let lhs = parse_operand(token_stream)?;
+                let rhs = parse_operand(token_stream)?;
+                Some(Instruction::Shr(dtype, Box::new(lhs), Box::new(rhs)))
+            }
+            Token::CmpEq => {
+                let lhs = parse_operand(token_stream)?;
+                let rhs = parse_operand(token_stream)?;
+                Some(Instruction::Eq(dtype, Box::new(lhs), Box::new(rhs)))
+            }
+            Token::CmpNe => {
+                let lhs = parse_operand(token_stream)?;
+                let rhs = parse_operand(token_stream)?;
+                Some(Instruction::Ne(dtype, Box::new(lhs), Box::new(rhs)))
+            }
+            Token::Lt => {
+                let lhs = parse_operand(token_stream)?;
+                let rhs = parse_operand(token_stream)?;
+                Some(Instruction::Lt(dtype, Box::new(lhs), Box::new(rhs)))
+            }
+            Token::Le => {
+                let lhs = parse_operand(token_stream)?;
+                let rhs = parse_operand(token_stream)?;
+                Some(Instruction::Le(dtype, Box::new(lhs), Box::new(rhs)))
+            }
+            Token::Gt => {
+                let lhs = parse_operand(token_stream)?;
+                let rhs = parse_operand(token_stream)?;
+                Some(Instruction::Gt(dtype, Box::new(lhs), Box::new(rhs)))
+            }
+            Token::Ge => {
+                let lhs = parse_operand(token_stream)?;
+                let rhs = parse_operand(token_stream)?;
+                Some(Instruction::Ge(dtype, Box::new(lhs), Box::new(rhs)))
+            }
             Token::NumU(u) => Some(Instruction::UInt(dtype, u)),
             Token::NumI(i) => Some(Instruction::Int(dtype, i)),
             Token::NumF(f) => Some(Instruction::Float(dtype, f)),
+            Token::String(bytes) => {
+                assert_eq!(dtype, DataType::Ptr, "String literals must be of type ptr");
+                Some(Instruction::String(bytes))
+            }
             Token::RegID(id) => Some(Instruction::Reg(dtype, id)),
             Token::ArgID(id) => Some(Instruction::Arg(dtype, id)),
+            Token::FnName(name) => {
+                assert_eq!(
+                    dtype,
+                    DataType::Ptr,
+                    "Address of `@{name}` must be of type ptr"
+                );
+                Some(Instruction::GlobalPtr(name))
+            }
             Token::RectParenOpen => {
                 let reg_id = *token_stream.next()?.as_reg_id()?;
                 token_stream.next()?; // RectParenClose
                 Some(Instruction::Load { id: reg_id, dtype })
             }
-            Token::Call => {
-                let fn_name = Rc::clone(token_stream.next()?.as_fn_name()?);
-                let mut args = Vec::<Instruction>::new();
-                token_stream.next()?; // ParenOpen
-                loop {
+            Token::Field => {
+                assert_eq!(dtype, DataType::Ptr, "Field pointers must be of type ptr");
+                let ty = parse_type(token_stream)?;
+                let id = *token_stream.next()?.as_reg_id()?;
+                let index = token_stream
+                    .next()?
+                    .as_num_u()
+                    .expect("Expects a constant field index");
+                Some(Instruction::FieldPtr { ty, id, index })
+            }
+            Token::Elem => {
+                assert_eq!(dtype, DataType::Ptr, "Element pointers must be of type ptr");
+                let ty = parse_type(token_stream)?;
+                let id = *token_stream.next()?.as_reg_id()?;
+                let index = parse_operand(token_stream)?;
+                Some(Instruction::ElemPtr {
+                    ty,
+                    id,
+                    index: Box::new(index),
+                })
+            }
+            Token::Call => parse_call(token_stream, Some(dtype), false),
+            Token::Tail => parse_tail_call(token_stream, Some(dtype)),
+            Token::VaArg => Some(Instruction::VaArg {
+                dtype,
+                id: *token_stream.next()?.as_reg_id()?,
+            }),
+            Token::Phi => {
+                let mut incoming = Vec::<(String, Instruction)>::new();
+                while token_stream.next_if_eq(&Token::RectParenOpen).is_some() {
+                    let label = parse_jump_target(token_stream)?;
+                    let value = parse_operand(token_stream)?;
                     match token_stream.next()? {
-                        Token::TypeName(t) => args.push(match token_stream.next()? {
-                            Token::RegID(id) => Instruction::Reg(t, id),
-                            Token::NumU(u) => Instruction::UInt(t, u),
-                            Token::NumI(i) => Instruction::Int(t, i),
-                            Token::NumF(f) => Instruction::Float(t, f),
-                            _ => panic!("Expects register or number as function argument"),
-                        }),
-                        Token::ParenClose => break,
-                        _ => panic!("Expects `)` or type name"),
+                        Token::RectParenClose => (),
+                        t => panic!("Expects `]` after incoming value of phi, found {t:?}"),
                     }
+                    incoming.push((label, value));
                 }
-                Some(Instruction::Call {
-                    ret_type: None,
-                    fn_name,
-                    args,
-                })
+                Some(Instruction::Phi { dtype, incoming })
             }
             dtype => panic!("Invalid token after {:?}", dtype),
         },
-        Token::Alloc => Some(Instruction::Alloc(token_stream.next()?.as_type_name()?)),
+        Token::Alloc => Some(Instruction::Alloc(parse_type(token_stream)?)),
         t => panic!("Invalid token for operand: {t:?}"),
     }
 }
+
+/// Parse the `:label` after `jmp` or `br`
+fn parse_jump_target(token_stream: &mut Peekable<IntoIter<Token>>) -> Option<String> {
+    match token_stream.next()? {
+        Token::Label(name) => Some(name),
+        t => panic!("Expects a label as jump target, found {t:?}"),
+    }
+}
+
+/// Parse the rest of a `tail call` after the `tail` keyword
+fn parse_tail_call(
+    token_stream: &mut Peekable<IntoIter<Token>>,
+    ret_type: Option<DataType>,
+) -> Option<Instruction> {
+    match token_stream.next()? {
+        Token::Call => parse_call(token_stream, ret_type, true),
+        t => panic!("Expects `call` after `tail`, found {t:?}"),
+    }
+}
+
+/// Parse the rest of a call after the `call` keyword
+/// The callee is either `@name`, or a pointer operand such as `ptr %fp` or `ptr [%vtable]`
+fn parse_call(
+    token_stream: &mut Peekable<IntoIter<Token>>,
+    ret_type: Option<DataType>,
+    is_tail: bool,
+) -> Option<Instruction> {
+    let callee = match token_stream.peek()? {
+        Token::FnName(name) => {
+            let name = Rc::clone(name);
+            token_stream.next()?;
+            Callee::Direct(name)
+        }
+        Token::TypeName(DataType::Ptr) => Callee::Indirect(Box::new(parse_operand(token_stream)?)),
+        t => panic!("Expects a function name or a function pointer after `call`, found {t:?}"),
+    };
+    match token_stream.next()? {
+        Token::ParenOpen => (),
+        t => panic!("Expects `(` after callee, found {t:?}"),
+    }
+    let mut args = Vec::<Instruction>::new();
+    loop {
+        match token_stream.peek()? {
+            Token::ParenClose => {
+                token_stream.next()?;
+                break;
+            }
+            Token::TypeName(_) => args.push(parse_operand(token_stream)?),
+            _ => panic!("Expects `)` or type name"),
+        }
+    }
+    Some(Instruction::Call {
+        ret_type,
+        callee,
+        args,
+        is_tail,
+    })
+}
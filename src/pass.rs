@@ -0,0 +1,167 @@
+//! Runs passes over the IR in a configurable order
+//!
+//! A module pass implements `Pass` and sees the whole program, a function pass implements
+//! `FunctionPass` and is run on the functions one at a time. Pipelines are put together with
+//! `PipelineBuilder`, which can also verify the IR after every pass and print it before or after
+//! the passes for debugging
+
+use std::{
+    collections::HashSet,
+    io::{self, Write},
+    rc::Rc,
+};
+
+use crate::{
+    ir::{DataType, Instruction, SymbolAttrs, TopLevel, TypeDefs},
+    printer::print_program,
+    verifier::verify,
+};
+
+/// A pass that transforms the whole program
+pub trait Pass {
+    /// Name of the pass in pipelines and in `--print-after=<pass>`
+    fn name(&self) -> &'static str;
+    fn run(&mut self, program: &mut Vec<TopLevel>);
+}
+
+/// A function given to a function pass, only the body can be changed
+pub struct Function<'a> {
+    pub name: &'a Rc<String>,
+    pub attrs: SymbolAttrs,
+    pub args: &'a [DataType],
+    pub is_variadic: bool,
+    pub body: &'a mut Vec<Instruction>,
+    pub type_defs: &'a TypeDefs,
+}
+
+/// A pass that transforms each function on its own
+pub trait FunctionPass {
+    /// Name of the pass in pipelines and in `--print-after=<pass>`
+    fn name(&self) -> &'static str;
+    fn run_on_function(&mut self, function: Function);
+}
+
+impl<P: FunctionPass> Pass for P {
+    fn name(&self) -> &'static str {
+        FunctionPass::name(self)
+    }
+    fn run(&mut self, program: &mut Vec<TopLevel>) {
+        let type_defs = TypeDefs::from_ir(program);
+        for top_level in program.iter_mut() {
+            if let TopLevel::Fn {
+                name,
+                attrs,
+                args,
+                is_variadic,
+                body,
+            } = top_level
+            {
+                self.run_on_function(Function {
+                    name,
+                    attrs: *attrs,
+                    args,
+                    is_variadic: *is_variadic,
+                    body,
+                    type_defs: &type_defs,
+                });
+            }
+        }
+    }
+}
+
+/// Passes to run in order, built by `PipelineBuilder`
+pub struct Pipeline {
+    passes: Vec<Box<dyn Pass>>,
+    verify_each: bool,
+    print_before_all: bool,
+    print_after: HashSet<String>,
+}
+impl Pipeline {
+    pub fn builder() -> PipelineBuilder {
+        PipelineBuilder::default()
+    }
+    /// Names of the passes in the order they are run
+    pub fn pass_names(&self) -> Vec<&'static str> {
+        self.passes.iter().map(|pass| pass.name()).collect()
+    }
+    /// Run all the passes on the program, printing the IR to stdout where asked for
+    /// Will panic if the verification is on and a pass leaves the IR invalid
+    pub fn run(&mut self, program: &mut Vec<TopLevel>) {
+        self.run_with_output(program, &mut io::stdout());
+    }
+    /// Run all the passes on the program, printing the IR to `output` where asked for
+    /// Will panic if the verification is on and a pass leaves the IR invalid
+    pub fn run_with_output(&mut self, program: &mut Vec<TopLevel>, output: &mut impl Write) {
+        for pass in &mut self.passes {
+            let name = pass.name();
+            if self.print_before_all {
+                write!(output, "\\ IR before `{name}`\n{}", print_program(program))
+                    .expect("Unable to print the IR");
+            }
+            pass.run(program);
+            if self.print_after.contains(name) {
+                write!(output, "\\ IR after `{name}`\n{}", print_program(program))
+                    .expect("Unable to print the IR");
+            }
+            if self.verify_each {
+                if let Err(errors) = verify(program) {
+                    let errors: Vec<String> = errors.iter().map(ToString::to_string).collect();
+                    panic!("IR is invalid after pass `{name}`:\n{}", errors.join("\n"));
+                }
+            }
+        }
+    }
+}
+
+/// Builds a `Pipeline`, the IR is verified after every pass unless turned off
+pub struct PipelineBuilder {
+    passes: Vec<Box<dyn Pass>>,
+    verify_each: bool,
+    print_before_all: bool,
+    print_after: HashSet<String>,
+}
+impl Default for PipelineBuilder {
+    fn default() -> Self {
+        Self {
+            passes: Vec::new(),
+            verify_each: true,
+            print_before_all: false,
+            print_after: HashSet::new(),
+        }
+    }
+}
+impl PipelineBuilder {
+    /// Add a pass to the end of the pipeline
+    pub fn pass(mut self, pass: impl Pass + 'static) -> Self {
+        self.passes.push(Box::new(pass));
+        self
+    }
+    /// Add a pass that's already boxed, such as one from `transform::pass_by_name`
+    pub fn boxed_pass(mut self, pass: Box<dyn Pass>) -> Self {
+        self.passes.push(pass);
+        self
+    }
+    /// Whether to run the verifier after every pass
+    pub fn verify_each(mut self, verify_each: bool) -> Self {
+        self.verify_each = verify_each;
+        self
+    }
+    /// Print the IR before every pass
+    pub fn print_before_all(mut self, print_before_all: bool) -> Self {
+        self.print_before_all = print_before_all;
+        self
+    }
+    /// Print the IR after every run of the pass named `name`
+    pub fn print_after(mut self, name: impl Into<String>) -> Self {
+        self.print_after.insert(name.into());
+        self
+    }
+    pub fn build(self) -> Pipeline {
+        Pipeline {
+            passes: self.passes,
+            verify_each: self.verify_each,
+            print_before_all: self.print_before_all,
+            print_after: self.print_after,
+        }
+    }
+}
@@ -0,0 +1,288 @@
+//! Prints the IR back into the text format accepted by the parser
+//! The output is canonical: parsing it gives back an identical IR, except for floats that are NaN
+//! or infinite, which the text format can't express
+
+use std::fmt::{self, Display, Formatter, Write};
+
+use crate::ir::{
+    Callee, Constant, DataType, Instruction, Linkage, SymbolAttrs, TopLevel, Type, Visibility,
+};
+
+/// Print a whole program, one top level item after another
+pub fn print_program(program: &[TopLevel]) -> String {
+    let mut text = String::new();
+    for top_level in program {
+        writeln!(text, "{top_level}").unwrap();
+    }
+    text
+}
+
+impl Display for DataType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DataType::U64 => "u64",
+            DataType::U32 => "u32",
+            DataType::U16 => "u16",
+            DataType::U8 => "u8",
+            DataType::USize => "usize",
+            DataType::I64 => "i64",
+            DataType::I32 => "i32",
+            DataType::I16 => "i16",
+            DataType::I8 => "i8",
+            DataType::ISize => "isize",
+            DataType::F64 => "f64",
+            DataType::F32 => "f32",
+            DataType::Ptr => "ptr",
+        })
+    }
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Scalar(dtype) => write!(f, "{dtype}"),
+            Type::Struct(name) => write!(f, "%{name}"),
+            Type::Array(elem, count) => write!(f, "[{count} x {elem}]"),
+        }
+    }
+}
+
+impl Display for Constant {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            // Negative numbers read better than their two's complement, and parse back the same
+            Constant::Int(i) => write!(f, "{}", *i as i64),
+            Constant::Float(x) => write!(f, "{}", FloatLiteral(*x)),
+            Constant::Bytes(bytes) => write!(f, "{}", StringLiteral(bytes)),
+            Constant::Aggregate(elements) => {
+                write!(f, "{{ ")?;
+                for (i, element) in elements.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{element}")?;
+                }
+                write!(f, " }}")
+            }
+        }
+    }
+}
+
+impl Display for SymbolAttrs {
+    /// The keywords before `fn`, `global` or `const`, each followed by a space, nothing for the
+    /// default attributes
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.linkage {
+            Linkage::Export => (),
+            Linkage::Internal => write!(f, "internal ")?,
+            Linkage::Private => write!(f, "private ")?,
+            Linkage::Weak => write!(f, "weak ")?,
+        }
+        match self.visibility {
+            Visibility::Default => (),
+            Visibility::Hidden => write!(f, "hidden ")?,
+            Visibility::Protected => write!(f, "protected ")?,
+        }
+        Ok(())
+    }
+}
+
+impl Display for Instruction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Instruction::Arg(dtype, id) => write!(f, "{dtype} #{id}"),
+            Instruction::Reg(dtype, id) => write!(f, "{dtype} %{id}"),
+            Instruction::UInt(dtype, u) => write!(f, "{dtype} ${u}"),
+            // `$+` keeps positive signed numbers from being parsed as `UInt`
+            Instruction::Int(dtype, i) if *i >= 0 => write!(f, "{dtype} $+{i}"),
+            Instruction::Int(dtype, i) => write!(f, "{dtype} ${i}"),
+            Instruction::Float(dtype, x) => write!(f, "{dtype} ${}", FloatLiteral(*x)),
+            Instruction::String(bytes) => write!(f, "ptr {}", StringLiteral(bytes)),
+            Instruction::Add(dtype, lhs, rhs) => write!(f, "{dtype} + {lhs} {rhs}"),
+            Instruction::Sub(dtype, lhs, rhs) => write!(f, "{dtype} - {lhs} {rhs}"),
+            Instruction::Mul(dtype, lhs, rhs) => write!(f, "{dtype} * {lhs} {rhs}"),
+            Instruction::Div(dtype, lhs, rhs) => write!(f, "{dtype} / {lhs} {rhs}"),
+            Instruction::Not(dtype, lhs, rhs) => write!(f, "{dtype} ~ {lhs} {rhs}"),
+            Instruction::And(dtype, lhs, rhs) => write!(f, "{dtype} & {lhs} {rhs}"),
+            Instruction::Or(dtype, lhs, rhs) => write!(f, "{dtype} | {lhs} {rhs}"),
+            Instruction::Xor(dtype, lhs, rhs) => write!(f, "{dtype} ^ {lhs} {rhs}"),
+            Instruction::Rem(dtype, lhs, rhs) => write!(f, "{dtype} % {lhs} {rhs}"),
+            Instruction::MulHi(dtype, lhs, rhs) => write!(f, "{dtype} mulhi {lhs} {rhs}"),
+            Instruction::Shl(dtype, lhs, rhs) => write!(f, "{dtype} << {lhs} {rhs}"),
+            Instruction::Shr(dtype, lhs, rhs) => write!(f, "{dtype} >> {lhs} {rhs}"),
+            Instruction::Eq(dtype, lhs, rhs) => write!(f, "{dtype} == {lhs} {rhs}"),
+            Instruction::Ne(dtype, lhs, rhs) => write!(f, "{dtype} != {lhs} {rhs}"),
+            Instruction::Lt(dtype, lhs, rhs) => write!(f, "{dtype} < {lhs} {rhs}"),
+            Instruction::Le(dtype, lhs, rhs) => write!(f, "{dtype} <= {lhs} {rhs}"),
+            Instruction::Gt(dtype, lhs, rhs) => write!(f, "{dtype} > {lhs} {rhs}"),
+            Instruction::Ge(dtype, lhs, rhs) => write!(f, "{dtype} >= {lhs} {rhs}"),
+            Instruction::Load { id, dtype } => write!(f, "{dtype} [%{id}]"),
+            Instruction::Alloc(ty) => write!(f, "alloc {ty}"),
+            Instruction::GlobalPtr(name) => write!(f, "ptr @{name}"),
+            Instruction::FieldPtr { ty, id, index } => write!(f, "ptr field {ty} %{id} ${index}"),
+            Instruction::ElemPtr { ty, id, index } => write!(f, "ptr elem {ty} %{id} {index}"),
+            Instruction::DefReg { id, rhs } => write!(f, "%{id} = {rhs}"),
+            Instruction::Store { lhs_dtype, id, rhs } => write!(f, "{lhs_dtype} [%{id}] = {rhs}"),
+            Instruction::Ret(None) => write!(f, "ret"),
+            Instruction::Ret(Some(val)) => write!(f, "ret {val}"),
+            Instruction::Call {
+                ret_type,
+                callee,
+                args,
+                is_tail,
+            } => {
+                if let Some(ret_type) = ret_type {
+                    write!(f, "{ret_type} ")?;
+                }
+                if *is_tail {
+                    write!(f, "tail ")?;
+                }
+                match callee {
+                    Callee::Direct(name) => write!(f, "call @{name}(")?,
+                    Callee::Indirect(fn_ptr) => write!(f, "call {fn_ptr}(")?,
+                }
+                for (i, arg) in args.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{arg}")?;
+                }
+                write!(f, ")")
+            }
+            Instruction::Label(name) => write!(f, ":{name}"),
+            Instruction::Jmp(label) => write!(f, "jmp :{label}"),
+            Instruction::Br {
+                cond,
+                if_true,
+                if_false,
+            } => write!(f, "br {cond} :{if_true} :{if_false}"),
+            Instruction::Phi { dtype, incoming } => {
+                write!(f, "{dtype} phi")?;
+                for (label, value) in incoming {
+                    write!(f, " [:{label} {value}]")?;
+                }
+                Ok(())
+            }
+            Instruction::VaStart(id) => write!(f, "va_start %{id}"),
+            Instruction::VaArg { dtype, id } => write!(f, "{dtype} va_arg %{id}"),
+            Instruction::VaEnd(id) => write!(f, "va_end %{id}"),
+        }
+    }
+}
+
+impl Display for TopLevel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            TopLevel::Extern {
+                name,
+                args,
+                is_variadic,
+            } => {
+                write!(f, "extern @{name}")?;
+                write_signature(f, args, *is_variadic)
+            }
+            TopLevel::TypeDef { name, fields } => {
+                write!(f, "type %{name} = {{ ")?;
+                for (i, field) in fields.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{field}")?;
+                }
+                write!(f, " }}")
+            }
+            TopLevel::Global {
+                name,
+                attrs,
+                ty,
+                init,
+                is_const,
+            } => {
+                let keyword = if *is_const { "const" } else { "global" };
+                write!(f, "{attrs}{keyword} @{name}: {ty}")?;
+                match init {
+                    Some(init) => write!(f, " = {init}"),
+                    None => Ok(()),
+                }
+            }
+            TopLevel::Fn {
+                name,
+                attrs,
+                args,
+                is_variadic,
+                body,
+            } => {
+                write!(f, "{attrs}fn @{name}")?;
+                write_signature(f, args, *is_variadic)?;
+                writeln!(f, " {{")?;
+                for instruction in body {
+                    match instruction {
+                        // Labels are not indented, so that blocks stand out
+                        Instruction::Label(_) => writeln!(f, "{instruction}")?,
+                        _ => writeln!(f, "    {instruction}")?,
+                    }
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+/// `(i32 ptr)`, or `(ptr ...)` if variadic
+fn write_signature(f: &mut Formatter<'_>, args: &[DataType], is_variadic: bool) -> fmt::Result {
+    write!(f, "(")?;
+    for (i, arg) in args.iter().enumerate() {
+        if i != 0 {
+            write!(f, " ")?;
+        }
+        write!(f, "{arg}")?;
+    }
+    if is_variadic {
+        if !args.is_empty() {
+            write!(f, " ")?;
+        }
+        write!(f, "...")?;
+    }
+    write!(f, ")")
+}
+
+/// A float that always has a `.`, otherwise the parser reads it as an integer
+struct FloatLiteral(f64);
+impl Display for FloatLiteral {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        // `Display` for f64 never uses exponents and prints the shortest digits that parse back
+        // into the same value
+        let text = self.0.to_string();
+        if self.0.is_finite() && !text.contains('.') {
+            write!(f, "{text}.0")
+        } else {
+            write!(f, "{text}")
+        }
+    }
+}
+
+/// A string literal, written as `c"..."` if it ends with a NUL
+struct StringLiteral<'a>(&'a [u8]);
+impl Display for StringLiteral<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let bytes = match self.0.split_last() {
+            Some((0, rest)) => {
+                write!(f, "c")?;
+                rest
+            }
+            _ => self.0,
+        };
+        write!(f, "\"")?;
+        for &byte in bytes {
+            match byte {
+                b'"' => write!(f, "\\\"")?,
+                b'\\' => write!(f, "\\\\")?,
+                b'\n' => write!(f, "\\n")?,
+                b'\t' => write!(f, "\\t")?,
+                0x20..=0x7E => write!(f, "{}", byte as char)?,
+                _ => write!(f, "\\x{byte:02X}")?,
+            }
+        }
+        write!(f, "\"")
+    }
+}
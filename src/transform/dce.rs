@@ -0,0 +1,227 @@
+//! Dead code elimination and dead store elimination
+//!
+//! `dce` removes unreachable blocks, and the definitions of vregs that are never used and have
+//! no side effects. It's a mark and sweep rather than counting uses, so vregs that are only used
+//! by each other, such as `phi`s around a loop that nothing reads, are removed as well
+//!
+//! `dse` removes stores to `alloc` slots that are never read afterwards, for slots whose address
+//! doesn't escape, so every access to them is a load or store through a pointer derived from the
+//! slot. Their `alloc`s are left for `dce` to remove once nothing uses them
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    analysis::{cfg::Cfg, escape::EscapeInfo},
+    ir::{Instruction, Type, TypeDefs},
+    pass::{Function, FunctionPass},
+};
+
+use super::{has_side_effects, used_vregs};
+
+/// `dce` as a pass
+pub struct Dce;
+impl FunctionPass for Dce {
+    fn name(&self) -> &'static str {
+        "dce"
+    }
+    fn run_on_function(&mut self, function: Function) {
+        dce(function.body);
+    }
+}
+
+/// `dse` as a pass
+pub struct Dse;
+impl FunctionPass for Dse {
+    fn name(&self) -> &'static str {
+        "dse"
+    }
+    fn run_on_function(&mut self, function: Function) {
+        dse(function.body, function.type_defs);
+    }
+}
+
+/// Remove unreachable blocks, and definitions of unused vregs without side effects
+pub fn dce(body: &mut Vec<Instruction>) {
+    remove_unreachable_blocks(body);
+    let defs: HashMap<u64, &Instruction> = body
+        .iter()
+        .filter_map(|instruction| match instruction {
+            Instruction::DefReg { id, rhs } => Some((*id, rhs.as_ref())),
+            _ => None,
+        })
+        .collect();
+    let mut live = HashSet::<u64>::new();
+    let mut worklist = Vec::<u64>::new();
+    for instruction in body.iter() {
+        match instruction {
+            Instruction::Label(_) => (),
+            Instruction::DefReg { id, rhs } if has_side_effects(rhs) => worklist.push(*id),
+            Instruction::DefReg { .. } => (),
+            _ => used_vregs(instruction, &mut worklist),
+        }
+    }
+    while let Some(id) = worklist.pop() {
+        if live.insert(id) {
+            if let Some(rhs) = defs.get(&id) {
+                used_vregs(rhs, &mut worklist);
+            }
+        }
+    }
+    body.retain(|instruction| match instruction {
+        Instruction::DefReg { id, .. } => live.contains(id),
+        _ => true,
+    });
+}
+
+/// Remove the blocks that can't be reached from the entry, along with the incoming values of
+/// `phi`s that come from them
+pub fn remove_unreachable_blocks(body: &mut Vec<Instruction>) {
+    let cfg = Cfg::new(body);
+    let reachable = cfg.reachable();
+    if reachable.iter().all(|&reachable| reachable) {
+        return;
+    }
+    let removed_labels: HashSet<&String> = cfg
+        .blocks
+        .iter()
+        .zip(&reachable)
+        .filter(|&(_, &reachable)| !reachable)
+        .filter_map(|(block, _)| block.label.as_ref())
+        .collect();
+    let mut new_body = Vec::with_capacity(body.len());
+    for (block, _) in cfg.blocks.iter().zip(&reachable).filter(|(_, &r)| r) {
+        if let Some(label) = &block.label {
+            new_body.push(Instruction::Label(label.clone()));
+        }
+        for instruction in &body[block.range.clone()] {
+            let mut instruction = instruction.clone();
+            if let Instruction::DefReg { rhs, .. } = &mut instruction {
+                if let Instruction::Phi { incoming, .. } = rhs.as_mut() {
+                    incoming.retain(|(label, _)| !removed_labels.contains(label));
+                }
+            }
+            new_body.push(instruction);
+        }
+    }
+    *body = new_body;
+}
+
+/// Remove stores to non-escaping `alloc` slots whose values are never loaded
+/// A store only overwrites the previous value of a slot if it covers the whole slot
+pub fn dse(body: &mut Vec<Instruction>, type_defs: &TypeDefs) {
+    let slots = Slots::new(body, type_defs);
+    if slots.sizes.is_empty() {
+        return;
+    }
+    let cfg = Cfg::new(body);
+    // Backward liveness of the slots, a slot is live if its value may still be loaded
+    let mut live_in = vec![vec![false; slots.sizes.len()]; cfg.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (i, block) in cfg.blocks.iter().enumerate().rev() {
+            let mut live = live_out(&cfg, i, &live_in, slots.sizes.len());
+            for instruction in body[block.range.clone()].iter().rev() {
+                slots.transfer(instruction, &mut live);
+            }
+            if live != live_in[i] {
+                live_in[i] = live;
+                changed = true;
+            }
+        }
+    }
+    let mut dead = HashSet::<usize>::new();
+    for (i, block) in cfg.blocks.iter().enumerate() {
+        let mut live = live_out(&cfg, i, &live_in, slots.sizes.len());
+        for j in block.range.clone().rev() {
+            if let Instruction::Store { id, .. } = &body[j] {
+                if slots.index(*id).is_some_and(|slot| !live[slot]) {
+                    dead.insert(j);
+                }
+            }
+            slots.transfer(&body[j], &mut live);
+        }
+    }
+    let mut i = 0;
+    body.retain(|_| {
+        i += 1;
+        !dead.contains(&(i - 1))
+    });
+}
+
+fn live_out(cfg: &Cfg, block: usize, live_in: &[Vec<bool>], slot_count: usize) -> Vec<bool> {
+    let mut live = vec![false; slot_count];
+    for &succ in &cfg.blocks[block].succs {
+        for (live, &succ_live) in live.iter_mut().zip(&live_in[succ]) {
+            *live |= succ_live;
+        }
+    }
+    live
+}
+
+/// The non-escaping slots that `dse` tracks
+struct Slots {
+    info: EscapeInfo,
+    /// Sizes of the slots, by their index
+    sizes: Vec<u64>,
+    index_of: HashMap<u64, usize>,
+}
+impl Slots {
+    fn new(body: &[Instruction], type_defs: &TypeDefs) -> Self {
+        let info = EscapeInfo::new(body, type_defs);
+        let types: HashMap<u64, &Type> = body
+            .iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::DefReg { id, rhs } => match rhs.as_ref() {
+                    Instruction::Alloc(ty) => Some((*id, ty)),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect();
+        let slots: Vec<u64> = info.non_escaping_slots().collect();
+        // TODO: dynamic word size
+        let sizes = slots
+            .iter()
+            .map(|slot| type_defs.layout(types[slot], 8).size)
+            .collect();
+        let index_of = slots.iter().enumerate().map(|(i, &id)| (id, i)).collect();
+        Self {
+            info,
+            sizes,
+            index_of,
+        }
+    }
+    /// Index of the tracked slot that the pointer in the vreg `id` points into
+    fn index(&self, id: u64) -> Option<usize> {
+        let slot = self.info.non_escaping_slot(id)?;
+        self.index_of.get(&slot).copied()
+    }
+    /// Update the liveness of the slots from after an instruction to before it
+    /// Loads in the incoming values of `phi`s happen at the end of the predecessor, treating them
+    /// as being at the start of this block keeps the slot live there as well
+    fn transfer(&self, instruction: &Instruction, live: &mut [bool]) {
+        if let Instruction::Store { lhs_dtype, id, .. } = instruction {
+            if let Some(slot) = self.index(*id) {
+                // TODO: dynamic word size
+                let covers_slot = self.info.pointer(*id).unwrap().offset == Some(0)
+                    && lhs_dtype.size(8) as u64 >= self.sizes[slot];
+                if covers_slot {
+                    live[slot] = false;
+                }
+            }
+        }
+        // Loads in the value of a store happen before the store itself
+        self.mark_loads(instruction, live);
+    }
+    fn mark_loads(&self, operand: &Instruction, live: &mut [bool]) {
+        if let Instruction::Load { id, .. } = operand {
+            if let Some(slot) = self.index(*id) {
+                live[slot] = true;
+            }
+        }
+        for operand in operand.operands() {
+            self.mark_loads(operand, live);
+        }
+    }
+}
@@ -0,0 +1,215 @@
+//! Global value numbering
+//!
+//! Walks down the dominator tree, keeping the pure expressions defined by the dominating
+//! instructions in scope. A vreg defined by an expression that's already in scope is replaced by
+//! the earlier one, so repeated arithmetic, address computations and comparisons are only done
+//! once
+//!
+//! Loads are reused within a block, and into a successor whose only predecessor is that block,
+//! as long as nothing in between may have written to the pointer. Stores into `alloc` slots that
+//! never escape only clobber that slot, other stores and calls clobber everything but those slots
+
+use std::collections::HashMap;
+
+use crate::{
+    analysis::{cfg::Cfg, dominators::DomTree, escape::EscapeInfo},
+    ir::{DataType, Instruction},
+    pass::{Function, FunctionPass},
+};
+
+use super::{has_side_effects, rename_vregs};
+
+/// `gvn` as a pass
+pub struct Gvn;
+impl FunctionPass for Gvn {
+    fn name(&self) -> &'static str {
+        "gvn"
+    }
+    fn run_on_function(&mut self, function: Function) {
+        let info = EscapeInfo::new(function.body, function.type_defs);
+        gvn(function.body, &info);
+    }
+}
+
+/// Replace vregs whose values are already computed by a dominating instruction
+/// `info` tells which pointers point into `alloc` slots that can only be accessed through them
+pub fn gvn(body: &mut Vec<Instruction>, info: &EscapeInfo) {
+    let cfg = Cfg::new(body);
+    let dom_tree = DomTree::new(&cfg);
+    let mut numberer = Numberer {
+        body,
+        cfg: &cfg,
+        dom_tree: &dom_tree,
+        info,
+        exprs: Vec::new(),
+        loads: Vec::new(),
+        renamed: HashMap::new(),
+    };
+    numberer.number(dom_tree.root());
+    let renamed = numberer.renamed;
+    if renamed.is_empty() {
+        return;
+    }
+    body.retain(|instruction| {
+        instruction
+            .as_def_reg_id()
+            .is_none_or(|id| !renamed.contains_key(&id))
+    });
+    // Uses that come before the definitions in the walk, such as in `phi`s of loop headers
+    for instruction in body.iter_mut() {
+        rename_vregs(instruction, &renamed);
+    }
+}
+
+/// A load that's still valid, `(pointer, dtype, vreg)`
+type AvailableLoad = (u64, DataType, u64);
+
+struct Numberer<'a> {
+    body: &'a mut [Instruction],
+    cfg: &'a Cfg,
+    dom_tree: &'a DomTree,
+    info: &'a EscapeInfo,
+    /// The pure expressions in scope and the vregs holding them
+    exprs: Vec<(Instruction, u64)>,
+    loads: Vec<AvailableLoad>,
+    /// The vregs that are replaced, and what they are replaced with
+    renamed: HashMap<u64, u64>,
+}
+impl Numberer<'_> {
+    fn number(&mut self, block: usize) {
+        let exprs_len = self.exprs.len();
+        for i in self.cfg.blocks[block].range.clone() {
+            let mut instruction = self.body[i].clone();
+            rename_vregs(&mut instruction, &self.renamed);
+            if instruction.is_phi_def() {
+                self.body[i] = instruction;
+                continue;
+            }
+            if has_side_effects(&instruction) || is_va_list_op(&instruction) {
+                self.clobber(None);
+            }
+            match &mut instruction {
+                Instruction::DefReg { id, rhs } => match rhs.as_mut() {
+                    &mut Instruction::Load { id: ptr, dtype } => {
+                        match self.available_load(ptr, dtype) {
+                            Some(value) => _ = self.renamed.insert(*id, value),
+                            None => self.loads.push((ptr, dtype, *id)),
+                        }
+                    }
+                    rhs => {
+                        self.reuse_loads(rhs);
+                        if is_pure(rhs) {
+                            match self.lookup(rhs) {
+                                Some(value) => _ = self.renamed.insert(*id, value),
+                                None => self.exprs.push((rhs.clone(), *id)),
+                            }
+                        }
+                    }
+                },
+                Instruction::Store { id, rhs, .. } => {
+                    self.reuse_loads(rhs);
+                    let id = *id;
+                    self.clobber(Some(id));
+                }
+                _ => {
+                    for operand in instruction.operands_mut() {
+                        self.reuse_loads(operand);
+                    }
+                }
+            }
+            self.body[i] = instruction;
+        }
+        let loads = std::mem::take(&mut self.loads);
+        for &child in self.dom_tree.children(block) {
+            self.loads = if self.cfg.blocks[child].preds == [block] {
+                loads.clone()
+            } else {
+                Vec::new()
+            };
+            self.number(child);
+        }
+        self.exprs.truncate(exprs_len);
+    }
+    /// The vreg holding an expression equal to `expr`, also trying the operands the other way
+    /// around for commutative operations
+    fn lookup(&self, expr: &Instruction) -> Option<u64> {
+        let commuted = commuted(expr);
+        self.exprs
+            .iter()
+            .rev()
+            .find(|(other, _)| other == expr || commuted.as_ref() == Some(other))
+            .map(|&(_, id)| id)
+    }
+    fn available_load(&self, ptr: u64, dtype: DataType) -> Option<u64> {
+        self.loads
+            .iter()
+            .rev()
+            .find(|load| load.0 == ptr && load.1 == dtype)
+            .map(|load| load.2)
+    }
+    /// Replace loads nested inside an operand with the vregs already holding their values
+    fn reuse_loads(&self, operand: &mut Instruction) {
+        if let &mut Instruction::Load { id, dtype } = operand {
+            if let Some(value) = self.available_load(id, dtype) {
+                *operand = Instruction::Reg(dtype, value);
+            }
+            return;
+        }
+        for operand in operand.operands_mut() {
+            self.reuse_loads(operand);
+        }
+    }
+    /// Forget the loads that a store to `stored`, or a call if `None`, may have overwritten
+    fn clobber(&mut self, stored: Option<u64>) {
+        let info = self.info;
+        match stored.and_then(|stored| info.non_escaping_slot(stored)) {
+            Some(slot) => self
+                .loads
+                .retain(|&(ptr, _, _)| info.non_escaping_slot(ptr) != Some(slot)),
+            None => self
+                .loads
+                .retain(|&(ptr, _, _)| info.non_escaping_slot(ptr).is_some()),
+        }
+    }
+}
+
+/// `va_start`, `va_arg` and `va_end` change the `va_list`, which may be in any memory
+fn is_va_list_op(instruction: &Instruction) -> bool {
+    matches!(instruction, Instruction::VaStart(_) | Instruction::VaEnd(_))
+}
+
+/// Whether the operand always evaluates to the same value, without reading memory
+fn is_pure(operand: &Instruction) -> bool {
+    match operand {
+        Instruction::Load { .. }
+        | Instruction::Alloc(_)
+        | Instruction::Call { .. }
+        | Instruction::Phi { .. }
+        | Instruction::VaArg { .. } => false,
+        _ => operand.operands().into_iter().all(is_pure),
+    }
+}
+
+/// The operation with its operands swapped, if it's commutative
+fn commuted(expr: &Instruction) -> Option<Instruction> {
+    let (lhs, rhs) = match expr {
+        Instruction::Add(_, lhs, rhs)
+        | Instruction::Mul(_, lhs, rhs)
+        | Instruction::And(_, lhs, rhs)
+        | Instruction::Or(_, lhs, rhs)
+        | Instruction::Xor(_, lhs, rhs)
+        | Instruction::Eq(_, lhs, rhs)
+        | Instruction::Ne(_, lhs, rhs) => (rhs.clone(), lhs.clone()),
+        _ => return None,
+    };
+    Some(match expr {
+        Instruction::Add(dtype, ..) => Instruction::Add(*dtype, lhs, rhs),
+        Instruction::Mul(dtype, ..) => Instruction::Mul(*dtype, lhs, rhs),
+        Instruction::And(dtype, ..) => Instruction::And(*dtype, lhs, rhs),
+        Instruction::Or(dtype, ..) => Instruction::Or(*dtype, lhs, rhs),
+        Instruction::Xor(dtype, ..) => Instruction::Xor(*dtype, lhs, rhs),
+        Instruction::Eq(dtype, ..) => Instruction::Eq(*dtype, lhs, rhs),
+        Instruction::Ne(dtype, ..) => Instruction::Ne(*dtype, lhs, rhs),
+        _ => unreachable!(),
+    })
+}
@@ -0,0 +1,357 @@
+//! Function inlining
+//!
+//! Calls to small functions defined in the same program are replaced by copies of their bodies.
+//! The arguments are substituted for the `#n`s, vregs and labels of the copy are renamed so they
+//! don't collide with the caller's, and each `ret` becomes a jump to a continuation block, where a
+//! `phi` picks up the returned value if there is more than one `ret`
+//!
+//! Functions are visited callees first, so a chain of small functions is inlined all the way
+//! down. Variadic and directly recursive functions are never inlined, and neither are functions
+//! with a `tail call`, which would no longer be in tail position in the caller. A `tail call`
+//! itself is kept as a call, so the stack doesn't grow with it
+
+use std::{
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+use crate::{
+    analysis::callgraph::CallGraph,
+    ir::{Callee, DataType, Instruction, TopLevel},
+    pass::Pass,
+};
+
+use super::{canonicalize_blocks, next_vreg_id, rename_vregs, LabelGenerator};
+
+/// Functions with at most this many instructions are inlined by default
+pub const DEFAULT_THRESHOLD: usize = 16;
+
+/// Functions with at most this many instructions are about as small as the calls to them, so
+/// inlining them doesn't grow the code
+pub const SIZE_THRESHOLD: usize = 4;
+
+/// `inline` as a pass
+pub struct Inline {
+    /// Largest size of functions to inline, in the number of instructions not counting labels
+    pub threshold: usize,
+}
+impl Default for Inline {
+    fn default() -> Self {
+        Self {
+            threshold: DEFAULT_THRESHOLD,
+        }
+    }
+}
+impl Pass for Inline {
+    fn name(&self) -> &'static str {
+        "inline"
+    }
+    fn run(&mut self, program: &mut Vec<TopLevel>) {
+        inline(program, self.threshold);
+    }
+}
+
+/// Inline calls to functions of at most `threshold` instructions
+pub fn inline(program: &mut [TopLevel], threshold: usize) {
+    let fn_indices: HashMap<Rc<String>, usize> = program
+        .iter()
+        .enumerate()
+        .filter_map(|(i, top_level)| match top_level {
+            TopLevel::Fn { name, .. } => Some((name.clone(), i)),
+            _ => None,
+        })
+        .collect();
+    let call_graph = CallGraph::new(program);
+    let order: Vec<usize> = call_graph
+        .bottom_up()
+        .map(|node| call_graph.functions[node])
+        .collect();
+    for i in order {
+        let TopLevel::Fn { body, .. } = &mut program[i] else {
+            unreachable!()
+        };
+        let mut body = std::mem::take(body);
+        inline_calls(&mut body, program, &fn_indices, threshold);
+        let TopLevel::Fn { body: fn_body, .. } = &mut program[i] else {
+            unreachable!()
+        };
+        *fn_body = body;
+    }
+}
+
+/// Size of a function body in the cost model
+pub fn inline_cost(body: &[Instruction]) -> usize {
+    body.iter()
+        .filter(|instruction| !matches!(instruction, Instruction::Label(_)))
+        .count()
+}
+
+/// Names of the functions called directly anywhere in the body
+fn direct_callees(body: &[Instruction]) -> HashSet<Rc<String>> {
+    fn collect(operand: &Instruction, callees: &mut HashSet<Rc<String>>) {
+        if let Instruction::Call {
+            callee: Callee::Direct(name),
+            ..
+        } = operand
+        {
+            callees.insert(name.clone());
+        }
+        for operand in operand.operands() {
+            collect(operand, callees);
+        }
+    }
+    let mut callees = HashSet::new();
+    for instruction in body {
+        collect(instruction, &mut callees);
+    }
+    callees
+}
+
+/// Whether the instruction is a `tail call`, which can only be a statement, the right hand side
+/// of a `DefReg` or returned
+fn is_tail_call(instruction: &Instruction) -> bool {
+    let call = match instruction {
+        Instruction::DefReg { rhs, .. } => rhs.as_ref(),
+        Instruction::Ret(Some(ret_val)) => ret_val.as_ref(),
+        instruction => instruction,
+    };
+    matches!(call, Instruction::Call { is_tail: true, .. })
+}
+
+/// The body of the function named `name` if it should be inlined
+fn inlinable<'a>(
+    name: &Rc<String>,
+    program: &'a [TopLevel],
+    fn_indices: &HashMap<Rc<String>, usize>,
+    threshold: usize,
+) -> Option<&'a [Instruction]> {
+    let &i = fn_indices.get(name)?;
+    let TopLevel::Fn {
+        is_variadic, body, ..
+    } = &program[i]
+    else {
+        unreachable!()
+    };
+    // The body is empty while it is the caller being inlined into
+    if *is_variadic
+        || body.is_empty()
+        || inline_cost(body) > threshold
+        || direct_callees(body).contains(name)
+        || body.iter().any(is_tail_call)
+    {
+        return None;
+    }
+    Some(body)
+}
+
+/// Inline the calls that are statements or the right hand side of a `DefReg`
+fn inline_calls(
+    body: &mut Vec<Instruction>,
+    program: &[TopLevel],
+    fn_indices: &HashMap<Rc<String>, usize>,
+    threshold: usize,
+) {
+    let mut labels = LabelGenerator::new(body);
+    let mut next_id = next_vreg_id(body);
+    // The blocks whose ends moved into a continuation block, for the `phi`s of their successors
+    let mut moved_ends = HashMap::<String, String>::new();
+    let mut block_label: Option<String> = None;
+    let mut new_body = Vec::with_capacity(body.len());
+    for instruction in std::mem::take(body) {
+        let (result, call) = match &instruction {
+            Instruction::DefReg { id, rhs } => (Some(*id), rhs.as_ref()),
+            call => (None, call),
+        };
+        let Instruction::Call {
+            ret_type,
+            callee: Callee::Direct(name),
+            args,
+            is_tail: false,
+        } = call
+        else {
+            match &instruction {
+                Instruction::Label(label) => block_label = Some(label.clone()),
+                instruction if instruction.is_terminator() => block_label = None,
+                _ => (),
+            }
+            new_body.push(instruction);
+            continue;
+        };
+        let Some(callee_body) = inlinable(name, program, fn_indices, threshold) else {
+            new_body.push(instruction);
+            continue;
+        };
+        let mut callee_body = callee_body.to_vec();
+        canonicalize_blocks(&mut callee_body);
+        let cont = labels.fresh(&format!("{name}.ret"));
+        let mut expander = Expander {
+            name,
+            labels: &mut labels,
+            next_id: &mut next_id,
+            out: &mut new_body,
+        };
+        let result = result.map(|id| (id, ret_type.expect("Result of a void call is used")));
+        expander.expand(&callee_body, args, result, &cont);
+        if let Some(label) = &block_label {
+            moved_ends.insert(label.clone(), cont);
+        }
+    }
+    for instruction in &mut new_body {
+        if let Instruction::DefReg { rhs, .. } = instruction {
+            if let Instruction::Phi { incoming, .. } = rhs.as_mut() {
+                for (label, _) in incoming {
+                    if let Some(moved) = moved_ends.get(label) {
+                        *label = moved.clone();
+                    }
+                }
+            }
+        }
+    }
+    *body = new_body;
+}
+
+/// Copies a callee's body into the caller
+struct Expander<'a> {
+    name: &'a str,
+    labels: &'a mut LabelGenerator,
+    next_id: &'a mut u64,
+    out: &'a mut Vec<Instruction>,
+}
+impl Expander<'_> {
+    /// `callee_body` needs to be canonicalized, `result` is the vreg of the returned value and its
+    /// type
+    fn expand(
+        &mut self,
+        callee_body: &[Instruction],
+        args: &[Instruction],
+        result: Option<(u64, DataType)>,
+        cont: &str,
+    ) {
+        // Arguments are evaluated once before the call, unless they are trivial
+        let args: Vec<Instruction> = args
+            .iter()
+            .map(|arg| match arg {
+                Instruction::UInt(..)
+                | Instruction::Int(..)
+                | Instruction::Float(..)
+                | Instruction::Reg(..)
+                | Instruction::Arg(..)
+                | Instruction::GlobalPtr(_) => arg.clone(),
+                arg => {
+                    let id = self.fresh_id();
+                    self.out.push(Instruction::DefReg {
+                        id,
+                        rhs: Box::new(arg.clone()),
+                    });
+                    Instruction::Reg(arg.dtype().expect("Argument has no value"), id)
+                }
+            })
+            .collect();
+        let vregs: HashMap<u64, u64> = callee_body
+            .iter()
+            .filter_map(Instruction::as_def_reg_id)
+            .map(|id| (id, self.fresh_id()))
+            .collect();
+        let labels: HashMap<String, String> = callee_body
+            .iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::Label(label) => {
+                    let hint = format!("{}.{label}", self.name);
+                    Some((label.clone(), self.labels.fresh(&hint)))
+                }
+                _ => None,
+            })
+            .collect();
+        let Instruction::Label(entry) = &callee_body[0] else {
+            unreachable!()
+        };
+        self.out.push(Instruction::Jmp(labels[entry].clone()));
+
+        let mut returns = Vec::<(String, Option<Instruction>)>::new();
+        let mut current_label = String::new();
+        for instruction in callee_body {
+            let mut instruction = instruction.clone();
+            rename_vregs(&mut instruction, &vregs);
+            substitute_args(&mut instruction, &args);
+            rename_labels(&mut instruction, &labels);
+            match instruction {
+                Instruction::DefReg { id, rhs } => self.out.push(Instruction::DefReg {
+                    id: vregs[&id],
+                    rhs,
+                }),
+                Instruction::Label(label) => {
+                    current_label = label.clone();
+                    self.out.push(Instruction::Label(label));
+                }
+                Instruction::Ret(value) => {
+                    returns.push((current_label.clone(), value.map(|value| *value)));
+                    self.out.push(Instruction::Jmp(cont.to_string()));
+                }
+                instruction => self.out.push(instruction),
+            }
+        }
+        if callee_body.last().is_none_or(|last| !last.is_terminator()) {
+            returns.push((current_label, None));
+            self.out.push(Instruction::Jmp(cont.to_string()));
+        }
+
+        self.out.push(Instruction::Label(cont.to_string()));
+        let Some((id, dtype)) = result else {
+            return;
+        };
+        let undefined = || match dtype {
+            DataType::F64 | DataType::F32 => Instruction::Float(dtype, 0.0),
+            _ => Instruction::UInt(dtype, 0),
+        };
+        let rhs = match returns.len() {
+            0 => undefined(),
+            1 => returns.pop().unwrap().1.unwrap_or_else(undefined),
+            _ => Instruction::Phi {
+                dtype,
+                incoming: returns
+                    .into_iter()
+                    .map(|(label, value)| (label, value.unwrap_or_else(undefined)))
+                    .collect(),
+            },
+        };
+        self.out.push(Instruction::DefReg {
+            id,
+            rhs: Box::new(rhs),
+        });
+    }
+    fn fresh_id(&mut self) -> u64 {
+        let id = *self.next_id;
+        *self.next_id += 1;
+        id
+    }
+}
+
+fn substitute_args(operand: &mut Instruction, args: &[Instruction]) {
+    if let &mut Instruction::Arg(_, i) = operand {
+        *operand = args[i as usize].clone();
+        return;
+    }
+    for operand in operand.operands_mut() {
+        substitute_args(operand, args);
+    }
+}
+
+fn rename_labels(instruction: &mut Instruction, renamed: &HashMap<String, String>) {
+    match instruction {
+        Instruction::Label(label) | Instruction::Jmp(label) => *label = renamed[label].clone(),
+        Instruction::Br {
+            if_true, if_false, ..
+        } => {
+            *if_true = renamed[if_true].clone();
+            *if_false = renamed[if_false].clone();
+        }
+        Instruction::DefReg { rhs, .. } => {
+            if let Instruction::Phi { incoming, .. } = rhs.as_mut() {
+                for (label, _) in incoming {
+                    *label = renamed[label].clone();
+                }
+            }
+        }
+        _ => (),
+    }
+}
@@ -0,0 +1,194 @@
+//! Interprocedural optimizations over the call graph
+//!
+//! `global_dce` removes the functions that can't be called from outside of the object file and
+//! that no function which can refers to, by a call or by taking its address
+//!
+//! `propagate_const_args` looks at every call to a function that can only be called directly
+//! from inside the program. An argument that's the same constant at all of them is substituted
+//! into the body of the function, so the constant can be folded there. Callers are visited
+//! before their callees, so constants are carried down a chain of calls
+
+use std::collections::HashSet;
+
+use crate::{
+    analysis::callgraph::CallGraph,
+    ir::{Callee, Instruction, TopLevel},
+    pass::Pass,
+};
+
+/// `global_dce` as a pass
+pub struct GlobalDce;
+impl Pass for GlobalDce {
+    fn name(&self) -> &'static str {
+        "global-dce"
+    }
+    fn run(&mut self, program: &mut Vec<TopLevel>) {
+        global_dce(program);
+    }
+}
+
+/// `propagate_const_args` as a pass
+pub struct ConstArgs;
+impl Pass for ConstArgs {
+    fn name(&self) -> &'static str {
+        "const-args"
+    }
+    fn run(&mut self, program: &mut Vec<TopLevel>) {
+        propagate_const_args(program);
+    }
+}
+
+/// Remove the internal functions that can't be reached from the ones visible outside
+pub fn global_dce(program: &mut Vec<TopLevel>) {
+    let call_graph = CallGraph::new(program);
+    let mut live = vec![false; call_graph.len()];
+    let mut worklist: Vec<usize> = (0..call_graph.len())
+        .filter(|&node| match &program[call_graph.functions[node]] {
+            TopLevel::Fn { attrs, .. } => attrs.is_global(),
+            _ => unreachable!(),
+        })
+        .collect();
+    while let Some(node) = worklist.pop() {
+        if std::mem::replace(&mut live[node], true) {
+            continue;
+        }
+        worklist.extend(&call_graph.callees[node]);
+        worklist.extend(&call_graph.address_refs[node]);
+    }
+    let dead: HashSet<usize> = (0..call_graph.len())
+        .filter(|&node| !live[node])
+        .map(|node| call_graph.functions[node])
+        .collect();
+    let mut indices = 0..;
+    program.retain(|_| !dead.contains(&indices.next().unwrap()));
+}
+
+/// Substitute the arguments that are the same constant at every call of an internal function
+pub fn propagate_const_args(program: &mut [TopLevel]) {
+    let call_graph = CallGraph::new(program);
+    let bottom_up: Vec<usize> = call_graph.bottom_up().collect();
+    for &node in bottom_up.iter().rev() {
+        let i = call_graph.functions[node];
+        let TopLevel::Fn {
+            name,
+            attrs,
+            args,
+            is_variadic,
+            ..
+        } = &program[i]
+        else {
+            unreachable!()
+        };
+        // Calls through a pointer pass arguments that can't be seen
+        if attrs.is_global()
+            || *is_variadic
+            || call_graph.address_taken[node]
+            || call_graph.callers[node].is_empty()
+        {
+            continue;
+        }
+        let name = name.clone();
+        let mut values = vec![ArgValue::Undefined; args.len()];
+        for &caller in &call_graph.callers[node] {
+            let TopLevel::Fn { body, .. } = &program[call_graph.functions[caller]] else {
+                unreachable!()
+            };
+            for instruction in body {
+                visit_calls(instruction, &name, &mut |call_args| {
+                    for (index, (arg, value)) in call_args.iter().zip(&mut values).enumerate() {
+                        // A recursive call passing the argument on unchanged
+                        if caller == node
+                            && matches!(arg, Instruction::Arg(_, n) if *n == index as u64)
+                        {
+                            continue;
+                        }
+                        value.meet(arg);
+                    }
+                });
+            }
+        }
+        let substituted: Vec<Option<Instruction>> = values
+            .into_iter()
+            .map(|value| match value {
+                ArgValue::Const(constant) => Some(constant),
+                _ => None,
+            })
+            .collect();
+        if substituted.iter().all(Option::is_none) {
+            continue;
+        }
+        let TopLevel::Fn { body, .. } = &mut program[i] else {
+            unreachable!()
+        };
+        for instruction in body {
+            substitute_args(instruction, &substituted);
+        }
+    }
+}
+
+/// What's known about an argument from the calls seen so far
+#[derive(Debug, Clone, PartialEq)]
+enum ArgValue {
+    /// No call seen yet
+    Undefined,
+    /// The same constant at every call
+    Const(Instruction),
+    Overdefined,
+}
+impl ArgValue {
+    fn meet(&mut self, arg: &Instruction) {
+        let is_const = matches!(
+            arg,
+            Instruction::UInt(..) | Instruction::Int(..) | Instruction::Float(..)
+        );
+        *self = match self {
+            _ if !is_const => Self::Overdefined,
+            Self::Undefined => Self::Const(arg.clone()),
+            Self::Const(constant) if same_const(constant, arg) => return,
+            _ => Self::Overdefined,
+        };
+    }
+}
+
+/// Whether two constants are the same, which for floats means the same bits, as `0.0 == -0.0`
+fn same_const(a: &Instruction, b: &Instruction) -> bool {
+    match (a, b) {
+        (Instruction::Float(a_dtype, a), Instruction::Float(b_dtype, b)) => {
+            a_dtype == b_dtype && a.to_bits() == b.to_bits()
+        }
+        _ => a == b,
+    }
+}
+
+/// Call `f` with the arguments of every direct call to the function named `name` inside the
+/// operand
+fn visit_calls(operand: &Instruction, name: &str, f: &mut impl FnMut(&[Instruction])) {
+    if let Instruction::Call {
+        callee: Callee::Direct(callee),
+        args,
+        ..
+    } = operand
+    {
+        if callee.as_str() == name {
+            f(args);
+        }
+    }
+    for operand in operand.operands() {
+        visit_calls(operand, name, f);
+    }
+}
+
+/// Replace the uses of the arguments with the constants they're always called with
+fn substitute_args(operand: &mut Instruction, substituted: &[Option<Instruction>]) {
+    if let Instruction::Arg(dtype, index) = operand {
+        if let Some(Some(constant)) = substituted.get(*index as usize) {
+            if constant.dtype() == Some(*dtype) {
+                *operand = constant.clone();
+                return;
+            }
+        }
+    }
+    for operand in operand.operands_mut() {
+        substitute_args(operand, substituted);
+    }
+}
@@ -0,0 +1,285 @@
+//! Loop-invariant code motion
+//!
+//! Every natural loop is given a preheader, a block that is the only way into the header from
+//! outside the loop. Definitions inside the loop whose values are the same on every iteration are
+//! moved to the end of the preheader, so they're computed once. Inner loops are done first, so an
+//! invariant can move out of several loops
+//!
+//! Pure arithmetic may be hoisted out of a branch of the loop that isn't always taken, since
+//! computing it anyway has no effect, but divisions are only hoisted if the divisor is a constant
+//! they can't trap on. A load is hoisted if nothing in the loop may write to its pointer, and
+//! the pointer is at a known offset into an `alloc` slot, or the load is in a block that every
+//! way out of the loop goes through
+
+use std::collections::HashSet;
+
+use crate::{
+    analysis::{cfg::Cfg, dominators::DomTree, escape::EscapeInfo, loops::LoopInfo},
+    ir::{Instruction, TypeDefs},
+    pass::{Function, FunctionPass},
+};
+
+use super::{
+    canonicalize_blocks, has_side_effects, next_vreg_id,
+    sccp::{sign_extend, truncate},
+    LabelGenerator,
+};
+
+/// `licm` as a pass
+pub struct Licm;
+impl FunctionPass for Licm {
+    fn name(&self) -> &'static str {
+        "licm"
+    }
+    fn run_on_function(&mut self, function: Function) {
+        licm(function.body, function.type_defs);
+    }
+}
+
+/// Move the loop-invariant definitions out of every loop
+pub fn licm(body: &mut Vec<Instruction>, type_defs: &TypeDefs) {
+    canonicalize_blocks(body);
+    let info = EscapeInfo::new(body, type_defs);
+    let headers: Vec<String> = {
+        let cfg = Cfg::new(body);
+        let loops = LoopInfo::new(&cfg, &DomTree::new(&cfg));
+        // Loops nested inside another one come after it
+        loops
+            .loops
+            .iter()
+            .rev()
+            .map(|l| cfg.blocks[l.header].label.clone().unwrap())
+            .collect()
+    };
+    let mut labels = LabelGenerator::new(body);
+    for header in headers {
+        let preheader = ensure_preheader(body, &header, &mut labels);
+        hoist(body, &header, &preheader, &info);
+    }
+}
+
+/// Make sure the loop with the header `header` has a preheader, returns its label
+/// The body needs to be canonicalized
+pub(crate) fn ensure_preheader(
+    body: &mut Vec<Instruction>,
+    header: &str,
+    labels: &mut LabelGenerator,
+) -> String {
+    let cfg = Cfg::new(body);
+    let loops = LoopInfo::new(&cfg, &DomTree::new(&cfg));
+    let header_block = cfg.block_of_label(header).unwrap();
+    let l = loops
+        .loops
+        .iter()
+        .find(|l| l.header == header_block)
+        .unwrap();
+    let outside_preds: Vec<usize> = cfg.blocks[header_block]
+        .preds
+        .iter()
+        .copied()
+        .filter(|&pred| !l.contains(pred))
+        .collect();
+    if let [pred] = outside_preds[..] {
+        if cfg.blocks[pred].succs == [header_block] {
+            return cfg.blocks[pred].label.clone().unwrap();
+        }
+    }
+    let outside_labels: HashSet<String> = outside_preds
+        .iter()
+        .map(|&pred| cfg.blocks[pred].label.clone().unwrap())
+        .collect();
+    let preheader = labels.fresh(&format!("{header}.preheader"));
+    for &pred in &outside_preds {
+        let last = cfg.blocks[pred].range.end - 1;
+        for target in body[last].jump_targets_mut() {
+            if target == header {
+                *target = preheader.clone();
+            }
+        }
+    }
+    // Values coming into the `phi`s of the header from outside the loop now come from the
+    // preheader, merged by a `phi` there if there's more than one of them
+    let mut next_id = next_vreg_id(body);
+    let mut preheader_body = vec![Instruction::Label(preheader.clone())];
+    for instruction in &mut body[cfg.blocks[header_block].range.clone()] {
+        let Instruction::DefReg { rhs, .. } = instruction else {
+            continue;
+        };
+        let Instruction::Phi { dtype, incoming } = rhs.as_mut() else {
+            continue;
+        };
+        let (outside, inside): (Vec<_>, Vec<_>) = std::mem::take(incoming)
+            .into_iter()
+            .partition(|(label, _)| outside_labels.contains(label));
+        let value = match <[_; 1]>::try_from(outside) {
+            Ok([(_, value)]) => value,
+            Err(outside) => {
+                let id = next_id;
+                next_id += 1;
+                preheader_body.push(Instruction::DefReg {
+                    id,
+                    rhs: Box::new(Instruction::Phi {
+                        dtype: *dtype,
+                        incoming: outside,
+                    }),
+                });
+                Instruction::Reg(*dtype, id)
+            }
+        };
+        incoming.push((preheader.clone(), value));
+        incoming.extend(inside);
+    }
+    preheader_body.push(Instruction::Jmp(header.to_string()));
+    // Right before the header's label
+    let at = cfg.blocks[header_block].range.start - 1;
+    body.splice(at..at, preheader_body);
+    preheader
+}
+
+/// Move the invariant definitions of the loop with the header `header` to the end of its
+/// preheader
+fn hoist(body: &mut Vec<Instruction>, header: &str, preheader: &str, info: &EscapeInfo) {
+    let cfg = Cfg::new(body);
+    let dom_tree = DomTree::new(&cfg);
+    let loops = LoopInfo::new(&cfg, &dom_tree);
+    let header_block = cfg.block_of_label(header).unwrap();
+    let l = loops
+        .loops
+        .iter()
+        .find(|l| l.header == header_block)
+        .unwrap();
+
+    let mut defined_inside = HashSet::<u64>::new();
+    let mut clobbers = Clobbers::default();
+    for &block in &l.blocks {
+        for instruction in &body[cfg.blocks[block].range.clone()] {
+            if let Some(id) = instruction.as_def_reg_id() {
+                defined_inside.insert(id);
+            }
+            clobbers.add(instruction, info);
+        }
+    }
+    let exiting: Vec<usize> = l
+        .blocks
+        .iter()
+        .copied()
+        .filter(|&block| cfg.blocks[block].succs.iter().any(|&s| !l.contains(s)))
+        .collect();
+    let mut hoisted = Vec::<usize>::new();
+    // Definitions come before their uses in the dominator tree, other than through `phi`s
+    for block in dom_tree.pre_order() {
+        if !l.contains(block) {
+            continue;
+        }
+        let always_done = exiting.iter().all(|&e| dom_tree.dominates(block, e));
+        for i in cfg.blocks[block].range.clone() {
+            let Instruction::DefReg { id, rhs } = &body[i] else {
+                continue;
+            };
+            let invariant = Invariance {
+                defined_inside: &defined_inside,
+                clobbers: &clobbers,
+                info,
+                always_done,
+            };
+            if invariant.check(rhs) {
+                defined_inside.remove(id);
+                hoisted.push(i);
+            }
+        }
+    }
+    if hoisted.is_empty() {
+        return;
+    }
+    let preheader_end = cfg.blocks[cfg.block_of_label(preheader).unwrap()].range.end;
+    let moved: Vec<Instruction> = hoisted.iter().map(|&i| body[i].clone()).collect();
+    let hoisted: HashSet<usize> = hoisted.into_iter().collect();
+    let mut new_body = Vec::with_capacity(body.len());
+    for (i, instruction) in std::mem::take(body).into_iter().enumerate() {
+        // Before the terminator of the preheader
+        if i + 1 == preheader_end {
+            new_body.extend(moved.iter().cloned());
+        }
+        if !hoisted.contains(&i) {
+            new_body.push(instruction);
+        }
+    }
+    *body = new_body;
+}
+
+/// What the instructions in a loop may write to
+#[derive(Default)]
+struct Clobbers {
+    /// Memory other than the slots that never escape, by stores through pointers or calls
+    escaping: bool,
+    /// Slots that never escape and are stored into
+    slots: HashSet<u64>,
+}
+impl Clobbers {
+    fn add(&mut self, instruction: &Instruction, info: &EscapeInfo) {
+        match instruction {
+            Instruction::Store { id, .. } => match info.non_escaping_slot(*id) {
+                Some(slot) => _ = self.slots.insert(slot),
+                None => self.escaping = true,
+            },
+            Instruction::VaStart(_) | Instruction::VaEnd(_) => self.escaping = true,
+            _ => (),
+        }
+        if has_side_effects(instruction) {
+            self.escaping = true;
+        }
+    }
+}
+
+struct Invariance<'a> {
+    /// Vregs defined in the loop that aren't hoisted
+    defined_inside: &'a HashSet<u64>,
+    clobbers: &'a Clobbers,
+    info: &'a EscapeInfo,
+    /// Whether the block is gone through every time the loop is left
+    always_done: bool,
+}
+impl Invariance<'_> {
+    /// Whether the operand has the same value on every iteration, and can be evaluated before
+    /// the loop
+    fn check(&self, operand: &Instruction) -> bool {
+        let outside = |id: &u64| !self.defined_inside.contains(id);
+        let valid = match operand {
+            Instruction::Phi { .. }
+            | Instruction::Call { .. }
+            | Instruction::Alloc(_)
+            | Instruction::VaArg { .. } => false,
+            Instruction::Reg(_, id)
+            | Instruction::FieldPtr { id, .. }
+            | Instruction::ElemPtr { id, .. } => outside(id),
+            Instruction::Load { id, .. } => {
+                let clobbered = match self.info.non_escaping_slot(*id) {
+                    Some(slot) => self.clobbers.slots.contains(&slot),
+                    None => self.clobbers.escaping,
+                };
+                // A pointer at a known offset into a slot can always be loaded from
+                let in_slot = self
+                    .info
+                    .pointer(*id)
+                    .is_some_and(|ptr| ptr.offset.is_some());
+                outside(id) && !clobbered && (in_slot || self.always_done)
+            }
+            Instruction::Div(dtype, _, rhs) | Instruction::Rem(dtype, _, rhs) => {
+                let divisor = match **rhs {
+                    Instruction::UInt(_, u) => Some(truncate(*dtype, u)),
+                    Instruction::Int(_, i) => Some(truncate(*dtype, i as u64)),
+                    _ => None,
+                };
+                divisor.is_some_and(|divisor| {
+                    divisor != 0 && !(dtype.is_signed() && sign_extend(*dtype, divisor) == -1)
+                })
+            }
+            _ => true,
+        };
+        valid
+            && operand
+                .operands()
+                .into_iter()
+                .all(|operand| self.check(operand))
+    }
+}
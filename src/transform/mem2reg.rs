@@ -0,0 +1,178 @@
+//! Promotion of stack slots into vregs
+//!
+//! `construct_ssa` only promotes scalar slots that are loaded and stored directly. `mem2reg`
+//! first uses the escape analysis to find the slots, including structs and arrays, that are only
+//! accessed through pointers derived from them at offsets known at compile time. Each of them is
+//! split into one scalar slot per offset it's accessed at, and the accesses are rewritten to go
+//! to those directly, after which `construct_ssa` promotes them
+//!
+//! A slot is left alone if two of its accesses overlap without being at the same offset and of
+//! the same type, since splitting it would lose the bytes they share
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::{
+    analysis::escape::EscapeInfo,
+    ir::{DataType, Instruction, Type, TypeDefs},
+    pass::{Function, FunctionPass},
+};
+
+use super::{canonicalize_blocks, next_vreg_id, ssa::construct_ssa};
+
+/// `mem2reg` as a pass
+pub struct Mem2Reg;
+impl FunctionPass for Mem2Reg {
+    fn name(&self) -> &'static str {
+        "mem2reg"
+    }
+    fn run_on_function(&mut self, function: Function) {
+        mem2reg(function.body, function.type_defs);
+    }
+}
+
+/// Promote every slot whose address doesn't escape and whose accesses don't overlap into vregs
+/// The body is also canonicalized by `canonicalize_blocks`
+pub fn mem2reg(body: &mut Vec<Instruction>, type_defs: &TypeDefs) {
+    canonicalize_blocks(body);
+    split_slots(body, type_defs);
+    construct_ssa(body);
+}
+
+/// Split the non-escaping slots that are accessed at known offsets into a scalar slot per
+/// offset
+fn split_slots(body: &mut Vec<Instruction>, type_defs: &TypeDefs) {
+    let info = EscapeInfo::new(body, type_defs);
+    // The type each slot is accessed as at each offset, `None` if the slot can't be split
+    let mut accesses: HashMap<u64, Option<BTreeMap<u64, DataType>>> = info
+        .non_escaping_slots()
+        .map(|slot| (slot, Some(BTreeMap::new())))
+        .collect();
+    let mut add_access = |id: u64, dtype: DataType| {
+        let Some(ptr) = info.pointer(id) else {
+            return;
+        };
+        let Some(Some(offsets)) = accesses.get_mut(&ptr.slot) else {
+            return;
+        };
+        let Some(offset) = ptr.offset else {
+            accesses.insert(ptr.slot, None);
+            return;
+        };
+        if *offsets.entry(offset).or_insert(dtype) != dtype {
+            accesses.insert(ptr.slot, None);
+        }
+    };
+    for instruction in body.iter() {
+        visit_accesses(instruction, &mut add_access);
+    }
+
+    let mut sizes = HashMap::<u64, u64>::new();
+    let mut direct_scalars = HashSet::<u64>::new();
+    for instruction in body.iter() {
+        if let Instruction::DefReg { id, rhs } = instruction {
+            if let Instruction::Alloc(ty) = rhs.as_ref() {
+                // TODO: dynamic word size
+                sizes.insert(*id, type_defs.layout(ty, 8).size);
+                if let Type::Scalar(_) = ty {
+                    direct_scalars.insert(*id);
+                }
+            }
+        }
+    }
+    // Scalar slots only ever accessed directly are already promoted by `construct_ssa` as they
+    // are
+    for id in body.iter().filter_map(Instruction::as_def_reg_id) {
+        if let Some(ptr) = info.pointer(id).filter(|ptr| ptr.slot != id) {
+            direct_scalars.remove(&ptr.slot);
+            // Its definition would be removed, along with the side effects of the index
+            if ptr.offset.is_none() {
+                accesses.insert(ptr.slot, None);
+            }
+        }
+    }
+    let mut next_id = next_vreg_id(body);
+    // New slot of each offset of each split slot
+    let mut new_slots = HashMap::<u64, BTreeMap<u64, (u64, DataType)>>::new();
+    for slot in info.non_escaping_slots() {
+        let Some(Some(offsets)) = accesses.remove(&slot) else {
+            continue;
+        };
+        if direct_scalars.contains(&slot) {
+            continue;
+        }
+        // TODO: dynamic word size
+        let mut end = 0;
+        let in_bounds_and_disjoint = offsets.iter().all(|(&offset, dtype)| {
+            let fits = offset >= end;
+            end = offset + dtype.size(8) as u64;
+            fits && end <= sizes[&slot]
+        });
+        if !in_bounds_and_disjoint {
+            continue;
+        }
+        let split = offsets
+            .into_iter()
+            .map(|(offset, dtype)| {
+                let id = next_id;
+                next_id += 1;
+                (offset, (id, dtype))
+            })
+            .collect();
+        new_slots.insert(slot, split);
+    }
+    if new_slots.is_empty() {
+        return;
+    }
+
+    let mut new_body = Vec::with_capacity(body.len());
+    for mut instruction in std::mem::take(body) {
+        if let Instruction::DefReg { id, .. } = &instruction {
+            if let Some(split) = new_slots.get(id) {
+                new_body.extend(split.values().map(|&(id, dtype)| Instruction::DefReg {
+                    id,
+                    rhs: Box::new(Instruction::Alloc(Type::Scalar(dtype))),
+                }));
+                continue;
+            }
+            // The pointers derived from the slot are not used anymore
+            if info
+                .pointer(*id)
+                .is_some_and(|ptr| new_slots.contains_key(&ptr.slot))
+            {
+                continue;
+            }
+        }
+        redirect_accesses(&mut instruction, &info, &new_slots);
+        new_body.push(instruction);
+    }
+    *body = new_body;
+}
+
+/// Call `f` with the pointer and the type of every load and store inside the instruction
+fn visit_accesses(instruction: &Instruction, f: &mut impl FnMut(u64, DataType)) {
+    match instruction {
+        Instruction::Load { id, dtype } => f(*id, *dtype),
+        Instruction::Store { lhs_dtype, id, .. } => f(*id, *lhs_dtype),
+        _ => (),
+    }
+    for operand in instruction.operands() {
+        visit_accesses(operand, f);
+    }
+}
+
+/// Point the loads and stores of the split slots inside the instruction to the new slots
+fn redirect_accesses(
+    instruction: &mut Instruction,
+    info: &EscapeInfo,
+    new_slots: &HashMap<u64, BTreeMap<u64, (u64, DataType)>>,
+) {
+    if let Instruction::Load { id, .. } | Instruction::Store { id, .. } = instruction {
+        let ptr = info.pointer(*id);
+        if let Some(split) = ptr.and_then(|ptr| new_slots.get(&ptr.slot)) {
+            *id = split[&ptr.unwrap().offset.unwrap()].0;
+        }
+    }
+    for operand in instruction.operands_mut() {
+        redirect_accesses(operand, info, new_slots);
+    }
+}
@@ -0,0 +1,211 @@
+//! Passes that rewrite the IR
+
+pub mod dce;
+pub mod gvn;
+pub mod inline;
+pub mod ipo;
+pub mod licm;
+pub mod mem2reg;
+pub mod sccp;
+pub mod ssa;
+pub mod strength;
+pub mod tailcall;
+pub mod unroll;
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    analysis::cfg::Cfg,
+    ir::Instruction,
+    pass::{Function, FunctionPass, Pass},
+};
+
+/// Every pass that can be put into a pipeline by its name
+pub const PASS_NAMES: &[&str] = &[
+    "canonicalize",
+    "ssa",
+    "mem2reg",
+    "out-of-ssa",
+    "sccp",
+    "dce",
+    "dse",
+    "gvn",
+    "inline",
+    "strength-reduce",
+    "licm",
+    "unroll",
+    "global-dce",
+    "const-args",
+    "tail-call",
+];
+
+/// Create the pass named `name`, `None` if there's no such pass
+pub fn pass_by_name(name: &str) -> Option<Box<dyn Pass>> {
+    Some(match name {
+        "canonicalize" => Box::new(CanonicalizeBlocks),
+        "ssa" => Box::new(ssa::ConstructSsa),
+        "mem2reg" => Box::new(mem2reg::Mem2Reg),
+        "out-of-ssa" => Box::new(ssa::DestructSsa),
+        "sccp" => Box::new(sccp::Sccp),
+        "dce" => Box::new(dce::Dce),
+        "dse" => Box::new(dce::Dse),
+        "gvn" => Box::new(gvn::Gvn),
+        "inline" => Box::new(inline::Inline::default()),
+        "strength-reduce" => Box::new(strength::StrengthReduce),
+        "licm" => Box::new(licm::Licm),
+        "unroll" => Box::new(unroll::Unroll),
+        "global-dce" => Box::new(ipo::GlobalDce),
+        "const-args" => Box::new(ipo::ConstArgs),
+        "tail-call" => Box::new(tailcall::TailCall),
+        _ => return None,
+    })
+}
+
+/// An ID larger than all the vregs defined in the body
+pub fn next_vreg_id(body: &[Instruction]) -> u64 {
+    body.iter()
+        .filter_map(Instruction::as_def_reg_id)
+        .max()
+        .map_or(0, |max| max + 1)
+}
+
+/// Whether evaluating the operand does anything other than producing a value
+pub fn has_side_effects(operand: &Instruction) -> bool {
+    match operand {
+        Instruction::Call { .. } | Instruction::VaArg { .. } => true,
+        _ => operand.operands().into_iter().any(has_side_effects),
+    }
+}
+
+/// Push the vregs used anywhere inside an instruction
+pub fn used_vregs(instruction: &Instruction, used: &mut Vec<u64>) {
+    match instruction {
+        Instruction::Reg(_, id)
+        | Instruction::Load { id, .. }
+        | Instruction::FieldPtr { id, .. }
+        | Instruction::ElemPtr { id, .. }
+        | Instruction::Store { id, .. }
+        | Instruction::VaArg { id, .. }
+        | Instruction::VaStart(id)
+        | Instruction::VaEnd(id) => used.push(*id),
+        _ => (),
+    }
+    for operand in instruction.operands() {
+        used_vregs(operand, used);
+    }
+}
+
+/// Replace every use of the vregs in `renamed` inside the instruction, including the pointers of
+/// loads and stores
+pub fn rename_vregs(instruction: &mut Instruction, renamed: &HashMap<u64, u64>) {
+    match instruction {
+        Instruction::Reg(_, id)
+        | Instruction::Load { id, .. }
+        | Instruction::FieldPtr { id, .. }
+        | Instruction::ElemPtr { id, .. }
+        | Instruction::Store { id, .. }
+        | Instruction::VaArg { id, .. }
+        | Instruction::VaStart(id)
+        | Instruction::VaEnd(id) => {
+            if let Some(&new_id) = renamed.get(id) {
+                *id = new_id;
+            }
+        }
+        _ => (),
+    }
+    for operand in instruction.operands_mut() {
+        rename_vregs(operand, renamed);
+    }
+}
+
+/// Hands out labels that don't collide with the ones already in a function body
+#[derive(Debug, Clone)]
+pub struct LabelGenerator {
+    used: HashSet<String>,
+    next: usize,
+}
+impl LabelGenerator {
+    pub fn new(body: &[Instruction]) -> Self {
+        let used = body
+            .iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::Label(label) => Some(label.clone()),
+                _ => None,
+            })
+            .collect();
+        Self { used, next: 0 }
+    }
+    /// A new label in the form of `{hint}.{n}`
+    pub fn fresh(&mut self, hint: &str) -> String {
+        loop {
+            let label = format!("{hint}.{}", self.next);
+            self.next += 1;
+            if self.used.insert(label.clone()) {
+                return label;
+            }
+        }
+    }
+}
+
+/// `canonicalize_blocks` as a pass
+pub struct CanonicalizeBlocks;
+impl FunctionPass for CanonicalizeBlocks {
+    fn name(&self) -> &'static str {
+        "canonicalize"
+    }
+    fn run_on_function(&mut self, function: Function) {
+        canonicalize_blocks(function.body);
+    }
+}
+
+/// Rewrite the body so that every block starts with a label and ends with a terminator (except
+/// for a last block that falls off the end), unreachable blocks are removed, and the entry block
+/// has no predecessors
+pub fn canonicalize_blocks(body: &mut Vec<Instruction>) {
+    let cfg = Cfg::new(body);
+    let reachable = cfg.reachable();
+    let removed_labels: HashSet<&String> = cfg
+        .blocks
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| !reachable[i])
+        .filter_map(|(_, block)| block.label.as_ref())
+        .collect();
+    let mut labels = LabelGenerator::new(body);
+    let block_labels: Vec<String> = cfg
+        .blocks
+        .iter()
+        .map(|block| match &block.label {
+            Some(label) => label.clone(),
+            None => labels.fresh("bb"),
+        })
+        .collect();
+    let mut new_body = Vec::with_capacity(body.len() + 2 * cfg.len());
+    if !cfg.blocks[cfg.entry()].preds.is_empty() {
+        new_body.push(Instruction::Label(labels.fresh("entry")));
+        new_body.push(Instruction::Jmp(block_labels[cfg.entry()].clone()));
+    }
+    for (i, block) in cfg.blocks.iter().enumerate() {
+        if !reachable[i] {
+            continue;
+        }
+        new_body.push(Instruction::Label(block_labels[i].clone()));
+        for instruction in &body[block.range.clone()] {
+            let mut instruction = instruction.clone();
+            if let Instruction::DefReg { rhs, .. } = &mut instruction {
+                if let Instruction::Phi { incoming, .. } = rhs.as_mut() {
+                    incoming.retain(|(label, _)| !removed_labels.contains(label));
+                }
+            }
+            new_body.push(instruction);
+        }
+        // Make the fall through explicit, as the next block may be moved or removed later on
+        let falls_through = body[block.range.clone()]
+            .last()
+            .is_none_or(|last| !last.is_terminator());
+        if falls_through && i + 1 < cfg.len() {
+            new_body.push(Instruction::Jmp(block_labels[i + 1].clone()));
+        }
+    }
+    *body = new_body;
+}
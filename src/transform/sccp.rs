@@ -0,0 +1,448 @@
+//! Sparse conditional constant propagation, from Wegman and Zadeck's "Constant Propagation with
+//! Conditional Branches"
+//!
+//! Every vreg starts out as undefined and is only lowered to a constant or to overdefined, and
+//! blocks are only visited once an executable edge leads to them, so a constant that decides a
+//! branch also keeps the values along the branch not taken out of the `phi`s
+//!
+//! Integers wrap around at the width of their type, and signed types are sign extended where it
+//! matters: division, and comparisons whose operands are signed. Divisions that would trap at
+//! run time, by zero or of the minimum value by -1, are left alone
+
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+};
+
+use crate::{
+    analysis::cfg::Cfg,
+    ir::{DataType, Instruction},
+    pass::{Function, FunctionPass},
+};
+
+/// `sccp` as a pass
+pub struct Sccp;
+impl FunctionPass for Sccp {
+    fn name(&self) -> &'static str {
+        "sccp"
+    }
+    fn run_on_function(&mut self, function: Function) {
+        sccp(function.body);
+    }
+}
+
+/// A constant of a scalar type
+#[derive(Debug, Clone, Copy)]
+enum Value {
+    /// Bits of an integer or pointer, truncated to the width of its type
+    Int(u64),
+    /// Floats of type `f32` are kept rounded to `f32`
+    Float(f64),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Lattice {
+    /// Not known yet, it may still turn out to be a constant
+    Undefined,
+    Const(DataType, Value),
+    /// Not a constant
+    Overdefined,
+}
+impl PartialEq for Lattice {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Undefined, Self::Undefined) | (Self::Overdefined, Self::Overdefined) => true,
+            (Self::Const(dtype0, Value::Int(x)), Self::Const(dtype1, Value::Int(y))) => {
+                dtype0 == dtype1 && x == y
+            }
+            // Floats are compared by their bits, so that NaN is equal to itself
+            (Self::Const(dtype0, Value::Float(x)), Self::Const(dtype1, Value::Float(y))) => {
+                dtype0 == dtype1 && x.to_bits() == y.to_bits()
+            }
+            _ => false,
+        }
+    }
+}
+impl Lattice {
+    fn meet(self, other: Self) -> Self {
+        match (self, other) {
+            (Self::Undefined, x) | (x, Self::Undefined) => x,
+            (x, y) if x == y => x,
+            _ => Self::Overdefined,
+        }
+    }
+}
+
+/// Fold constants in a function body, and turn branches on constants into jumps
+/// The blocks that become unreachable are not removed
+pub fn sccp(body: &mut [Instruction]) {
+    let cfg = Cfg::new(body);
+    let solver = Solver::solve(body, &cfg);
+    let block_of = cfg.block_of_instructions();
+    // Incoming values of `phi`s that no longer come from their predecessor
+    let mut dead_edges = HashSet::<(String, String)>::new();
+    for (i, instruction) in body.iter_mut().enumerate() {
+        match instruction {
+            Instruction::DefReg { id, rhs } => match solver.value_of_reg(*id) {
+                Lattice::Const(dtype, value) if !is_literal(rhs) => **rhs = literal(dtype, value),
+                _ => solver.fold_operands(rhs),
+            },
+            Instruction::Br {
+                cond,
+                if_true,
+                if_false,
+            } if solver.executable[block_of[i]] => match solver.eval(cond) {
+                Lattice::Const(dtype, Value::Int(bits)) if !dtype.is_float() => {
+                    let (taken, not_taken) = if bits != 0 {
+                        (if_true, if_false)
+                    } else {
+                        (if_false, if_true)
+                    };
+                    if taken != not_taken {
+                        if let Some(label) = &cfg.blocks[block_of[i]].label {
+                            dead_edges.insert((label.clone(), not_taken.clone()));
+                        }
+                    }
+                    *instruction = Instruction::Jmp(taken.clone());
+                }
+                _ => solver.fold_operands(instruction),
+            },
+            _ => solver.fold_operands(instruction),
+        }
+    }
+    if dead_edges.is_empty() {
+        return;
+    }
+    let mut current_label = None::<String>;
+    for instruction in body.iter_mut() {
+        match instruction {
+            Instruction::Label(label) => current_label = Some(label.clone()),
+            Instruction::DefReg { rhs, .. } => {
+                if let (Instruction::Phi { incoming, .. }, Some(current_label)) =
+                    (rhs.as_mut(), &current_label)
+                {
+                    incoming.retain(|(pred, _)| {
+                        !dead_edges.contains(&(pred.clone(), current_label.clone()))
+                    });
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+struct Solver {
+    values: HashMap<u64, Lattice>,
+    executable: Vec<bool>,
+}
+impl Solver {
+    /// Find the values of the vregs and the executable blocks
+    /// Values only ever move down the lattice, so iterating until nothing changes terminates
+    fn solve(body: &[Instruction], cfg: &Cfg) -> Self {
+        let mut solver = Self {
+            values: HashMap::new(),
+            executable: vec![false; cfg.len()],
+        };
+        if cfg.is_empty() {
+            return solver;
+        }
+        solver.executable[cfg.entry()] = true;
+        let mut executable_edges = HashSet::<(usize, usize)>::new();
+        let reverse_post_order = cfg.reverse_post_order();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &block in &reverse_post_order {
+                if !solver.executable[block] {
+                    continue;
+                }
+                let mut taken = Vec::<usize>::new();
+                let range = cfg.blocks[block].range.clone();
+                for instruction in &body[range.clone()] {
+                    match instruction {
+                        Instruction::DefReg { id, rhs } => {
+                            let value = match rhs.as_ref() {
+                                Instruction::Phi { incoming, .. } => incoming
+                                    .iter()
+                                    .filter(|(label, _)| {
+                                        cfg.block_of_label(label).is_some_and(|pred| {
+                                            executable_edges.contains(&(pred, block))
+                                        })
+                                    })
+                                    .map(|(_, value)| solver.eval(value))
+                                    .fold(Lattice::Undefined, Lattice::meet),
+                                rhs => solver.eval(rhs),
+                            };
+                            let old = solver.value_of_reg(*id);
+                            let new = old.meet(value);
+                            if new != old {
+                                solver.values.insert(*id, new);
+                                changed = true;
+                            }
+                        }
+                        Instruction::Br {
+                            cond,
+                            if_true,
+                            if_false,
+                        } => {
+                            let targets = match solver.eval(cond) {
+                                Lattice::Undefined => Vec::new(),
+                                Lattice::Const(dtype, Value::Int(bits)) if !dtype.is_float() => {
+                                    vec![if bits != 0 { if_true } else { if_false }]
+                                }
+                                _ => vec![if_true, if_false],
+                            };
+                            taken.extend(
+                                targets
+                                    .into_iter()
+                                    .filter_map(|label| cfg.block_of_label(label)),
+                            );
+                        }
+                        _ => (),
+                    }
+                }
+                // Jumps and fall throughs always go to all of their successors
+                if !matches!(
+                    range.clone().last().map(|i| &body[i]),
+                    Some(Instruction::Br { .. })
+                ) {
+                    taken.extend(&cfg.blocks[block].succs);
+                }
+                for succ in taken {
+                    if executable_edges.insert((block, succ)) {
+                        solver.executable[succ] = true;
+                        changed = true;
+                    }
+                }
+            }
+        }
+        solver
+    }
+    fn value_of_reg(&self, id: u64) -> Lattice {
+        self.values.get(&id).copied().unwrap_or(Lattice::Undefined)
+    }
+    /// The value of an operand
+    fn eval(&self, operand: &Instruction) -> Lattice {
+        match operand {
+            Instruction::UInt(dtype, u) if !dtype.is_float() => {
+                Lattice::Const(*dtype, Value::Int(truncate(*dtype, *u)))
+            }
+            Instruction::Int(dtype, i) if !dtype.is_float() => {
+                Lattice::Const(*dtype, Value::Int(truncate(*dtype, *i as u64)))
+            }
+            Instruction::Float(dtype, x) if dtype.is_float() => {
+                Lattice::Const(*dtype, Value::Float(round(*dtype, *x)))
+            }
+            Instruction::Reg(_, id) => self.value_of_reg(*id),
+            Instruction::Add(dtype, lhs, rhs)
+            | Instruction::Sub(dtype, lhs, rhs)
+            | Instruction::Mul(dtype, lhs, rhs)
+            | Instruction::Div(dtype, lhs, rhs)
+            | Instruction::And(dtype, lhs, rhs)
+            | Instruction::Or(dtype, lhs, rhs)
+            | Instruction::Xor(dtype, lhs, rhs)
+            | Instruction::Rem(dtype, lhs, rhs)
+            | Instruction::MulHi(dtype, lhs, rhs)
+            | Instruction::Shl(dtype, lhs, rhs)
+            | Instruction::Shr(dtype, lhs, rhs)
+            | Instruction::Eq(dtype, lhs, rhs)
+            | Instruction::Ne(dtype, lhs, rhs)
+            | Instruction::Lt(dtype, lhs, rhs)
+            | Instruction::Le(dtype, lhs, rhs)
+            | Instruction::Gt(dtype, lhs, rhs)
+            | Instruction::Ge(dtype, lhs, rhs) => match (self.eval(lhs), self.eval(rhs)) {
+                (Lattice::Overdefined, _) | (_, Lattice::Overdefined) => Lattice::Overdefined,
+                (Lattice::Const(lhs_dtype, lhs), Lattice::Const(_, rhs)) => {
+                    match fold(operand, *dtype, lhs_dtype, lhs, rhs) {
+                        Some(value) => Lattice::Const(*dtype, value),
+                        None => Lattice::Overdefined,
+                    }
+                }
+                _ => Lattice::Undefined,
+            },
+            _ => Lattice::Overdefined,
+        }
+    }
+    /// Replace the constant operands inside an instruction with literals
+    fn fold_operands(&self, instruction: &mut Instruction) {
+        for operand in instruction.operands_mut() {
+            match self.eval(operand) {
+                _ if is_literal(operand) => (),
+                Lattice::Const(dtype, value) => *operand = literal(dtype, value),
+                _ => self.fold_operands(operand),
+            }
+        }
+    }
+}
+
+/// Evaluate an integer operand made of constants and vregs with known values, `None` if it's not
+/// a constant
+pub(crate) fn eval_int(
+    operand: &Instruction,
+    value_of_reg: &dyn Fn(u64) -> Option<u64>,
+) -> Option<u64> {
+    match operand {
+        Instruction::UInt(dtype, u) if !dtype.is_float() => Some(truncate(*dtype, *u)),
+        Instruction::Int(dtype, i) if !dtype.is_float() => Some(truncate(*dtype, *i as u64)),
+        Instruction::Reg(dtype, id) if !dtype.is_float() => value_of_reg(*id),
+        Instruction::Add(dtype, lhs, rhs)
+        | Instruction::Sub(dtype, lhs, rhs)
+        | Instruction::Mul(dtype, lhs, rhs)
+        | Instruction::Div(dtype, lhs, rhs)
+        | Instruction::And(dtype, lhs, rhs)
+        | Instruction::Or(dtype, lhs, rhs)
+        | Instruction::Xor(dtype, lhs, rhs)
+        | Instruction::Rem(dtype, lhs, rhs)
+        | Instruction::MulHi(dtype, lhs, rhs)
+        | Instruction::Shl(dtype, lhs, rhs)
+        | Instruction::Shr(dtype, lhs, rhs)
+        | Instruction::Eq(dtype, lhs, rhs)
+        | Instruction::Ne(dtype, lhs, rhs)
+        | Instruction::Lt(dtype, lhs, rhs)
+        | Instruction::Le(dtype, lhs, rhs)
+        | Instruction::Gt(dtype, lhs, rhs)
+        | Instruction::Ge(dtype, lhs, rhs) => {
+            let lhs_dtype = lhs.dtype().filter(|dtype| !dtype.is_float())?;
+            let lhs = Value::Int(eval_int(lhs, value_of_reg)?);
+            let rhs = Value::Int(eval_int(rhs, value_of_reg)?);
+            match fold(operand, *dtype, lhs_dtype, lhs, rhs)? {
+                Value::Int(bits) => Some(bits),
+                Value::Float(_) => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Evaluate a binary operation, `None` if it can't be done at compile time
+fn fold(
+    operation: &Instruction,
+    dtype: DataType,
+    operand_dtype: DataType,
+    lhs: Value,
+    rhs: Value,
+) -> Option<Value> {
+    let ordering = match (lhs, rhs) {
+        (Value::Int(x), Value::Int(y)) if operand_dtype.is_signed() => {
+            sign_extend(operand_dtype, x).cmp(&sign_extend(operand_dtype, y))
+        }
+        (Value::Int(x), Value::Int(y)) => x.cmp(&y),
+        (Value::Float(x), Value::Float(y)) => match x.partial_cmp(&y) {
+            Some(ordering) => ordering,
+            // Only `!=` is true when comparing with NaN
+            None => return Some(Value::Int(matches!(operation, Instruction::Ne(..)) as u64)),
+        },
+        _ => return None,
+    };
+    let comparison = match operation {
+        Instruction::Eq(..) => Some(ordering == Ordering::Equal),
+        Instruction::Ne(..) => Some(ordering != Ordering::Equal),
+        Instruction::Lt(..) => Some(ordering == Ordering::Less),
+        Instruction::Le(..) => Some(ordering != Ordering::Greater),
+        Instruction::Gt(..) => Some(ordering == Ordering::Greater),
+        Instruction::Ge(..) => Some(ordering != Ordering::Less),
+        _ => None,
+    };
+    if let Some(comparison) = comparison {
+        return Some(Value::Int(comparison as u64));
+    }
+    match (lhs, rhs) {
+        (Value::Int(x), Value::Int(y)) => {
+            let result = match operation {
+                Instruction::Add(..) => x.wrapping_add(y),
+                Instruction::Sub(..) => x.wrapping_sub(y),
+                Instruction::Mul(..) => x.wrapping_mul(y),
+                Instruction::Div(..) if dtype.is_signed() => {
+                    let (x, y) = (sign_extend(dtype, x), sign_extend(dtype, y));
+                    if y == 0 || (y == -1 && x == sign_extend(dtype, min_signed(dtype))) {
+                        return None;
+                    }
+                    (x / y) as u64
+                }
+                Instruction::Div(..) => x.checked_div(y)?,
+                Instruction::Rem(..) if dtype.is_signed() => {
+                    let (x, y) = (sign_extend(dtype, x), sign_extend(dtype, y));
+                    if y == 0 || (y == -1 && x == sign_extend(dtype, min_signed(dtype))) {
+                        return None;
+                    }
+                    (x % y) as u64
+                }
+                Instruction::Rem(..) => x.checked_rem(y)?,
+                Instruction::MulHi(..) if dtype.is_signed() => {
+                    let product = sign_extend(dtype, x) as i128 * sign_extend(dtype, y) as i128;
+                    (product >> bit_width(dtype)) as u64
+                }
+                Instruction::MulHi(..) => ((x as u128 * y as u128) >> bit_width(dtype)) as u64,
+                Instruction::Shl(..) | Instruction::Shr(..) if y >= bit_width(dtype) as u64 => {
+                    return None
+                }
+                Instruction::Shl(..) => x << y,
+                Instruction::Shr(..) if dtype.is_signed() => (sign_extend(dtype, x) >> y) as u64,
+                Instruction::Shr(..) => x >> y,
+                Instruction::And(..) => x & y,
+                Instruction::Or(..) => x | y,
+                Instruction::Xor(..) => x ^ y,
+                _ => return None,
+            };
+            Some(Value::Int(truncate(dtype, result)))
+        }
+        (Value::Float(x), Value::Float(y)) => {
+            let result = match operation {
+                Instruction::Add(..) => x + y,
+                Instruction::Sub(..) => x - y,
+                Instruction::Mul(..) => x * y,
+                Instruction::Div(..) => x / y,
+                _ => return None,
+            };
+            Some(Value::Float(round(dtype, result)))
+        }
+        _ => None,
+    }
+}
+
+/// Number of bits in an integer type
+pub(crate) fn bit_width(dtype: DataType) -> u32 {
+    // TODO: dynamic word size
+    dtype.size(8) as u32 * 8
+}
+
+/// Keep only the bits that fit in the type
+pub(crate) fn truncate(dtype: DataType, bits: u64) -> u64 {
+    match bit_width(dtype) {
+        64 => bits,
+        width => bits & ((1 << width) - 1),
+    }
+}
+
+pub(crate) fn sign_extend(dtype: DataType, bits: u64) -> i64 {
+    let shift = 64 - bit_width(dtype);
+    ((bits << shift) as i64) >> shift
+}
+
+/// Bits of the smallest value of a signed type
+fn min_signed(dtype: DataType) -> u64 {
+    1 << (bit_width(dtype) - 1)
+}
+
+fn round(dtype: DataType, x: f64) -> f64 {
+    match dtype {
+        DataType::F32 => x as f32 as f64,
+        _ => x,
+    }
+}
+
+fn is_literal(operand: &Instruction) -> bool {
+    matches!(
+        operand,
+        Instruction::UInt(..) | Instruction::Int(..) | Instruction::Float(..)
+    )
+}
+
+/// The literal operand of a constant
+fn literal(dtype: DataType, value: Value) -> Instruction {
+    match value {
+        Value::Int(bits) if dtype.is_signed() => Instruction::Int(dtype, sign_extend(dtype, bits)),
+        Value::Int(bits) => Instruction::UInt(dtype, bits),
+        Value::Float(x) => Instruction::Float(dtype, x),
+    }
+}
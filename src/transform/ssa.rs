@@ -0,0 +1,376 @@
+//! Conversion into and out of SSA form
+//!
+//! `construct_ssa` promotes `alloc` slots of scalars, whose addresses are only ever used to
+//! load and store them, into vregs, inserting `phi`s where the value may come from more than one
+//! store. This is the classic construction by Cytron et al., using dominance frontiers
+//!
+//! `destruct_ssa` lowers `phi`s back into copies, which is needed before code generation
+
+use std::collections::HashMap;
+
+use crate::{
+    analysis::{cfg::Cfg, dominators::DomTree},
+    ir::{DataType, Instruction, Type},
+    pass::{Function, FunctionPass},
+};
+
+use super::{canonicalize_blocks, next_vreg_id};
+
+/// `construct_ssa` as a pass
+pub struct ConstructSsa;
+impl FunctionPass for ConstructSsa {
+    fn name(&self) -> &'static str {
+        "ssa"
+    }
+    fn run_on_function(&mut self, function: Function) {
+        construct_ssa(function.body);
+    }
+}
+
+/// `destruct_ssa` as a pass
+pub struct DestructSsa;
+impl FunctionPass for DestructSsa {
+    fn name(&self) -> &'static str {
+        "out-of-ssa"
+    }
+    fn run_on_function(&mut self, function: Function) {
+        destruct_ssa(function.body);
+    }
+}
+
+/// A promotable `alloc` slot
+#[derive(Debug, Clone, Copy)]
+struct Var {
+    slot: u64,
+    dtype: DataType,
+}
+
+/// Promote the promotable `alloc` slots in a function body into SSA values
+/// The body is also canonicalized by `canonicalize_blocks`
+pub fn construct_ssa(body: &mut Vec<Instruction>) {
+    canonicalize_blocks(body);
+    let vars = promotable_slots(body);
+    if vars.is_empty() {
+        return;
+    }
+    let var_of_slot: HashMap<u64, usize> = vars
+        .iter()
+        .enumerate()
+        .map(|(i, var)| (var.slot, i))
+        .collect();
+    let cfg = Cfg::new(body);
+    let dom_tree = DomTree::new(&cfg);
+    let mut next_id = next_vreg_id(body);
+
+    // Place phis at the iterated dominance frontiers of the blocks that store into each slot
+    let frontiers = dom_tree.frontiers();
+    let mut def_blocks = vec![Vec::<usize>::new(); vars.len()];
+    for (i, block) in cfg.blocks.iter().enumerate() {
+        for instruction in &body[block.range.clone()] {
+            if let Instruction::Store { id, .. } = instruction {
+                if let Some(&var) = var_of_slot.get(id) {
+                    if !def_blocks[var].contains(&i) {
+                        def_blocks[var].push(i);
+                    }
+                }
+            }
+        }
+    }
+    // Phis in each block, as the variable and the ID of the vreg the phi defines
+    let mut phis = vec![Vec::<(usize, u64)>::new(); cfg.len()];
+    for (var, blocks) in def_blocks.into_iter().enumerate() {
+        let mut worklist = blocks.clone();
+        let mut has_phi = vec![false; cfg.len()];
+        while let Some(block) = worklist.pop() {
+            for &frontier in &frontiers[block] {
+                if has_phi[frontier] {
+                    continue;
+                }
+                has_phi[frontier] = true;
+                phis[frontier].push((var, next_id));
+                next_id += 1;
+                if !blocks.contains(&frontier) {
+                    worklist.push(frontier);
+                }
+            }
+        }
+    }
+
+    let mut renamer = Renamer {
+        body,
+        cfg: &cfg,
+        dom_tree: &dom_tree,
+        vars: &vars,
+        var_of_slot: &var_of_slot,
+        phis: &phis,
+        incoming: phis
+            .iter()
+            .map(|block_phis| vec![Vec::new(); block_phis.len()])
+            .collect(),
+        stacks: vec![Vec::new(); vars.len()],
+        new_blocks: vec![Vec::new(); cfg.len()],
+        next_id,
+    };
+    renamer.rename(dom_tree.root());
+    let Renamer {
+        incoming,
+        new_blocks,
+        ..
+    } = renamer;
+
+    let mut new_body = Vec::<Instruction>::with_capacity(body.len());
+    for (((block, instructions), block_phis), block_incoming) in
+        cfg.blocks.iter().zip(new_blocks).zip(&phis).zip(incoming)
+    {
+        new_body.push(Instruction::Label(block.label.clone().unwrap()));
+        for (&(var, id), incoming) in block_phis.iter().zip(block_incoming) {
+            new_body.push(Instruction::DefReg {
+                id,
+                rhs: Box::new(Instruction::Phi {
+                    dtype: vars[var].dtype,
+                    incoming,
+                }),
+            });
+        }
+        new_body.extend(instructions);
+    }
+    *body = new_body;
+}
+
+/// Scalar `alloc` slots that are only used as the target of loads and stores of the same type
+fn promotable_slots(body: &[Instruction]) -> Vec<Var> {
+    let mut vars = Vec::<Var>::new();
+    for instruction in body {
+        if let Instruction::DefReg { id, rhs } = instruction {
+            if let Instruction::Alloc(Type::Scalar(dtype)) = rhs.as_ref() {
+                vars.push(Var {
+                    slot: *id,
+                    dtype: *dtype,
+                });
+            }
+        }
+    }
+    let mut promotable: HashMap<u64, DataType> =
+        vars.iter().map(|var| (var.slot, var.dtype)).collect();
+    // A slot defined more than once is not valid IR, leave it alone
+    for instruction in body {
+        if let Some(id) = instruction.as_def_reg_id() {
+            if vars.iter().filter(|var| var.slot == id).count() > 1
+                || (promotable.contains_key(&id) && !is_alloc_def(instruction))
+            {
+                promotable.remove(&id);
+            }
+        }
+    }
+    for instruction in body {
+        match instruction {
+            Instruction::Store { lhs_dtype, id, rhs } => {
+                if promotable.get(id).is_some_and(|dtype| dtype != lhs_dtype) {
+                    promotable.remove(id);
+                }
+                disqualify_escaping(rhs, false, &mut promotable);
+            }
+            Instruction::VaStart(id) | Instruction::VaEnd(id) => {
+                promotable.remove(id);
+            }
+            _ => {
+                for operand in instruction.operands() {
+                    disqualify_escaping(operand, false, &mut promotable);
+                }
+            }
+        }
+    }
+    vars.retain(|var| promotable.contains_key(&var.slot));
+    vars
+}
+
+fn is_alloc_def(instruction: &Instruction) -> bool {
+    matches!(instruction, Instruction::DefReg { rhs, .. } if matches!(rhs.as_ref(), Instruction::Alloc(_)))
+}
+
+/// Remove the slots that the operand uses in any way other than a load of the same type
+/// Loads in the incoming values of phis also don't count, since they happen in another block
+fn disqualify_escaping(
+    operand: &Instruction,
+    in_phi: bool,
+    promotable: &mut HashMap<u64, DataType>,
+) {
+    match operand {
+        Instruction::Load { id, dtype }
+            if in_phi
+                || promotable
+                    .get(id)
+                    .is_some_and(|slot_dtype| slot_dtype != dtype) =>
+        {
+            promotable.remove(id);
+        }
+        Instruction::Reg(_, id)
+        | Instruction::FieldPtr { id, .. }
+        | Instruction::ElemPtr { id, .. }
+        | Instruction::VaArg { id, .. } => {
+            promotable.remove(id);
+        }
+        _ => (),
+    }
+    let in_phi = in_phi || matches!(operand, Instruction::Phi { .. });
+    for operand in operand.operands() {
+        disqualify_escaping(operand, in_phi, promotable);
+    }
+}
+
+/// The value of a slot that's loaded before anything is stored into it
+fn undefined_value(dtype: DataType) -> Instruction {
+    match dtype {
+        DataType::F64 | DataType::F32 => Instruction::Float(dtype, 0.0),
+        _ => Instruction::UInt(dtype, 0),
+    }
+}
+
+/// Replaces loads and stores of the promoted slots with the values, walking down the dominator
+/// tree so that the latest stored value of each slot is on the top of its stack
+struct Renamer<'a> {
+    body: &'a [Instruction],
+    cfg: &'a Cfg,
+    dom_tree: &'a DomTree,
+    vars: &'a [Var],
+    var_of_slot: &'a HashMap<u64, usize>,
+    phis: &'a [Vec<(usize, u64)>],
+    /// Incoming values of the phis in `phis`
+    incoming: Vec<Vec<Vec<(String, Instruction)>>>,
+    /// Current value of each variable
+    stacks: Vec<Vec<Instruction>>,
+    /// The renamed instructions of each block, without the label and the new phis
+    new_blocks: Vec<Vec<Instruction>>,
+    next_id: u64,
+}
+impl Renamer<'_> {
+    fn rename(&mut self, block: usize) {
+        let stack_lens: Vec<usize> = self.stacks.iter().map(Vec::len).collect();
+        for &(var, id) in &self.phis[block] {
+            self.stacks[var].push(Instruction::Reg(self.vars[var].dtype, id));
+        }
+        let mut instructions = Vec::new();
+        for instruction in &self.body[self.cfg.blocks[block].range.clone()] {
+            let mut instruction = instruction.clone();
+            match &mut instruction {
+                Instruction::DefReg { id, .. } if self.var_of_slot.contains_key(id) => continue,
+                Instruction::Store { id, rhs, .. } if self.var_of_slot.contains_key(id) => {
+                    let var = self.var_of_slot[id];
+                    self.replace_loads(rhs);
+                    let value_id = self.next_id;
+                    self.next_id += 1;
+                    instructions.push(Instruction::DefReg {
+                        id: value_id,
+                        rhs: rhs.clone(),
+                    });
+                    self.stacks[var].push(Instruction::Reg(self.vars[var].dtype, value_id));
+                }
+                _ => {
+                    for operand in instruction.operands_mut() {
+                        self.replace_loads(operand);
+                    }
+                    instructions.push(instruction);
+                }
+            }
+        }
+        self.new_blocks[block] = instructions;
+
+        let label = self.cfg.blocks[block].label.clone().unwrap();
+        for &succ in &self.cfg.blocks[block].succs {
+            for (i, &(var, _)) in self.phis[succ].iter().enumerate() {
+                let value = self.current_value(var);
+                self.incoming[succ][i].push((label.clone(), value));
+            }
+        }
+        for &child in self.dom_tree.children(block) {
+            self.rename(child);
+        }
+        for (stack, len) in self.stacks.iter_mut().zip(stack_lens) {
+            stack.truncate(len);
+        }
+    }
+    fn current_value(&self, var: usize) -> Instruction {
+        self.stacks[var]
+            .last()
+            .cloned()
+            .unwrap_or_else(|| undefined_value(self.vars[var].dtype))
+    }
+    fn replace_loads(&self, operand: &mut Instruction) {
+        if let Instruction::Load { id, .. } = operand {
+            if let Some(&var) = self.var_of_slot.get(id) {
+                *operand = self.current_value(var);
+                return;
+            }
+        }
+        for operand in operand.operands_mut() {
+            self.replace_loads(operand);
+        }
+    }
+}
+
+/// Replace every `phi` with a load from a new stack slot, which each predecessor stores the
+/// incoming value into right before leaving for the block of the `phi`
+/// Going through memory rather than a vreg keeps every vreg defined only once, which the
+/// register allocator relies on, and sidesteps the lost copy and swap problems of inserting
+/// copies on critical edges
+pub fn destruct_ssa(body: &mut Vec<Instruction>) {
+    if !body.iter().any(Instruction::is_phi_def) {
+        return;
+    }
+    let cfg = Cfg::new(body);
+    let is_terminator: Vec<bool> = body.iter().map(Instruction::is_terminator).collect();
+    let mut next_id = next_vreg_id(body);
+    let mut slots = Vec::<Instruction>::new();
+    // Copies to insert before each instruction, or at the end if the index is the body length
+    let mut copies = HashMap::<usize, Vec<Instruction>>::new();
+    for instruction in body.iter_mut() {
+        let Instruction::DefReg { rhs, .. } = instruction else {
+            continue;
+        };
+        let Instruction::Phi { dtype, incoming } = rhs.as_mut() else {
+            continue;
+        };
+        let dtype = *dtype;
+        let slot = next_id;
+        next_id += 1;
+        slots.push(Instruction::DefReg {
+            id: slot,
+            rhs: Box::new(Instruction::Alloc(Type::Scalar(dtype))),
+        });
+        for (label, value) in incoming.drain(..) {
+            let pred = cfg
+                .block_of_label(&label)
+                .unwrap_or_else(|| panic!("Incoming label `:{label}` of phi is not defined"));
+            let range = cfg.blocks[pred].range.clone();
+            let at = match range.clone().last() {
+                Some(last) if is_terminator[last] => last,
+                _ => range.end,
+            };
+            copies.entry(at).or_default().push(Instruction::Store {
+                lhs_dtype: dtype,
+                id: slot,
+                rhs: Box::new(value),
+            });
+        }
+        **rhs = Instruction::Load { id: slot, dtype };
+    }
+
+    // The slots are allocated at the start of the entry block, after its label if it has one
+    let slots_at = cfg.blocks[cfg.entry()].label.is_some() as usize;
+    let len = body.len();
+    let mut new_body = Vec::with_capacity(len + slots.len() + copies.len());
+    for (i, instruction) in body.drain(..).enumerate() {
+        if i == slots_at {
+            new_body.append(&mut slots);
+        }
+        if let Some(mut copies) = copies.remove(&i) {
+            new_body.append(&mut copies);
+        }
+        new_body.push(instruction);
+    }
+    // Copies at the end of the last block, if it falls off the end of the body
+    if let Some(mut copies) = copies.remove(&len) {
+        new_body.append(&mut copies);
+    }
+    *body = new_body;
+}
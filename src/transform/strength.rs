@@ -0,0 +1,332 @@
+//! Strength reduction and algebraic simplification
+//!
+//! Integer operations with a constant operand are replaced with cheaper ones: multiplications by
+//! powers of two become shifts, and divisions and remainders by constants become shifts, masks,
+//! or a multiply-high by a magic number followed by shifts, as in Granlund and Montgomery's
+//! "Division by Invariant Integers using Multiplication". Identities such as `x + 0`, `x * 1`,
+//! `x ^ x` and `x & 0` are removed
+//!
+//! An operand that a replacement needs more than once is moved into a new vreg before the
+//! instruction, unless it's already a vreg, an argument or a constant
+
+use crate::{
+    ir::{DataType, Instruction},
+    pass::{Function, FunctionPass},
+};
+
+use super::{
+    has_side_effects, next_vreg_id,
+    sccp::{bit_width, sign_extend, truncate},
+};
+
+/// `strength_reduce` as a pass
+pub struct StrengthReduce;
+impl FunctionPass for StrengthReduce {
+    fn name(&self) -> &'static str {
+        "strength-reduce"
+    }
+    fn run_on_function(&mut self, function: Function) {
+        strength_reduce(function.body);
+    }
+}
+
+/// Replace integer operations with constant operands by cheaper ones
+pub fn strength_reduce(body: &mut Vec<Instruction>) {
+    let mut next_id = next_vreg_id(body);
+    let mut new_body = Vec::with_capacity(body.len());
+    for mut instruction in std::mem::take(body) {
+        // The incoming values of `phi`s are evaluated at the end of the predecessors, so nothing
+        // can be inserted for them here
+        if !instruction.is_phi_def() {
+            let mut reducer = Reducer {
+                next_id: &mut next_id,
+                prelude: Vec::new(),
+                has_side_effects: has_side_effects(&instruction),
+            };
+            for operand in instruction.operands_mut() {
+                reducer.reduce(operand);
+            }
+            new_body.extend(reducer.prelude);
+        }
+        new_body.push(instruction);
+    }
+    *body = new_body;
+}
+
+struct Reducer<'a> {
+    next_id: &'a mut u64,
+    /// Definitions of the operands used more than once, to insert before the instruction
+    prelude: Vec<Instruction>,
+    /// Whether the instruction may write to memory while it's evaluated, in which case loads
+    /// can't be moved before it
+    has_side_effects: bool,
+}
+impl Reducer<'_> {
+    fn reduce(&mut self, operand: &mut Instruction) {
+        for operand in operand.operands_mut() {
+            self.reduce(operand);
+        }
+        if let Some(reduced) = self.simplify(operand) {
+            *operand = reduced;
+        }
+    }
+    fn simplify(&mut self, operand: &Instruction) -> Option<Instruction> {
+        use Instruction::*;
+        let dtype = operand.dtype()?;
+        if dtype.is_float() || dtype == DataType::Ptr {
+            return None;
+        }
+        let (lhs, rhs) = match operand {
+            Add(_, lhs, rhs)
+            | Sub(_, lhs, rhs)
+            | Mul(_, lhs, rhs)
+            | Div(_, lhs, rhs)
+            | Rem(_, lhs, rhs)
+            | MulHi(_, lhs, rhs)
+            | And(_, lhs, rhs)
+            | Or(_, lhs, rhs)
+            | Xor(_, lhs, rhs)
+            | Shl(_, lhs, rhs)
+            | Shr(_, lhs, rhs) => (lhs.as_ref(), rhs.as_ref()),
+            _ => return None,
+        };
+        let (lc, rc) = (constant(dtype, lhs), constant(dtype, rhs));
+        // Left for `sccp` to fold
+        if lc.is_some() && rc.is_some() {
+            return None;
+        }
+        let all_ones = Some(truncate(dtype, u64::MAX));
+        let droppable = |operand: &Instruction| !has_side_effects(operand);
+        let zero_by = |c: Option<u64>, other: &Instruction| c == Some(0) && droppable(other);
+        let zero = int(dtype, 0);
+        Some(match operand {
+            Add(..) | Sub(..) | Or(..) | Xor(..) | Shl(..) | Shr(..) if rc == Some(0) => {
+                lhs.clone()
+            }
+            Add(..) | Or(..) | Xor(..) if lc == Some(0) => rhs.clone(),
+            Sub(..) | Xor(..) if lhs == rhs && droppable(lhs) => zero,
+            And(..) | Or(..) if lhs == rhs && droppable(lhs) => lhs.clone(),
+            Mul(..) | MulHi(..) | And(..) if zero_by(rc, lhs) || zero_by(lc, rhs) => zero,
+            Mul(..) if rc == Some(1) => lhs.clone(),
+            Mul(..) if lc == Some(1) => rhs.clone(),
+            Mul(..) => match (lc, rc) {
+                (_, Some(c)) if c.is_power_of_two() => {
+                    shift(Shl, dtype, lhs.clone(), c.trailing_zeros())
+                }
+                (Some(c), _) if c.is_power_of_two() => {
+                    shift(Shl, dtype, rhs.clone(), c.trailing_zeros())
+                }
+                _ => return None,
+            },
+            And(..) if rc == all_ones => lhs.clone(),
+            And(..) if lc == all_ones => rhs.clone(),
+            Div(..) => self.divide(dtype, lhs, rc?)?,
+            Rem(..) => self.remainder(dtype, lhs, rc?)?,
+            _ => return None,
+        })
+    }
+    /// `x / c`, `None` if it can't be done cheaper
+    fn divide(&mut self, dtype: DataType, x: &Instruction, c: u64) -> Option<Instruction> {
+        use Instruction::*;
+        let width = bit_width(dtype);
+        if c == 0 {
+            return None;
+        }
+        if !dtype.is_signed() {
+            if c == 1 {
+                return Some(x.clone());
+            }
+            if c.is_power_of_two() {
+                return Some(shift(Shr, dtype, x.clone(), c.trailing_zeros()));
+            }
+            // The quotient can only be 0 or 1
+            if c > truncate(dtype, u64::MAX) >> 1 {
+                return Some(binary(Ge, dtype, x.clone(), int(dtype, c)));
+            }
+            let (magic, total_shift) = unsigned_magic(c, width);
+            if magic >> width == 0 {
+                let q = binary(MulHi, dtype, x.clone(), int(dtype, magic as u64));
+                return Some(shift(Shr, dtype, q, total_shift - width));
+            }
+            // The magic number has `width + 1` bits, so its top bit is added back separately
+            // without overflowing: `q = (((x - t) >> 1) + t) >> (shift - 1)`
+            let x = self.reusable(x)?;
+            let magic = truncate(dtype, magic as u64);
+            let t = self.define(binary(MulHi, dtype, x.clone(), int(dtype, magic)));
+            let q = binary(Sub, dtype, x, t.clone());
+            let q = binary(Add, dtype, shift(Shr, dtype, q, 1), t);
+            return Some(shift(Shr, dtype, q, total_shift - width - 1));
+        }
+        let c = sign_extend(dtype, c);
+        match c {
+            1 => return Some(x.clone()),
+            -1 => return Some(binary(Sub, dtype, int(dtype, 0), x.clone())),
+            // The quotient is 1 for the smallest value and 0 otherwise
+            _ if c == sign_extend(dtype, 1 << (width - 1)) => {
+                return Some(binary(Eq, dtype, x.clone(), int(dtype, c as u64)));
+            }
+            _ => (),
+        }
+        let d = c.unsigned_abs();
+        let x = self.reusable(x)?;
+        // All ones if `x` is negative, zero otherwise
+        let sign = shift(Shr, dtype, x.clone(), width - 1);
+        let q = if d.is_power_of_two() {
+            // Shifting rounds down, so negative dividends are biased by `d - 1` to round towards
+            // zero instead
+            let k = d.trailing_zeros();
+            let bias = binary(And, dtype, sign, int(dtype, d - 1));
+            shift(Shr, dtype, binary(Add, dtype, x, bias), k)
+        } else {
+            let (magic, s) = signed_magic(d, width);
+            let mut q = binary(MulHi, dtype, x.clone(), int(dtype, magic));
+            if sign_extend(dtype, magic) < 0 {
+                q = binary(Add, dtype, q, x);
+            }
+            // Rounds down as well, which is one less than the quotient for negative dividends
+            binary(Sub, dtype, shift(Shr, dtype, q, s), sign)
+        };
+        Some(match c < 0 {
+            true => binary(Sub, dtype, int(dtype, 0), q),
+            false => q,
+        })
+    }
+    /// `x % c`, `None` if it can't be done cheaper
+    fn remainder(&mut self, dtype: DataType, x: &Instruction, c: u64) -> Option<Instruction> {
+        use Instruction::*;
+        if c == 0 {
+            return None;
+        }
+        let signed = sign_extend(dtype, c);
+        if c == 1 || dtype.is_signed() && signed == -1 {
+            return (!has_side_effects(x)).then(|| int(dtype, 0));
+        }
+        if !dtype.is_signed() && c.is_power_of_two() {
+            return Some(binary(And, dtype, x.clone(), int(dtype, c - 1)));
+        }
+        // The remainder has the sign of the dividend, so the sign of the divisor doesn't matter,
+        // except for the smallest value which has no positive counterpart
+        let smallest = i64::MIN >> (64 - bit_width(dtype));
+        let c = if dtype.is_signed() && signed < 0 && signed != smallest {
+            truncate(dtype, signed.unsigned_abs())
+        } else {
+            c
+        };
+        let x = self.reusable(x)?;
+        let q = self.divide(dtype, &x, c)?;
+        let product = match c.is_power_of_two() {
+            true => shift(Shl, dtype, q, c.trailing_zeros()),
+            false => binary(Mul, dtype, q, int(dtype, c)),
+        };
+        Some(binary(Sub, dtype, x, product))
+    }
+    /// The operand in a form that can be evaluated more than once, `None` if it can't be
+    fn reusable(&mut self, operand: &Instruction) -> Option<Instruction> {
+        match operand {
+            Instruction::Reg(..)
+            | Instruction::Arg(..)
+            | Instruction::UInt(..)
+            | Instruction::Int(..) => Some(operand.clone()),
+            _ if has_side_effects(operand) => None,
+            _ if self.has_side_effects && reads_memory(operand) => None,
+            _ => Some(self.define(operand.clone())),
+        }
+    }
+    /// Define a new vreg holding `value` before the instruction
+    fn define(&mut self, value: Instruction) -> Instruction {
+        let dtype = value.dtype().expect("Defining a vreg with no value");
+        let id = *self.next_id;
+        *self.next_id += 1;
+        self.prelude.push(Instruction::DefReg {
+            id,
+            rhs: Box::new(value),
+        });
+        Instruction::Reg(dtype, id)
+    }
+}
+
+fn reads_memory(operand: &Instruction) -> bool {
+    matches!(operand, Instruction::Load { .. }) || operand.operands().into_iter().any(reads_memory)
+}
+
+/// Bits of an integer constant, truncated to the type
+fn constant(dtype: DataType, operand: &Instruction) -> Option<u64> {
+    match *operand {
+        Instruction::UInt(_, u) => Some(truncate(dtype, u)),
+        Instruction::Int(_, i) => Some(truncate(dtype, i as u64)),
+        _ => None,
+    }
+}
+
+/// An integer constant from its bits
+fn int(dtype: DataType, bits: u64) -> Instruction {
+    match dtype.is_signed() {
+        true => Instruction::Int(dtype, sign_extend(dtype, bits)),
+        false => Instruction::UInt(dtype, truncate(dtype, bits)),
+    }
+}
+
+fn binary(
+    op: fn(DataType, Box<Instruction>, Box<Instruction>) -> Instruction,
+    dtype: DataType,
+    lhs: Instruction,
+    rhs: Instruction,
+) -> Instruction {
+    op(dtype, Box::new(lhs), Box::new(rhs))
+}
+
+/// `lhs` shifted by a constant amount, or `lhs` itself if the amount is 0
+fn shift(
+    op: fn(DataType, Box<Instruction>, Box<Instruction>) -> Instruction,
+    dtype: DataType,
+    lhs: Instruction,
+    amount: u32,
+) -> Instruction {
+    match amount {
+        0 => lhs,
+        amount => binary(op, dtype, lhs, int(dtype, amount as u64)),
+    }
+}
+
+/// The smallest `p` and `m = ceil(2^p / d)` such that `x / d == (x * m) >> p` for every unsigned
+/// `x` of `width` bits, `d` must not be a power of two and at most `2^(width - 1)`
+fn unsigned_magic(d: u64, width: u32) -> (u128, u32) {
+    let d = d as u128;
+    (width..2 * width)
+        .find_map(|p| {
+            let two_p = 1u128 << p;
+            let m = two_p.div_ceil(d);
+            (m * d - two_p <= 1 << (p - width)).then_some((m, p))
+        })
+        .expect("No magic number for the divisor")
+}
+
+/// The magic number `m` and shift `s` for a signed division by `d`, such that the quotient is
+/// `mulhi(x, m) >> s` rounded towards zero, with `x` added before shifting if `m` is negative
+/// From Hacker's Delight, section 10-4. `d` must be at least 2 and not a power of two
+fn signed_magic(d: u64, width: u32) -> (u64, u32) {
+    let d = d as u128;
+    let two_w1 = 1u128 << (width - 1);
+    let anc = two_w1 - 1 - two_w1 % d;
+    let mut p = width - 1;
+    let (mut q1, mut r1) = (two_w1 / anc, two_w1 % anc);
+    let (mut q2, mut r2) = (two_w1 / d, two_w1 % d);
+    loop {
+        p += 1;
+        (q1, r1) = (q1 * 2, r1 * 2);
+        if r1 >= anc {
+            q1 += 1;
+            r1 -= anc;
+        }
+        (q2, r2) = (q2 * 2, r2 * 2);
+        if r2 >= d {
+            q2 += 1;
+            r2 -= d;
+        }
+        let delta = d - r2;
+        if !(q1 < delta || (q1 == delta && r1 == 0)) {
+            break;
+        }
+    }
+    ((q2 + 1) as u64, p - width)
+}
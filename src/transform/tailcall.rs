@@ -0,0 +1,56 @@
+//! Marking calls in tail position as `tail call`s
+//!
+//! A call is in tail position if it's returned right away, either as `ret call ...`, as a call
+//! statement followed by `ret`, or assigned to a vreg that's returned by the next instruction.
+//! Such a call is lowered into a jump once it's marked, so the caller's frame is reused and
+//! recursion doesn't grow the stack
+//!
+//! The frame is torn down before the jump, so nothing may point into it anymore. Calls are only
+//! marked in functions where no `alloc` slot escapes, and when all the arguments are passed in
+//! registers
+
+use crate::{
+    analysis::escape::EscapeInfo,
+    generation::platform::x86_64::args_fit_in_regs,
+    ir::{Instruction, TypeDefs},
+    pass::{Function, FunctionPass},
+};
+
+/// `mark_tail_calls` as a pass
+pub struct TailCall;
+impl FunctionPass for TailCall {
+    fn name(&self) -> &'static str {
+        "tail-call"
+    }
+    fn run_on_function(&mut self, function: Function) {
+        mark_tail_calls(function.body, function.type_defs);
+    }
+}
+
+/// Mark every call in tail position that can be lowered into a jump as a `tail call`
+pub fn mark_tail_calls(body: &mut [Instruction], type_defs: &TypeDefs) {
+    let info = EscapeInfo::new(body, type_defs);
+    if info.any_escapes() {
+        return;
+    }
+    for index in 0..body.len() {
+        let (current, rest) = body[index..].split_first_mut().unwrap();
+        let next = rest.first();
+        let call = match current {
+            Instruction::Ret(Some(ret_val)) => ret_val.as_mut(),
+            Instruction::DefReg { id, rhs } => match next {
+                Some(Instruction::Ret(Some(ret_val))) if matches!(ret_val.as_ref(), Instruction::Reg(_, ret_id) if ret_id == id) => {
+                    rhs.as_mut()
+                }
+                _ => continue,
+            },
+            call if next == Some(&Instruction::Ret(None)) => call,
+            _ => continue,
+        };
+        if let Instruction::Call { args, is_tail, .. } = call {
+            if args_fit_in_regs(args.iter().filter_map(Instruction::dtype)) {
+                *is_tail = true;
+            }
+        }
+    }
+}
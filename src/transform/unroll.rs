@@ -0,0 +1,507 @@
+//! Loop unrolling
+//!
+//! A loop that runs a number of times known at compile time is fully unrolled into straight line
+//! code if that's small enough. Otherwise, if the loop is left from its header, the body is
+//! repeated 4 or 2 times per trip around the loop and the checks in between are left out. The
+//! iterations left over when the number of iterations isn't a multiple of that are peeled off in
+//! front of the loop
+//!
+//! The number of iterations is found by running the loop at compile time, so the `phi`s that the
+//! exit condition depends on need constant initial values. Only innermost loops with a single
+//! latch and a single exit, taken from the header or the latch, are unrolled. The body needs to
+//! be in SSA form
+
+use std::collections::HashMap;
+
+use crate::{
+    analysis::{cfg::Cfg, dominators::DomTree, loops::LoopInfo},
+    ir::{DataType, Instruction},
+    pass::{Function, FunctionPass},
+};
+
+use super::{
+    canonicalize_blocks, has_side_effects, next_vreg_id, rename_vregs, sccp::eval_int,
+    LabelGenerator,
+};
+
+/// Largest number of instructions a loop is fully unrolled into
+pub const FULL_UNROLL_SIZE: usize = 64;
+/// Largest number of instructions a partially unrolled loop can have
+pub const PARTIAL_UNROLL_SIZE: usize = 32;
+/// Loops that run more times than this are not simulated to the end
+const MAX_TRIP_COUNT: usize = 1 << 12;
+
+/// `unroll` as a pass
+pub struct Unroll;
+impl FunctionPass for Unroll {
+    fn name(&self) -> &'static str {
+        "unroll"
+    }
+    fn run_on_function(&mut self, function: Function) {
+        unroll(function.body);
+    }
+}
+
+/// Unroll the innermost loops whose trip counts are constant
+pub fn unroll(body: &mut Vec<Instruction>) {
+    canonicalize_blocks(body);
+    let headers: Vec<String> = {
+        let cfg = Cfg::new(body);
+        let loops = LoopInfo::new(&cfg, &DomTree::new(&cfg));
+        (0..loops.loops.len())
+            .filter(|&i| loops.loops.iter().all(|l| l.parent != Some(i)))
+            .map(|i| cfg.blocks[loops.loops[i].header].label.clone().unwrap())
+            .collect()
+    };
+    let mut labels = LabelGenerator::new(body);
+    for header in headers {
+        let cfg = Cfg::new(body);
+        let dom_tree = DomTree::new(&cfg);
+        let Some(shape) = LoopShape::new(body, &cfg, &dom_tree, &header) else {
+            continue;
+        };
+        let Some(exit_at) = shape.trip_count(body) else {
+            continue;
+        };
+        let size = shape.size(body);
+        let plan = if (exit_at + 1) * size <= FULL_UNROLL_SIZE {
+            Plan::Full(exit_at + 1)
+        } else {
+            let exits_from_header = shape.exiting == shape.blocks[0];
+            let factor = [4, 2]
+                .into_iter()
+                .find(|&factor| factor * size <= PARTIAL_UNROLL_SIZE);
+            match factor {
+                Some(factor) if exits_from_header => Plan::Partial {
+                    factor,
+                    peeled: exit_at % factor,
+                },
+                _ => continue,
+            }
+        };
+        let mut unroller = Unroller {
+            shape: &shape,
+            plan,
+            labels: &mut labels,
+            next_id: next_vreg_id(body),
+        };
+        *body = unroller.unroll(body, &cfg);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Plan {
+    /// Copy the loop this many times, the last copy leaves the loop
+    Full(usize),
+    /// Do the body `factor` times per trip around the loop, after `peeled` copies in front of it
+    Partial { factor: usize, peeled: usize },
+}
+
+/// A header `phi`, `(id, dtype, initial value, value from the latch)`
+type HeaderPhi = (u64, DataType, Instruction, Instruction);
+
+/// A loop in the form that can be unrolled
+struct LoopShape {
+    /// Blocks of the loop in the order of the body, the header is the first one
+    blocks: Vec<usize>,
+    /// Blocks of the loop in the order of the dominator tree
+    dom_order: Vec<usize>,
+    header: String,
+    preheader: String,
+    latch: String,
+    /// The block with the branch that leaves the loop
+    exiting: usize,
+    /// Target of the exiting branch that stays in the loop
+    stay_target: String,
+    exit_target: String,
+    /// Whether the exit is taken when the condition is true
+    exit_if_true: bool,
+    phis: Vec<HeaderPhi>,
+    /// The vregs defined in the loop
+    defined_inside: Vec<u64>,
+    /// Constant vregs defined outside the loop
+    constants: HashMap<u64, u64>,
+}
+impl LoopShape {
+    fn new(body: &[Instruction], cfg: &Cfg, dom_tree: &DomTree, header: &str) -> Option<Self> {
+        let loops = LoopInfo::new(cfg, dom_tree);
+        let header_block = cfg.block_of_label(header)?;
+        let l = loops.loops.iter().find(|l| l.header == header_block)?;
+        let [latch] = l.latches[..] else {
+            return None;
+        };
+        let outside_preds: Vec<usize> = cfg.blocks[header_block]
+            .preds
+            .iter()
+            .copied()
+            .filter(|&pred| !l.contains(pred))
+            .collect();
+        let [preheader] = outside_preds[..] else {
+            return None;
+        };
+        let mut exiting = l
+            .blocks
+            .iter()
+            .copied()
+            .filter(|&block| cfg.blocks[block].succs.iter().any(|&s| !l.contains(s)));
+        let (Some(exiting), None) = (exiting.next(), exiting.next()) else {
+            return None;
+        };
+        if exiting != header_block && exiting != latch {
+            return None;
+        }
+        let Instruction::Br {
+            cond,
+            if_true,
+            if_false,
+        } = &body[cfg.blocks[exiting].range.end - 1]
+        else {
+            return None;
+        };
+        let in_loop = |label: &String| l.contains(cfg.block_of_label(label).unwrap());
+        let (stay_target, exit_target, exit_if_true) = match (in_loop(if_true), in_loop(if_false)) {
+            (false, true) => (if_false.clone(), if_true.clone(), true),
+            (true, false) => (if_true.clone(), if_false.clone(), false),
+            _ => return None,
+        };
+        if has_side_effects(cond) {
+            return None;
+        }
+
+        let label_of = |block: usize| cfg.blocks[block].label.clone().unwrap();
+        let (preheader, latch) = (label_of(preheader), label_of(latch));
+        let mut phis = Vec::new();
+        let mut defined_inside = Vec::new();
+        for &block in &l.blocks {
+            for instruction in &body[cfg.blocks[block].range.clone()] {
+                match instruction {
+                    Instruction::DefReg { rhs, .. } if matches!(**rhs, Instruction::Alloc(_)) => {
+                        // Each copy would get its own slot
+                        return None;
+                    }
+                    Instruction::DefReg { id, rhs } => {
+                        defined_inside.push(*id);
+                        let Instruction::Phi { dtype, incoming } = rhs.as_ref() else {
+                            continue;
+                        };
+                        if block != header_block {
+                            continue;
+                        }
+                        let value_from = |from: &String| {
+                            incoming
+                                .iter()
+                                .find(|(label, _)| label == from)
+                                .map(|(_, value)| value.clone())
+                        };
+                        if incoming.len() != 2 {
+                            return None;
+                        }
+                        phis.push((*id, *dtype, value_from(&preheader)?, value_from(&latch)?));
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        let mut constants = HashMap::new();
+        for (i, block) in cfg.blocks.iter().enumerate() {
+            if l.contains(i) {
+                continue;
+            }
+            for instruction in &body[block.range.clone()] {
+                if let Instruction::DefReg { id, rhs } = instruction {
+                    if let Some(value) = eval_int(rhs, &|id| constants.get(&id).copied()) {
+                        constants.insert(*id, value);
+                    }
+                }
+            }
+        }
+        Some(Self {
+            blocks: l.blocks.clone(),
+            dom_order: dom_tree
+                .pre_order()
+                .into_iter()
+                .filter(|&block| l.contains(block))
+                .collect(),
+            header: header.to_string(),
+            preheader,
+            latch,
+            exiting,
+            stay_target,
+            exit_target,
+            exit_if_true,
+            phis,
+            defined_inside,
+            constants,
+        })
+        .filter(|shape| shape.blocks[0] == header_block)
+    }
+    /// Number of instructions in the loop, not counting labels
+    fn size(&self, body: &[Instruction]) -> usize {
+        let cfg = Cfg::new(body);
+        self.blocks
+            .iter()
+            .map(|&block| cfg.blocks[block].range.len())
+            .sum()
+    }
+    /// Which time through the exiting block leaves the loop, counting from 0
+    /// `None` if that's not known at compile time
+    fn trip_count(&self, body: &[Instruction]) -> Option<usize> {
+        let cfg = Cfg::new(body);
+        let cond = match &body[cfg.blocks[self.exiting].range.end - 1] {
+            Instruction::Br { cond, .. } => cond,
+            _ => unreachable!(),
+        };
+        let mut phi_values: Vec<Option<u64>> = self
+            .phis
+            .iter()
+            .map(|(_, _, init, _)| eval_int(init, &|id| self.constants.get(&id).copied()))
+            .collect();
+        for k in 0..MAX_TRIP_COUNT {
+            let mut values = self.constants.clone();
+            for ((id, ..), value) in self.phis.iter().zip(&phi_values) {
+                if let Some(value) = value {
+                    values.insert(*id, *value);
+                }
+            }
+            for &block in &self.dom_order {
+                for instruction in &body[cfg.blocks[block].range.clone()] {
+                    let Instruction::DefReg { id, rhs } = instruction else {
+                        continue;
+                    };
+                    if matches!(**rhs, Instruction::Phi { .. }) {
+                        continue;
+                    }
+                    if let Some(value) = eval_int(rhs, &|id| values.get(&id).copied()) {
+                        values.insert(*id, value);
+                    }
+                }
+            }
+            let cond = eval_int(cond, &|id| values.get(&id).copied())?;
+            if (cond != 0) == self.exit_if_true {
+                return Some(k);
+            }
+            phi_values = self
+                .phis
+                .iter()
+                .map(|(_, _, _, next)| eval_int(next, &|id| values.get(&id).copied()))
+                .collect();
+        }
+        None
+    }
+}
+
+struct Unroller<'a> {
+    shape: &'a LoopShape,
+    plan: Plan,
+    labels: &'a mut LabelGenerator,
+    next_id: u64,
+}
+/// The vregs and labels of one copy of the loop. The first copy of a fully unrolled loop and the
+/// copy with the check of a partially unrolled one keep the original ones
+#[derive(Default)]
+struct Copy {
+    vregs: HashMap<u64, u64>,
+    labels: HashMap<String, String>,
+}
+impl Copy {
+    fn vreg(&self, id: u64) -> u64 {
+        self.vregs.get(&id).copied().unwrap_or(id)
+    }
+    fn label(&self, label: &str) -> String {
+        self.labels
+            .get(label)
+            .cloned()
+            .unwrap_or_else(|| label.to_string())
+    }
+    fn value(&self, value: &Instruction) -> Instruction {
+        let mut value = value.clone();
+        rename_vregs(&mut value, &self.vregs);
+        value
+    }
+}
+impl Unroller<'_> {
+    fn unroll(&mut self, body: &[Instruction], cfg: &Cfg) -> Vec<Instruction> {
+        let shape = self.shape;
+        let (count, original) = match self.plan {
+            Plan::Full(count) => (count, 0),
+            Plan::Partial { factor, peeled } => (peeled + factor, peeled),
+        };
+        let loop_labels: Vec<String> = shape
+            .blocks
+            .iter()
+            .map(|&block| cfg.blocks[block].label.clone().unwrap())
+            .collect();
+        let copies: Vec<Copy> = (0..count)
+            .map(|k| match k == original {
+                true => Copy::default(),
+                false => Copy {
+                    vregs: shape
+                        .defined_inside
+                        .iter()
+                        .map(|&id| (id, self.fresh_id()))
+                        .collect(),
+                    labels: loop_labels
+                        .iter()
+                        .map(|label| (label.clone(), self.labels.fresh(&format!("{label}.unroll"))))
+                        .collect(),
+                },
+            })
+            .collect();
+        let last = &copies[count - 1];
+        let exiting_label = cfg.blocks[shape.exiting].label.clone().unwrap();
+        let preheader = cfg.block_of_label(&shape.preheader).unwrap();
+
+        let mut new_body = Vec::with_capacity(body.len() + count * self.shape.size(body));
+        for (i, block) in cfg.blocks.iter().enumerate() {
+            if i == shape.blocks[0] {
+                for k in 0..count {
+                    self.emit_copy(body, cfg, &copies, k, &mut new_body);
+                }
+                continue;
+            }
+            if shape.blocks.contains(&i) {
+                continue;
+            }
+            new_body.push(Instruction::Label(block.label.clone().unwrap()));
+            for instruction in &body[block.range.clone()] {
+                let mut instruction = instruction.clone();
+                // Into the first of the peeled copies
+                if i == preheader && instruction.is_terminator() {
+                    for target in instruction.jump_targets_mut() {
+                        if *target == shape.header {
+                            *target = copies[0].label(target);
+                        }
+                    }
+                }
+                // The loop is left from the last copy, which has the values the code after the
+                // loop sees
+                if let Plan::Full(_) = self.plan {
+                    rename_vregs(&mut instruction, &last.vregs);
+                    if let Instruction::DefReg { rhs, .. } = &mut instruction {
+                        if let Instruction::Phi { incoming, .. } = rhs.as_mut() {
+                            for (label, _) in incoming {
+                                if *label == exiting_label {
+                                    *label = last.label(label);
+                                }
+                            }
+                        }
+                    }
+                }
+                new_body.push(instruction);
+            }
+        }
+        new_body
+    }
+    fn emit_copy(
+        &mut self,
+        body: &[Instruction],
+        cfg: &Cfg,
+        copies: &[Copy],
+        k: usize,
+        out: &mut Vec<Instruction>,
+    ) {
+        let shape = self.shape;
+        let copy = &copies[k];
+        let count = copies.len();
+        let is_last = k + 1 == count;
+        // The copy that checks whether to leave the partially unrolled loop
+        let check = match self.plan {
+            Plan::Full(_) => None,
+            Plan::Partial { peeled, .. } => Some(peeled),
+        };
+        let next = match (check, is_last) {
+            (Some(check), true) => Some(&copies[check]),
+            _ => copies.get(k + 1),
+        };
+        let previous = k.checked_sub(1).map(|k| &copies[k]);
+        let resolve = |target: &String| match *target == shape.header {
+            true => next.expect("Jumping back from the last copy").label(target),
+            false => copy.label(target),
+        };
+        for &block in &shape.blocks {
+            // The last copy of a loop left from the header never gets past the header
+            let exits_from_header = shape.exiting == shape.blocks[0];
+            if matches!(self.plan, Plan::Full(_))
+                && is_last
+                && exits_from_header
+                && block != shape.blocks[0]
+            {
+                continue;
+            }
+            let label = cfg.blocks[block].label.as_ref().unwrap();
+            out.push(Instruction::Label(copy.label(label)));
+            for (i, instruction) in body[cfg.blocks[block].range.clone()].iter().enumerate() {
+                let is_exiting_branch =
+                    block == shape.exiting && i + 1 == cfg.blocks[block].range.len();
+                if is_exiting_branch {
+                    let target = match check {
+                        None if is_last => Some(shape.exit_target.clone()),
+                        // The only check left in the partially unrolled loop
+                        Some(check) if check == k => None,
+                        _ => Some(resolve(&shape.stay_target)),
+                    };
+                    if let Some(target) = target {
+                        out.push(Instruction::Jmp(target));
+                        continue;
+                    }
+                }
+                let header_phi = shape
+                    .phis
+                    .iter()
+                    .find(|phi| Some(phi.0) == instruction.as_def_reg_id());
+                if let Some((id, dtype, init, next_value)) = header_phi {
+                    let from_previous = match previous {
+                        Some(previous) => {
+                            (previous.label(&shape.latch), previous.value(next_value))
+                        }
+                        None => (shape.preheader.clone(), init.clone()),
+                    };
+                    let rhs = match check == Some(k) {
+                        true => {
+                            let last = &copies[count - 1];
+                            let incoming = vec![
+                                from_previous,
+                                (last.label(&shape.latch), last.value(next_value)),
+                            ];
+                            Instruction::Phi {
+                                dtype: *dtype,
+                                incoming,
+                            }
+                        }
+                        false => from_previous.1,
+                    };
+                    out.push(Instruction::DefReg {
+                        id: copy.vreg(*id),
+                        rhs: Box::new(rhs),
+                    });
+                    continue;
+                }
+                let mut instruction = instruction.clone();
+                rename_vregs(&mut instruction, &copy.vregs);
+                match &mut instruction {
+                    Instruction::DefReg { id, rhs } => {
+                        *id = copy.vreg(*id);
+                        if let Instruction::Phi { incoming, .. } = rhs.as_mut() {
+                            for (label, _) in incoming {
+                                *label = copy.label(label);
+                            }
+                        }
+                    }
+                    instruction if instruction.is_terminator() => {
+                        for target in instruction.jump_targets_mut() {
+                            *target = resolve(target);
+                        }
+                    }
+                    _ => (),
+                }
+                out.push(instruction);
+            }
+        }
+    }
+    fn fresh_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+}
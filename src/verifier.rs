@@ -0,0 +1,747 @@
+//! Checks that a program is valid before it's handed to code generation, so that mistakes are
+//! reported with a location instead of panicking somewhere deep in the backend
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{self, Display, Formatter},
+    rc::Rc,
+};
+
+use crate::{
+    analysis::cfg::Cfg,
+    generation::platform::x86_64::args_fit_in_regs,
+    ir::{Callee, DataType, Instruction, TopLevel, Type},
+};
+
+/// A rule of the IR that's broken
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    /// Name of the function, global or type the error is in
+    pub symbol: Rc<String>,
+    /// Index of the offending instruction in the function body, `None` if the error is about
+    /// the whole item
+    pub index: Option<usize>,
+    /// The offending instruction, printed
+    pub instruction: Option<String>,
+    pub message: String,
+}
+impl VerifyError {
+    /// An error about a whole function, global or type
+    fn item(symbol: &Rc<String>, message: String) -> Self {
+        Self {
+            symbol: Rc::clone(symbol),
+            index: None,
+            instruction: None,
+            message,
+        }
+    }
+}
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "in `{}`", self.symbol)?;
+        if let Some(index) = self.index {
+            write!(f, ", instruction {index}")?;
+        }
+        if let Some(instruction) = &self.instruction {
+            write!(f, " `{instruction}`")?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// Check the whole program, returns every violation found
+/// Checks:
+/// - symbols and struct types are defined once, and exist where they are referred to
+/// - struct types don't contain themselves, and the fields taken from them exist
+/// - vregs are defined once and before they are used, in the order of the function body
+/// - operand types, such as both sides of an arithmetic or a store having the same type
+/// - stores, loads and other pointer operations are done through `ptr`s
+/// - labels are unique and jumps go to existing labels
+/// - calls match the arity and argument types of the callee
+/// - each block ends with exactly one terminator
+/// - `tail call`s are returned right away, pass their arguments in registers and don't pass
+///   pointers into the caller's stack frame
+/// - no `~`, which the backend can't lower
+pub fn verify(program: &[TopLevel]) -> Result<(), Vec<VerifyError>> {
+    let mut errors = Vec::<VerifyError>::new();
+
+    let mut signatures = HashMap::<Rc<String>, (&[DataType], bool)>::new();
+    let mut symbols = HashSet::<Rc<String>>::new();
+    // Fields of the struct types, by the first definition of each
+    let mut structs = HashMap::<Rc<String>, &[Type]>::new();
+    for top_level in program {
+        match top_level {
+            TopLevel::Extern {
+                name,
+                args,
+                is_variadic,
+            }
+            | TopLevel::Fn {
+                name,
+                args,
+                is_variadic,
+                ..
+            } => {
+                if !symbols.insert(Rc::clone(name)) {
+                    errors.push(VerifyError::item(
+                        name,
+                        format!("`@{name}` is defined more than once"),
+                    ));
+                }
+                signatures.insert(Rc::clone(name), (args, *is_variadic));
+            }
+            TopLevel::Global { name, .. } => {
+                if !symbols.insert(Rc::clone(name)) {
+                    errors.push(VerifyError::item(
+                        name,
+                        format!("`@{name}` is defined more than once"),
+                    ));
+                }
+            }
+            TopLevel::TypeDef { name, fields } => {
+                if structs.contains_key(name) {
+                    errors.push(VerifyError::item(
+                        name,
+                        format!("struct type `%{name}` is defined more than once"),
+                    ));
+                } else {
+                    structs.insert(Rc::clone(name), fields);
+                }
+            }
+        }
+    }
+
+    let mut cycles_checked = HashSet::<&Rc<String>>::new();
+    for top_level in program {
+        match top_level {
+            TopLevel::Extern { .. } => (),
+            TopLevel::TypeDef { name, fields } => {
+                for field in fields {
+                    if let Some(undefined) = undefined_struct(field, &structs) {
+                        errors.push(VerifyError::item(
+                            name,
+                            format!("struct type `%{undefined}` is not defined"),
+                        ));
+                    }
+                }
+                // A struct defined more than once is only reported once
+                if cycles_checked.insert(name) && contains_itself(name, &structs) {
+                    errors.push(VerifyError::item(
+                        name,
+                        format!("struct type `%{name}` contains itself"),
+                    ));
+                }
+            }
+            TopLevel::Global { name, ty, .. } => {
+                if let Some(undefined) = undefined_struct(ty, &structs) {
+                    errors.push(VerifyError::item(
+                        name,
+                        format!("struct type `%{undefined}` is not defined"),
+                    ));
+                }
+            }
+            TopLevel::Fn {
+                name,
+                args,
+                is_variadic,
+                body,
+                ..
+            } => FnVerifier {
+                name,
+                args,
+                is_variadic: *is_variadic,
+                signatures: &signatures,
+                symbols: &symbols,
+                structs: &structs,
+                body,
+                cfg: Cfg::new(body),
+                block_of: Vec::new(),
+                vreg_types: HashMap::new(),
+                frame_ptrs: HashSet::new(),
+                defined: HashSet::new(),
+                in_phi: false,
+                index: 0,
+                errors: &mut errors,
+            }
+            .verify(),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// A struct type referred to by `ty` that's not defined, if there is one
+fn undefined_struct(ty: &Type, structs: &HashMap<Rc<String>, &[Type]>) -> Option<Rc<String>> {
+    match ty {
+        Type::Scalar(_) => None,
+        Type::Struct(name) if structs.contains_key(name) => None,
+        Type::Struct(name) => Some(Rc::clone(name)),
+        Type::Array(elem, _) => undefined_struct(elem, structs),
+    }
+}
+
+/// Whether the struct type `name` contains a field of its own type, directly or through other
+/// structs and arrays, which would make it infinitely large
+fn contains_itself(name: &Rc<String>, structs: &HashMap<Rc<String>, &[Type]>) -> bool {
+    let mut seen = HashSet::<&Rc<String>>::new();
+    let mut worklist: Vec<&Type> = structs[name].iter().collect();
+    while let Some(ty) = worklist.pop() {
+        match ty {
+            Type::Scalar(_) => (),
+            Type::Array(elem, _) => worklist.push(elem),
+            Type::Struct(field) if field == name => return true,
+            Type::Struct(field) => {
+                if seen.insert(field) {
+                    worklist.extend(
+                        structs
+                            .get(field)
+                            .into_iter()
+                            .flat_map(|fields| fields.iter()),
+                    );
+                }
+            }
+        }
+    }
+    false
+}
+
+struct FnVerifier<'a> {
+    name: &'a Rc<String>,
+    args: &'a [DataType],
+    is_variadic: bool,
+    signatures: &'a HashMap<Rc<String>, (&'a [DataType], bool)>,
+    symbols: &'a HashSet<Rc<String>>,
+    structs: &'a HashMap<Rc<String>, &'a [Type]>,
+    body: &'a [Instruction],
+    cfg: Cfg,
+    /// The block that each instruction is in
+    block_of: Vec<usize>,
+    /// Types of all the vregs defined anywhere in the function
+    vreg_types: HashMap<u64, DataType>,
+    /// VRegs that may point into the stack frame, which is gone once a `tail call` jumps away
+    frame_ptrs: HashSet<u64>,
+    /// VRegs defined so far
+    defined: HashSet<u64>,
+    /// Whether the incoming values of a phi are being checked, which may be defined later in
+    /// the body, such as at the end of a loop
+    in_phi: bool,
+    /// Index of the instruction being checked
+    index: usize,
+    errors: &'a mut Vec<VerifyError>,
+}
+impl<'a> FnVerifier<'a> {
+    fn verify(mut self) {
+        let first_error = self.errors.len();
+        // Collect the types first, so a use before the definition doesn't also cause type errors
+        for instruction in self.body {
+            if let Instruction::DefReg { id, rhs } = instruction {
+                if let Some(dtype) = rhs.dtype() {
+                    self.vreg_types.entry(*id).or_insert(dtype);
+                }
+            }
+        }
+        self.find_frame_ptrs();
+        self.block_of = self.cfg.block_of_instructions();
+        let labels = self.verify_labels();
+        self.verify_blocks();
+        for (index, instruction) in self.body.iter().enumerate() {
+            self.index = index;
+            self.verify_instruction(instruction, &labels);
+        }
+        // Report in the order of the function body, errors about the whole function go last
+        self.errors[first_error..].sort_by_key(|error| error.index.unwrap_or(usize::MAX));
+    }
+
+    fn find_frame_ptrs(&mut self) {
+        // A pointer may be used in the body before it's defined, such as at the end of a loop
+        loop {
+            let len = self.frame_ptrs.len();
+            for instruction in self.body {
+                let Instruction::DefReg { id, rhs } = instruction else {
+                    continue;
+                };
+                let is_frame_ptr = match rhs.as_ref() {
+                    Instruction::Alloc(_) => true,
+                    Instruction::Reg(DataType::Ptr, ptr)
+                    | Instruction::FieldPtr { id: ptr, .. }
+                    | Instruction::ElemPtr { id: ptr, .. } => self.frame_ptrs.contains(ptr),
+                    Instruction::Phi { incoming, .. } => incoming.iter().any(|(_, value)| {
+                        matches!(value, Instruction::Reg(_, ptr) if self.frame_ptrs.contains(ptr))
+                    }),
+                    _ => false,
+                };
+                if is_frame_ptr {
+                    self.frame_ptrs.insert(*id);
+                }
+            }
+            if self.frame_ptrs.len() == len {
+                break;
+            }
+        }
+    }
+
+    fn error(&mut self, message: String) {
+        self.errors.push(VerifyError {
+            symbol: Rc::clone(self.name),
+            index: Some(self.index),
+            instruction: Some(self.body[self.index].to_string()),
+            message,
+        });
+    }
+
+    fn verify_labels(&mut self) -> HashSet<&'a String> {
+        let mut labels = HashSet::<&String>::new();
+        let body = self.body;
+        for (index, instruction) in body.iter().enumerate() {
+            if let Instruction::Label(label) = instruction {
+                if !labels.insert(label) {
+                    self.index = index;
+                    self.error(format!("label `:{label}` is defined more than once"));
+                }
+            }
+        }
+        labels
+    }
+
+    /// Every block, the instructions from one label to the next, must end with exactly one
+    /// terminator
+    fn verify_blocks(&mut self) {
+        // Whether the block being checked has any instruction yet, instructions before the first
+        // label are in the entry block, which can be empty if the body starts with a label
+        let mut block_is_empty = true;
+        let mut last_is_terminator = false;
+        for (index, instruction) in self.body.iter().enumerate() {
+            match instruction {
+                Instruction::Label(label) => {
+                    if !block_is_empty && !last_is_terminator {
+                        self.index = index;
+                        self.error(format!(
+                            "the block before `:{label}` does not end with a terminator"
+                        ));
+                    }
+                    block_is_empty = false;
+                    last_is_terminator = false;
+                }
+                _ => {
+                    if last_is_terminator {
+                        self.index = index;
+                        self.error(
+                            "instruction after a terminator, start a new block with a label"
+                                .to_string(),
+                        );
+                    }
+                    block_is_empty = false;
+                    last_is_terminator = instruction.is_terminator();
+                }
+            }
+        }
+        if !last_is_terminator {
+            self.errors.push(VerifyError::item(
+                self.name,
+                "the last block does not end with a terminator".to_string(),
+            ));
+        }
+    }
+
+    fn verify_instruction(&mut self, instruction: &Instruction, labels: &HashSet<&String>) {
+        for label in instruction.jump_targets() {
+            if !labels.contains(label) {
+                self.error(format!("jump to undefined label `:{label}`"));
+            }
+        }
+        match instruction {
+            Instruction::DefReg { id, rhs } => {
+                match rhs.as_ref() {
+                    Instruction::Call { ret_type: None, .. } => {
+                        self.error(format!("`%{id}` is assigned a call without a return value"));
+                        self.verify_operand(rhs);
+                    }
+                    Instruction::Phi { dtype, incoming } => self.verify_phi(*dtype, incoming),
+                    _ => {
+                        self.verify_operand(rhs);
+                    }
+                }
+                if !self.defined.insert(*id) {
+                    self.error(format!("`%{id}` is defined more than once"));
+                }
+            }
+            Instruction::Store { lhs_dtype, id, rhs } => {
+                self.verify_ptr(*id);
+                if let Some(rhs_dtype) = self.verify_operand(rhs) {
+                    self.expect_dtype(*lhs_dtype, rhs_dtype, "stored value");
+                }
+            }
+            Instruction::Ret(Some(val)) => {
+                self.verify_operand(val);
+            }
+            Instruction::Ret(None) | Instruction::Label(_) | Instruction::Jmp(_) => (),
+            Instruction::Br { cond, .. } => {
+                if let Some(dtype) = self.verify_operand(cond) {
+                    if dtype.is_float() {
+                        self.error(format!(
+                            "branch condition must be an integer, found {dtype}"
+                        ));
+                    }
+                }
+            }
+            Instruction::Call { .. } => {
+                self.verify_operand(instruction);
+            }
+            Instruction::VaStart(id) | Instruction::VaEnd(id) => {
+                self.verify_variadic();
+                self.verify_ptr(*id);
+            }
+            _ => self.error("not a valid statement".to_string()),
+        }
+    }
+
+    /// Check an operand and everything inside it, returns its type if it's well formed
+    fn verify_operand(&mut self, operand: &Instruction) -> Option<DataType> {
+        match operand {
+            Instruction::Arg(dtype, i) => match self.args.get(*i as usize) {
+                Some(arg_dtype) => {
+                    self.expect_dtype(*arg_dtype, *dtype, &format!("argument #{i}"));
+                    Some(*dtype)
+                }
+                None => {
+                    self.error(format!(
+                        "argument #{i} is out of range, `@{}` has {} arguments",
+                        self.name,
+                        self.args.len()
+                    ));
+                    None
+                }
+            },
+            Instruction::Reg(dtype, id) => {
+                let def_dtype = self.verify_use(*id)?;
+                self.expect_dtype(def_dtype, *dtype, &format!("`%{id}`"));
+                Some(*dtype)
+            }
+            Instruction::UInt(dtype, _) | Instruction::Int(dtype, _) => Some(*dtype),
+            Instruction::Float(dtype, _) => {
+                if !dtype.is_float() {
+                    self.error(format!("float literal cannot be of type {dtype}"));
+                }
+                Some(*dtype)
+            }
+            Instruction::String(_) => Some(DataType::Ptr),
+            Instruction::Add(dtype, lhs, rhs)
+            | Instruction::Sub(dtype, lhs, rhs)
+            | Instruction::Mul(dtype, lhs, rhs)
+            | Instruction::Div(dtype, lhs, rhs) => {
+                self.verify_binary(*dtype, lhs, rhs);
+                Some(*dtype)
+            }
+            Instruction::Rem(dtype, lhs, rhs) | Instruction::MulHi(dtype, lhs, rhs) => {
+                if dtype.is_float() {
+                    self.error(format!("integer arithmetic cannot be done on {dtype}"));
+                }
+                self.verify_binary(*dtype, lhs, rhs);
+                Some(*dtype)
+            }
+            Instruction::Not(dtype, lhs, rhs) => {
+                self.error("`~` is not supported by the backend yet".to_string());
+                self.verify_binary(*dtype, lhs, rhs);
+                Some(*dtype)
+            }
+            Instruction::And(dtype, lhs, rhs)
+            | Instruction::Or(dtype, lhs, rhs)
+            | Instruction::Xor(dtype, lhs, rhs)
+            | Instruction::Shl(dtype, lhs, rhs)
+            | Instruction::Shr(dtype, lhs, rhs) => {
+                if dtype.is_float() {
+                    self.error(format!("bitwise operation cannot be done on {dtype}"));
+                }
+                self.verify_binary(*dtype, lhs, rhs);
+                Some(*dtype)
+            }
+            Instruction::Eq(dtype, lhs, rhs)
+            | Instruction::Ne(dtype, lhs, rhs)
+            | Instruction::Lt(dtype, lhs, rhs)
+            | Instruction::Le(dtype, lhs, rhs)
+            | Instruction::Gt(dtype, lhs, rhs)
+            | Instruction::Ge(dtype, lhs, rhs) => {
+                if dtype.is_float() {
+                    self.error(format!(
+                        "result of a comparison must be an integer, found {dtype}"
+                    ));
+                }
+                let lhs_dtype = self.verify_operand(lhs);
+                let rhs_dtype = self.verify_operand(rhs);
+                if let (Some(lhs_dtype), Some(rhs_dtype)) = (lhs_dtype, rhs_dtype) {
+                    if lhs_dtype != rhs_dtype {
+                        self.error(format!(
+                            "comparing operands of different types {lhs_dtype} and {rhs_dtype}"
+                        ));
+                    }
+                }
+                Some(*dtype)
+            }
+            Instruction::Load { id, dtype } => {
+                self.verify_ptr(*id);
+                Some(*dtype)
+            }
+            Instruction::Alloc(ty) => {
+                self.verify_type(ty);
+                Some(DataType::Ptr)
+            }
+            Instruction::GlobalPtr(name) => {
+                if !self.symbols.contains(name) {
+                    self.error(format!("`@{name}` is not defined"));
+                }
+                Some(DataType::Ptr)
+            }
+            Instruction::FieldPtr { ty, id, index } => {
+                self.verify_type(ty);
+                match ty {
+                    Type::Scalar(dtype) => {
+                        self.error(format!("cannot take a field of scalar type {dtype}"))
+                    }
+                    // Undefined structs are already reported by `verify_type`
+                    Type::Struct(name) => {
+                        if let Some(fields) = self.structs.get(name) {
+                            if *index >= fields.len() as u64 {
+                                self.error(format!("struct type `%{name}` has no field {index}"));
+                            }
+                        }
+                    }
+                    Type::Array(_, count) => {
+                        if index >= count {
+                            self.error(format!(
+                                "field {index} is out of bounds for an array of {count} elements"
+                            ));
+                        }
+                    }
+                }
+                self.verify_ptr(*id);
+                Some(DataType::Ptr)
+            }
+            Instruction::ElemPtr { ty, id, index } => {
+                self.verify_type(ty);
+                self.verify_ptr(*id);
+                if let Some(dtype) = self.verify_operand(index) {
+                    if dtype.is_float() {
+                        self.error(format!("element index must be an integer, found {dtype}"));
+                    }
+                }
+                Some(DataType::Ptr)
+            }
+            Instruction::Call {
+                ret_type,
+                callee,
+                args,
+                is_tail,
+            } => {
+                let arg_types: Vec<Option<DataType>> =
+                    args.iter().map(|arg| self.verify_operand(arg)).collect();
+                match callee {
+                    Callee::Direct(name) => self.verify_call_args(name, &arg_types),
+                    Callee::Indirect(fn_ptr) => {
+                        if let Some(dtype) = self.verify_operand(fn_ptr) {
+                            self.expect_dtype(DataType::Ptr, dtype, "function pointer");
+                        }
+                    }
+                }
+                if *is_tail {
+                    self.verify_tail_call(operand, args, &arg_types);
+                }
+                *ret_type
+            }
+            Instruction::VaArg { dtype, id } => {
+                self.verify_variadic();
+                self.verify_ptr(*id);
+                Some(*dtype)
+            }
+            Instruction::Phi { .. } => {
+                self.error(
+                    "phi can only be the right hand side of a definition at the start of a block"
+                        .to_string(),
+                );
+                None
+            }
+            Instruction::DefReg { .. }
+            | Instruction::Store { .. }
+            | Instruction::Ret(_)
+            | Instruction::Label(_)
+            | Instruction::Jmp(_)
+            | Instruction::Br { .. }
+            | Instruction::VaStart(_)
+            | Instruction::VaEnd(_) => {
+                self.error("a statement cannot be used as an operand".to_string());
+                None
+            }
+        }
+    }
+
+    /// A phi must be at the start of its block, and have exactly one incoming value for each
+    /// predecessor
+    fn verify_phi(&mut self, dtype: DataType, incoming: &[(String, Instruction)]) {
+        let block = self.block_of[self.index];
+        let block_start = self.cfg.blocks[block].range.start;
+        if !self.body[block_start..self.index]
+            .iter()
+            .all(Instruction::is_phi_def)
+        {
+            self.error(
+                "phi must be at the start of a block, before other instructions".to_string(),
+            );
+        }
+        let mut covered = Vec::<usize>::new();
+        for (label, value) in incoming {
+            match self.cfg.block_of_label(label) {
+                Some(pred) if covered.contains(&pred) => {
+                    self.error(format!("more than one incoming value from `:{label}`"))
+                }
+                Some(pred) if self.cfg.blocks[block].preds.contains(&pred) => covered.push(pred),
+                Some(_) => self.error(format!("`:{label}` is not a predecessor of this block")),
+                None => self.error(format!("incoming label `:{label}` is not defined")),
+            }
+            self.in_phi = true;
+            let value_dtype = self.verify_operand(value);
+            self.in_phi = false;
+            if let Some(value_dtype) = value_dtype {
+                self.expect_dtype(
+                    dtype,
+                    value_dtype,
+                    &format!("incoming value from `:{label}`"),
+                );
+            }
+        }
+        for pred in self.cfg.blocks[block].preds.clone() {
+            if covered.contains(&pred) {
+                continue;
+            }
+            match self.cfg.blocks[pred].label.clone() {
+                Some(label) => self.error(format!("no incoming value from predecessor `:{label}`")),
+                None => {
+                    self.error("a predecessor without a label can't be named by phi".to_string())
+                }
+            }
+        }
+    }
+
+    fn verify_binary(&mut self, dtype: DataType, lhs: &Instruction, rhs: &Instruction) {
+        if let Some(lhs_dtype) = self.verify_operand(lhs) {
+            self.expect_dtype(dtype, lhs_dtype, "left hand side");
+        }
+        if let Some(rhs_dtype) = self.verify_operand(rhs) {
+            self.expect_dtype(dtype, rhs_dtype, "right hand side");
+        }
+    }
+
+    fn verify_call_args(&mut self, name: &Rc<String>, arg_types: &[Option<DataType>]) {
+        let Some(&(params, is_variadic)) = self.signatures.get(name) else {
+            self.error(format!(
+                "call to `@{name}`, which is not defined or declared"
+            ));
+            return;
+        };
+        let arity_matches = if is_variadic {
+            arg_types.len() >= params.len()
+        } else {
+            arg_types.len() == params.len()
+        };
+        if !arity_matches {
+            self.error(format!(
+                "`@{name}` expects {}{} arguments, found {}",
+                if is_variadic { "at least " } else { "" },
+                params.len(),
+                arg_types.len()
+            ));
+            return;
+        }
+        for (i, (&param, arg)) in params.iter().zip(arg_types).enumerate() {
+            if let Some(arg) = *arg {
+                self.expect_dtype(param, arg, &format!("argument {i} of `@{name}`"));
+            }
+        }
+    }
+
+    /// A `tail call` must be in tail position, and lowering it into a jump must not lose anything
+    /// that lives in the caller's stack frame
+    fn verify_tail_call(
+        &mut self,
+        call: &Instruction,
+        args: &[Instruction],
+        arg_types: &[Option<DataType>],
+    ) {
+        let next = self.body.get(self.index + 1);
+        let in_tail_position = match &self.body[self.index] {
+            Instruction::Ret(Some(ret_val)) => std::ptr::eq(ret_val.as_ref(), call),
+            Instruction::DefReg { id, rhs } if std::ptr::eq(rhs.as_ref(), call) => matches!(
+                next,
+                Some(Instruction::Ret(Some(ret_val)))
+                    if matches!(ret_val.as_ref(), Instruction::Reg(_, ret_id) if ret_id == id)
+            ),
+            statement => std::ptr::eq(statement, call) && next == Some(&Instruction::Ret(None)),
+        };
+        if !in_tail_position {
+            self.error("`tail call` is not returned right away".to_string());
+        }
+        if !args_fit_in_regs(arg_types.iter().flatten().copied()) {
+            self.error(
+                "`tail call` has too many arguments to pass them all in registers".to_string(),
+            );
+        }
+        let passes_frame_ptr = args.iter().any(|arg| match arg {
+            Instruction::Reg(_, id)
+            | Instruction::FieldPtr { id, .. }
+            | Instruction::ElemPtr { id, .. } => self.frame_ptrs.contains(id),
+            _ => false,
+        });
+        if passes_frame_ptr {
+            self.error(
+                "`tail call` passes a pointer into the caller's stack frame, which is gone by the \
+                 time the callee runs"
+                    .to_string(),
+            );
+        }
+    }
+
+    /// Check that `%id` is defined before here, returns its type
+    fn verify_use(&mut self, id: u64) -> Option<DataType> {
+        let dtype = self.vreg_types.get(&id).copied();
+        if self.in_phi && dtype.is_none() {
+            self.error(format!("`%{id}` is not defined"));
+        } else if !self.in_phi && !self.defined.contains(&id) {
+            match dtype {
+                Some(_) => self.error(format!("`%{id}` is used before its definition")),
+                None => self.error(format!("`%{id}` is not defined")),
+            }
+        }
+        dtype
+    }
+
+    /// Check that `%id` is defined and is a pointer
+    fn verify_ptr(&mut self, id: u64) {
+        if let Some(dtype) = self.verify_use(id) {
+            if dtype != DataType::Ptr {
+                self.error(format!("`%{id}` is used as a pointer, but is {dtype}"));
+            }
+        }
+    }
+
+    fn verify_type(&mut self, ty: &Type) {
+        if let Some(name) = undefined_struct(ty, self.structs) {
+            self.error(format!("struct type `%{name}` is not defined"));
+        }
+    }
+
+    fn verify_variadic(&mut self) {
+        if !self.is_variadic {
+            self.error(format!("`@{}` is not variadic", self.name));
+        }
+    }
+
+    fn expect_dtype(&mut self, expected: DataType, found: DataType, what: &str) {
+        if expected != found {
+            self.error(format!(
+                "{what} is expected to be {expected}, found {found}"
+            ));
+        }
+    }
+}
@@ -0,0 +1,319 @@
+//! Dominators, loops and escaping slots of small hand-written functions
+
+mod common;
+
+use common::parse;
+use mir::{
+    analysis::{
+        cfg::Cfg,
+        dominators::DomTree,
+        escape::{EscapeInfo, SlotPtr},
+        loops::LoopInfo,
+    },
+    ir::{Instruction, TopLevel, TypeDefs},
+};
+
+/// Body of the only function in `source`
+fn body(source: &str) -> Vec<Instruction> {
+    let program = parse(source);
+    match program.into_iter().next() {
+        Some(TopLevel::Fn { body, .. }) => body,
+        top_level => panic!("Expects a function, found {top_level:?}"),
+    }
+}
+
+/// Index of the block starting with `:label`
+fn block(cfg: &Cfg, label: &str) -> usize {
+    cfg.block_of_label(label)
+        .unwrap_or_else(|| panic!("No block `:{label}`"))
+}
+
+/// Blocks given by label, sorted
+fn blocks(cfg: &Cfg, labels: &[&str]) -> Vec<usize> {
+    let mut blocks: Vec<usize> = labels.iter().map(|label| block(cfg, label)).collect();
+    blocks.sort();
+    blocks
+}
+
+fn sorted(blocks: &[usize]) -> Vec<usize> {
+    let mut blocks = blocks.to_vec();
+    blocks.sort();
+    blocks
+}
+
+/// `a` branches to `b` and `c`, which both go to `d`, `c` also loops to itself
+const DIAMOND: &str = "
+fn @f(u8) {
+:a
+    br u8 #0 :b :c
+:b
+    jmp :d
+:c
+    br u8 #0 :c :d
+:d
+    ret
+:dead
+    jmp :d
+}
+";
+
+#[test]
+fn immediate_dominators() {
+    let body = body(DIAMOND);
+    let cfg = Cfg::new(&body);
+    let tree = DomTree::new(&cfg);
+    let [a, b, c, d, dead] = ["a", "b", "c", "d", "dead"].map(|label| block(&cfg, label));
+    assert_eq!(tree.root(), a);
+    assert_eq!(tree.idom(a), None);
+    assert_eq!(tree.idom(b), Some(a));
+    assert_eq!(tree.idom(c), Some(a));
+    assert_eq!(tree.idom(d), Some(a));
+    assert_eq!(sorted(tree.children(a)), blocks(&cfg, &["b", "c", "d"]));
+    assert!(tree.dominates(a, d) && tree.dominates(d, d));
+    assert!(!tree.strictly_dominates(d, d));
+    assert!(!tree.dominates(b, d) && !tree.dominates(c, d));
+    // Unreachable blocks are not in the tree, even though they jump into it
+    assert!(!tree.is_reachable(dead));
+    assert_eq!(tree.idom(dead), None);
+    assert!(!tree.dominates(a, dead));
+}
+
+#[test]
+fn dominance_frontiers() {
+    let body = body(DIAMOND);
+    let cfg = Cfg::new(&body);
+    let frontiers = DomTree::new(&cfg).frontiers();
+    let [a, b, c, d] = ["a", "b", "c", "d"].map(|label| block(&cfg, label));
+    assert_eq!(frontiers[a], []);
+    assert_eq!(frontiers[b], [d]);
+    assert_eq!(sorted(&frontiers[c]), blocks(&cfg, &["c", "d"]));
+    assert_eq!(frontiers[d], []);
+}
+
+#[test]
+fn frontiers_of_an_entry_that_is_a_loop_header() {
+    let source = "
+fn @f(u8) {
+:head
+    br u8 #0 :body :exit
+:body
+    jmp :head
+:exit
+    ret
+}
+";
+    let body = body(source);
+    let cfg = Cfg::new(&body);
+    let frontiers = DomTree::new(&cfg).frontiers();
+    let [head, latch, exit] = ["head", "body", "exit"].map(|label| block(&cfg, label));
+    // Control also reaches the entry from outside the function, so it's a join point even
+    // though the back edge is its only predecessor
+    assert_eq!(frontiers[head], [head]);
+    assert_eq!(frontiers[latch], [head]);
+    assert_eq!(frontiers[exit], []);
+}
+
+#[test]
+fn post_dominators_go_up_to_the_virtual_exit() {
+    let source = "
+fn @f(u8) {
+:a
+    br u8 #0 :b :c
+:b
+    br u8 #0 :d :e
+:c
+    jmp :d
+:d
+    ret
+:e
+    ret
+:forever
+    jmp :forever
+}
+";
+    let body = body(source);
+    let cfg = Cfg::new(&body);
+    let tree = DomTree::post_dominators(&cfg);
+    let exit = cfg.len();
+    let [a, b, c, d, e, forever] =
+        ["a", "b", "c", "d", "e", "forever"].map(|label| block(&cfg, label));
+    assert_eq!(tree.root(), exit);
+    assert_eq!(tree.idom(d), Some(exit));
+    assert_eq!(tree.idom(e), Some(exit));
+    assert_eq!(tree.idom(c), Some(d));
+    // `b` can leave through either `d` or `e`, so only the exit post-dominates it, and the same
+    // goes for `a`
+    assert_eq!(tree.idom(b), Some(exit));
+    assert_eq!(tree.idom(a), Some(exit));
+    assert!(tree.dominates(d, c) && !tree.dominates(d, a));
+    // Never reaches the exit
+    assert!(!tree.is_reachable(forever));
+    // Reverse dominance frontiers: `c` only runs depending on the branch in `a`, `d` and `e`
+    // depend on the branch in `b`, and `d` on the one in `a` too
+    let frontiers = tree.frontiers();
+    assert_eq!(frontiers[c], [a]);
+    assert_eq!(frontiers[e], [b]);
+    assert_eq!(sorted(&frontiers[d]), blocks(&cfg, &["a", "b"]));
+}
+
+#[test]
+fn nested_loops() {
+    let source = "
+fn @f(u8) {
+:entry
+    jmp :outer
+:outer
+    br u8 #0 :inner :exit
+:inner
+    br u8 #0 :inner_body :outer_latch
+:inner_body
+    br u8 #0 :inner :inner_continue
+:inner_continue
+    jmp :inner
+:outer_latch
+    jmp :outer
+:exit
+    ret
+}
+";
+    let body = body(source);
+    let cfg = Cfg::new(&body);
+    let loop_info = LoopInfo::new(&cfg, &DomTree::new(&cfg));
+    let [entry, outer, inner, inner_body, inner_continue, outer_latch, exit] = [
+        "entry",
+        "outer",
+        "inner",
+        "inner_body",
+        "inner_continue",
+        "outer_latch",
+        "exit",
+    ]
+    .map(|label| block(&cfg, label));
+    assert_eq!(loop_info.loops.len(), 2);
+    let outer_loop = &loop_info.loops[0];
+    assert_eq!(outer_loop.header, outer);
+    assert_eq!(outer_loop.latches, [outer_latch]);
+    assert_eq!(
+        outer_loop.blocks,
+        blocks(
+            &cfg,
+            &[
+                "outer",
+                "inner",
+                "inner_body",
+                "inner_continue",
+                "outer_latch"
+            ]
+        )
+    );
+    assert_eq!(outer_loop.parent, None);
+    assert_eq!(outer_loop.depth, 1);
+    // Both back edges into `inner` make a single loop
+    let inner_loop = &loop_info.loops[1];
+    assert_eq!(inner_loop.header, inner);
+    assert_eq!(sorted(&inner_loop.latches), [inner_body, inner_continue]);
+    assert_eq!(
+        inner_loop.blocks,
+        blocks(&cfg, &["inner", "inner_body", "inner_continue"])
+    );
+    assert_eq!(inner_loop.parent, Some(0));
+    assert_eq!(inner_loop.depth, 2);
+    assert_eq!(loop_info.depth(entry), 0);
+    assert_eq!(loop_info.depth(outer), 1);
+    assert_eq!(loop_info.depth(outer_latch), 1);
+    assert_eq!(loop_info.depth(inner_body), 2);
+    assert_eq!(loop_info.depth(exit), 0);
+    assert_eq!(loop_info.innermost_loop(inner_continue), Some(1));
+    assert_eq!(loop_info.innermost_loop(outer_latch), Some(0));
+}
+
+/// Escape analysis of the only function in `source`
+fn escape_info(source: &str) -> EscapeInfo {
+    let program = parse(source);
+    let type_defs = TypeDefs::from_ir(&program);
+    let body = program
+        .iter()
+        .find_map(|top_level| match top_level {
+            TopLevel::Fn { body, .. } => Some(body),
+            _ => None,
+        })
+        .expect("Expects a function");
+    EscapeInfo::new(body, &type_defs)
+}
+
+#[test]
+fn slots_escape_through_calls_stores_and_returns() {
+    let info = escape_info(
+        "
+type %Pair = { i64, i64 }
+extern @g(ptr)
+fn @f(ptr u8) {
+:entry
+    %1 = alloc %Pair
+    %2 = ptr field %Pair %1 $1
+    call @g(ptr %2)
+    %3 = alloc i64
+    %8 = ptr #0
+    ptr [%8] = ptr %3
+    %4 = alloc i64
+    %5 = alloc i64
+    %6 = ptr %5
+    i64 [%6] = i64 $1
+    ptr [%6] = ptr %8
+    %7 = i64 [%5]
+    br u8 #1 :done :other
+:other
+    ret ptr %4
+:done
+    ret i64 %7
+}
+",
+    );
+    // Passed to a call through a field pointer, stored into memory and returned
+    assert!(info.escapes(1));
+    assert!(info.escapes(3));
+    assert!(info.escapes(4));
+    // Only loaded from and stored into, through itself and a copy
+    assert!(!info.escapes(5));
+    assert!(info.any_escapes());
+    assert_eq!(info.non_escaping_slots().collect::<Vec<_>>(), [5]);
+    // Pointers derived from a slot lead back to it only while it doesn't escape
+    assert_eq!(info.non_escaping_slot(6), Some(5));
+    assert_eq!(info.non_escaping_slot(2), None);
+    assert_eq!(info.non_escaping_slot(8), None);
+}
+
+#[test]
+fn derived_pointers_keep_their_offsets() {
+    let info = escape_info(
+        "
+type %Pair = { i32, i64 }
+fn @f(i64) {
+    %1 = alloc [4 x %Pair]
+    %2 = ptr elem %Pair %1 i64 $2
+    %3 = ptr field %Pair %2 $1
+    %4 = ptr elem %Pair %1 i64 #0
+    %5 = ptr field %Pair %4 $1
+    ret i64 [%3]
+}
+",
+    );
+    let at = |offset| {
+        Some(SlotPtr {
+            slot: 1,
+            offset: Some(offset),
+        })
+    };
+    assert_eq!(info.pointer(1), at(0));
+    assert_eq!(info.pointer(2), at(32));
+    assert_eq!(info.pointer(3), at(40));
+    // The index is only known at run time
+    let unknown = Some(SlotPtr {
+        slot: 1,
+        offset: None,
+    });
+    assert_eq!(info.pointer(4), unknown);
+    assert_eq!(info.pointer(5), unknown);
+    assert_eq!(info.pointer(6), None);
+    assert!(!info.any_escapes());
+}
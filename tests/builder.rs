@@ -0,0 +1,120 @@
+//! Programs built with the builder API, and the checks it makes while building
+
+use mir::{
+    builder::{Comparison, ModuleBuilder},
+    compile::{compile, CompileOptions, OptLevel},
+    fileformat::FileFormat,
+    ir::{DataType, Instruction, SymbolAttrs, TopLevel},
+    printer::print_program,
+    verifier::verify,
+};
+
+/// `@sum(n)` adds up the numbers below `n` with a loop
+fn build_sum() -> Vec<TopLevel> {
+    let mut module = ModuleBuilder::new();
+    let mut f = module.function("sum", SymbolAttrs::default(), vec![DataType::I64], false);
+    let entry = f.create_block("entry");
+    let cond = f.create_block("cond");
+    let body = f.create_block("body");
+    let end = f.create_block("end");
+    f.switch_to_block(&entry);
+    f.jmp(&cond);
+    f.switch_to_block(&cond);
+    let i = f.phi(DataType::I64);
+    let total = f.phi(DataType::I64);
+    let n = f.arg(0);
+    let more = f.cmp(Comparison::Lt, i, n);
+    f.br(more, &body, &end);
+    f.switch_to_block(&body);
+    let next_total = f.add(total, i);
+    let next_i = f.add(i, Instruction::Int(DataType::I64, 1));
+    f.jmp(&cond);
+    f.add_incoming(i, &entry, Instruction::Int(DataType::I64, 0));
+    f.add_incoming(i, &body, next_i);
+    f.add_incoming(total, &entry, Instruction::Int(DataType::I64, 0));
+    f.add_incoming(total, &body, next_total);
+    f.switch_to_block(&end);
+    f.ret(Some(total.into()));
+    f.finish();
+    module.finish()
+}
+
+#[test]
+fn builds_a_loop() {
+    let program = build_sum();
+    assert_eq!(verify(&program), Ok(()));
+    let expected = "\
+fn @sum(i64) {
+:entry.0
+    jmp :cond.1
+:cond.1
+    %0 = i64 phi [:entry.0 i64 $+0] [:body.2 i64 %4]
+    %1 = i64 phi [:entry.0 i64 $+0] [:body.2 i64 %3]
+    %2 = u8 < i64 %0 i64 #0
+    br u8 %2 :body.2 :end.3
+:body.2
+    %3 = i64 + i64 %1 i64 %0
+    %4 = i64 + i64 %0 i64 $+1
+    jmp :cond.1
+:end.3
+    ret i64 %1
+}
+";
+    assert_eq!(print_program(&program), expected);
+}
+
+#[test]
+fn built_program_compiles() {
+    for opt_level in [OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::Os] {
+        let options = CompileOptions {
+            opt_level,
+            file_format: FileFormat::Elf64,
+        };
+        let asm = compile(build_sum(), &options);
+        assert!(asm.contains("sum:"), "{asm}");
+    }
+}
+
+#[test]
+#[should_panic(expected = "Operands of + have different types: I64 and I32")]
+fn binary_operands_must_have_the_same_type() {
+    let mut module = ModuleBuilder::new();
+    let mut f = module.function("f", SymbolAttrs::default(), vec![DataType::I64], false);
+    let arg = f.arg(0);
+    f.add(arg, Instruction::Int(DataType::I32, 1));
+}
+
+#[test]
+#[should_panic(expected = "%0 is I64, expects ptr")]
+fn loads_must_go_through_a_ptr() {
+    let mut module = ModuleBuilder::new();
+    let mut f = module.function("f", SymbolAttrs::default(), vec![DataType::I64], false);
+    let arg = f.arg(0);
+    let not_ptr = f.def(arg);
+    f.load(DataType::I64, not_ptr);
+}
+
+#[test]
+#[should_panic(expected = "Arguments [I64] do not match the signature of `@g`: [I64, I64]")]
+fn calls_must_match_the_arity() {
+    let mut module = ModuleBuilder::new();
+    module.extern_fn("g", vec![DataType::I64, DataType::I64], false);
+    let mut f = module.function("f", SymbolAttrs::default(), Vec::new(), false);
+    f.call("g", None, vec![Instruction::Int(DataType::I64, 1)]);
+}
+
+#[test]
+#[should_panic(expected = "Arguments [I64, F64] do not match the signature of `@g`: [I64, I64]")]
+fn calls_must_match_the_argument_types() {
+    let mut module = ModuleBuilder::new();
+    module.extern_fn("g", vec![DataType::I64, DataType::I64], false);
+    let mut f = module.function("f", SymbolAttrs::default(), Vec::new(), false);
+    f.call(
+        "g",
+        None,
+        vec![
+            Instruction::Int(DataType::I64, 1),
+            Instruction::Float(DataType::F64, 1.0),
+        ],
+    );
+}
@@ -0,0 +1,38 @@
+//! Helpers shared by the integration tests
+
+// Each test crate only uses some of these
+#![allow(dead_code)]
+
+use mir::{
+    ir::{Instruction, TopLevel},
+    parser::{parse_string_into_tokens, parse_tokens_into_ir},
+    pass::Pipeline,
+    printer::print_program,
+    transform::pass_by_name,
+};
+
+pub fn parse(source: &str) -> Vec<TopLevel> {
+    parse_tokens_into_ir(parse_string_into_tokens(source.to_string()))
+}
+
+/// Parse `source`, run the passes named `passes` on it and print the result
+pub fn run_passes(source: &str, passes: &[&str]) -> String {
+    let mut program = parse(source);
+    let mut pipeline = Pipeline::builder();
+    for name in passes {
+        pipeline = pipeline.boxed_pass(pass_by_name(name).unwrap());
+    }
+    pipeline.build().run(&mut program);
+    print_program(&program)
+}
+
+/// Parse `source`, run `transform` on every function body and print the result
+pub fn transform_bodies(source: &str, transform: impl Fn(&mut Vec<Instruction>)) -> String {
+    let mut program = parse(source);
+    for top_level in &mut program {
+        if let TopLevel::Fn { body, .. } = top_level {
+            transform(body);
+        }
+    }
+    print_program(&program)
+}
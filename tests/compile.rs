@@ -0,0 +1,111 @@
+mod common;
+
+use std::{fs, process::Command};
+
+use common::parse;
+use mir::{
+    compile::{compile, CompileOptions, OptLevel},
+    printer::print_program,
+};
+
+const SOURCE: &str = "
+extern @g(i64)
+fn @square(i64) {
+    ret i64 * i64 #0 i64 #0
+}
+fn @f(i32 i64) {
+    %1 = alloc i32
+    %2 = alloc i64
+    i32 [%1] = i32 #0
+    i64 [%2] = i64 $0
+    jmp :cond
+:cond
+    %3 = u8 < i32 [%1] i32 $10
+    br u8 %3 :body :end
+:body
+    %4 = i64 call @square(i64 #1)
+    %5 = i64 / i64 %4 i64 $7
+    i64 [%2] = i64 + i64 [%2] i64 %5
+    i32 [%1] = i32 + i32 [%1] i32 $1
+    jmp :cond
+:end
+    call @g(i64 [%2])
+    ret i64 [%2]
+}
+";
+
+#[test]
+fn every_level_is_deterministic() {
+    for opt_level in [OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::Os] {
+        let options = CompileOptions {
+            opt_level,
+            ..Default::default()
+        };
+        let first = compile(parse(SOURCE), &options);
+        for _ in 0..4 {
+            assert_eq!(compile(parse(SOURCE), &options), first, "{opt_level:?}");
+        }
+    }
+}
+
+#[test]
+fn o0_keeps_values_on_the_stack() {
+    let asm = compile(parse(SOURCE), &CompileOptions::default());
+    // Values are only ever in the scratch registers between instructions
+    for reg in ["rbx", "rcx", "rdx", "r8", "r9", "r10"] {
+        assert!(
+            !asm.lines()
+                .any(|line| line.contains(reg) && !line.contains("push") && !line.contains("pop")),
+            "`{reg}` is used at -O0:\n{asm}"
+        );
+    }
+}
+
+/// Run the driver on `SOURCE` with `args`, returns its stdout and the path of the assembly
+fn run_driver(name: &str, args: &[&str]) -> (String, String) {
+    let dir = std::env::temp_dir().join(format!("madeline-{name}-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let source_path = dir.join("f.mir");
+    let asm_path = dir.join("f.s");
+    fs::write(&source_path, SOURCE).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_madeline"))
+        .arg(&source_path)
+        .arg(&asm_path)
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{args:?}: {output:?}");
+    assert!(!fs::read_to_string(&asm_path).unwrap().is_empty());
+    fs::remove_dir_all(&dir).unwrap();
+    (
+        String::from_utf8(output.stdout).unwrap(),
+        asm_path.to_str().unwrap().to_string(),
+    )
+}
+
+#[test]
+fn codegen_does_not_print_to_stdout() {
+    for level in ["-O0", "-O1", "-O2", "-Os"] {
+        let (stdout, asm_path) = run_driver("quiet", &[level]);
+        // Only where the output went, the IR isn't echoed unless asked for
+        assert_eq!(
+            stdout,
+            format!("Output written to {asm_path:?}\n"),
+            "{level}"
+        );
+    }
+}
+
+#[test]
+fn emit_ir_prints_the_program_after_the_passes() {
+    for (level, opt_level) in [("-O0", OptLevel::O0), ("-O2", OptLevel::O2)] {
+        let (stdout, asm_path) = run_driver("emit-ir", &[level, "--emit-ir"]);
+        let mut program = parse(SOURCE);
+        opt_level.pipeline().build().run(&mut program);
+        let expected = format!(
+            "{}Output written to {asm_path:?}\n",
+            print_program(&program)
+        );
+        assert_eq!(stdout, expected, "{level}");
+    }
+}
@@ -0,0 +1,146 @@
+//! Globals and string literals in the data sections, checked through the assembly
+
+mod common;
+
+use common::parse;
+use mir::{
+    compile::{compile, CompileOptions},
+    fileformat::FileFormat,
+    ir::{Linkage, SymbolAttrs, Visibility},
+};
+
+/// Labels and contents of the items in `.rodata`
+fn rodata(asm: &str) -> Vec<(&str, &str)> {
+    let (_, rodata) = asm
+        .split_once("\tsection\t.rodata\n")
+        .unwrap_or_else(|| panic!("No `.rodata` section in:\n{asm}"));
+    let lines: Vec<&str> = rodata
+        .lines()
+        .take_while(|line| !line.starts_with("\tsection"))
+        .collect();
+    lines
+        .windows(2)
+        .filter_map(|pair| {
+            let label = pair[0].strip_suffix(':')?;
+            Some((label, pair[1].strip_prefix("\tdb\t")?))
+        })
+        .collect()
+}
+
+#[test]
+fn identical_string_literals_share_one_copy() {
+    let source = "
+extern @puts(ptr)
+fn @f() {
+    call @puts(ptr c\"hi\")
+    call @puts(ptr c\"bye\")
+    ret
+}
+fn @g() {
+    call @puts(ptr c\"hi\")
+    ret
+}
+";
+    let asm = compile(parse(source), &CompileOptions::default());
+    let [(hi, hi_bytes), (bye, bye_bytes)] = rodata(&asm)[..] else {
+        panic!("Expected two strings in `.rodata`:\n{asm}");
+    };
+    assert_eq!(hi_bytes, "0x68, 0x69, 0x0");
+    assert_eq!(bye_bytes, "0x62, 0x79, 0x65, 0x0");
+    assert_eq!(asm.matches(&format!("[{hi}]")).count(), 2, "{asm}");
+    assert_eq!(asm.matches(&format!("[{bye}]")).count(), 1, "{asm}");
+}
+
+/// The `global` directives of a function `@f` and a global `@x` with the same attributes, in the
+/// order they're written
+fn global_directives(attrs: SymbolAttrs, file_format: FileFormat) -> Vec<String> {
+    let source = format!("{attrs}fn @f() {{\n    ret\n}}\n{attrs}global @x: i64 = 5\n");
+    let options = CompileOptions {
+        file_format,
+        ..Default::default()
+    };
+    compile(parse(&source), &options)
+        .lines()
+        .filter(|line| line.starts_with("\tglobal\t"))
+        .map(String::from)
+        .collect()
+}
+
+#[test]
+fn global_directives_carry_linkage_and_visibility() {
+    use Linkage::*;
+    use Visibility::*;
+    // Linkage, visibility, then what follows the names in ELF for `@f` and `@x`, and in Mach-O
+    let cases = [
+        (Export, Default, ":function", ":data", ""),
+        (
+            Export,
+            Hidden,
+            ":function hidden",
+            ":data hidden",
+            ":private_extern",
+        ),
+        (
+            Export,
+            Protected,
+            ":function protected",
+            ":data protected",
+            "",
+        ),
+        (Weak, Default, ":function weak", ":data weak", ":weak"),
+        (
+            Weak,
+            Hidden,
+            ":function weak hidden",
+            ":data weak hidden",
+            ":weak private_extern",
+        ),
+        (
+            Weak,
+            Protected,
+            ":function weak protected",
+            ":data weak protected",
+            ":weak",
+        ),
+    ];
+    for (linkage, visibility, elf_function, elf_data, macho) in cases {
+        let attrs = SymbolAttrs {
+            linkage,
+            visibility,
+        };
+        assert_eq!(
+            global_directives(attrs, FileFormat::Elf64),
+            [
+                format!("\tglobal\tf{elf_function}"),
+                format!("\tglobal\tx{elf_data}")
+            ],
+            "{attrs}"
+        );
+        assert_eq!(
+            global_directives(attrs, FileFormat::Macho64),
+            [
+                format!("\tglobal\t_f{macho}"),
+                format!("\tglobal\t_x{macho}")
+            ],
+            "{attrs}"
+        );
+    }
+}
+
+/// NASM keeps them as local symbols without any directive, visibility can't be given to them
+#[test]
+fn internal_and_private_symbols_are_not_global() {
+    for linkage in [Linkage::Internal, Linkage::Private] {
+        let attrs = SymbolAttrs {
+            linkage,
+            ..Default::default()
+        };
+        for file_format in [FileFormat::Elf64, FileFormat::Macho64] {
+            assert_eq!(
+                global_directives(attrs, file_format),
+                Vec::<String>::new(),
+                "{attrs}{file_format:?}"
+            );
+        }
+    }
+}
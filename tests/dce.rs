@@ -0,0 +1,164 @@
+//! Dead code and dead store elimination, checked through the printer
+
+mod common;
+
+use common::run_passes;
+
+#[test]
+fn stores_overwritten_by_a_full_width_store_are_removed() {
+    let source = "
+fn @f(i64) {
+    %1 = alloc i64
+    i64 [%1] = i64 $1
+    i64 [%1] = i64 #0
+    ret i64 [%1]
+}
+";
+    let expected = "\
+fn @f(i64) {
+    %1 = alloc i64
+    i64 [%1] = i64 #0
+    ret i64 [%1]
+}
+";
+    assert_eq!(run_passes(source, &["dse"]), expected);
+}
+
+/// The upper half of the first store is still read by the load
+#[test]
+fn stores_overwritten_by_a_narrower_store_are_kept() {
+    let source = "
+fn @f(i64) {
+    %1 = alloc i64
+    i64 [%1] = i64 #0
+    i32 [%1] = i32 $1
+    ret i64 [%1]
+}
+";
+    assert_eq!(run_passes(source, &["dse"]), source.trim_start());
+}
+
+#[test]
+fn stores_read_by_a_later_load_are_kept() {
+    let source = "
+fn @f(i64) {
+    %1 = alloc i64
+    i64 [%1] = i64 #0
+    %2 = i64 [%1]
+    i64 [%1] = i64 $1
+    ret i64 + i64 %2 i64 [%1]
+}
+";
+    assert_eq!(run_passes(source, &["dse"]), source.trim_start());
+}
+
+/// The stores at the end of `:body` are read by the loads in `:header` and `:body` on the next
+/// iteration
+#[test]
+fn stores_read_through_a_back_edge_are_kept() {
+    let source = "
+fn @f(i64) {
+:entry
+    %1 = alloc i64
+    %2 = alloc i64
+    i64 [%1] = i64 $0
+    i64 [%2] = i64 $0
+    jmp :header
+:header
+    br u8 < i64 [%1] i64 #0 :body :exit
+:body
+    i64 [%2] = i64 + i64 [%2] i64 [%1]
+    i64 [%1] = i64 + i64 [%1] i64 $1
+    jmp :header
+:exit
+    ret i64 [%2]
+}
+";
+    assert_eq!(run_passes(source, &["dse"]), source.trim_start());
+}
+
+/// `@g` may keep the pointer and read the slot later, the stored pointer may be read by the
+/// caller, and `va_arg` reads the `va_list` that `va_start` fills in
+#[test]
+fn stores_to_escaping_slots_are_kept() {
+    let source = "
+extern @g(ptr)
+fn @passed(i64) {
+    %1 = alloc i64
+    i64 [%1] = i64 #0
+    call @g(ptr %1)
+    i64 [%1] = i64 $1
+    ret
+}
+fn @stored(ptr) {
+    %1 = alloc i64
+    %2 = ptr #0
+    i64 [%1] = i64 $1
+    ptr [%2] = ptr %1
+    ret
+}
+fn @variadic(i64 ...) {
+    %1 = alloc [3 x u64]
+    u64 [%1] = u64 $0
+    va_start %1
+    %2 = i64 va_arg %1
+    va_end %1
+    ret i64 %2
+}
+";
+    assert_eq!(run_passes(source, &["dse"]), source.trim_start());
+}
+
+#[test]
+fn unreachable_blocks_are_removed_from_phis() {
+    let source = "
+fn @f(u8) {
+:entry
+    br u8 #0 :a :b
+:a
+    jmp :join
+:dead
+    jmp :join
+:b
+    jmp :join
+:join
+    %1 = i64 phi [:a i64 $1] [:dead i64 $2] [:b i64 $3]
+    ret i64 %1
+}
+";
+    let expected = "\
+fn @f(u8) {
+:entry
+    br u8 #0 :a :b
+:a
+    jmp :join
+:b
+    jmp :join
+:join
+    %1 = i64 phi [:a i64 $1] [:b i64 $3]
+    ret i64 %1
+}
+";
+    assert_eq!(run_passes(source, &["dce"]), expected);
+}
+
+/// The result of the call isn't used, but `@h` may still do something
+#[test]
+fn unused_calls_are_kept() {
+    let source = "
+extern @h()
+fn @f() {
+    %1 = i64 call @h()
+    %2 = i64 + i64 %1 i64 $2
+    ret
+}
+";
+    let expected = "\
+extern @h()
+fn @f() {
+    %1 = i64 call @h()
+    ret
+}
+";
+    assert_eq!(run_passes(source, &["dce"]), expected);
+}
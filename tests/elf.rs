@@ -0,0 +1,316 @@
+mod common;
+
+use std::{fs, io::ErrorKind, process::Command};
+
+use common::parse;
+use mir::{
+    compile::{compile_to_object, CompileOptions, OptLevel},
+    fileformat::FileFormat,
+};
+
+const SOURCE: &str = r#"
+extern @puts(ptr)
+extern @printf(ptr ...)
+global @calls: i64 = 0
+global @base: i64 = 40
+internal global @step: i64 = 2
+weak hidden global @scale: i32 = 3
+const @banner: [6 x u8] = "hello"
+
+internal fn @bump() {
+    %1 = ptr @calls
+    i64 [%1] = i64 + i64 [%1] i64 $1
+    ret
+}
+fn @gcd(i64 i64) {
+    br u8 == i64 #1 i64 $0 :done :rec
+:done
+    ret i64 #0
+:rec
+    %2 = i64 % i64 #0 i64 #1
+    ret i64 call @gcd(i64 #1 i64 %2)
+}
+weak fn @answer() {
+    call @bump()
+    %1 = ptr @banner
+    call @puts(ptr %1)
+    call @puts(ptr c"world")
+    %2 = ptr @base
+    %3 = ptr @step
+    %4 = i64 + i64 [%2] i64 [%3]
+    %5 = ptr @calls
+    call @printf(ptr c"%ld %ld\n" i64 %4 i64 [%5])
+    ret i64 %4
+}
+"#;
+
+const MAIN: &str = r#"
+#include <stdio.h>
+long answer(void);
+long gcd(long, long);
+extern long calls;
+int main(void) {
+    long a = answer();
+    printf("%ld %ld %ld\n", a, gcd(1071, 462), calls);
+    return 0;
+}
+"#;
+
+const SHT_SYMTAB: u32 = 2;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+
+fn object(opt_level: OptLevel) -> Vec<u8> {
+    let options = CompileOptions {
+        opt_level,
+        file_format: FileFormat::Elf64,
+    };
+    compile_to_object(parse(SOURCE), &options)
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn name_at(strtab: &[u8], offset: usize) -> String {
+    let end = strtab[offset..].iter().position(|&b| b == 0).unwrap();
+    String::from_utf8(strtab[offset..offset + end].to_vec()).unwrap()
+}
+
+#[derive(Debug)]
+struct SectionHeader {
+    name: String,
+    kind: u32,
+    flags: u64,
+    offset: usize,
+    size: usize,
+    link: u32,
+    info: u32,
+}
+
+#[derive(Debug, PartialEq)]
+struct Symbol {
+    name: String,
+    binding: u8,
+    kind: u8,
+    visibility: u8,
+    section: u16,
+}
+
+#[derive(Debug, PartialEq)]
+struct Relocation {
+    offset: u64,
+    symbol: String,
+    kind: u32,
+    addend: i64,
+}
+
+/// Just enough of an ELF64 reader to look into the objects we write
+struct ElfFile {
+    bytes: Vec<u8>,
+    sections: Vec<SectionHeader>,
+}
+impl ElfFile {
+    fn parse(bytes: Vec<u8>) -> Self {
+        assert_eq!(bytes[..7], [0x7F, b'E', b'L', b'F', 2, 1, 1]);
+        // Relocatable, x86_64
+        assert_eq!(u16_at(&bytes, 16), 1);
+        assert_eq!(u16_at(&bytes, 18), 62);
+        let headers = u64_at(&bytes, 40) as usize;
+        assert_eq!(u16_at(&bytes, 58), 64);
+        let count = u16_at(&bytes, 60) as usize;
+        let names = u16_at(&bytes, 62) as usize;
+        let header = |i: usize| &bytes[headers + i * 64..headers + (i + 1) * 64];
+        let names = header(names);
+        let names = &bytes[u64_at(names, 24) as usize..][..u64_at(names, 32) as usize];
+        let sections = (0..count)
+            .map(|i| {
+                let header = header(i);
+                SectionHeader {
+                    name: name_at(names, u32_at(header, 0) as usize),
+                    kind: u32_at(header, 4),
+                    flags: u64_at(header, 8),
+                    offset: u64_at(header, 24) as usize,
+                    size: u64_at(header, 32) as usize,
+                    link: u32_at(header, 40),
+                    info: u32_at(header, 44),
+                }
+            })
+            .collect();
+        Self { bytes, sections }
+    }
+    fn section(&self, name: &str) -> &SectionHeader {
+        self.sections.iter().find(|s| s.name == name).unwrap()
+    }
+    fn contents(&self, section: &SectionHeader) -> &[u8] {
+        &self.bytes[section.offset..section.offset + section.size]
+    }
+    fn symbols(&self) -> Vec<Symbol> {
+        let symtab = self.sections.iter().find(|s| s.kind == SHT_SYMTAB).unwrap();
+        let strtab = self.contents(&self.sections[symtab.link as usize]);
+        self.contents(symtab)
+            .chunks(24)
+            .map(|entry| Symbol {
+                name: name_at(strtab, u32_at(entry, 0) as usize),
+                binding: entry[4] >> 4,
+                kind: entry[4] & 0xF,
+                visibility: entry[5],
+                section: u16_at(entry, 6),
+            })
+            .collect()
+    }
+    /// Relocations of the section, with section symbols named after their sections
+    fn relocations(&self, name: &str) -> Vec<Relocation> {
+        let target = self.sections.iter().position(|s| s.name == name).unwrap();
+        let symbols = self.symbols();
+        let symbol_name = |index: usize| match &symbols[index] {
+            symbol if symbol.kind == 3 => self.sections[symbol.section as usize].name.clone(),
+            symbol => symbol.name.clone(),
+        };
+        self.sections
+            .iter()
+            .filter(|s| s.kind == SHT_RELA && s.info as usize == target)
+            .flat_map(|s| self.contents(s).chunks(24))
+            .map(|entry| Relocation {
+                offset: u64_at(entry, 0),
+                symbol: symbol_name((u64_at(entry, 8) >> 32) as usize),
+                kind: u64_at(entry, 8) as u32,
+                addend: u64_at(entry, 16) as i64,
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn sections_are_laid_out() {
+    let elf = ElfFile::parse(object(OptLevel::O1));
+    let names: Vec<_> = elf.sections.iter().map(|s| s.name.as_str()).collect();
+    assert_eq!(
+        names[..5],
+        ["", ".text", ".data", ".rodata", ".bss"],
+        "{names:?}"
+    );
+    assert_eq!(elf.section(".text").flags, 0x6);
+    assert_eq!(elf.section(".data").flags, 0x3);
+    assert_eq!(elf.section(".rodata").flags, 0x2);
+    // `@base`, `@step` and `@scale`
+    assert_eq!(elf.contents(elf.section(".data"))[..8], 40u64.to_le_bytes());
+    assert!(elf.section(".rodata").size >= 6);
+    let bss = elf.section(".bss");
+    assert_eq!(bss.kind, SHT_NOBITS);
+    assert_eq!(bss.size, 8);
+    assert!(elf.section(".rela.text").flags & 0x40 != 0);
+    assert!(names.contains(&".note.GNU-stack"));
+}
+
+#[test]
+fn symbols_honor_linkage() {
+    let elf = ElfFile::parse(object(OptLevel::O1));
+    let symbols = elf.symbols();
+    let symbol = |name: &str| symbols.iter().find(|s| s.name == name).unwrap();
+    // Local symbols come first, as `sh_info` of the symbol table says
+    let symtab = elf.sections.iter().find(|s| s.kind == SHT_SYMTAB).unwrap();
+    for (i, symbol) in symbols.iter().enumerate() {
+        assert_eq!(symbol.binding == 0, i < symtab.info as usize, "{symbol:?}");
+    }
+    let text = 1;
+    assert_eq!((symbol("bump").binding, symbol("bump").kind), (0, 2));
+    assert_eq!(symbol("bump").section, text);
+    assert_eq!((symbol("gcd").binding, symbol("gcd").kind), (1, 2));
+    assert_eq!(symbol("answer").binding, 2);
+    assert_eq!((symbol("calls").binding, symbol("calls").kind), (1, 1));
+    assert_eq!(symbol("calls").section, 4);
+    assert_eq!(symbol("base").section, 2);
+    assert_eq!(symbol("step").binding, 0);
+    assert_eq!(
+        (symbol("scale").binding, symbol("scale").visibility),
+        (2, 2)
+    );
+    assert_eq!(symbol("banner").section, 3);
+    for name in ["puts", "printf"] {
+        assert_eq!((symbol(name).binding, symbol(name).section), (1, 0));
+    }
+    // String literals and labels inside functions stay out of the symbol table
+    assert!(
+        symbols
+            .iter()
+            .all(|s| !s.name.starts_with("__str") && !s.name.contains('.')),
+        "{symbols:?}"
+    );
+}
+
+#[test]
+fn calls_to_externs_are_relocated() {
+    let elf = ElfFile::parse(object(OptLevel::O1));
+    let relocations = elf.relocations(".text");
+    let kinds = |name: &str| -> Vec<u32> {
+        relocations
+            .iter()
+            .filter(|r| r.symbol == name)
+            .map(|r| r.kind)
+            .collect()
+    };
+    const PC32: u32 = 2;
+    const PLT32: u32 = 4;
+    assert_eq!(kinds("puts"), [PLT32, PLT32]);
+    assert_eq!(kinds("printf"), [PLT32]);
+    // The recursive call is a jump, but it may still be overridden from outside
+    assert_eq!(kinds("gcd"), [PLT32]);
+    assert!(kinds(".rodata").iter().all(|&kind| kind == PC32));
+    assert!(kinds("calls").iter().all(|&kind| kind == PC32));
+    assert!(!kinds("calls").is_empty());
+    for relocation in &relocations {
+        assert!(relocation.addend <= -4 || relocation.symbol == ".rodata");
+    }
+    // `@bump` is internal, so calling it needs no relocation
+    assert!(kinds("bump").is_empty());
+}
+
+#[test]
+fn objects_link_with_the_system_compiler() {
+    let dir = std::env::temp_dir().join(format!("madeline-elf-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let main = dir.join("main.c");
+    fs::write(&main, MAIN).unwrap();
+    for (opt_level, suffix) in [
+        (OptLevel::O0, "O0"),
+        (OptLevel::O1, "O1"),
+        (OptLevel::O2, "O2"),
+        (OptLevel::Os, "Os"),
+    ] {
+        let object_path = dir.join(format!("answer-{suffix}.o"));
+        let exe = dir.join(format!("answer-{suffix}"));
+        fs::write(&object_path, object(opt_level)).unwrap();
+        let status = Command::new("cc")
+            .arg("-o")
+            .arg(&exe)
+            .arg(&main)
+            .arg(&object_path)
+            .status();
+        let status = match status {
+            Ok(status) => status,
+            Err(error) if error.kind() == ErrorKind::NotFound => {
+                eprintln!("Skipping, no `cc` to link with");
+                return;
+            }
+            Err(error) => panic!("{error}"),
+        };
+        assert!(status.success(), "Linking failed at {suffix}");
+        let output = Command::new(&exe).output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "hello\nworld\n42 1\n42 21 1\n",
+            "{suffix}"
+        );
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,574 @@
+use std::rc::Rc;
+
+use mir::{
+    generation::platform::x86_64::{
+        encode::{encode, Object, Relocation, RelocationKind},
+        Condition, EvalTreeNode, FloatOp, Instruction, Operand, Section, X64Register, X86WordSize,
+    },
+    ir::{Linkage, SymbolAttrs},
+};
+use X64Register::*;
+
+fn reg(reg: X64Register) -> Operand {
+    Operand::Reg(reg)
+}
+
+fn imm(value: i64) -> Operand {
+    Operand::Im(value.to_be_bytes())
+}
+
+fn num(value: i64) -> EvalTreeNode {
+    EvalTreeNode::Num(value as u64)
+}
+
+fn add(lhs: impl Into<EvalTreeNode>, rhs: impl Into<EvalTreeNode>) -> EvalTreeNode {
+    EvalTreeNode::Add(Box::new(lhs.into()), Box::new(rhs.into()))
+}
+
+fn sub(lhs: impl Into<EvalTreeNode>, rhs: impl Into<EvalTreeNode>) -> EvalTreeNode {
+    EvalTreeNode::Sub(Box::new(lhs.into()), Box::new(rhs.into()))
+}
+
+fn mul(lhs: impl Into<EvalTreeNode>, rhs: impl Into<EvalTreeNode>) -> EvalTreeNode {
+    EvalTreeNode::Mul(Box::new(lhs.into()), Box::new(rhs.into()))
+}
+
+fn label(name: &str) -> EvalTreeNode {
+    EvalTreeNode::Label(Rc::new(name.to_string()))
+}
+
+fn qword(address: impl Into<EvalTreeNode>) -> Operand {
+    Operand::WordPtr(X86WordSize::Qword, address.into())
+}
+
+fn dword(address: impl Into<EvalTreeNode>) -> Operand {
+    Operand::WordPtr(X86WordSize::Dword, address.into())
+}
+
+fn byte(address: impl Into<EvalTreeNode>) -> Operand {
+    Operand::WordPtr(X86WordSize::Byte, address.into())
+}
+
+fn attrs(linkage: Linkage) -> SymbolAttrs {
+    SymbolAttrs {
+        linkage,
+        ..Default::default()
+    }
+}
+
+fn text(instructions: &[Instruction]) -> Vec<u8> {
+    let object = encode(instructions);
+    assert!(object.relocations.is_empty(), "{:?}", object.relocations);
+    object.section(Section::Text).bytes.clone()
+}
+
+/// Check each instruction on its own against its bytes
+fn assert_encodes(cases: &[(Instruction, &[u8])]) {
+    for (instruction, bytes) in cases {
+        assert_eq!(
+            text(std::slice::from_ref(instruction)),
+            *bytes,
+            "{instruction:?}"
+        );
+    }
+}
+
+#[test]
+fn prolog_and_epilog() {
+    let bytes = text(&[
+        Instruction::FnProlog,
+        Instruction::AllocStack(16),
+        Instruction::DeallocStack(16),
+        Instruction::pop_rop(),
+        Instruction::Ret,
+        Instruction::AllocStack(4096),
+    ]);
+    assert_eq!(
+        bytes,
+        [
+            0x55, 0x48, 0x89, 0xE5, 0x48, 0x83, 0xEC, 0x10, 0x48, 0x83, 0xC4, 0x10, 0x5D, 0xC3,
+            0x48, 0x81, 0xEC, 0x00, 0x10, 0x00, 0x00,
+        ]
+    );
+}
+
+#[test]
+fn moves() {
+    assert_encodes(&[
+        (Instruction::Mov(reg(Rax), reg(Rbx)), &[0x48, 0x89, 0xD8]),
+        (Instruction::Mov(reg(R9), reg(Rdx)), &[0x49, 0x89, 0xD1]),
+        (Instruction::Mov(reg(Ecx), reg(R10d)), &[0x44, 0x89, 0xD1]),
+        (Instruction::Mov(reg(Ax), reg(Si)), &[0x66, 0x89, 0xF0]),
+        (Instruction::Mov(reg(Al), reg(Dl)), &[0x88, 0xD0]),
+        // `sil` and `dil` need a REX prefix, or they would be `dh` and `bh`
+        (Instruction::Mov(reg(Sil), reg(Dil)), &[0x40, 0x88, 0xFE]),
+        (
+            Instruction::Mov(reg(R8d), dword(sub(Rbp, num(8)))),
+            &[0x44, 0x8B, 0x45, 0xF8],
+        ),
+        (
+            Instruction::Mov(qword(add(Rsp, num(8))), reg(R12)),
+            &[0x4C, 0x89, 0x64, 0x24, 0x08],
+        ),
+        (
+            Instruction::Mov(byte(R13), reg(Sil)),
+            &[0x41, 0x88, 0x75, 0x00],
+        ),
+        // `mov reg, 0` is written out as a `xor`
+        (Instruction::Mov(reg(Rax), imm(0)), &[0x48, 0x31, 0xC0]),
+        (
+            Instruction::Mov(reg(Rcx), imm(1)),
+            &[0xB9, 0x01, 0x00, 0x00, 0x00],
+        ),
+        (
+            Instruction::Mov(reg(Rax), imm(-1)),
+            &[0x48, 0xC7, 0xC0, 0xFF, 0xFF, 0xFF, 0xFF],
+        ),
+        (
+            Instruction::Mov(reg(R10), imm(0x1122334455667788)),
+            &[0x49, 0xBA, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11],
+        ),
+        (Instruction::Mov(reg(R11b), imm(7)), &[0x41, 0xB3, 0x07]),
+        (
+            Instruction::Mov(dword(sub(Rbp, num(4))), imm(7)),
+            &[0xC7, 0x45, 0xFC, 0x07, 0x00, 0x00, 0x00],
+        ),
+        (
+            Instruction::Mov(qword(Rdi), imm(-2)),
+            &[0x48, 0xC7, 0x07, 0xFE, 0xFF, 0xFF, 0xFF],
+        ),
+        (Instruction::Movzx(reg(Eax), reg(Al)), &[0x0F, 0xB6, 0xC0]),
+        (
+            Instruction::Movzx(reg(Rax), Operand::WordPtr(X86WordSize::Word, Rbx.into())),
+            &[0x48, 0x0F, 0xB7, 0x03],
+        ),
+        (
+            Instruction::Movsx(reg(R8), byte(sub(Rbp, num(1)))),
+            &[0x4C, 0x0F, 0xBE, 0x45, 0xFF],
+        ),
+        (Instruction::Movsx(reg(Rax), reg(Ecx)), &[0x48, 0x63, 0xC1]),
+    ]);
+}
+
+#[test]
+fn addresses() {
+    assert_encodes(&[
+        // `rbp` and `r13` always need a displacement
+        (
+            Instruction::Mov(reg(Rax), qword(Rbp)),
+            &[0x48, 0x8B, 0x45, 0x00],
+        ),
+        // `rsp` and `r12` always need a SIB byte
+        (
+            Instruction::Mov(reg(Rax), qword(R12)),
+            &[0x49, 0x8B, 0x04, 0x24],
+        ),
+        (
+            Instruction::Lea(
+                reg(Rax),
+                Operand::Load(add(sub(Rbp, num(44)), mul(Rax, num(1)))),
+            ),
+            &[0x48, 0x8D, 0x44, 0x05, 0xD4],
+        ),
+        (
+            Instruction::Lea(
+                reg(Rdx),
+                Operand::Load(add(add(R12, mul(Rcx, num(8))), num(300))),
+            ),
+            &[0x49, 0x8D, 0x94, 0xCC, 0x2C, 0x01, 0x00, 0x00],
+        ),
+        (
+            Instruction::Lea(reg(R9), Operand::Load(add(Rbx, mul(R11, num(4))))),
+            &[0x4E, 0x8D, 0x0C, 0x9B],
+        ),
+        // An index without a base takes a 32-bit displacement
+        (
+            Instruction::Lea(reg(Rax), Operand::Load(mul(Rcx, num(4)))),
+            &[0x48, 0x8D, 0x04, 0x8D, 0x00, 0x00, 0x00, 0x00],
+        ),
+        (
+            Instruction::Mov(reg(Rax), qword(sub(Rbp, num(200)))),
+            &[0x48, 0x8B, 0x85, 0x38, 0xFF, 0xFF, 0xFF],
+        ),
+    ]);
+}
+
+#[test]
+fn arithmetic() {
+    assert_encodes(&[
+        (
+            Instruction::Add(reg(Rax), imm(1)),
+            &[0x48, 0x83, 0xC0, 0x01],
+        ),
+        (
+            Instruction::Sub(reg(Rsp), imm(4096)),
+            &[0x48, 0x81, 0xEC, 0x00, 0x10, 0x00, 0x00],
+        ),
+        (
+            Instruction::And(reg(Eax), imm(255)),
+            &[0x81, 0xE0, 0xFF, 0x00, 0x00, 0x00],
+        ),
+        (Instruction::Cmp(byte(Rdi), imm(10)), &[0x80, 0x3F, 0x0A]),
+        (Instruction::Xor(reg(R11), reg(R11)), &[0x4D, 0x31, 0xDB]),
+        (Instruction::Or(reg(Ecx), reg(Edx)), &[0x09, 0xD1]),
+        (
+            Instruction::Add(reg(Rax), qword(sub(Rbp, num(8)))),
+            &[0x48, 0x03, 0x45, 0xF8],
+        ),
+        (
+            Instruction::Sub(qword(sub(Rbp, num(8))), reg(Rcx)),
+            &[0x48, 0x29, 0x4D, 0xF8],
+        ),
+        (Instruction::Cmp(reg(Rax), reg(R11)), &[0x4C, 0x39, 0xD8]),
+        (Instruction::Test(reg(Al), reg(Al)), &[0x84, 0xC0]),
+        (Instruction::Test(reg(Rdi), reg(Rsi)), &[0x48, 0x85, 0xF7]),
+        (
+            Instruction::Test(reg(Ecx), imm(1)),
+            &[0xF7, 0xC1, 0x01, 0x00, 0x00, 0x00],
+        ),
+        (
+            Instruction::Imul(reg(Rax), reg(Rcx)),
+            &[0x48, 0x0F, 0xAF, 0xC1],
+        ),
+        (
+            Instruction::Imul(reg(Rdx), imm(100)),
+            &[0x48, 0x6B, 0xD2, 0x64],
+        ),
+        (
+            Instruction::Imul(reg(Eax), imm(1000)),
+            &[0x69, 0xC0, 0xE8, 0x03, 0x00, 0x00],
+        ),
+        (Instruction::Mul(reg(Rcx)), &[0x48, 0xF7, 0xE1]),
+        (
+            Instruction::ImulWide(qword(sub(Rbp, num(8)))),
+            &[0x48, 0xF7, 0x6D, 0xF8],
+        ),
+        (Instruction::Div(reg(R8)), &[0x49, 0xF7, 0xF0]),
+        (Instruction::Idiv(reg(Ecx)), &[0xF7, 0xF9]),
+        (Instruction::Cqo, &[0x48, 0x99]),
+    ]);
+}
+
+#[test]
+fn shifts() {
+    assert_encodes(&[
+        (Instruction::Shl(reg(Rax), imm(1)), &[0x48, 0xD1, 0xE0]),
+        (
+            Instruction::Shr(reg(Rdx), imm(3)),
+            &[0x48, 0xC1, 0xEA, 0x03],
+        ),
+        (Instruction::Sar(reg(R9d), reg(Cl)), &[0x41, 0xD3, 0xF9]),
+        (
+            Instruction::Shl(byte(sub(Rbp, num(1))), reg(Cl)),
+            &[0xD2, 0x65, 0xFF],
+        ),
+        (
+            Instruction::Sar(reg(Rax), imm(63)),
+            &[0x48, 0xC1, 0xF8, 0x3F],
+        ),
+    ]);
+}
+
+#[test]
+fn conditions() {
+    assert_encodes(&[
+        (
+            Instruction::Setcc(Condition::E, reg(Al)),
+            &[0x0F, 0x94, 0xC0],
+        ),
+        (
+            Instruction::Setcc(Condition::L, reg(Dil)),
+            &[0x40, 0x0F, 0x9C, 0xC7],
+        ),
+        (
+            Instruction::Setcc(Condition::Ae, reg(R10b)),
+            &[0x41, 0x0F, 0x93, 0xC2],
+        ),
+        (
+            Instruction::Setcc(Condition::G, byte(sub(Rbp, num(1)))),
+            &[0x0F, 0x9F, 0x45, 0xFF],
+        ),
+        (
+            Instruction::Setcc(Condition::Np, reg(R11b)),
+            &[0x41, 0x0F, 0x9B, 0xC3],
+        ),
+        (
+            Instruction::Setcc(Condition::P, reg(Al)),
+            &[0x0F, 0x9A, 0xC0],
+        ),
+    ]);
+}
+
+#[test]
+fn float_arithmetic() {
+    let (xmm0, xmm1) = (Operand::Xmm(0), Operand::Xmm(1));
+    assert_encodes(&[
+        (
+            Instruction::FloatArith(FloatOp::Add, X86WordSize::Qword, xmm0.clone(), xmm1.clone()),
+            &[0xF2, 0x0F, 0x58, 0xC1],
+        ),
+        (
+            Instruction::FloatArith(FloatOp::Sub, X86WordSize::Dword, xmm0.clone(), xmm1.clone()),
+            &[0xF3, 0x0F, 0x5C, 0xC1],
+        ),
+        (
+            Instruction::FloatArith(FloatOp::Mul, X86WordSize::Qword, xmm0.clone(), xmm1.clone()),
+            &[0xF2, 0x0F, 0x59, 0xC1],
+        ),
+        (
+            Instruction::FloatArith(FloatOp::Div, X86WordSize::Dword, xmm0.clone(), xmm1.clone()),
+            &[0xF3, 0x0F, 0x5E, 0xC1],
+        ),
+        (
+            Instruction::Ucomis(X86WordSize::Qword, xmm1.clone(), xmm0.clone()),
+            &[0x66, 0x0F, 0x2E, 0xC8],
+        ),
+        (
+            Instruction::Ucomis(X86WordSize::Dword, xmm0, xmm1),
+            &[0x0F, 0x2E, 0xC1],
+        ),
+    ]);
+}
+
+#[test]
+fn vector_moves() {
+    assert_encodes(&[
+        (
+            Instruction::Movq(Operand::Xmm(0), reg(Rax)),
+            &[0x66, 0x48, 0x0F, 0x6E, 0xC0],
+        ),
+        (
+            Instruction::Movq(reg(Rax), Operand::Xmm(1)),
+            &[0x66, 0x48, 0x0F, 0x7E, 0xC8],
+        ),
+        (
+            Instruction::Movd(Operand::Xmm(2), reg(Eax)),
+            &[0x66, 0x0F, 0x6E, 0xD0],
+        ),
+        (
+            Instruction::Movq(qword(sub(Rbp, num(16))), Operand::Xmm(0)),
+            &[0x66, 0x48, 0x0F, 0x7E, 0x45, 0xF0],
+        ),
+        (
+            Instruction::Movq(Operand::Xmm(9), reg(R8)),
+            &[0x66, 0x4D, 0x0F, 0x6E, 0xC8],
+        ),
+        (
+            Instruction::Movq(Operand::Xmm(1), Operand::Xmm(2)),
+            &[0xF3, 0x0F, 0x7E, 0xCA],
+        ),
+        (
+            Instruction::Movaps(Operand::Load(sub(Rbp, num(128))), Operand::Xmm(0)),
+            &[0x0F, 0x29, 0x45, 0x80],
+        ),
+        (
+            Instruction::Movaps(Operand::Xmm(3), Operand::Xmm(12)),
+            &[0x41, 0x0F, 0x28, 0xDC],
+        ),
+    ]);
+}
+
+#[test]
+fn stack() {
+    assert_encodes(&[
+        (Instruction::Push(reg(Rbx)), &[0x53]),
+        (Instruction::Push(reg(R12)), &[0x41, 0x54]),
+        (Instruction::Pop(reg(R15)), &[0x41, 0x5F]),
+        (Instruction::Pop(reg(Rdx)), &[0x5A]),
+        (Instruction::Push(imm(1)), &[0x6A, 0x01]),
+        (
+            Instruction::Push(imm(1000)),
+            &[0x68, 0xE8, 0x03, 0x00, 0x00],
+        ),
+        (
+            Instruction::Push(qword(sub(Rbp, num(8)))),
+            &[0xFF, 0x75, 0xF8],
+        ),
+        (Instruction::Pop(qword(Rax)), &[0x8F, 0x00]),
+    ]);
+}
+
+#[test]
+fn indirect_calls_and_jumps() {
+    assert_encodes(&[
+        (Instruction::Call(reg(R10)), &[0x41, 0xFF, 0xD2]),
+        (
+            Instruction::Call(qword(sub(Rbp, num(8)))),
+            &[0xFF, 0x55, 0xF8],
+        ),
+        (Instruction::TailJmp(reg(R10)), &[0x41, 0xFF, 0xE2]),
+        (
+            Instruction::TailJmp(Operand::Load(sub(Rbp, num(16)))),
+            &[0xFF, 0x65, 0xF0],
+        ),
+    ]);
+}
+
+#[test]
+fn jumps_to_labels() {
+    let bytes = text(&[
+        Instruction::Label("loop".to_string()),
+        Instruction::Jcc(Condition::Ne, "end".to_string()),
+        Instruction::Add(reg(Rax), imm(1)),
+        Instruction::Jmp("loop".to_string()),
+        Instruction::Label("end".to_string()),
+        Instruction::Jcc(Condition::B, "loop".to_string()),
+        Instruction::Ret,
+    ]);
+    assert_eq!(
+        bytes,
+        [
+            // jne end, forward so it's always near
+            0x0F, 0x85, 0x06, 0x00, 0x00, 0x00, //
+            0x48, 0x83, 0xC0, 0x01, //
+            // jmp loop, short since it's back
+            0xEB, 0xF4, //
+            // jb loop
+            0x72, 0xF2, //
+            0xC3,
+        ]
+    );
+}
+
+#[test]
+fn far_backward_jumps_are_near() {
+    let mut instructions = vec![Instruction::Label("start".to_string())];
+    instructions.extend(std::iter::repeat_n(Instruction::Cqo, 100));
+    instructions.push(Instruction::Jmp("start".to_string()));
+    let bytes = text(&instructions);
+    assert_eq!(bytes[200..], [0xE9, 0x33, 0xFF, 0xFF, 0xFF]);
+}
+
+#[test]
+fn calls_to_internal_functions_are_resolved() {
+    let bytes = text(&[
+        Instruction::GlobalLabel(Rc::new("f".to_string()), attrs(Linkage::Internal)),
+        Instruction::Ret,
+        Instruction::GlobalLabel(Rc::new("g".to_string()), attrs(Linkage::Private)),
+        Instruction::Call(Operand::Label("f".to_string())),
+        Instruction::TailJmp(Operand::Label("f".to_string())),
+    ]);
+    assert_eq!(
+        bytes,
+        [
+            0xC3, //
+            0xE8, 0xFA, 0xFF, 0xFF, 0xFF, //
+            0xE9, 0xF5, 0xFF, 0xFF, 0xFF,
+        ]
+    );
+}
+
+#[test]
+fn global_and_extern_symbols_are_relocated() {
+    let object = encode(&[
+        Instruction::Extern(Rc::new("puts".to_string())),
+        Instruction::GlobalLabel(Rc::new("main".to_string()), attrs(Linkage::Export)),
+        Instruction::Call(Operand::Label("puts".to_string())),
+        Instruction::TailJmp(Operand::Label("main".to_string())),
+    ]);
+    assert_eq!(
+        object.section(Section::Text).bytes,
+        [0xE8, 0, 0, 0, 0, 0xE9, 0, 0, 0, 0]
+    );
+    assert_eq!(object.externs, [Rc::new("puts".to_string())]);
+    assert_eq!(
+        object.relocations,
+        [
+            Relocation {
+                section: Section::Text,
+                offset: 1,
+                symbol: Rc::new("puts".to_string()),
+                kind: RelocationKind::Plt32,
+                addend: -4,
+            },
+            Relocation {
+                section: Section::Text,
+                offset: 6,
+                symbol: Rc::new("main".to_string()),
+                kind: RelocationKind::Plt32,
+                addend: -4,
+            },
+        ]
+    );
+}
+
+#[test]
+fn globals_are_rip_relative() {
+    let object = encode(&[
+        Instruction::Lea(reg(Rdi), Operand::Load(label("str"))),
+        // The offset is from the end of the instruction, after the immediate
+        Instruction::Mov(dword(add(label("counter"), num(4))), imm(5)),
+        Instruction::Ret,
+        Instruction::Section(Section::Rodata),
+        Instruction::Label("str".to_string()),
+        Instruction::Bytes(b"hi\0".to_vec()),
+        Instruction::Section(Section::Data),
+        Instruction::Align(4),
+        Instruction::GlobalLabel(Rc::new("counter".to_string()), attrs(Linkage::Export)),
+        Instruction::Bytes(vec![0; 8]),
+    ]);
+    assert_eq!(
+        object.section(Section::Text).bytes,
+        [
+            0x48, 0x8D, 0x3D, 0, 0, 0, 0, //
+            0xC7, 0x05, 0, 0, 0, 0, 0x05, 0x00, 0x00, 0x00, //
+            0xC3,
+        ]
+    );
+    let relocation = |offset, symbol: &str, addend| Relocation {
+        section: Section::Text,
+        offset,
+        symbol: Rc::new(symbol.to_string()),
+        kind: RelocationKind::Pc32,
+        addend,
+    };
+    assert_eq!(
+        object.relocations,
+        [relocation(3, "str", -4), relocation(9, "counter", -4)]
+    );
+}
+
+#[test]
+fn rip_relative_labels_in_the_same_section_are_resolved() {
+    let bytes = text(&[
+        Instruction::Lea(reg(Rax), Operand::Load(label("here"))),
+        Instruction::Label("here".to_string()),
+        Instruction::Ret,
+    ]);
+    assert_eq!(bytes, [0x48, 0x8D, 0x05, 0x00, 0x00, 0x00, 0x00, 0xC3]);
+}
+
+#[test]
+fn sections() {
+    let object: Object = encode(&[
+        Instruction::Ret,
+        Instruction::Align(16),
+        Instruction::Ret,
+        Instruction::Section(Section::Data),
+        Instruction::Bytes(vec![1]),
+        Instruction::Align(8),
+        Instruction::GlobalLabel(Rc::new("x".to_string()), attrs(Linkage::Export)),
+        Instruction::Bytes(vec![2, 3]),
+        Instruction::Section(Section::Bss),
+        Instruction::Reserve(3),
+        Instruction::Align(4),
+        Instruction::Label("y".to_string()),
+        Instruction::Reserve(4),
+    ]);
+    let text = object.section(Section::Text);
+    assert_eq!(text.bytes[..2], [0xC3, 0x90]);
+    assert_eq!(text.bytes[15..], [0x90, 0xC3]);
+    assert_eq!(text.align, 16);
+    let data = object.section(Section::Data);
+    assert_eq!(data.bytes, [1, 0, 0, 0, 0, 0, 0, 0, 2, 3]);
+    assert_eq!(data.align, 8);
+    let bss = object.section(Section::Bss);
+    assert!(bss.bytes.is_empty());
+    assert_eq!((bss.size, bss.align), (8, 4));
+    let offsets: Vec<(&str, Section, usize)> = object
+        .symbols
+        .iter()
+        .map(|symbol| (symbol.name.as_str(), symbol.section, symbol.offset))
+        .collect();
+    assert_eq!(offsets, [("x", Section::Data, 8), ("y", Section::Bss, 4)]);
+}
@@ -0,0 +1,81 @@
+//! `dse`, `gvn` and `licm` share the escape analysis, so they all see through pointers derived
+//! from a slot, and all stop optimizing it once one of those pointers escapes
+
+mod common;
+
+use common::run_passes;
+
+/// Each field of `%1` is only accessed through its own field pointer, or `@g` also gets a
+/// pointer to the second field when `escape` is set
+fn pair(escape: bool, body: &str) -> String {
+    let escape = if escape { "    call @g(ptr %3)\n" } else { "" };
+    format!(
+        "
+type %Pair = {{ i64, i64 }}
+extern @g(ptr)
+extern @h()
+fn @f(i64) {{
+:entry
+    %1 = alloc %Pair
+    %2 = ptr field %Pair %1 $0
+    %3 = ptr field %Pair %1 $1
+{escape}{body}}}
+"
+    )
+}
+
+#[test]
+fn dead_stores_through_field_pointers_are_removed() {
+    let body = "    i64 [%2] = i64 #0
+    i64 [%3] = i64 $2
+    ret i64 #0
+";
+    let removed = run_passes(&pair(false, body), &["dse"]);
+    assert!(!removed.contains(" = i64 #0\n"), "{removed}");
+    assert!(!removed.contains(" = i64 $2\n"), "{removed}");
+
+    let source = pair(true, body);
+    assert_eq!(run_passes(&source, &["dse"]), source.trim_start());
+}
+
+#[test]
+fn loads_through_field_pointers_are_reused_across_calls() {
+    let body = "    i64 [%2] = i64 #0
+    %4 = i64 [%2]
+    call @h()
+    %5 = i64 [%2]
+    ret i64 + i64 %4 i64 %5
+";
+    let reused = run_passes(&pair(false, body), &["gvn"]);
+    assert!(reused.contains("    ret i64 + i64 %4 i64 %4\n"), "{reused}");
+
+    let source = pair(true, body);
+    assert_eq!(run_passes(&source, &["gvn"]), source.trim_start());
+}
+
+#[test]
+fn loads_through_field_pointers_are_hoisted_above_calls() {
+    let body = "    i64 [%3] = i64 #0
+    jmp :header
+:header
+    %4 = i64 phi [:entry i64 $0] [:body i64 %7]
+    %5 = i64 phi [:entry i64 $0] [:body i64 %8]
+    br u8 < i64 %4 i64 $10 :body :exit
+:body
+    call @h()
+    %6 = i64 [%3]
+    %7 = i64 + i64 %4 i64 $1
+    %8 = i64 + i64 %5 i64 %6
+    jmp :header
+:exit
+    ret i64 %5
+";
+    let hoisted = run_passes(&pair(false, body), &["licm"]);
+    assert!(
+        hoisted.contains("    %6 = i64 [%3]\n    jmp :header\n"),
+        "{hoisted}"
+    );
+
+    let source = pair(true, body);
+    assert_eq!(run_passes(&source, &["licm"]), source.trim_start());
+}
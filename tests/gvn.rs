@@ -0,0 +1,177 @@
+//! Global value numbering, checked through the printer
+
+mod common;
+
+use common::run_passes;
+
+/// `%2` is `%1` with its operands the other way around
+#[test]
+fn expressions_in_dominated_blocks_are_replaced() {
+    let source = "
+fn @f(i64 u8) {
+:entry
+    %1 = i64 * i64 #0 i64 $3
+    br u8 #1 :then :done
+:then
+    %2 = i64 * i64 $3 i64 #0
+    %3 = i64 + i64 %1 i64 %2
+    ret i64 %3
+:done
+    ret i64 %1
+}
+";
+    let expected = "\
+fn @f(i64 u8) {
+:entry
+    %1 = i64 * i64 #0 i64 $3
+    br u8 #1 :then :done
+:then
+    %3 = i64 + i64 %1 i64 %1
+    ret i64 %3
+:done
+    ret i64 %1
+}
+";
+    assert_eq!(run_passes(source, &["gvn"]), expected);
+}
+
+/// Neither branch dominates the other, so `%1` isn't computed when `:b` is taken
+#[test]
+fn expressions_in_sibling_branches_are_not_shared() {
+    let source = "
+fn @f(i64 u8) {
+:entry
+    br u8 #1 :a :b
+:a
+    %1 = i64 * i64 #0 i64 $3
+    ret i64 %1
+:b
+    %2 = i64 * i64 #0 i64 $3
+    ret i64 %2
+}
+";
+    assert_eq!(run_passes(source, &["gvn"]), source.trim_start());
+}
+
+/// `:child` is only entered from `:entry`, so nothing can write to `#0` in between
+#[test]
+fn loads_are_reused_in_the_block_and_into_a_single_predecessor_child() {
+    let source = "
+fn @f(ptr u8) {
+:entry
+    %1 = ptr #0
+    %2 = i64 [%1]
+    %3 = i64 [%1]
+    br u8 #1 :child :other
+:child
+    %4 = i64 [%1]
+    ret i64 + i64 %3 i64 %4
+:other
+    ret i64 %2
+}
+";
+    let expected = "\
+fn @f(ptr u8) {
+:entry
+    %1 = ptr #0
+    %2 = i64 [%1]
+    br u8 #1 :child :other
+:child
+    ret i64 + i64 %2 i64 %2
+:other
+    ret i64 %2
+}
+";
+    assert_eq!(run_passes(source, &["gvn"]), expected);
+}
+
+#[test]
+fn loads_are_not_reused_across_a_store_to_the_slot() {
+    let source = "
+fn @f(i64) {
+    %1 = alloc i64
+    i64 [%1] = i64 #0
+    %2 = i64 [%1]
+    i64 [%1] = i64 $1
+    %3 = i64 [%1]
+    ret i64 + i64 %2 i64 %3
+}
+";
+    assert_eq!(run_passes(source, &["gvn"]), source.trim_start());
+}
+
+/// `@h` may write to the slot through the pointer that `@g` kept
+#[test]
+fn loads_are_not_reused_across_a_call_once_the_slot_escapes() {
+    let body = "
+    %2 = i64 [%1]
+    call @h()
+    %3 = i64 [%1]
+    ret i64 + i64 %2 i64 %3
+}
+";
+    let source = format!(
+        "
+extern @g(ptr)
+extern @h()
+fn @f(i64) {{
+    %1 = alloc i64
+    i64 [%1] = i64 #0
+    call @g(ptr %1){body}"
+    );
+    assert_eq!(run_passes(&source, &["gvn"]), source.trim_start());
+
+    let local = format!(
+        "
+extern @h()
+fn @f(i64) {{
+    %1 = alloc i64
+    i64 [%1] = i64 #0{body}"
+    );
+    let reused = run_passes(&local, &["gvn"]);
+    assert!(reused.contains("    ret i64 + i64 %2 i64 %2\n"), "{reused}");
+}
+
+/// `:join` is entered from two blocks, the loads aren't carried through either of them
+#[test]
+fn loads_are_not_reused_into_a_join_block() {
+    let source = "
+fn @f(ptr u8) {
+:entry
+    %1 = ptr #0
+    %2 = i64 [%1]
+    br u8 #1 :a :b
+:a
+    jmp :join
+:b
+    jmp :join
+:join
+    %3 = i64 [%1]
+    ret i64 + i64 %2 i64 %3
+}
+";
+    assert_eq!(run_passes(source, &["gvn"]), source.trim_start());
+}
+
+/// `%3` reads only the low half of what `%2` does
+#[test]
+fn loads_of_different_types_are_not_merged() {
+    let source = "
+fn @f(ptr) {
+    %1 = ptr #0
+    %2 = i64 [%1]
+    %3 = i32 [%1]
+    %4 = i64 [%1]
+    ret i64 + i64 %2 i64 %4
+}
+";
+    let expected = "\
+fn @f(ptr) {
+    %1 = ptr #0
+    %2 = i64 [%1]
+    %3 = i32 [%1]
+    ret i64 + i64 %2 i64 %2
+}
+";
+    assert_eq!(run_passes(source, &["gvn"]), expected);
+}
@@ -0,0 +1,147 @@
+//! Function inlining, checked through the printer
+
+mod common;
+
+use common::run_passes;
+
+/// The copies of the callee get their own labels and vregs, and the arguments replace the `#n`s
+#[test]
+fn labels_and_vregs_of_the_callee_are_renamed() {
+    let source = "
+fn @abs(i64) {
+:start
+    %1 = u8 < i64 #0 i64 $0
+    br u8 %1 :negative :done
+:negative
+    %2 = i64 - i64 $0 i64 #0
+    ret i64 %2
+:done
+    ret i64 #0
+}
+fn @f(i64 i64) {
+:start
+    %1 = i64 call @abs(i64 #0)
+    %2 = i64 call @abs(i64 #1)
+    br u8 < i64 %1 i64 %2 :negative :done
+:negative
+    ret i64 %2
+:done
+    ret i64 %1
+}
+";
+    let expected = "\
+fn @abs(i64) {
+:start
+    %1 = u8 < i64 #0 i64 $0
+    br u8 %1 :negative :done
+:negative
+    %2 = i64 - i64 $0 i64 #0
+    ret i64 %2
+:done
+    ret i64 #0
+}
+fn @f(i64 i64) {
+:start
+    jmp :abs.start.1
+:abs.start.1
+    %3 = u8 < i64 #0 i64 $0
+    br u8 %3 :abs.negative.2 :abs.done.3
+:abs.negative.2
+    %4 = i64 - i64 $0 i64 #0
+    jmp :abs.ret.0
+:abs.done.3
+    jmp :abs.ret.0
+:abs.ret.0
+    %1 = i64 phi [:abs.negative.2 i64 %4] [:abs.done.3 i64 #0]
+    jmp :abs.start.5
+:abs.start.5
+    %5 = u8 < i64 #1 i64 $0
+    br u8 %5 :abs.negative.6 :abs.done.7
+:abs.negative.6
+    %6 = i64 - i64 $0 i64 #1
+    jmp :abs.ret.4
+:abs.done.7
+    jmp :abs.ret.4
+:abs.ret.4
+    %2 = i64 phi [:abs.negative.6 i64 %6] [:abs.done.7 i64 #1]
+    br u8 < i64 %1 i64 %2 :negative :done
+:negative
+    ret i64 %2
+:done
+    ret i64 %1
+}
+";
+    assert_eq!(run_passes(source, &["inline"]), expected);
+}
+
+/// Fresh labels skip the ones the caller already has, and recursive functions and functions
+/// with a `tail call` are not inlined
+#[test]
+fn renamed_labels_do_not_collide_with_the_caller() {
+    let source = "
+fn @twice(i64) {
+    %1 = i64 + i64 #0 i64 #0
+    ret i64 %1
+}
+fn @g(i64) {
+:abs.ret.0
+    %1 = i64 call @twice(i64 #0)
+    jmp :twice.ret.0
+:twice.ret.0
+    ret i64 %1
+}
+fn @rec(i64) {
+    br u8 == i64 #0 i64 $0 :done :more
+:more
+    %1 = i64 - i64 #0 i64 $1
+    %2 = i64 call @rec(i64 %1)
+    ret i64 %2
+:done
+    ret i64 $0
+}
+fn @tail(i64) {
+    ret i64 tail call @twice(i64 #0)
+}
+fn @h(i64) {
+    %1 = i64 call @rec(i64 #0)
+    %2 = i64 call @tail(i64 %1)
+    ret i64 %2
+}
+";
+    let expected = "\
+fn @twice(i64) {
+    %1 = i64 + i64 #0 i64 #0
+    ret i64 %1
+}
+fn @g(i64) {
+:abs.ret.0
+    jmp :twice.bb.0.2
+:twice.bb.0.2
+    %2 = i64 + i64 #0 i64 #0
+    jmp :twice.ret.1
+:twice.ret.1
+    %1 = i64 %2
+    jmp :twice.ret.0
+:twice.ret.0
+    ret i64 %1
+}
+fn @rec(i64) {
+    br u8 == i64 #0 i64 $0 :done :more
+:more
+    %1 = i64 - i64 #0 i64 $1
+    %2 = i64 call @rec(i64 %1)
+    ret i64 %2
+:done
+    ret i64 $0
+}
+fn @tail(i64) {
+    ret i64 tail call @twice(i64 #0)
+}
+fn @h(i64) {
+    %1 = i64 call @rec(i64 #0)
+    %2 = i64 call @tail(i64 %1)
+    ret i64 %2
+}
+";
+    assert_eq!(run_passes(source, &["inline"]), expected);
+}
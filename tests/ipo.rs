@@ -0,0 +1,186 @@
+//! The call graph, and the interprocedural passes that use it
+
+mod common;
+
+use std::rc::Rc;
+
+use common::{parse, run_passes};
+use mir::analysis::callgraph::CallGraph;
+
+/// Node of the function `name`
+fn node(graph: &CallGraph, name: &str) -> usize {
+    graph
+        .node_of(&Rc::new(name.to_string()))
+        .unwrap_or_else(|| panic!("No function `@{name}`"))
+}
+
+/// `@even` and `@odd` call each other, `@fact` calls itself, and `@main` calls `@even`, `@fact`
+/// and `@square`
+const RECURSION: &str = "
+fn @even(i64) {
+:entry
+    br u8 == i64 #0 i64 $0 :yes :no
+:yes
+    ret u8 $1
+:no
+    ret u8 call @odd(i64 - i64 #0 i64 $1)
+}
+fn @odd(i64) {
+:entry
+    br u8 == i64 #0 i64 $0 :yes :no
+:yes
+    ret u8 $0
+:no
+    ret u8 call @even(i64 - i64 #0 i64 $1)
+}
+fn @fact(i64) {
+:entry
+    br u8 == i64 #0 i64 $0 :yes :no
+:yes
+    ret i64 $1
+:no
+    ret i64 * i64 #0 i64 call @fact(i64 - i64 #0 i64 $1)
+}
+fn @square(i64) {
+    ret i64 * i64 #0 i64 #0
+}
+fn @main() {
+    %1 = u8 call @even(i64 $4)
+    %2 = i64 call @fact(i64 $5)
+    ret i64 call @square(i64 %2)
+}
+";
+
+#[test]
+fn mutual_recursion_is_one_component() {
+    let graph = CallGraph::new(&parse(RECURSION));
+    let [even, odd, fact, square, main] =
+        ["even", "odd", "fact", "square", "main"].map(|name| node(&graph, name));
+    assert_eq!(graph.scc_of(even), graph.scc_of(odd));
+    let mut pair = vec![even, odd];
+    pair.sort();
+    assert_eq!(graph.sccs[graph.scc_of(even)], pair);
+    for node in [fact, square, main] {
+        assert_eq!(graph.sccs[graph.scc_of(node)], [node]);
+    }
+    assert_eq!(graph.sccs.len(), 4);
+    assert_eq!(graph.callees[fact], [fact]);
+    assert_eq!(graph.callers[square], [main]);
+}
+
+#[test]
+fn recursive_functions() {
+    let graph = CallGraph::new(&parse(RECURSION));
+    let recursive = |name| graph.is_recursive(node(&graph, name));
+    assert!(recursive("even"));
+    assert!(recursive("odd"));
+    assert!(recursive("fact"));
+    assert!(!recursive("square"));
+    assert!(!recursive("main"));
+}
+
+#[test]
+fn callees_come_before_their_callers_bottom_up() {
+    let graph = CallGraph::new(&parse(RECURSION));
+    let order: Vec<usize> = graph.bottom_up().collect();
+    let position = |name| {
+        let node = node(&graph, name);
+        order.iter().position(|&n| n == node).unwrap()
+    };
+    assert_eq!(order.len(), graph.len());
+    for callee in ["even", "odd", "fact", "square"] {
+        assert!(position(callee) < position("main"), "{callee} in {order:?}");
+    }
+}
+
+#[test]
+fn taking_the_address_of_a_function_is_not_a_call() {
+    let graph = CallGraph::new(&parse(
+        "
+extern @apply(ptr)
+fn @callback() {
+    ret
+}
+fn @main() {
+    call @apply(ptr @callback)
+    ret
+}
+",
+    ));
+    let (callback, main) = (node(&graph, "callback"), node(&graph, "main"));
+    assert!(graph.callees[main].is_empty());
+    assert_eq!(graph.address_refs[main], [callback]);
+    assert!(graph.address_taken[callback]);
+    assert!(!graph.address_taken[main]);
+}
+
+/// `@callback` is only reachable through the pointer passed to `@apply`, `@dead_taker` takes
+/// the address of `@dead_callback` but can't be called itself
+#[test]
+fn internal_functions_whose_address_is_taken_are_kept() {
+    let source = "
+extern @apply(ptr)
+internal fn @callback() {
+    ret
+}
+internal fn @dead_callback() {
+    ret
+}
+internal fn @dead_taker() {
+    call @apply(ptr @dead_callback)
+    ret
+}
+fn @main() {
+    call @apply(ptr @callback)
+    ret
+}
+";
+    let expected = "\
+extern @apply(ptr)
+internal fn @callback() {
+    ret
+}
+fn @main() {
+    call @apply(ptr @callback)
+    ret
+}
+";
+    assert_eq!(run_passes(source, &["global-dce"]), expected);
+}
+
+/// `@callback` may be called through the pointer with any argument, `@helper` is only called
+/// with `$1`
+#[test]
+fn constants_are_not_propagated_into_functions_whose_address_is_taken() {
+    let source = "
+extern @apply(ptr i64)
+internal fn @callback(i64) {
+    ret i64 * i64 #0 i64 $2
+}
+internal fn @helper(i64) {
+    ret i64 + i64 #0 i64 $1
+}
+fn @main() {
+    %1 = i64 call @helper(i64 $1)
+    %2 = i64 call @callback(i64 $3)
+    call @apply(ptr @callback i64 %1)
+    ret i64 %2
+}
+";
+    let expected = "\
+extern @apply(ptr i64)
+internal fn @callback(i64) {
+    ret i64 * i64 #0 i64 $2
+}
+internal fn @helper(i64) {
+    ret i64 + i64 $1 i64 $1
+}
+fn @main() {
+    %1 = i64 call @helper(i64 $1)
+    %2 = i64 call @callback(i64 $3)
+    call @apply(ptr @callback i64 %1)
+    ret i64 %2
+}
+";
+    assert_eq!(run_passes(source, &["const-args"]), expected);
+}
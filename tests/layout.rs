@@ -0,0 +1,77 @@
+//! C-like layouts of struct and array types
+
+mod common;
+
+use std::rc::Rc;
+
+use common::parse;
+use mir::ir::{DataType, Type, TypeDefs};
+
+const TYPES: &str = "
+type %Padded = { i8, i32, i8, i64, i16 }
+type %Tail = { i64, i8 }
+type %Pair = { i32, i8 }
+type %Nested = { i8, [3 x %Pair], i16 }
+type %Empty = { i8, [0 x i64] }
+";
+
+fn named(name: &str) -> Type {
+    Type::Struct(Rc::new(name.to_string()))
+}
+
+#[test]
+fn fields_are_aligned_with_padding_in_between() {
+    let layout = TypeDefs::from_ir(&parse(TYPES)).layout(&named("Padded"), 8);
+    assert_eq!(layout.field_offsets, [0, 4, 8, 16, 24]);
+    assert_eq!(layout.align, 8);
+    // 26 bytes of fields, rounded up to the alignment
+    assert_eq!(layout.size, 32);
+}
+
+/// Otherwise the `i64` of the second element of an array would be misaligned
+#[test]
+fn size_is_rounded_up_to_the_alignment() {
+    let layout = TypeDefs::from_ir(&parse(TYPES)).layout(&named("Tail"), 8);
+    assert_eq!(layout.field_offsets, [0, 8]);
+    assert_eq!((layout.size, layout.align), (16, 8));
+}
+
+#[test]
+fn arrays_of_structs_are_laid_out_element_after_element() {
+    let type_defs = TypeDefs::from_ir(&parse(TYPES));
+    let pair = type_defs.layout(&named("Pair"), 8);
+    assert_eq!((pair.size, pair.align), (8, 4));
+
+    let array = Type::Array(Box::new(named("Pair")), 3);
+    let layout = type_defs.layout(&array, 8);
+    assert_eq!((layout.size, layout.align), (24, 4));
+    assert!(layout.field_offsets.is_empty());
+    assert_eq!(type_defs.offset_of(&array, 2, 8), 16);
+
+    let nested = type_defs.layout(&named("Nested"), 8);
+    assert_eq!(nested.field_offsets, [0, 4, 28]);
+    assert_eq!((nested.size, nested.align), (32, 4));
+    assert_eq!(type_defs.offset_of(&named("Nested"), 2, 8), 28);
+}
+
+/// A zero-length array takes no space, but still aligns what comes after it
+#[test]
+fn zero_length_arrays_have_no_size() {
+    let type_defs = TypeDefs::from_ir(&parse(TYPES));
+    let array = type_defs.layout(&Type::Array(Box::new(Type::Scalar(DataType::I64)), 0), 8);
+    assert_eq!((array.size, array.align), (0, 8));
+
+    let layout = type_defs.layout(&named("Empty"), 8);
+    assert_eq!(layout.field_offsets, [0, 8]);
+    assert_eq!((layout.size, layout.align), (8, 8));
+}
+
+#[test]
+fn pointers_are_the_size_of_a_word() {
+    let type_defs = TypeDefs::default();
+    let ptr = Type::Scalar(DataType::Ptr);
+    assert_eq!(type_defs.layout(&ptr, 8).size, 8);
+    assert_eq!(type_defs.layout(&ptr, 4).size, 4);
+    let array = Type::Array(Box::new(ptr), 3);
+    assert_eq!(type_defs.offset_of(&array, 2, 4), 8);
+}
@@ -0,0 +1,219 @@
+//! Loop-invariant code motion and loop unrolling, checked through the printer
+
+mod common;
+
+use common::run_passes;
+
+/// The load and the multiplication by an argument are the same on every iteration
+#[test]
+fn invariant_loads_and_arithmetic_are_hoisted() {
+    let source = "
+fn @scaled_sum(ptr i64) {
+:entry
+    %1 = ptr #0
+    jmp :header
+:header
+    %2 = i64 phi [:entry i64 $0] [:body i64 %6]
+    %3 = i64 phi [:entry i64 $0] [:body i64 %7]
+    %4 = i64 [%1]
+    br u8 < i64 %2 i64 #1 :body :exit
+:body
+    %5 = i64 * i64 %4 i64 #1
+    %6 = i64 + i64 %2 i64 $1
+    %7 = i64 + i64 %3 i64 %5
+    jmp :header
+:exit
+    ret i64 %3
+}
+";
+    let expected = "\
+fn @scaled_sum(ptr i64) {
+:entry
+    %1 = ptr #0
+    %4 = i64 [%1]
+    %5 = i64 * i64 %4 i64 #1
+    jmp :header
+:header
+    %2 = i64 phi [:entry i64 $0] [:body i64 %6]
+    %3 = i64 phi [:entry i64 $0] [:body i64 %7]
+    br u8 < i64 %2 i64 #1 :body :exit
+:body
+    %6 = i64 + i64 %2 i64 $1
+    %7 = i64 + i64 %3 i64 %5
+    jmp :header
+:exit
+    ret i64 %3
+}
+";
+    assert_eq!(run_passes(source, &["licm"]), expected);
+}
+
+/// `#0` and `#1` may point to the same memory, so the load sees the store of the last iteration
+#[test]
+fn loads_are_not_hoisted_above_a_store_that_may_alias() {
+    let source = "
+fn @store(ptr ptr i64) {
+:entry
+    %1 = ptr #0
+    %2 = ptr #1
+    jmp :header
+:header
+    %3 = i64 phi [:entry i64 $0] [:body i64 %6]
+    %4 = i64 phi [:entry i64 $0] [:body i64 %7]
+    %5 = i64 [%1]
+    br u8 < i64 %3 i64 #2 :body :exit
+:body
+    i64 [%2] = i64 %3
+    %6 = i64 + i64 %3 i64 $1
+    %7 = i64 + i64 %4 i64 %5
+    jmp :header
+:exit
+    ret i64 %4
+}
+";
+    assert_eq!(run_passes(source, &["licm"]), source.trim_start());
+}
+
+/// The callee may write to what `#0` points to
+#[test]
+fn loads_are_not_hoisted_above_a_call() {
+    let source = "
+extern @touch()
+fn @call(ptr i64) {
+:entry
+    %1 = ptr #0
+    jmp :header
+:header
+    %2 = i64 phi [:entry i64 $0] [:body i64 %5]
+    %3 = i64 phi [:entry i64 $0] [:body i64 %6]
+    %4 = i64 [%1]
+    br u8 < i64 %2 i64 #1 :body :exit
+:body
+    call @touch()
+    %5 = i64 + i64 %2 i64 $1
+    %6 = i64 + i64 %3 i64 %4
+    jmp :header
+:exit
+    ret i64 %3
+}
+";
+    assert_eq!(run_passes(source, &["licm"]), source.trim_start());
+}
+
+/// Ten iterations don't fit in a full unroll, so two are peeled off in front of a loop that does
+/// four per trip and checks at 2, 6 and 10
+#[test]
+fn left_over_iterations_are_peeled_before_a_partially_unrolled_loop() {
+    let source = "
+extern @use(i64)
+fn @sum_of_squares() {
+:entry
+    jmp :header
+:header
+    %1 = i64 phi [:entry i64 $0] [:body i64 %4]
+    %2 = i64 phi [:entry i64 $0] [:body i64 %5]
+    br u8 < i64 %1 i64 $10 :body :exit
+:body
+    %3 = i64 * i64 %1 i64 %1
+    %4 = i64 + i64 %1 i64 $1
+    %5 = i64 + i64 %2 i64 %3
+    jmp :header
+:exit
+    call @use(i64 %2)
+    ret
+}
+";
+    let expected = "\
+extern @use(i64)
+fn @sum_of_squares() {
+:entry
+    jmp :header.unroll.0
+:header.unroll.0
+    %6 = i64 $0
+    %7 = i64 $0
+    jmp :body.unroll.1
+:body.unroll.1
+    %8 = i64 * i64 %6 i64 %6
+    %9 = i64 + i64 %6 i64 $1
+    %10 = i64 + i64 %7 i64 %8
+    jmp :header.unroll.2
+:header.unroll.2
+    %11 = i64 %9
+    %12 = i64 %10
+    jmp :body.unroll.3
+:body.unroll.3
+    %13 = i64 * i64 %11 i64 %11
+    %14 = i64 + i64 %11 i64 $1
+    %15 = i64 + i64 %12 i64 %13
+    jmp :header
+:header
+    %1 = i64 phi [:body.unroll.3 i64 %14] [:body.unroll.9 i64 %29]
+    %2 = i64 phi [:body.unroll.3 i64 %15] [:body.unroll.9 i64 %30]
+    br u8 < i64 %1 i64 $10 :body :exit
+:body
+    %3 = i64 * i64 %1 i64 %1
+    %4 = i64 + i64 %1 i64 $1
+    %5 = i64 + i64 %2 i64 %3
+    jmp :header.unroll.4
+:header.unroll.4
+    %16 = i64 %4
+    %17 = i64 %5
+    jmp :body.unroll.5
+:body.unroll.5
+    %18 = i64 * i64 %16 i64 %16
+    %19 = i64 + i64 %16 i64 $1
+    %20 = i64 + i64 %17 i64 %18
+    jmp :header.unroll.6
+:header.unroll.6
+    %21 = i64 %19
+    %22 = i64 %20
+    jmp :body.unroll.7
+:body.unroll.7
+    %23 = i64 * i64 %21 i64 %21
+    %24 = i64 + i64 %21 i64 $1
+    %25 = i64 + i64 %22 i64 %23
+    jmp :header.unroll.8
+:header.unroll.8
+    %26 = i64 %24
+    %27 = i64 %25
+    jmp :body.unroll.9
+:body.unroll.9
+    %28 = i64 * i64 %26 i64 %26
+    %29 = i64 + i64 %26 i64 $1
+    %30 = i64 + i64 %27 i64 %28
+    jmp :header
+:exit
+    call @use(i64 %2)
+    ret
+}
+";
+    assert_eq!(run_passes(source, &["unroll"]), expected);
+}
+
+/// Twelve iterations divide evenly, so nothing is peeled
+#[test]
+fn loops_that_divide_evenly_are_not_peeled() {
+    let source = "
+extern @use(i64)
+fn @sum_of_squares() {
+:entry
+    jmp :header
+:header
+    %1 = i64 phi [:entry i64 $0] [:body i64 %4]
+    %2 = i64 phi [:entry i64 $0] [:body i64 %5]
+    br u8 < i64 %1 i64 $12 :body :exit
+:body
+    %3 = i64 * i64 %1 i64 %1
+    %4 = i64 + i64 %1 i64 $1
+    %5 = i64 + i64 %2 i64 %3
+    jmp :header
+:exit
+    call @use(i64 %2)
+    ret
+}
+";
+    let output = run_passes(source, &["unroll"]);
+    assert!(output.contains(":entry\n    jmp :header\n"), "{output}");
+    assert_eq!(output.matches("br ").count(), 1, "{output}");
+    assert_eq!(output.matches(" * ").count(), 4, "{output}");
+}
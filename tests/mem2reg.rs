@@ -0,0 +1,66 @@
+//! Promotion of stack slots into vregs, checked through the printer
+
+mod common;
+
+use common::run_passes;
+
+#[test]
+fn fields_of_a_struct_become_vregs() {
+    let source = "
+type %Pair = { i64, i64 }
+fn @f(i64) {
+    %1 = alloc %Pair
+    %2 = ptr field %Pair %1 $0
+    %3 = ptr field %Pair %1 $1
+    i64 [%2] = i64 #0
+    i64 [%3] = i64 $2
+    ret i64 + i64 [%2] i64 [%3]
+}
+";
+    let expected = "\
+type %Pair = { i64, i64 }
+fn @f(i64) {
+:bb.0
+    %6 = i64 #0
+    %7 = i64 $2
+    ret i64 + i64 %6 i64 %7
+}
+";
+    assert_eq!(run_passes(source, &["mem2reg"]), expected);
+}
+
+/// `@g` may read or write either field through the pointer to the second one
+#[test]
+fn slots_whose_address_is_passed_to_a_call_stay_in_memory() {
+    let source = "
+type %Pair = { i64, i64 }
+extern @g(ptr)
+fn @f(i64) {
+:bb.0
+    %1 = alloc %Pair
+    %2 = ptr field %Pair %1 $0
+    %3 = ptr field %Pair %1 $1
+    i64 [%2] = i64 #0
+    i64 [%3] = i64 $2
+    call @g(ptr %3)
+    ret i64 + i64 [%2] i64 [%3]
+}
+";
+    assert_eq!(run_passes(source, &["mem2reg"]), source.trim_start());
+}
+
+/// The slot can be written through the stored pointer once the function returns to its caller
+#[test]
+fn slots_whose_address_is_stored_stay_in_memory() {
+    let source = "
+fn @f(ptr i64) {
+:bb.0
+    %1 = ptr #0
+    %2 = alloc i64
+    i64 [%2] = i64 #1
+    ptr [%1] = ptr %2
+    ret i64 [%2]
+}
+";
+    assert_eq!(run_passes(source, &["mem2reg"]), source.trim_start());
+}
@@ -0,0 +1,108 @@
+//! Putting passes together with `PipelineBuilder`
+
+mod common;
+
+use std::{cell::RefCell, rc::Rc};
+
+use common::parse;
+use mir::{
+    ir::{DataType, Instruction, TopLevel},
+    pass::{Function, FunctionPass, Pass, Pipeline},
+    printer::print_program,
+    transform::{dce::Dce, sccp::Sccp},
+};
+
+const SOURCE: &str = "
+fn @f() {
+    %1 = i64 + i64 $1 i64 $2
+    %2 = i64 * i64 %1 i64 $3
+    ret i64 %1
+}
+";
+
+/// Records its name into `log` every time it's run
+struct Record {
+    name: &'static str,
+    log: Rc<RefCell<Vec<&'static str>>>,
+}
+impl Pass for Record {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+    fn run(&mut self, _: &mut Vec<TopLevel>) {
+        self.log.borrow_mut().push(self.name);
+    }
+}
+
+#[test]
+fn passes_run_in_the_order_they_are_added() {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let record = |name| Record {
+        name,
+        log: Rc::clone(&log),
+    };
+    let mut pipeline = Pipeline::builder()
+        .pass(record("first"))
+        .boxed_pass(Box::new(record("second")))
+        .pass(record("third"))
+        .pass(record("first"))
+        .build();
+    assert_eq!(pipeline.pass_names(), ["first", "second", "third", "first"]);
+    pipeline.run(&mut parse(SOURCE));
+    assert_eq!(*log.borrow(), ["first", "second", "third", "first"]);
+}
+
+#[test]
+fn ir_is_only_printed_after_the_named_pass() {
+    let mut after_sccp = parse(SOURCE);
+    Pipeline::builder()
+        .pass(Sccp)
+        .build()
+        .run_with_output(&mut after_sccp, &mut Vec::new());
+
+    let mut program = parse(SOURCE);
+    let mut output = Vec::<u8>::new();
+    Pipeline::builder()
+        .pass(Sccp)
+        .pass(Dce)
+        .print_after("sccp")
+        .build()
+        .run_with_output(&mut program, &mut output);
+    let expected = format!("\\ IR after `sccp`\n{}", print_program(&after_sccp));
+    assert_eq!(String::from_utf8(output).unwrap(), expected);
+    // `dce` still ran, but wasn't printed
+    assert_ne!(program, after_sccp);
+}
+
+/// Replaces the returned value with an undefined vreg
+struct Break;
+impl FunctionPass for Break {
+    fn name(&self) -> &'static str {
+        "break"
+    }
+    fn run_on_function(&mut self, function: Function) {
+        let ret = function.body.last_mut().unwrap();
+        *ret = Instruction::Ret(Some(Box::new(Instruction::Reg(DataType::I64, 99))));
+    }
+}
+
+#[test]
+#[should_panic(expected = "IR is invalid after pass `break`")]
+fn passes_that_break_the_ir_are_stopped() {
+    Pipeline::builder()
+        .pass(Break)
+        .pass(Dce)
+        .build()
+        .run(&mut parse(SOURCE));
+}
+
+#[test]
+fn broken_ir_goes_through_without_verify_each() {
+    let mut program = parse(SOURCE);
+    Pipeline::builder()
+        .pass(Break)
+        .verify_each(false)
+        .build()
+        .run(&mut program);
+    assert!(print_program(&program).contains("    ret i64 %99\n"));
+}
@@ -0,0 +1,204 @@
+//! Peephole rules on short instruction lists, the ones that fire and the ones that must not
+
+use mir::generation::platform::x86_64::{
+    peephole::peephole, EvalTreeNode, Instruction, Operand, X64Register, X86WordSize,
+};
+use Instruction::*;
+use X64Register::*;
+
+fn reg(reg: X64Register) -> Operand {
+    Operand::Reg(reg)
+}
+
+fn imm(value: i64) -> Operand {
+    Operand::Im(value.to_be_bytes())
+}
+
+fn stack(offset: u64) -> EvalTreeNode {
+    EvalTreeNode::Sub(
+        Box::new(EvalTreeNode::Reg(Rbp)),
+        Box::new(EvalTreeNode::Num(offset)),
+    )
+}
+
+/// The address 8 bytes above `reg`
+fn above(reg: X64Register) -> EvalTreeNode {
+    EvalTreeNode::Add(
+        Box::new(EvalTreeNode::Reg(reg)),
+        Box::new(EvalTreeNode::Num(8)),
+    )
+}
+
+fn qword(address: EvalTreeNode) -> Operand {
+    Operand::WordPtr(X86WordSize::Qword, address)
+}
+
+fn dword(address: EvalTreeNode) -> Operand {
+    Operand::WordPtr(X86WordSize::Dword, address)
+}
+
+fn word(address: EvalTreeNode) -> Operand {
+    Operand::WordPtr(X86WordSize::Word, address)
+}
+
+#[track_caller]
+fn assert_peephole(before: Vec<Instruction>, after: Vec<Instruction>) {
+    let mut code = before;
+    peephole(&mut code);
+    assert_eq!(code, after);
+}
+
+/// Left as is
+#[track_caller]
+fn assert_unchanged(code: Vec<Instruction>) {
+    assert_peephole(code.clone(), code);
+}
+
+#[test]
+fn compare_with_zero_becomes_test() {
+    assert_peephole(vec![Cmp(reg(Rcx), imm(0))], vec![Test(reg(Rcx), reg(Rcx))]);
+    assert_unchanged(vec![Cmp(reg(Rcx), imm(1))]);
+    assert_unchanged(vec![Cmp(qword(stack(8)), imm(0))]);
+}
+
+#[test]
+fn self_moves_are_removed_except_32_bit_ones() {
+    assert_peephole(vec![Mov(reg(Rax), reg(Rax)), Ret], vec![Ret]);
+    assert_peephole(vec![Mov(reg(Ax), reg(Ax)), Ret], vec![Ret]);
+    assert_peephole(vec![Mov(reg(Al), reg(Al)), Ret], vec![Ret]);
+    // Clears the upper half of `rax`
+    assert_unchanged(vec![Mov(reg(Eax), reg(Eax)), Ret]);
+}
+
+#[test]
+fn moving_a_value_back_is_removed() {
+    assert_peephole(
+        vec![Mov(reg(Rax), reg(Rbx)), Mov(reg(Rbx), reg(Rax))],
+        vec![Mov(reg(Rax), reg(Rbx))],
+    );
+    assert_peephole(
+        vec![
+            Mov(qword(stack(8)), reg(Rax)),
+            Mov(reg(Rax), qword(stack(8))),
+        ],
+        vec![Mov(qword(stack(8)), reg(Rax))],
+    );
+    // Clears the upper half of `rbx`
+    assert_unchanged(vec![Mov(reg(Eax), reg(Ebx)), Mov(reg(Ebx), reg(Eax))]);
+    assert_unchanged(vec![
+        Mov(dword(stack(8)), reg(Eax)),
+        Mov(reg(Eax), dword(stack(8))),
+    ]);
+    // The first `mov` changes `rax`, so `[rax]` is somewhere else the second time
+    let at_rax = || qword(EvalTreeNode::Reg(Rax));
+    assert_unchanged(vec![Mov(reg(Rax), at_rax()), Mov(at_rax(), reg(Rax))]);
+}
+
+#[test]
+fn overwritten_moves_are_removed() {
+    assert_peephole(
+        vec![Mov(reg(Rax), imm(1)), Mov(reg(Rax), reg(Rcx))],
+        vec![Mov(reg(Rax), reg(Rcx))],
+    );
+    assert_peephole(
+        vec![Mov(qword(stack(8)), reg(Rax)), Mov(qword(stack(8)), imm(2))],
+        vec![Mov(qword(stack(8)), imm(2))],
+    );
+    // The second `mov` reads what the first one wrote, through any part of the register
+    assert_unchanged(vec![
+        Mov(reg(Rax), imm(1)),
+        Mov(reg(Rax), qword(above(Rax))),
+    ]);
+    assert_unchanged(vec![
+        Mov(reg(Rax), imm(1)),
+        Mov(reg(Rax), qword(above(Eax))),
+    ]);
+    assert_unchanged(vec![Mov(reg(Rcx), reg(Rdx)), Mov(reg(Rcx), reg(Ecx))]);
+    // Only the same operand is overwritten, `eax` leaves the upper half alone
+    assert_unchanged(vec![Mov(reg(Rax), imm(1)), Mov(reg(Eax), imm(2))]);
+}
+
+#[test]
+fn lea_is_folded_into_a_load_that_overwrites_the_address() {
+    let address = || Operand::Load(stack(16));
+    assert_peephole(
+        vec![
+            Lea(reg(Rax), address()),
+            Mov(reg(Rax), qword(EvalTreeNode::Reg(Rax))),
+        ],
+        vec![Mov(reg(Rax), qword(stack(16)))],
+    );
+    assert_peephole(
+        vec![
+            Lea(reg(Rax), address()),
+            Mov(reg(Eax), dword(EvalTreeNode::Reg(Rax))),
+        ],
+        vec![Mov(reg(Eax), dword(stack(16)))],
+    );
+    // `rax` still holds the address afterwards
+    assert_unchanged(vec![
+        Lea(reg(Rax), address()),
+        Mov(reg(Rcx), qword(EvalTreeNode::Reg(Rax))),
+    ]);
+    // A 16-bit `mov` leaves the upper bytes of the address in `rax`
+    assert_unchanged(vec![
+        Lea(reg(Rax), address()),
+        Mov(reg(Ax), word(EvalTreeNode::Reg(Rax))),
+    ]);
+}
+
+#[test]
+fn stack_adjustments_are_merged() {
+    assert_peephole(vec![AllocStack(8), AllocStack(16)], vec![AllocStack(24)]);
+    assert_peephole(
+        vec![DeallocStack(8), DeallocStack(8)],
+        vec![DeallocStack(16)],
+    );
+    assert_peephole(vec![AllocStack(8), DeallocStack(8)], vec![]);
+    assert_peephole(vec![AllocStack(24), DeallocStack(8)], vec![AllocStack(16)]);
+    assert_peephole(
+        vec![DeallocStack(24), AllocStack(8)],
+        vec![DeallocStack(16)],
+    );
+}
+
+#[test]
+fn push_then_pop_of_the_same_operand_is_removed() {
+    assert_peephole(vec![Push(reg(Rax)), Pop(reg(Rax))], vec![]);
+    assert_unchanged(vec![Push(reg(Rax)), Pop(reg(Rcx))]);
+}
+
+#[test]
+fn jumps_to_the_next_instruction_are_removed() {
+    assert_peephole(
+        vec![Jmp("a".to_string()), Label("a".to_string()), Ret],
+        vec![Label("a".to_string()), Ret],
+    );
+    assert_unchanged(vec![
+        Jmp("b".to_string()),
+        Label("a".to_string()),
+        Label("b".to_string()),
+        Ret,
+    ]);
+}
+
+#[test]
+fn code_after_ret_or_jmp_is_removed_until_a_label() {
+    assert_peephole(
+        vec![
+            Ret,
+            Mov(reg(Rax), imm(1)),
+            Jmp("a".to_string()),
+            Label("b".to_string()),
+            Mov(reg(Rax), imm(2)),
+            Jmp("b".to_string()),
+            Ret,
+        ],
+        vec![
+            Ret,
+            Label("b".to_string()),
+            Mov(reg(Rax), imm(2)),
+            Jmp("b".to_string()),
+        ],
+    );
+}
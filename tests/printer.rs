@@ -0,0 +1,333 @@
+mod common;
+
+use std::rc::Rc;
+
+use common::parse;
+use mir::{
+    ir::{
+        Callee, Constant, DataType, Instruction, Linkage, SymbolAttrs, TopLevel, Type, Visibility,
+    },
+    printer::print_program,
+};
+use proptest::{collection::vec, option, prelude::*};
+
+fn data_type() -> impl Strategy<Value = DataType> {
+    prop_oneof![
+        Just(DataType::U64),
+        Just(DataType::U32),
+        Just(DataType::U16),
+        Just(DataType::U8),
+        Just(DataType::USize),
+        Just(DataType::I64),
+        Just(DataType::I32),
+        Just(DataType::I16),
+        Just(DataType::I8),
+        Just(DataType::ISize),
+        Just(DataType::F64),
+        Just(DataType::F32),
+        Just(DataType::Ptr),
+    ]
+}
+
+fn symbol_name() -> impl Strategy<Value = Rc<String>> {
+    "[a-zA-Z_][a-zA-Z0-9_.]{0,8}".prop_map(Rc::new)
+}
+
+fn struct_name() -> impl Strategy<Value = Rc<String>> {
+    "[a-zA-Z][a-zA-Z0-9_.]{0,8}".prop_map(Rc::new)
+}
+
+fn label() -> impl Strategy<Value = String> {
+    "[a-zA-Z0-9_.]{1,8}"
+}
+
+/// Floats that the text format can express
+fn float() -> impl Strategy<Value = f64> {
+    prop_oneof![
+        any::<f64>().prop_filter("finite", |x| x.is_finite()),
+        (-1000i32..1000).prop_map(|i| i as f64 / 8.0),
+    ]
+}
+
+fn ty() -> impl Strategy<Value = Type> {
+    let leaf = prop_oneof![
+        data_type().prop_map(Type::Scalar),
+        struct_name().prop_map(Type::Struct),
+    ];
+    leaf.prop_recursive(3, 8, 1, |elem| {
+        (elem, any::<u64>()).prop_map(|(elem, count)| Type::Array(Box::new(elem), count))
+    })
+}
+
+fn symbol_attrs() -> impl Strategy<Value = SymbolAttrs> {
+    let linkage = prop_oneof![
+        Just(Linkage::Export),
+        Just(Linkage::Internal),
+        Just(Linkage::Private),
+        Just(Linkage::Weak),
+    ];
+    let visibility = prop_oneof![
+        Just(Visibility::Default),
+        Just(Visibility::Hidden),
+        Just(Visibility::Protected),
+    ];
+    (linkage, visibility).prop_map(|(linkage, visibility)| SymbolAttrs {
+        linkage,
+        // Visibility is only allowed on symbols that are visible outside
+        visibility: match linkage {
+            Linkage::Export | Linkage::Weak => visibility,
+            Linkage::Internal | Linkage::Private => Visibility::Default,
+        },
+    })
+}
+
+fn constant() -> impl Strategy<Value = Constant> {
+    let leaf = prop_oneof![
+        any::<u64>().prop_map(Constant::Int),
+        float().prop_map(Constant::Float),
+        vec(any::<u8>(), 0..12).prop_map(Constant::Bytes),
+    ];
+    leaf.prop_recursive(3, 16, 4, |element| {
+        vec(element, 0..4).prop_map(Constant::Aggregate)
+    })
+}
+
+fn reg_id() -> impl Strategy<Value = u64> {
+    0u64..64
+}
+
+/// Operands that are not expressions, such as `i32 %1` or `ptr "hi"`
+fn simple_operand() -> impl Strategy<Value = Instruction> {
+    prop_oneof![
+        (data_type(), 0u64..8).prop_map(|(dtype, id)| Instruction::Arg(dtype, id)),
+        (data_type(), reg_id()).prop_map(|(dtype, id)| Instruction::Reg(dtype, id)),
+        (data_type(), any::<u64>()).prop_map(|(dtype, u)| Instruction::UInt(dtype, u)),
+        (data_type(), any::<i64>()).prop_map(|(dtype, i)| Instruction::Int(dtype, i)),
+        (data_type(), float()).prop_map(|(dtype, x)| Instruction::Float(dtype, x)),
+        vec(any::<u8>(), 0..12).prop_map(Instruction::String),
+        (data_type(), reg_id()).prop_map(|(dtype, id)| Instruction::Load { id, dtype }),
+        symbol_name().prop_map(Instruction::GlobalPtr),
+    ]
+}
+
+/// A function name, or a function pointer which the parser requires to be a `ptr` operand
+fn callee() -> impl Strategy<Value = Callee> {
+    let fn_ptr = prop_oneof![
+        (0u64..8).prop_map(|id| Instruction::Arg(DataType::Ptr, id)),
+        reg_id().prop_map(|id| Instruction::Reg(DataType::Ptr, id)),
+        reg_id().prop_map(|id| Instruction::Load {
+            id,
+            dtype: DataType::Ptr
+        }),
+        symbol_name().prop_map(Instruction::GlobalPtr),
+    ];
+    prop_oneof![
+        symbol_name().prop_map(Callee::Direct),
+        fn_ptr.prop_map(|fn_ptr| Callee::Indirect(Box::new(fn_ptr))),
+    ]
+}
+
+fn operand() -> BoxedStrategy<Instruction> {
+    simple_operand()
+        .prop_recursive(3, 24, 4, |inner| {
+            macro_rules! binary {
+                ($variant: path) => {
+                    (data_type(), inner.clone(), inner.clone())
+                        .prop_map(|(dtype, lhs, rhs)| $variant(dtype, Box::new(lhs), Box::new(rhs)))
+                };
+            }
+            prop_oneof![
+                binary!(Instruction::Add),
+                binary!(Instruction::Sub),
+                binary!(Instruction::Mul),
+                binary!(Instruction::Div),
+                binary!(Instruction::Not),
+                binary!(Instruction::And),
+                binary!(Instruction::Or),
+                binary!(Instruction::Xor),
+                binary!(Instruction::Rem),
+                binary!(Instruction::MulHi),
+                binary!(Instruction::Shl),
+                binary!(Instruction::Shr),
+                binary!(Instruction::Eq),
+                binary!(Instruction::Ne),
+                binary!(Instruction::Lt),
+                binary!(Instruction::Le),
+                binary!(Instruction::Gt),
+                binary!(Instruction::Ge),
+                (ty(), reg_id(), any::<u64>()).prop_map(|(ty, id, index)| Instruction::FieldPtr {
+                    ty,
+                    id,
+                    index
+                }),
+                (ty(), reg_id(), inner.clone()).prop_map(|(ty, id, index)| {
+                    Instruction::ElemPtr {
+                        ty,
+                        id,
+                        index: Box::new(index),
+                    }
+                }),
+                (
+                    data_type(),
+                    callee(),
+                    vec(inner.clone(), 0..4),
+                    any::<bool>()
+                )
+                    .prop_map(|(ret_type, callee, args, is_tail)| {
+                        Instruction::Call {
+                            ret_type: Some(ret_type),
+                            callee,
+                            args,
+                            is_tail,
+                        }
+                    }),
+                (data_type(), reg_id()).prop_map(|(dtype, id)| Instruction::VaArg { dtype, id }),
+                (data_type(), vec((label(), inner.clone()), 0..4))
+                    .prop_map(|(dtype, incoming)| Instruction::Phi { dtype, incoming }),
+            ]
+        })
+        .boxed()
+}
+
+fn statement() -> impl Strategy<Value = Instruction> {
+    prop_oneof![
+        (reg_id(), operand()).prop_map(|(id, rhs)| Instruction::DefReg {
+            id,
+            rhs: Box::new(rhs),
+        }),
+        (reg_id(), data_type(), vec((label(), operand()), 0..4)).prop_map(
+            |(id, dtype, incoming)| Instruction::DefReg {
+                id,
+                rhs: Box::new(Instruction::Phi { dtype, incoming }),
+            }
+        ),
+        (reg_id(), ty()).prop_map(|(id, ty)| Instruction::DefReg {
+            id,
+            rhs: Box::new(Instruction::Alloc(ty)),
+        }),
+        (data_type(), reg_id(), operand()).prop_map(|(lhs_dtype, id, rhs)| {
+            Instruction::Store {
+                lhs_dtype,
+                id,
+                rhs: Box::new(rhs),
+            }
+        }),
+        option::of(operand()).prop_map(|val| Instruction::Ret(val.map(Box::new))),
+        (callee(), vec(operand(), 0..4), any::<bool>()).prop_map(|(callee, args, is_tail)| {
+            Instruction::Call {
+                ret_type: None,
+                callee,
+                args,
+                is_tail,
+            }
+        }),
+        label().prop_map(Instruction::Label),
+        label().prop_map(Instruction::Jmp),
+        (operand(), label(), label()).prop_map(|(cond, if_true, if_false)| Instruction::Br {
+            cond: Box::new(cond),
+            if_true,
+            if_false,
+        }),
+        reg_id().prop_map(Instruction::VaStart),
+        reg_id().prop_map(Instruction::VaEnd),
+    ]
+}
+
+fn top_level() -> impl Strategy<Value = TopLevel> {
+    prop_oneof![
+        (symbol_name(), vec(data_type(), 0..4), any::<bool>()).prop_map(
+            |(name, args, is_variadic)| TopLevel::Extern {
+                name,
+                args,
+                is_variadic,
+            }
+        ),
+        (struct_name(), vec(ty(), 0..4))
+            .prop_map(|(name, fields)| TopLevel::TypeDef { name, fields }),
+        (symbol_name(), symbol_attrs(), ty(), option::of(constant())).prop_map(
+            |(name, attrs, ty, init)| TopLevel::Global {
+                name,
+                attrs,
+                ty,
+                init,
+                is_const: false,
+            }
+        ),
+        (symbol_name(), symbol_attrs(), ty(), constant()).prop_map(|(name, attrs, ty, init)| {
+            TopLevel::Global {
+                name,
+                attrs,
+                ty,
+                init: Some(init),
+                is_const: true,
+            }
+        }),
+        (
+            symbol_name(),
+            symbol_attrs(),
+            vec(data_type(), 0..4),
+            any::<bool>(),
+            vec(statement(), 0..12),
+        )
+            .prop_map(|(name, attrs, args, is_variadic, body)| TopLevel::Fn {
+                name,
+                attrs,
+                args,
+                is_variadic,
+                body,
+            }),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn print_then_parse_gives_the_same_ir(program in vec(top_level(), 0..6)) {
+        let text = print_program(&program);
+        prop_assert_eq!(parse(&text), program, "printed as:\n{}", text);
+    }
+
+    #[test]
+    fn parse_print_parse_is_a_fixed_point(program in vec(top_level(), 0..6)) {
+        let text = print_program(&program);
+        let reparsed = parse(&text);
+        let reprinted = print_program(&reparsed);
+        prop_assert_eq!(&reprinted, &text);
+        prop_assert_eq!(parse(&reprinted), reparsed);
+    }
+}
+
+#[test]
+fn hand_written_source_round_trips() {
+    let source = r#"
+type %Point = { i32, i8, f64 }
+global @counter: i64 = 0
+weak hidden global @origin: %Point = { 1, -2, 0.5 }
+const @name: [8 x u8] = "hi\n"
+extern @printf(ptr ...)
+
+internal fn @sum(i32 i32) {
+    %1 = alloc i32
+    i32 [%1] = i32 $0
+    jmp :cond
+:cond
+    %3 = u8 < i32 [%1] i32 #1
+    br u8 %3 :body :end
+:body
+    i32 [%1] = i32 + i32 [%1] i32 $0x10
+    jmp :cond
+:end
+    ret i32 [%1]
+}
+fn @main() {
+    %1 = i32 call @sum(i32 $1 i32 $11)
+    %2 = i32 / i32 %1 i32 $-5
+    %3 = f64 $-1.5
+    call @printf(ptr c"%d %d\n" i32 %1 i32 %2)
+    ret i32 $0
+}
+"#;
+    let program = parse(source);
+    let text = print_program(&program);
+    assert_eq!(parse(&text), program);
+    assert_eq!(print_program(&parse(&text)), text);
+}
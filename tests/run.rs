@@ -0,0 +1,401 @@
+//! Programs compiled at every level, linked with a C driver and run
+
+use std::{fs, io::ErrorKind, path::PathBuf, process::Command};
+
+use mir::{
+    compile::{compile_to_object, CompileOptions, OptLevel},
+    fileformat::FileFormat,
+    parser::{parse_string_into_tokens, parse_tokens_into_ir},
+};
+
+const LEVELS: [(OptLevel, &str); 4] = [
+    (OptLevel::O0, "O0"),
+    (OptLevel::O1, "O1"),
+    (OptLevel::O2, "O2"),
+    (OptLevel::Os, "Os"),
+];
+
+/// Compile `source` at every level, link each with `main`, and check that they all print
+/// `expected`
+/// Does nothing if there's no `cc` to link with
+fn assert_runs(test_name: &str, source: &str, main: &str, expected: &str) {
+    let dir: PathBuf =
+        std::env::temp_dir().join(format!("madeline-{test_name}-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let main_path = dir.join("main.c");
+    fs::write(&main_path, main).unwrap();
+    for (opt_level, suffix) in LEVELS {
+        let options = CompileOptions {
+            opt_level,
+            file_format: FileFormat::Elf64,
+        };
+        let program = parse_tokens_into_ir(parse_string_into_tokens(source.to_string()));
+        let object_path = dir.join(format!("{suffix}.o"));
+        let exe = dir.join(suffix);
+        fs::write(&object_path, compile_to_object(program, &options)).unwrap();
+        let status = Command::new("cc")
+            .arg("-o")
+            .arg(&exe)
+            .arg(&main_path)
+            .arg(&object_path)
+            .status();
+        let status = match status {
+            Ok(status) => status,
+            Err(error) if error.kind() == ErrorKind::NotFound => {
+                eprintln!("Skipping, no `cc` to link with");
+                return;
+            }
+            Err(error) => panic!("{error}"),
+        };
+        assert!(status.success(), "Linking failed at {suffix}");
+        let output = Command::new(&exe).output().unwrap();
+        assert!(output.status.success(), "{suffix}: {output:?}");
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            expected,
+            "{suffix}"
+        );
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn floats_are_returned_in_xmm0() {
+    let source = "
+extern @twice(f64)
+fn @constant() {
+    ret f64 $2.5
+}
+fn @constant_f32() {
+    ret f32 $1.5
+}
+fn @quadruple(f64) {
+    %1 = f64 call @twice(f64 #0)
+    %2 = f64 call @twice(f64 %1)
+    ret f64 %2
+}
+fn @forward(f64) {
+    ret f64 call @twice(f64 #0)
+}
+";
+    let main = r#"
+#include <stdio.h>
+double twice(double x) { return x * 2; }
+double constant(void);
+float constant_f32(void);
+double quadruple(double);
+double forward(double);
+int main(void) {
+    printf("%g %g %g %g\n", constant(), constant_f32(), quadruple(1.25), forward(3));
+    return 0;
+}
+"#;
+    assert_runs("float-returns", source, main, "2.5 1.5 5 6\n");
+}
+
+#[test]
+fn float_arithmetic_and_comparisons() {
+    let source = "
+fn @scale(f64) {
+    %1 = f64 * f64 #0 f64 $2.5
+    %2 = f64 + f64 %1 f64 $0.25
+    ret f64 %2
+}
+fn @mix(f64 f64) {
+    %1 = f64 / f64 #0 f64 #1
+    %2 = f64 - f64 #0 f64 %1
+    ret f64 * f64 %2 f64 #1
+}
+fn @mix_f32(f32 f32) {
+    %1 = f32 / f32 #0 f32 #1
+    %2 = f32 - f32 #0 f32 %1
+    ret f32 * f32 %2 f32 #1
+}
+fn @compare(f64 f64) {
+    %1 = i32 < f64 #0 f64 #1
+    %2 = i32 <= f64 #0 f64 #1
+    %3 = i32 > f64 #0 f64 #1
+    %4 = i32 >= f64 #0 f64 #1
+    %5 = i32 == f64 #0 f64 #1
+    %6 = i32 != f64 #0 f64 #1
+    %7 = i32 * i32 %1 i32 $1
+    %8 = i32 * i32 %2 i32 $10
+    %9 = i32 * i32 %3 i32 $100
+    %10 = i32 * i32 %4 i32 $1000
+    %11 = i32 * i32 %5 i32 $10000
+    %12 = i32 * i32 %6 i32 $100000
+    %13 = i32 + i32 %7 i32 %8
+    %14 = i32 + i32 %13 i32 %9
+    %15 = i32 + i32 %14 i32 %10
+    %16 = i32 + i32 %15 i32 %11
+    ret i32 + i32 %16 i32 %12
+}
+fn @less_f32(f32 f32) {
+    br u8 < f32 #0 f32 #1 :yes :no
+:yes
+    ret i32 $1
+:no
+    ret i32 $0
+}
+";
+    let main = r#"
+#include <stdio.h>
+double scale(double);
+double mix(double, double);
+float mix_f32(float, float);
+int compare(double, double);
+int less_f32(float, float);
+int main(void) {
+    double nan = __builtin_nan("");
+    printf("%g %g %g\n", scale(4), mix(6, 4), mix_f32(6, 4));
+    printf("%06d %06d %06d %06d\n", compare(-1, -2), compare(-2, -1), compare(3, 3), compare(nan, 1));
+    printf("%d %d %d\n", less_f32(1, 2), less_f32(2, 1), less_f32(nan, 1));
+    return 0;
+}
+"#;
+    assert_runs(
+        "float-arithmetic",
+        source,
+        main,
+        "10.25 18 18\n101100 100011 011010 100000\n1 0 0\n",
+    );
+}
+
+#[test]
+fn arguments_beyond_the_registers_go_on_the_stack() {
+    let source = "
+extern @sum_c(i64 i32 i64 i64 i64 i64 i64 i8 f64 f64 f64 f64 f64 f64 f64 f64 f64 f32)
+extern @printf(ptr ...)
+const @format: [13 x u8] = \"%d %g %d %g\\n\"
+fn @sum_ints(i64 i64 i64 i64 i64 i64 i64 f64 i64) {
+    %1 = i64 + i64 #0 i64 #6
+    %2 = i64 * i64 #8 i64 $100
+    ret i64 + i64 %1 i64 %2
+}
+fn @sum_floats(f64 f64 f64 f64 f64 f64 f64 f64 i64 f64 f64) {
+    %1 = f64 + f64 #0 f64 #9
+    %2 = f64 * f64 #10 f64 $10.0
+    ret f64 + f64 %1 f64 %2
+}
+fn @call_c(i64) {
+    %1 = i64 * i64 #0 i64 $2
+    ret f64 call @sum_c(i64 $1 i32 $2 i64 $3 i64 $4 i64 $5 i64 $6 i64 %1 i8 $-8 f64 $0.5 f64 $0.0 f64 $0.0 f64 $0.0 f64 $0.0 f64 $0.0 f64 $0.0 f64 $0.0 f64 $0.25 f32 $1.5)
+}
+fn @print(i32 f64) {
+    %1 = ptr @format
+    %2 = i32 call @printf(ptr %1 i32 #0 f64 #1 i32 $7 f64 $0.5)
+    %3 = i32 call @printf(ptr %1 i32 $1 f64 $1.0 i32 $2 f64 $2.0)
+    ret i32 %2
+}
+";
+    let main = r#"
+#include <stdio.h>
+long sum_ints(long, long, long, long, long, long, long, double, long);
+double sum_floats(double, double, double, double, double, double, double, double,
+                  long, double, double);
+double call_c(long);
+int print(int, double);
+double sum_c(long a, int b, long c, long d, long e, long f, long g, signed char h,
+             double i, double j, double k, double l, double m, double n, double o,
+             double p, double q, float r) {
+    return a + b + c + d + e + f + g * 1000 + h + i + q * 100 + r * 10000;
+}
+int main(void) {
+    printf("%ld %g\n", sum_ints(1, 0, 0, 0, 0, 0, 2, 0, 3),
+           sum_floats(0.5, 0, 0, 0, 0, 0, 0, 0, 9, 0.25, 4));
+    printf("%g\n", call_c(5));
+    print(3, 1.5);
+    return 0;
+}
+"#;
+    assert_runs(
+        "stack-arguments",
+        source,
+        main,
+        "303 40.75\n25038.5\n3 1.5 7 0.5\n1 1 2 2\n",
+    );
+}
+
+/// `@apply` and `@apply_second` get the function pointer in the register that the first argument
+/// goes into, so it's lost if the arguments are moved in before the pointer is read
+#[test]
+fn calls_through_function_pointers() {
+    let source = "
+extern @scale(i64 i64)
+fn @sub(i64 i64) {
+    ret i64 - i64 #0 i64 #1
+}
+fn @through_global(i64 i64) {
+    %1 = ptr @sub
+    ret i64 call ptr %1(i64 #0 i64 #1)
+}
+fn @apply(ptr i64 i64) {
+    ret i64 call ptr #0(i64 #2 i64 #1)
+}
+fn @apply_second(i64 ptr i64) {
+    ret i64 call ptr #1(i64 #2 i64 #0)
+}
+fn @apply_c(i64) {
+    %1 = ptr @scale
+    %2 = i64 call ptr %1(i64 #0 i64 $3)
+    %3 = ptr @sub
+    ret i64 call @apply(ptr %3 i64 %2 i64 $1)
+}
+";
+    let main = r#"
+#include <stdio.h>
+long sub(long, long);
+long through_global(long, long);
+long apply(long (*)(long, long), long, long);
+long apply_second(long, long (*)(long, long), long);
+long apply_c(long);
+long scale(long a, long b) {
+    return a * b;
+}
+int main(void) {
+    printf("%ld %ld %ld\n", through_global(10, 3), apply(sub, 10, 3), apply(scale, 4, 5));
+    printf("%ld %ld\n", apply_second(2, sub, 30), apply_c(7));
+    return 0;
+}
+"#;
+    assert_runs("function-pointers", source, main, "7 -7 20\n28 -20\n");
+}
+
+#[test]
+fn phis_are_copied_in_parallel() {
+    let source = "
+fn @swap(i64) {
+:entry
+    jmp :loop
+:loop
+    %1 = i64 phi [:entry i64 $1] [:loop i64 %2]
+    %2 = i64 phi [:entry i64 $2] [:loop i64 %1]
+    %3 = i64 phi [:entry i64 #0] [:loop i64 %4]
+    %4 = i64 - i64 %3 i64 $1
+    br u8 != i64 %4 i64 $0 :loop :done
+:done
+    ret i64 - i64 %1 i64 %2
+}
+fn @lost_copy(i64) {
+:entry
+    jmp :loop
+:loop
+    %1 = i64 phi [:entry i64 $0] [:loop i64 %2]
+    %2 = i64 + i64 %1 i64 $1
+    br u8 < i64 %2 i64 #0 :loop :done
+:done
+    ret i64 %1
+}
+";
+    let main = r#"
+#include <stdio.h>
+long swap(long);
+long lost_copy(long);
+int main(void) {
+    printf("%ld %ld %ld\n", swap(2), swap(3), lost_copy(5));
+    return 0;
+}
+"#;
+    assert_runs("phis", source, main, "1 -1 4\n");
+}
+
+/// Folded at `-O1` and above, computed at run time at `-O0`
+#[test]
+fn constant_folding_matches_the_hardware() {
+    let source = "
+fn @wrap_i8() {
+    %1 = i8 + i8 $127 i8 $1
+    ret i8 / i8 %1 i8 $3
+}
+fn @wrap_u8() {
+    %1 = u8 * u8 $200 u8 $2
+    ret u8 - u8 %1 u8 $145
+}
+fn @wrap_i32() {
+    %1 = i32 * i32 $65536 i32 $65535
+    ret i32 - i32 %1 i32 $1
+}
+fn @rem_i8() {
+    ret i8 % i8 $-7 i8 $2
+}
+fn @shr_i16() {
+    ret i16 >> i16 $-32768 i16 $14
+}
+fn @shr_u16() {
+    ret u16 >> u16 $32768 u16 $14
+}
+fn @compare() {
+    %1 = u8 < i8 $-1 i8 $1
+    %2 = u8 < u8 $255 u8 $1
+    %3 = u8 * u8 %2 u8 $2
+    %4 = u8 > u32 $2147483648 u32 $0
+    %5 = u8 * u8 %4 u8 $4
+    %6 = u8 > i32 $-2147483648 i32 $0
+    %7 = u8 * u8 %6 u8 $8
+    %8 = u8 + u8 %1 u8 %3
+    %9 = u8 + u8 %8 u8 %5
+    ret u8 + u8 %9 u8 %7
+}
+";
+    let main = r#"
+#include <stdio.h>
+signed char wrap_i8(void);
+unsigned char wrap_u8(void);
+int wrap_i32(void);
+signed char rem_i8(void);
+short shr_i16(void);
+unsigned short shr_u16(void);
+unsigned char compare(void);
+int main(void) {
+    printf("%d %d %d %d %d %d %d\n", wrap_i8(), wrap_u8(), wrap_i32(), rem_i8(), shr_i16(),
+           shr_u16(), compare());
+    return 0;
+}
+"#;
+    assert_runs(
+        "constant-folding",
+        source,
+        main,
+        "-42 255 -65537 -1 -2 2 5\n",
+    );
+}
+
+/// Partially unrolled at `-O2`, with the iterations left over peeled off in front of the loop
+#[test]
+fn unrolled_loops_do_every_iteration() {
+    let mut source = String::new();
+    for n in [10, 11, 12, 13] {
+        source += &format!(
+            "
+fn @sum_of_squares_{n}() {{
+:entry
+    jmp :header
+:header
+    %1 = i64 phi [:entry i64 $0] [:body i64 %4]
+    %2 = i64 phi [:entry i64 $0] [:body i64 %5]
+    br u8 < i64 %1 i64 ${n} :body :exit
+:body
+    %3 = i64 * i64 %1 i64 %1
+    %4 = i64 + i64 %1 i64 $1
+    %5 = i64 + i64 %2 i64 %3
+    jmp :header
+:exit
+    ret i64 %2
+}}
+"
+        );
+    }
+    let main = r#"
+#include <stdio.h>
+long sum_of_squares_10(void);
+long sum_of_squares_11(void);
+long sum_of_squares_12(void);
+long sum_of_squares_13(void);
+int main(void) {
+    printf("%ld %ld %ld %ld\n", sum_of_squares_10(), sum_of_squares_11(), sum_of_squares_12(),
+           sum_of_squares_13());
+    return 0;
+}
+"#;
+    assert_runs("unroll", &source, main, "285 385 506 650\n");
+}
@@ -0,0 +1,159 @@
+//! Sparse conditional constant propagation, checked through the printer
+
+mod common;
+
+use common::run_passes;
+
+#[test]
+fn integers_wrap_at_the_width_of_their_type() {
+    let source = "
+fn @wrap() {
+    %1 = i8 + i8 $127 i8 $1
+    %2 = u8 * u8 $200 u8 $2
+    %3 = i32 * i32 $65536 i32 $65536
+    %4 = u8 - u8 $0 u8 $1
+    %5 = i32 - i32 $-2147483648 i32 $1
+    %6 = i8 / i8 %1 i8 $3
+    %7 = u8 / u8 %4 u8 $16
+    %8 = i8 % i8 $-7 i8 $2
+    %9 = i16 >> i16 $-32768 i16 $15
+    %10 = u16 >> u16 $32768 u16 $15
+    ret
+}
+";
+    let expected = "\
+fn @wrap() {
+    %1 = i8 $-128
+    %2 = u8 $144
+    %3 = i32 $+0
+    %4 = u8 $255
+    %5 = i32 $+2147483647
+    %6 = i8 $-42
+    %7 = u8 $15
+    %8 = i8 $-1
+    %9 = i16 $-1
+    %10 = u16 $1
+    ret
+}
+";
+    assert_eq!(run_passes(source, &["sccp"]), expected);
+}
+
+/// Division by zero and the minimum value divided by -1 trap at run time, so they are kept
+#[test]
+fn trapping_divisions_are_not_folded() {
+    let source = "
+fn @traps() {
+    %1 = i32 / i32 $-2147483648 i32 $-1
+    %2 = i32 % i32 $-2147483648 i32 $-1
+    %3 = i64 / i64 $-9223372036854775808 i64 $-1
+    %4 = i8 / i8 $-128 i8 $-1
+    %5 = u32 / u32 $1 u32 $0
+    %6 = u8 / u8 $128 u8 $255
+    ret
+}
+";
+    let expected = "\
+fn @traps() {
+    %1 = i32 / i32 $-2147483648 i32 $-1
+    %2 = i32 % i32 $-2147483648 i32 $-1
+    %3 = i64 / i64 $-9223372036854775808 i64 $-1
+    %4 = i8 / i8 $-128 i8 $-1
+    %5 = u32 / u32 $1 u32 $0
+    %6 = u8 $0
+    ret
+}
+";
+    assert_eq!(run_passes(source, &["sccp"]), expected);
+}
+
+#[test]
+fn shifts_by_the_width_or_more_are_not_folded() {
+    let source = "
+fn @shifts() {
+    %1 = i32 << i32 $1 i32 $32
+    %2 = u8 >> u8 $1 u8 $8
+    %3 = i64 << i64 $1 i64 $64
+    %4 = u8 << u8 $1 u8 $7
+    %5 = u8 << u8 $3 u8 $7
+    ret
+}
+";
+    let expected = "\
+fn @shifts() {
+    %1 = i32 << i32 $1 i32 $32
+    %2 = u8 >> u8 $1 u8 $8
+    %3 = i64 << i64 $1 i64 $64
+    %4 = u8 $128
+    %5 = u8 $128
+    ret
+}
+";
+    assert_eq!(run_passes(source, &["sccp"]), expected);
+}
+
+#[test]
+fn comparisons_follow_the_signedness_of_the_operands() {
+    let source = "
+fn @compare() {
+    %1 = u8 < i8 $-1 i8 $1
+    %2 = u8 < u8 $255 u8 $1
+    %3 = u8 > i32 $-2147483648 i32 $0
+    %4 = u8 > u32 $2147483648 u32 $0
+    %5 = u8 <= i64 $-1 i64 $0
+    %6 = u8 <= u64 $18446744073709551615 u64 $0
+    ret
+}
+";
+    let expected = "\
+fn @compare() {
+    %1 = u8 $1
+    %2 = u8 $0
+    %3 = u8 $0
+    %4 = u8 $1
+    %5 = u8 $1
+    %6 = u8 $0
+    ret
+}
+";
+    assert_eq!(run_passes(source, &["sccp"]), expected);
+}
+
+/// The branch becomes a jump, and the value from the block that's no longer reachable is left
+/// out of the `phi`
+#[test]
+fn constant_branches_make_blocks_unreachable() {
+    let source = "
+fn @branch(i64) {
+:entry
+    %1 = i64 $5
+    br u8 < i64 %1 i64 $3 :small :big
+:small
+    %2 = i64 + i64 #0 i64 $1
+    jmp :join
+:big
+    %3 = i64 * i64 %1 i64 $2
+    jmp :join
+:join
+    %4 = i64 phi [:small i64 %2] [:big i64 %3]
+    ret i64 %4
+}
+";
+    let expected = "\
+fn @branch(i64) {
+:entry
+    %1 = i64 $5
+    jmp :big
+:small
+    %2 = i64 + i64 #0 i64 $1
+    jmp :join
+:big
+    %3 = i64 $+10
+    jmp :join
+:join
+    %4 = i64 $+10
+    ret i64 $+10
+}
+";
+    assert_eq!(run_passes(source, &["sccp"]), expected);
+}
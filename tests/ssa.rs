@@ -0,0 +1,194 @@
+//! Conversion into and out of SSA form, checked through the printer
+
+mod common;
+
+use common::transform_bodies;
+use mir::transform::ssa::{construct_ssa, destruct_ssa};
+
+#[test]
+fn slots_in_a_loop_become_phis() {
+    let source = "
+fn @counter(i64) {
+    %1 = alloc i64
+    %2 = alloc i64
+    i64 [%1] = i64 $0
+    i64 [%2] = i64 $0
+    jmp :cond
+:cond
+    br u8 < i64 [%1] i64 #0 :body :end
+:body
+    i64 [%2] = i64 + i64 [%2] i64 [%1]
+    i64 [%1] = i64 + i64 [%1] i64 $1
+    jmp :cond
+:end
+    ret i64 [%2]
+}
+";
+    let expected = "\
+fn @counter(i64) {
+:bb.0
+    %5 = i64 $0
+    %6 = i64 $0
+    jmp :cond
+:cond
+    %3 = i64 phi [:bb.0 i64 %5] [:body i64 %8]
+    %4 = i64 phi [:bb.0 i64 %6] [:body i64 %7]
+    br u8 < i64 %3 i64 #0 :body :end
+:body
+    %7 = i64 + i64 %4 i64 %3
+    %8 = i64 + i64 %3 i64 $1
+    jmp :cond
+:end
+    ret i64 %4
+}
+";
+    assert_eq!(transform_bodies(source, construct_ssa), expected);
+}
+
+#[test]
+fn slots_whose_address_escapes_are_kept() {
+    let source = "
+extern @g(ptr)
+fn @f() {
+    %1 = alloc i64
+    i64 [%1] = i64 $1
+    call @g(ptr %1)
+    ret i64 [%1]
+}
+";
+    let expected = "\
+extern @g(ptr)
+fn @f() {
+:bb.0
+    %1 = alloc i64
+    i64 [%1] = i64 $1
+    call @g(ptr %1)
+    ret i64 [%1]
+}
+";
+    assert_eq!(transform_bodies(source, construct_ssa), expected);
+}
+
+/// Phis that read each other have to see the values from before either is updated
+#[test]
+fn swap_keeps_the_old_values() {
+    let source = "
+fn @swap(i64) {
+:entry
+    jmp :loop
+:loop
+    %1 = i64 phi [:entry i64 $1] [:loop i64 %2]
+    %2 = i64 phi [:entry i64 $2] [:loop i64 %1]
+    %3 = i64 phi [:entry i64 #0] [:loop i64 %4]
+    %4 = i64 - i64 %3 i64 $1
+    br u8 != i64 %4 i64 $0 :loop :done
+:done
+    ret i64 - i64 %1 i64 %2
+}
+";
+    let expected = "\
+fn @swap(i64) {
+:entry
+    %5 = alloc i64
+    %6 = alloc i64
+    %7 = alloc i64
+    i64 [%5] = i64 $1
+    i64 [%6] = i64 $2
+    i64 [%7] = i64 #0
+    jmp :loop
+:loop
+    %1 = i64 [%5]
+    %2 = i64 [%6]
+    %3 = i64 [%7]
+    %4 = i64 - i64 %3 i64 $1
+    i64 [%5] = i64 %2
+    i64 [%6] = i64 %1
+    i64 [%7] = i64 %4
+    br u8 != i64 %4 i64 $0 :loop :done
+:done
+    ret i64 - i64 %1 i64 %2
+}
+";
+    assert_eq!(transform_bodies(source, destruct_ssa), expected);
+}
+
+/// A phi that's used after the loop has to keep the value of the last iteration, not the one
+/// copied in for the next
+#[test]
+fn lost_copy_keeps_the_value_of_the_last_iteration() {
+    let source = "
+fn @lost_copy(i64) {
+:entry
+    jmp :loop
+:loop
+    %1 = i64 phi [:entry i64 $0] [:loop i64 %2]
+    %2 = i64 + i64 %1 i64 $1
+    br u8 < i64 %2 i64 #0 :loop :done
+:done
+    ret i64 %1
+}
+";
+    let expected = "\
+fn @lost_copy(i64) {
+:entry
+    %3 = alloc i64
+    i64 [%3] = i64 $0
+    jmp :loop
+:loop
+    %1 = i64 [%3]
+    %2 = i64 + i64 %1 i64 $1
+    i64 [%3] = i64 %2
+    br u8 < i64 %2 i64 #0 :loop :done
+:done
+    ret i64 %1
+}
+";
+    assert_eq!(transform_bodies(source, destruct_ssa), expected);
+}
+
+#[test]
+fn into_and_out_of_ssa() {
+    let source = "
+fn @counter(i64) {
+    %1 = alloc i64
+    %2 = alloc i64
+    i64 [%1] = i64 $0
+    i64 [%2] = i64 $0
+    jmp :cond
+:cond
+    br u8 < i64 [%1] i64 #0 :body :end
+:body
+    i64 [%2] = i64 + i64 [%2] i64 [%1]
+    i64 [%1] = i64 + i64 [%1] i64 $1
+    jmp :cond
+:end
+    ret i64 [%2]
+}
+";
+    let expected = "\
+fn @counter(i64) {
+:bb.0
+    %9 = alloc i64
+    %10 = alloc i64
+    %5 = i64 $0
+    %6 = i64 $0
+    i64 [%9] = i64 %5
+    i64 [%10] = i64 %6
+    jmp :cond
+:cond
+    %3 = i64 [%9]
+    %4 = i64 [%10]
+    br u8 < i64 %3 i64 #0 :body :end
+:body
+    %7 = i64 + i64 %4 i64 %3
+    %8 = i64 + i64 %3 i64 $1
+    i64 [%9] = i64 %8
+    i64 [%10] = i64 %7
+    jmp :cond
+:end
+    ret i64 %4
+}
+";
+    let ssa = transform_bodies(source, construct_ssa);
+    assert_eq!(transform_bodies(&ssa, destruct_ssa), expected);
+}
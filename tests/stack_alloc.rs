@@ -0,0 +1,82 @@
+//! Placing variables, arrays and aggregates in the stack frame
+
+use mir::generation::stack_alloc::{StackAllocation, StackAllocator};
+use proptest::{collection::vec, prelude::*};
+
+/// The bytes below the base pointer that each space takes up, as `[start, end)`
+fn ranges(allocation: &StackAllocation, spaces: &[(usize, usize)]) -> Vec<(usize, usize)> {
+    spaces
+        .iter()
+        .zip(&allocation.locations)
+        .map(|(&(size, _), &location)| (location - size.max(1), location))
+        .collect()
+}
+
+#[test]
+fn smaller_spaces_fill_the_gaps_after_bigger_ones() {
+    let mut allocator = StackAllocator::new(16, 0);
+    let byte = allocator.add_var(1);
+    let word = allocator.add_var(8);
+    let pair = allocator.add_aggregate(12, 4);
+    let allocation = allocator.allocate();
+    assert_eq!(allocation.var_location(word), 8);
+    assert_eq!(allocation.var_location(pair), 20);
+    assert_eq!(allocation.var_location(byte), 21);
+    assert_eq!(allocation.stack_depth, 32);
+}
+
+#[test]
+fn array_elements_go_up_from_the_first_one() {
+    let mut allocator = StackAllocator::new(16, 8);
+    let array = allocator.add_arr(4, 3);
+    let allocation = allocator.allocate();
+    assert_eq!(allocation.var_location(array), 20);
+    assert_eq!(allocation.arr_location(array, 4, 0), 20);
+    assert_eq!(allocation.arr_location(array, 4, 2), 12);
+    assert_eq!(allocation.stack_depth, 32);
+}
+
+#[test]
+#[should_panic(expected = "Stack space alignment 32 exceeds stack alignment 16")]
+fn spaces_cannot_be_aligned_more_than_the_stack() {
+    StackAllocator::new(16, 0).add_aggregate(32, 32);
+}
+
+fn space() -> impl Strategy<Value = (usize, usize)> {
+    (
+        0..40usize,
+        prop_oneof![Just(1), Just(2), Just(4), Just(8), Just(16)],
+    )
+}
+
+proptest! {
+    #[test]
+    fn slots_are_aligned_and_do_not_overlap(
+        initial_offset in 0..24usize,
+        spaces in vec(space(), 0..12),
+    ) {
+        let mut allocator = StackAllocator::new(16, initial_offset);
+        for &(size, align) in &spaces {
+            allocator.add_aggregate(size, align);
+        }
+        let allocation = allocator.allocate();
+        prop_assert_eq!(allocation.stack_depth % 16, 0);
+
+        let ranges = ranges(&allocation, &spaces);
+        for (i, (&(_, align), &(start, end))) in spaces.iter().zip(&ranges).enumerate() {
+            // The base pointer is aligned to 16, so the address is aligned if the location is
+            prop_assert_eq!(end % align, 0, "space {} at {}", i, end);
+            prop_assert!(start >= initial_offset, "space {} overlaps the initial offset", i);
+            prop_assert!(end <= allocation.stack_depth, "space {} is below the frame", i);
+            for (j, &(other_start, other_end)) in ranges.iter().enumerate().skip(i + 1) {
+                prop_assert!(
+                    end <= other_start || other_end <= start,
+                    "spaces {} and {} overlap: {:?}",
+                    i,
+                    j,
+                    ranges
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,268 @@
+//! Divisions and remainders by constants after strength reduction, evaluated and checked
+//! against native `/` and `%`
+
+use std::collections::HashMap;
+
+use mir::{
+    ir::{DataType, Instruction},
+    transform::strength::strength_reduce,
+};
+use proptest::prelude::*;
+
+fn width(dtype: DataType) -> u32 {
+    dtype.size(8) as u32 * 8
+}
+
+fn truncate(dtype: DataType, bits: u64) -> u64 {
+    match width(dtype) {
+        64 => bits,
+        width => bits & ((1 << width) - 1),
+    }
+}
+
+fn sign_extend(dtype: DataType, bits: u64) -> i64 {
+    let shift = 64 - width(dtype);
+    ((bits << shift) as i64) >> shift
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Div,
+    Rem,
+}
+
+/// `%0 = #0 op c` then `ret %0`, after strength reduction
+fn reduced(dtype: DataType, op: Op, c: u64) -> Vec<Instruction> {
+    let x = Box::new(Instruction::Arg(dtype, 0));
+    let c = Box::new(match dtype.is_signed() {
+        true => Instruction::Int(dtype, sign_extend(dtype, c)),
+        false => Instruction::UInt(dtype, c),
+    });
+    let rhs = match op {
+        Op::Div => Instruction::Div(dtype, x, c),
+        Op::Rem => Instruction::Rem(dtype, x, c),
+    };
+    let mut body = vec![
+        Instruction::DefReg {
+            id: 0,
+            rhs: Box::new(rhs),
+        },
+        Instruction::Ret(Some(Box::new(Instruction::Reg(dtype, 0)))),
+    ];
+    strength_reduce(&mut body);
+    body
+}
+
+/// Run the body with `x` as the argument, returns the bits of the returned value
+fn run(body: &[Instruction], x: u64) -> u64 {
+    let mut regs = HashMap::<u64, u64>::new();
+    for instruction in body {
+        match instruction {
+            Instruction::DefReg { id, rhs } => {
+                let value = eval(rhs, x, &regs);
+                regs.insert(*id, value);
+            }
+            Instruction::Ret(Some(value)) => return eval(value, x, &regs),
+            instruction => panic!("Unexpected {instruction:?}"),
+        }
+    }
+    panic!("No `ret` in {body:?}")
+}
+
+/// Evaluate the operand the way the hardware would, each operation wrapping at the width of its
+/// type
+fn eval(operand: &Instruction, x: u64, regs: &HashMap<u64, u64>) -> u64 {
+    use Instruction::*;
+    let dtype = operand.dtype().unwrap();
+    let bits = match operand {
+        Arg(_, 0) => x,
+        Reg(_, id) => regs[id],
+        UInt(_, u) => *u,
+        Int(_, i) => *i as u64,
+        Add(_, lhs, rhs)
+        | Sub(_, lhs, rhs)
+        | Mul(_, lhs, rhs)
+        | MulHi(_, lhs, rhs)
+        | And(_, lhs, rhs)
+        | Shl(_, lhs, rhs)
+        | Shr(_, lhs, rhs)
+        | Eq(_, lhs, rhs)
+        | Ge(_, lhs, rhs) => {
+            let operand_dtype = lhs.dtype().unwrap();
+            let (a, b) = (eval(lhs, x, regs), eval(rhs, x, regs));
+            let (sa, sb) = (sign_extend(operand_dtype, a), sign_extend(operand_dtype, b));
+            match operand {
+                Add(..) => a.wrapping_add(b),
+                Sub(..) => a.wrapping_sub(b),
+                Mul(..) => a.wrapping_mul(b),
+                MulHi(..) if dtype.is_signed() => {
+                    ((sa as i128 * sb as i128) >> width(dtype)) as u64
+                }
+                MulHi(..) => ((a as u128 * b as u128) >> width(dtype)) as u64,
+                And(..) => a & b,
+                Shl(..) => a << b,
+                Shr(..) if dtype.is_signed() => (sa >> b) as u64,
+                Shr(..) => a >> b,
+                Eq(..) => (a == b) as u64,
+                Ge(..) if operand_dtype.is_signed() => (sa >= sb) as u64,
+                Ge(..) => (a >= b) as u64,
+                _ => unreachable!(),
+            }
+        }
+        operand => panic!("{operand:?} is not reduced"),
+    };
+    truncate(dtype, bits)
+}
+
+/// Check `x op c` for every `x` in `xs`
+#[track_caller]
+fn check(dtype: DataType, op: Op, c: u64, xs: impl IntoIterator<Item = u64>) {
+    let c = truncate(dtype, c);
+    if c == 0 {
+        return;
+    }
+    let body = reduced(dtype, op, c);
+    for x in xs {
+        let x = truncate(dtype, x);
+        let expected = match (dtype.is_signed(), op) {
+            // Wrapping for the smallest value divided by -1, which traps on the hardware
+            (true, Op::Div) => sign_extend(dtype, x).wrapping_div(sign_extend(dtype, c)) as u64,
+            (true, Op::Rem) => sign_extend(dtype, x).wrapping_rem(sign_extend(dtype, c)) as u64,
+            (false, Op::Div) => x / c,
+            (false, Op::Rem) => x % c,
+        };
+        assert_eq!(
+            run(&body, x),
+            truncate(dtype, expected),
+            "{dtype:?} {x} {op:?} {c}, reduced to {body:?}"
+        );
+    }
+}
+
+/// Values around zero and the ends of the range of every width, truncated to `dtype`
+fn edge_values(dtype: DataType) -> Vec<u64> {
+    let mut values = Vec::new();
+    for width in [8, 16, 32, 64] {
+        let min = 1u64 << (width - 1);
+        for around in [0, min, min.wrapping_sub(1)] {
+            for delta in 0..3 {
+                values.push(around.wrapping_add(delta));
+                values.push(around.wrapping_sub(delta));
+            }
+        }
+    }
+    values.extend([3, 7, 10, 100, 1000, 12345, 0x5555_5555_5555_5555]);
+    let mut values: Vec<_> = values.into_iter().map(|x| truncate(dtype, x)).collect();
+    values.sort_unstable();
+    values.dedup();
+    values
+}
+
+/// The values next to the largest multiples of `c` and of `-c`, where the quotient steps
+fn around_multiples(dtype: DataType, c: u64) -> Vec<u64> {
+    let max = truncate(dtype, u64::MAX);
+    let mut values = Vec::new();
+    for c in [c, c.wrapping_neg()] {
+        let c = truncate(dtype, c);
+        if c == 0 {
+            continue;
+        }
+        let multiple = max / c * c;
+        for x in [c, multiple, multiple.wrapping_neg()] {
+            values.extend([x.wrapping_sub(1), x, x.wrapping_add(1)]);
+        }
+    }
+    values
+}
+
+#[test]
+fn every_8_bit_division_and_remainder() {
+    for dtype in [DataType::I8, DataType::U8] {
+        for op in [Op::Div, Op::Rem] {
+            for c in 0..=0xFF {
+                check(dtype, op, c, 0..=0xFF);
+            }
+        }
+    }
+}
+
+#[test]
+fn every_16_bit_divisor() {
+    for dtype in [DataType::I16, DataType::U16] {
+        for op in [Op::Div, Op::Rem] {
+            for c in 0..=0xFFFF {
+                check(dtype, op, c, [0, 1, 0x7FFF, 0x8000, 0xFFFF]);
+                check(dtype, op, c, around_multiples(dtype, c));
+            }
+        }
+    }
+}
+
+#[test]
+fn edge_divisors_of_every_width() {
+    let types = [
+        DataType::I16,
+        DataType::U16,
+        DataType::I32,
+        DataType::U32,
+        DataType::I64,
+        DataType::U64,
+    ];
+    for dtype in types {
+        let edges = edge_values(dtype);
+        let xs = |c| edges.iter().copied().chain(around_multiples(dtype, c));
+        for op in [Op::Div, Op::Rem] {
+            for &c in &edges {
+                check(dtype, op, c, xs(c));
+            }
+            for k in 0..64 {
+                check(dtype, op, 1 << k, xs(1 << k));
+                check(
+                    dtype,
+                    op,
+                    (1u64 << k).wrapping_neg(),
+                    xs((1u64 << k).wrapping_neg()),
+                );
+            }
+        }
+    }
+}
+
+/// Divisors biased towards powers of two, their negations and neighbours, and small values
+fn divisor() -> impl Strategy<Value = u64> {
+    prop_oneof![
+        any::<u64>(),
+        (0u32..64, -1i64..=1, any::<bool>()).prop_map(|(k, delta, negate)| {
+            let c = (1u64 << k).wrapping_add(delta as u64);
+            if negate {
+                c.wrapping_neg()
+            } else {
+                c
+            }
+        }),
+        (-1000i64..1000).prop_map(|c| c as u64),
+    ]
+}
+
+fn data_type() -> impl Strategy<Value = DataType> {
+    prop_oneof![
+        Just(DataType::I16),
+        Just(DataType::U16),
+        Just(DataType::I32),
+        Just(DataType::U32),
+        Just(DataType::I64),
+        Just(DataType::U64),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn sampled_divisions_and_remainders(
+        dtype in data_type(),
+        c in divisor(),
+        xs in proptest::collection::vec(any::<u64>(), 1..16),
+    ) {
+        check(dtype, Op::Div, c, xs.iter().copied());
+        check(dtype, Op::Rem, c, xs);
+    }
+}
@@ -0,0 +1,103 @@
+//! Marking calls in tail position, and lowering them into jumps
+
+mod common;
+
+use common::{parse, run_passes};
+
+use mir::{
+    compile::{compile, CompileOptions, OptLevel},
+    fileformat::FileFormat,
+};
+
+#[test]
+fn calls_in_tail_position_are_marked() {
+    let source = "
+extern @g(i64)
+extern @h()
+fn @returned(i64) {
+    ret i64 call @g(i64 #0)
+}
+fn @statement() {
+    call @h()
+    ret
+}
+fn @through_vreg(i64) {
+    %1 = i64 call @g(i64 #0)
+    ret i64 %1
+}
+";
+    let expected = "\
+extern @g(i64)
+extern @h()
+fn @returned(i64) {
+    ret i64 tail call @g(i64 #0)
+}
+fn @statement() {
+    tail call @h()
+    ret
+}
+fn @through_vreg(i64) {
+    %1 = i64 tail call @g(i64 #0)
+    ret i64 %1
+}
+";
+    assert_eq!(run_passes(source, &["tail-call"]), expected);
+}
+
+/// The result is used after the call, the seventh integer argument goes on the stack, and `@g`
+/// may read the slot after the frame is gone
+#[test]
+fn calls_that_cant_be_jumps_are_left_alone() {
+    let source = "
+extern @g(i64)
+extern @many(i64 i64 i64 i64 i64 i64 i64)
+extern @keep(ptr)
+fn @used_after(i64) {
+    %1 = i64 call @g(i64 #0)
+    %2 = i64 + i64 %1 i64 $1
+    ret i64 %2
+}
+fn @stack_args(i64) {
+    ret i64 call @many(i64 #0 i64 #0 i64 #0 i64 #0 i64 #0 i64 #0 i64 #0)
+}
+fn @escaping(i64) {
+    %1 = alloc i64
+    i64 [%1] = i64 #0
+    ret i64 call @keep(ptr %1)
+}
+";
+    assert_eq!(run_passes(source, &["tail-call"]), source.trim_start());
+}
+
+/// Compile at `opt_level` into assembly for ELF
+fn asm(source: &str, opt_level: OptLevel) -> String {
+    let options = CompileOptions {
+        opt_level,
+        file_format: FileFormat::Elf64,
+    };
+    compile(parse(source), &options)
+}
+
+/// The frame is torn down before jumping to `@g`, which returns straight to the caller of `@f`
+#[test]
+fn returned_calls_become_jumps() {
+    let source = "
+extern @g(i64)
+fn @f(i64) {
+    %1 = i64 + i64 #0 i64 $1
+    ret i64 call @g(i64 %1)
+}
+";
+    let optimized = asm(source, OptLevel::O1);
+    assert!(optimized.contains("\tjmp\tg wrt ..plt\n"), "{optimized}");
+    assert!(!optimized.contains("\tcall\t"), "{optimized}");
+    // The jump comes right after the frame is popped
+    assert!(optimized.contains("\tpop\trbp\n\tjmp\tg"), "{optimized}");
+
+    let unoptimized = asm(source, OptLevel::O0);
+    assert!(
+        unoptimized.contains("\tcall\tg wrt ..plt\n"),
+        "{unoptimized}"
+    );
+    assert!(!unoptimized.contains("\tjmp\tg"), "{unoptimized}");
+}
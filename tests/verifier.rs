@@ -0,0 +1,835 @@
+//! Programs that break a rule of the IR, and the errors the verifier reports for them
+
+use std::rc::Rc;
+
+use mir::{
+    ir::{Callee, DataType, Instruction, SymbolAttrs, TopLevel},
+    parser::{parse_string_into_tokens, parse_tokens_into_ir},
+    verifier::verify,
+};
+
+/// Verify `source`, returns the symbol, index and message of every error
+fn errors(source: &str) -> Vec<(String, Option<usize>, String)> {
+    program_errors(&parse_tokens_into_ir(parse_string_into_tokens(
+        source.to_string(),
+    )))
+}
+
+fn program_errors(program: &[TopLevel]) -> Vec<(String, Option<usize>, String)> {
+    match verify(program) {
+        Ok(()) => Vec::new(),
+        Err(errors) => errors
+            .into_iter()
+            .map(|error| (error.symbol.to_string(), error.index, error.message))
+            .collect(),
+    }
+}
+
+/// A function `@f` with `body`, for what the parser can't express
+fn function(body: Vec<Instruction>) -> Vec<TopLevel> {
+    vec![TopLevel::Fn {
+        name: Rc::new("f".to_string()),
+        attrs: SymbolAttrs::default(),
+        args: Vec::new(),
+        is_variadic: false,
+        body,
+    }]
+}
+
+fn error(symbol: &str, index: Option<usize>, message: &str) -> (String, Option<usize>, String) {
+    (symbol.to_string(), index, message.to_string())
+}
+
+#[test]
+fn not_is_rejected() {
+    let source = "
+fn @f(i64) {
+    %1 = i64 ~ i64 #0 i64 $1
+    ret i64 %1
+}
+";
+    assert_eq!(
+        errors(source),
+        [error(
+            "f",
+            Some(0),
+            "`~` is not supported by the backend yet"
+        )]
+    );
+}
+
+#[test]
+fn valid_program_has_no_errors() {
+    let source = "
+type %Pair = { i64, f64 }
+extern @printf(ptr ...)
+global @count: i64 = 0
+fn @f(i64 f64) {
+:entry
+    %1 = alloc %Pair
+    %2 = ptr field %Pair %1 $1
+    f64 [%2] = f64 #1
+    %3 = ptr @count
+    br u8 < i64 #0 i64 $0 :negative :done
+:negative
+    %4 = i64 - i64 $0 i64 #0
+    jmp :done
+:done
+    %5 = i64 phi [:negative i64 %4] [:entry i64 #0]
+    i64 [%3] = i64 %5
+    %6 = i32 call @printf(ptr c\"%d\" i64 %5)
+    ret f64 [%2]
+}
+";
+    assert_eq!(errors(source), []);
+}
+
+#[test]
+fn symbols_are_defined_once() {
+    let source = "
+extern @f()
+global @f: i64 = 0
+type %T = { i64 }
+type %T = { i32 }
+";
+    assert_eq!(
+        errors(source),
+        [
+            error("f", None, "`@f` is defined more than once"),
+            error("T", None, "struct type `%T` is defined more than once"),
+        ]
+    );
+}
+
+#[test]
+fn struct_types_are_defined() {
+    let source = "
+type %T = { i64, [2 x %U] }
+global @g: %V
+fn @f() {
+    %1 = alloc %W
+    ret
+}
+";
+    assert_eq!(
+        errors(source),
+        [
+            error("T", None, "struct type `%U` is not defined"),
+            error("g", None, "struct type `%V` is not defined"),
+            error("f", Some(0), "struct type `%W` is not defined"),
+        ]
+    );
+}
+
+/// `%B` and `%C` contain each other, `%D` only contains them, and an array of `%E` is as
+/// infinite as `%E` itself
+#[test]
+fn struct_types_do_not_contain_themselves() {
+    let source = "
+type %A = { i32, %A }
+type %B = { %C }
+type %C = { i64, %B }
+type %D = { %B, %B }
+type %E = { [2 x %E] }
+type %F = { i32, [4 x %A] }
+";
+    assert_eq!(
+        errors(source),
+        [
+            error("A", None, "struct type `%A` contains itself"),
+            error("B", None, "struct type `%B` contains itself"),
+            error("C", None, "struct type `%C` contains itself"),
+            error("E", None, "struct type `%E` contains itself"),
+        ]
+    );
+}
+
+/// Each of the problems is reported once, before anything computes a layout
+#[test]
+fn broken_struct_types_are_reported_together() {
+    let source = "
+type %T = { i64, %T }
+type %T = { i32 }
+type %U = { %V }
+fn @f() {
+    %1 = alloc %T
+    ret
+}
+";
+    assert_eq!(
+        errors(source),
+        [
+            error("T", None, "struct type `%T` is defined more than once"),
+            error("T", None, "struct type `%T` contains itself"),
+            error("U", None, "struct type `%V` is not defined"),
+        ]
+    );
+}
+
+#[test]
+fn fields_exist() {
+    let source = "
+type %Pair = { i64, i64 }
+fn @f() {
+    %1 = alloc [4 x %Pair]
+    %2 = ptr field [4 x %Pair] %1 $3
+    %3 = ptr field %Pair %2 $1
+    %4 = ptr field [4 x %Pair] %1 $4
+    %5 = ptr field %Pair %2 $2
+    ret
+}
+";
+    assert_eq!(
+        errors(source),
+        [
+            error(
+                "f",
+                Some(3),
+                "field 4 is out of bounds for an array of 4 elements"
+            ),
+            error("f", Some(4), "struct type `%Pair` has no field 2"),
+        ]
+    );
+}
+
+#[test]
+fn globals_are_defined() {
+    let source = "
+fn @f() {
+    %1 = ptr @missing
+    ret ptr %1
+}
+";
+    assert_eq!(
+        errors(source),
+        [error("f", Some(0), "`@missing` is not defined")]
+    );
+}
+
+#[test]
+fn labels_are_unique() {
+    let source = "
+fn @f() {
+    jmp :a
+:a
+    jmp :a
+:a
+    ret
+}
+";
+    assert_eq!(
+        errors(source),
+        [error("f", Some(3), "label `:a` is defined more than once")]
+    );
+}
+
+#[test]
+fn jumps_go_to_existing_labels() {
+    let source = "
+fn @f(u8) {
+    br u8 #0 :a :b
+:a
+    jmp :c
+}
+";
+    assert_eq!(
+        errors(source),
+        [
+            error("f", Some(0), "jump to undefined label `:b`"),
+            error("f", Some(2), "jump to undefined label `:c`"),
+        ]
+    );
+}
+
+#[test]
+fn blocks_end_with_one_terminator() {
+    let source = "
+fn @f() {
+    %1 = i64 $1
+:a
+    ret
+    %2 = i64 $2
+:b
+    %3 = i64 $3
+}
+";
+    assert_eq!(
+        errors(source),
+        [
+            error(
+                "f",
+                Some(1),
+                "the block before `:a` does not end with a terminator"
+            ),
+            error(
+                "f",
+                Some(3),
+                "instruction after a terminator, start a new block with a label"
+            ),
+            error(
+                "f",
+                Some(4),
+                "the block before `:b` does not end with a terminator"
+            ),
+            error("f", None, "the last block does not end with a terminator"),
+        ]
+    );
+}
+
+#[test]
+fn vregs_are_defined_once_and_before_use() {
+    let source = "
+fn @f() {
+    %1 = i64 + i64 %2 i64 %3
+    %2 = i64 $1
+    %2 = i64 $2
+    ret i64 %1
+}
+";
+    assert_eq!(
+        errors(source),
+        [
+            error("f", Some(0), "`%2` is used before its definition"),
+            error("f", Some(0), "`%3` is not defined"),
+            error("f", Some(2), "`%2` is defined more than once"),
+        ]
+    );
+}
+
+#[test]
+fn vregs_are_used_with_their_type() {
+    let source = "
+fn @f() {
+    %1 = i64 $1
+    ret i32 %1
+}
+";
+    assert_eq!(
+        errors(source),
+        [error("f", Some(1), "`%1` is expected to be i64, found i32")]
+    );
+}
+
+#[test]
+fn arguments_exist_and_have_their_type() {
+    let source = "
+fn @f(i64) {
+    %1 = i32 #0
+    %2 = i64 #1
+    ret
+}
+";
+    assert_eq!(
+        errors(source),
+        [
+            error("f", Some(0), "argument #0 is expected to be i64, found i32"),
+            error(
+                "f",
+                Some(1),
+                "argument #1 is out of range, `@f` has 1 arguments"
+            ),
+        ]
+    );
+}
+
+#[test]
+fn arithmetic_operands_have_the_result_type() {
+    let source = "
+fn @f() {
+    %1 = i64 + i32 $1 i64 $2
+    %2 = i64 * i64 $1 i8 $2
+    ret
+}
+";
+    assert_eq!(
+        errors(source),
+        [
+            error(
+                "f",
+                Some(0),
+                "left hand side is expected to be i64, found i32"
+            ),
+            error(
+                "f",
+                Some(1),
+                "right hand side is expected to be i64, found i8"
+            ),
+        ]
+    );
+}
+
+#[test]
+fn integer_and_bitwise_operations_are_not_done_on_floats() {
+    let source = "
+fn @f() {
+    %1 = f64 % f64 $1.0 f64 $2.0
+    %2 = f32 & f32 $1.0 f32 $2.0
+    %3 = f64 << f64 $1.0 f64 $2.0
+    ret
+}
+";
+    assert_eq!(
+        errors(source),
+        [
+            error("f", Some(0), "integer arithmetic cannot be done on f64"),
+            error("f", Some(1), "bitwise operation cannot be done on f32"),
+            error("f", Some(2), "bitwise operation cannot be done on f64"),
+        ]
+    );
+}
+
+#[test]
+fn comparisons_give_integers_from_operands_of_one_type() {
+    let source = "
+fn @f() {
+    %1 = f64 < i64 $1 i64 $2
+    %2 = u8 == i64 $1 i32 $2
+    ret
+}
+";
+    assert_eq!(
+        errors(source),
+        [
+            error(
+                "f",
+                Some(0),
+                "result of a comparison must be an integer, found f64"
+            ),
+            error(
+                "f",
+                Some(1),
+                "comparing operands of different types i64 and i32"
+            ),
+        ]
+    );
+}
+
+#[test]
+fn float_literals_have_a_float_type() {
+    let program = function(vec![Instruction::Ret(Some(Box::new(Instruction::Float(
+        DataType::I64,
+        1.5,
+    ))))]);
+    assert_eq!(
+        program_errors(&program),
+        [error("f", Some(0), "float literal cannot be of type i64")]
+    );
+}
+
+#[test]
+fn memory_is_accessed_through_ptrs() {
+    let source = "
+fn @f() {
+    %1 = i64 $0
+    %2 = i64 [%1]
+    i64 [%1] = i64 $1
+    %3 = ptr elem [4 x i64] %1 i64 $0
+    ret
+}
+";
+    assert_eq!(
+        errors(source),
+        [
+            error("f", Some(1), "`%1` is used as a pointer, but is i64"),
+            error("f", Some(2), "`%1` is used as a pointer, but is i64"),
+            error("f", Some(3), "`%1` is used as a pointer, but is i64"),
+        ]
+    );
+}
+
+#[test]
+fn stored_values_have_the_stored_type() {
+    let source = "
+fn @f() {
+    %1 = alloc i64
+    i64 [%1] = i32 $1
+    ret
+}
+";
+    assert_eq!(
+        errors(source),
+        [error(
+            "f",
+            Some(1),
+            "stored value is expected to be i64, found i32"
+        )]
+    );
+}
+
+#[test]
+fn fields_and_elements_are_taken_from_aggregates() {
+    let source = "
+fn @f() {
+    %1 = alloc [4 x i64]
+    %2 = ptr field i64 %1 $0
+    %3 = ptr elem i64 %1 f64 $1.0
+    ret
+}
+";
+    assert_eq!(
+        errors(source),
+        [
+            error("f", Some(1), "cannot take a field of scalar type i64"),
+            error("f", Some(2), "element index must be an integer, found f64"),
+        ]
+    );
+}
+
+#[test]
+fn branch_conditions_are_integers() {
+    let source = "
+fn @f(f64) {
+    br f64 #0 :a :a
+:a
+    ret
+}
+";
+    assert_eq!(
+        errors(source),
+        [error(
+            "f",
+            Some(0),
+            "branch condition must be an integer, found f64"
+        )]
+    );
+}
+
+#[test]
+fn calls_match_the_callee() {
+    let source = "
+extern @g(i64 f64)
+extern @printf(ptr ...)
+fn @f() {
+    call @g(i64 $1)
+    call @g(i64 $1 i64 $2)
+    call @printf()
+    call @missing()
+    %2 = i64 $0
+    call ptr %2()
+    ret
+}
+";
+    assert_eq!(
+        errors(source),
+        [
+            error("f", Some(0), "`@g` expects 2 arguments, found 1"),
+            error(
+                "f",
+                Some(1),
+                "argument 1 of `@g` is expected to be f64, found i64"
+            ),
+            error(
+                "f",
+                Some(2),
+                "`@printf` expects at least 1 arguments, found 0"
+            ),
+            error(
+                "f",
+                Some(3),
+                "call to `@missing`, which is not defined or declared"
+            ),
+            error("f", Some(5), "`%2` is expected to be i64, found ptr"),
+        ]
+    );
+}
+
+#[test]
+fn calls_without_a_return_value_are_not_assigned() {
+    let mut program = parse_tokens_into_ir(parse_string_into_tokens("extern @g()".to_string()));
+    program.extend(function(vec![
+        Instruction::DefReg {
+            id: 1,
+            rhs: Box::new(Instruction::Call {
+                ret_type: None,
+                callee: Callee::Direct(Rc::new("g".to_string())),
+                args: Vec::new(),
+                is_tail: false,
+            }),
+        },
+        Instruction::Ret(None),
+    ]));
+    assert_eq!(
+        program_errors(&program),
+        [error(
+            "f",
+            Some(0),
+            "`%1` is assigned a call without a return value"
+        )]
+    );
+}
+
+#[test]
+fn function_pointers_are_ptrs() {
+    let program = function(vec![
+        Instruction::Call {
+            ret_type: None,
+            callee: Callee::Indirect(Box::new(Instruction::Int(DataType::I64, 0))),
+            args: Vec::new(),
+            is_tail: false,
+        },
+        Instruction::Ret(None),
+    ]);
+    assert_eq!(
+        program_errors(&program),
+        [error(
+            "f",
+            Some(0),
+            "function pointer is expected to be ptr, found i64"
+        )]
+    );
+}
+
+#[test]
+fn varargs_are_only_used_in_variadic_functions() {
+    let source = "
+fn @f() {
+    %1 = alloc [3 x u64]
+    va_start %1
+    %2 = i64 va_arg %1
+    va_end %1
+    ret
+}
+";
+    assert_eq!(
+        errors(source),
+        [
+            error("f", Some(1), "`@f` is not variadic"),
+            error("f", Some(2), "`@f` is not variadic"),
+            error("f", Some(3), "`@f` is not variadic"),
+        ]
+    );
+}
+
+#[test]
+fn phis_are_at_the_start_of_a_block() {
+    let source = "
+fn @f() {
+:entry
+    jmp :a
+:a
+    %1 = i64 $1
+    %2 = i64 phi [:entry i64 $0]
+    ret
+}
+";
+    assert_eq!(
+        errors(source),
+        [error(
+            "f",
+            Some(4),
+            "phi must be at the start of a block, before other instructions"
+        )]
+    );
+}
+
+#[test]
+fn phis_have_one_incoming_value_per_predecessor() {
+    let source = "
+fn @f(u8) {
+:entry
+    br u8 #0 :a :b
+:a
+    jmp :b
+:b
+    %1 = i64 phi [:entry i64 $0] [:entry i64 $1] [:b i64 $2] [:c i64 $3] [:entry i32 $4]
+    ret
+}
+";
+    assert_eq!(
+        errors(source),
+        [
+            error("f", Some(5), "more than one incoming value from `:entry`"),
+            error("f", Some(5), "`:b` is not a predecessor of this block"),
+            error("f", Some(5), "incoming label `:c` is not defined"),
+            error("f", Some(5), "more than one incoming value from `:entry`"),
+            error(
+                "f",
+                Some(5),
+                "incoming value from `:entry` is expected to be i64, found i32"
+            ),
+            error("f", Some(5), "no incoming value from predecessor `:a`"),
+        ]
+    );
+}
+
+#[test]
+fn phis_can_name_every_predecessor() {
+    let source = "
+fn @f(u8) {
+:entry
+    br u8 #0 :a :b
+:a
+    jmp :b
+    jmp :b
+:b
+    %1 = i64 phi [:entry i64 $0] [:a i64 $1]
+    ret
+}
+";
+    assert_eq!(
+        errors(source),
+        [
+            error(
+                "f",
+                Some(4),
+                "instruction after a terminator, start a new block with a label"
+            ),
+            error(
+                "f",
+                Some(6),
+                "a predecessor without a label can't be named by phi"
+            ),
+        ]
+    );
+}
+
+#[test]
+fn statements_and_operands_are_not_mixed_up() {
+    let program = function(vec![
+        Instruction::Add(
+            DataType::I64,
+            Box::new(Instruction::Int(DataType::I64, 1)),
+            Box::new(Instruction::Int(DataType::I64, 2)),
+        ),
+        Instruction::Ret(Some(Box::new(Instruction::Ret(None)))),
+    ]);
+    assert_eq!(
+        program_errors(&program),
+        [
+            error("f", Some(0), "not a valid statement"),
+            error("f", Some(1), "a statement cannot be used as an operand"),
+        ]
+    );
+    let program = function(vec![Instruction::Ret(Some(Box::new(Instruction::Phi {
+        dtype: DataType::I64,
+        incoming: Vec::new(),
+    })))]);
+    assert_eq!(
+        program_errors(&program),
+        [error(
+            "f",
+            Some(0),
+            "phi can only be the right hand side of a definition at the start of a block"
+        )]
+    );
+}
+
+#[test]
+fn every_error_is_reported() {
+    let source = "
+global @g: i64 = 0
+global @g: i32 = 0
+fn @f(i64) {
+    %1 = i32 + i64 #0 i32 $1
+    %1 = f64 % f64 $1.0 f64 $2.0
+    jmp :nowhere
+}
+fn @h() {
+    ret i64 %5
+}
+";
+    let errors = errors(source);
+    assert_eq!(
+        errors,
+        [
+            error("g", None, "`@g` is defined more than once"),
+            error(
+                "f",
+                Some(0),
+                "left hand side is expected to be i32, found i64"
+            ),
+            error("f", Some(1), "integer arithmetic cannot be done on f64"),
+            error("f", Some(1), "`%1` is defined more than once"),
+            error("f", Some(2), "jump to undefined label `:nowhere`"),
+            error("h", Some(0), "`%5` is not defined"),
+        ]
+    );
+}
+
+#[test]
+fn tail_calls_are_returned_right_away() {
+    let source = "
+extern @g(i64)
+fn @f(i64) {
+    %1 = i64 tail call @g(i64 #0)
+    %2 = i64 + i64 %1 i64 $1
+    ret i64 %2
+}
+fn @h(i64) {
+    tail call @g(i64 #0)
+    %1 = i64 #0
+    ret
+}
+";
+    assert_eq!(
+        errors(source),
+        [
+            error("f", Some(0), "`tail call` is not returned right away"),
+            error("h", Some(0), "`tail call` is not returned right away"),
+        ]
+    );
+}
+
+/// Each form of tail position: returned directly, as a statement before `ret`, and through a
+/// vreg returned by the next instruction
+#[test]
+fn tail_calls_in_tail_position_are_valid() {
+    let source = "
+extern @g(i64 i64 i64 i64 i64 f64)
+extern @h()
+fn @f(i64) {
+    ret i64 tail call @g(i64 #0 i64 #0 i64 #0 i64 #0 i64 #0 f64 $1.0)
+}
+fn @statement() {
+    tail call @h()
+    ret
+}
+fn @through_vreg(i64) {
+    %1 = i64 tail call @g(i64 #0 i64 #0 i64 #0 i64 #0 i64 #0 f64 $1.0)
+    ret i64 %1
+}
+";
+    assert_eq!(errors(source), []);
+}
+
+/// The seventh integer argument would go on the stack, which the jump reuses for the callee's
+/// frame
+#[test]
+fn tail_calls_pass_every_argument_in_registers() {
+    let source = "
+extern @g(i64 i64 i64 i64 i64 i64 i64)
+fn @f(i64) {
+    ret i64 tail call @g(i64 #0 i64 #0 i64 #0 i64 #0 i64 #0 i64 #0 i64 #0)
+}
+";
+    assert_eq!(
+        errors(source),
+        [error(
+            "f",
+            Some(0),
+            "`tail call` has too many arguments to pass them all in registers"
+        )]
+    );
+}
+
+#[test]
+fn tail_calls_dont_pass_pointers_into_the_stack_frame() {
+    let source = "
+type %Pair = { i64, i64 }
+extern @g(ptr)
+fn @f() {
+    %1 = alloc %Pair
+    %2 = ptr field %Pair %1 $1
+    ret i64 tail call @g(ptr %2)
+}
+";
+    assert_eq!(
+        errors(source),
+        [error(
+            "f",
+            Some(2),
+            "`tail call` passes a pointer into the caller's stack frame, which is gone by the \
+             time the callee runs"
+        )]
+    );
+}